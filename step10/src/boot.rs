@@ -0,0 +1,268 @@
+//! The boot module places the boot images in the memory and sets up the boot ROM, following the
+//! RISC-V boot protocol used by the QEMU virt machine: the firmware runs at the start of the
+//! memory in M-mode, and the kernel, the initial ramdisk and the device tree are placed above it.
+
+use std::fmt;
+
+use crate::bus::*;
+use crate::fdt::*;
+use crate::machine::*;
+use crate::rom::*;
+
+/// The offset of the kernel from the start of the memory when it's loaded with a firmware. Same
+/// as the address where OpenSBI jumps to on RV64.
+pub const KERNEL_OFFSET: u64 = 0x20_0000;
+/// The largest offset of the initial ramdisk from the start of the memory.
+const INITRD_MAX_OFFSET: u64 = 512 * 1024 * 1024;
+/// The alignment of the images.
+const IMAGE_ALIGN: u64 = 4096;
+/// The alignment of the device tree. Same as QEMU, it's placed at the top of the memory in its own
+/// 2 MiB page so that the kernel doesn't map it together with other data.
+const FDT_ALIGN: u64 = 2 * 1024 * 1024;
+
+/// The images loaded in addition to the firmware.
+#[derive(Debug, Default, Clone)]
+pub struct BootImages {
+    /// The kernel started by the firmware in S-mode.
+    pub kernel: Option<Vec<u8>>,
+    /// The initial ramdisk.
+    pub initrd: Option<Vec<u8>>,
+    /// The kernel command line.
+    pub bootargs: Option<String>,
+}
+
+/// Where the boot images are placed, as `[start, end)` guest physical addresses.
+#[derive(Debug, Default, Clone)]
+pub struct BootLayout {
+    /// The address the firmware jumps to after it's done.
+    pub kernel_entry: u64,
+    pub firmware: Option<(u64, u64)>,
+    pub kernel: Option<(u64, u64)>,
+    pub initrd: Option<(u64, u64)>,
+    pub bootargs: Option<String>,
+    /// The device tree blob and where it's placed.
+    pub dtb: Vec<u8>,
+    pub fdt: (u64, u64),
+}
+
+/// An error returned when the boot images can't be placed in the memory.
+#[derive(Debug)]
+pub enum BootError {
+    /// The image doesn't fit in the memory.
+    TooLarge { image: &'static str, size: u64 },
+    /// Two images overlap.
+    Overlap {
+        image: &'static str,
+        other: &'static str,
+    },
+}
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootError::TooLarge { image, size } => {
+                write!(
+                    f,
+                    "the {} ({} bytes) doesn't fit in the memory",
+                    image, size
+                )
+            }
+            BootError::Overlap { image, other } => {
+                write!(f, "the {} overlaps the {}", image, other)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BootError {}
+
+/// Return an error if `[start, end)` overlaps any of `others`.
+fn check_overlap(
+    image: &'static str,
+    (start, end): (u64, u64),
+    others: &[(&'static str, Option<(u64, u64)>)],
+) -> Result<(), BootError> {
+    for (other, range) in others {
+        if let Some((other_start, other_end)) = range {
+            if start < *other_end && *other_start < end {
+                return Err(BootError::Overlap { image, other });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Round `addr` up to the next multiple of `IMAGE_ALIGN`.
+fn align_up(addr: u64) -> u64 {
+    addr.div_ceil(IMAGE_ALIGN) * IMAGE_ALIGN
+}
+
+/// Place `firmware` at the start of the memory of `machine`, `images` and the device tree above
+/// it, and set up the boot ROM.
+pub fn load_boot_images(
+    machine: &Machine,
+    firmware: &[u8],
+    images: BootImages,
+) -> Result<BootLayout, BootError> {
+    let bus = &machine.bus;
+    let memory_size = bus.memory().size();
+    let mut layout = BootLayout {
+        kernel_entry: MEMORY_BASE + KERNEL_OFFSET,
+        bootargs: images.bootargs,
+        ..BootLayout::default()
+    };
+
+    if !firmware.is_empty() {
+        let size = firmware.len() as u64;
+        if size > memory_size {
+            return Err(BootError::TooLarge {
+                image: "firmware",
+                size,
+            });
+        }
+        bus.memory().write_bytes(0, firmware);
+        layout.firmware = Some((MEMORY_BASE, MEMORY_BASE + size));
+    }
+
+    if let Some(kernel) = images.kernel {
+        let size = kernel.len() as u64;
+        if KERNEL_OFFSET + size > memory_size {
+            return Err(BootError::TooLarge {
+                image: "kernel",
+                size,
+            });
+        }
+        let (start, end) = (
+            MEMORY_BASE + KERNEL_OFFSET,
+            MEMORY_BASE + KERNEL_OFFSET + size,
+        );
+        check_overlap("kernel", (start, end), &[("firmware", layout.firmware)])?;
+        bus.memory().write_bytes(KERNEL_OFFSET, &kernel);
+        layout.kernel = Some((start, end));
+    }
+
+    if let Some(initrd) = images.initrd {
+        // Same as QEMU: in the middle of the memory, but not too far from the kernel.
+        let size = initrd.len() as u64;
+        let offset = align_up((memory_size / 2).min(INITRD_MAX_OFFSET));
+        if offset + size > memory_size {
+            return Err(BootError::TooLarge {
+                image: "initial ramdisk",
+                size,
+            });
+        }
+        let (start, end) = (MEMORY_BASE + offset, MEMORY_BASE + offset + size);
+        check_overlap(
+            "initial ramdisk",
+            (start, end),
+            &[("firmware", layout.firmware), ("kernel", layout.kernel)],
+        )?;
+        bus.memory().write_bytes(offset, &initrd);
+        layout.initrd = Some((start, end));
+    }
+
+    layout.dtb = generate(bus, machine.harts.len(), &layout);
+    let size = layout.dtb.len() as u64;
+    let offset = match memory_size.checked_sub(size) {
+        Some(offset) if offset >= FDT_ALIGN => offset / FDT_ALIGN * FDT_ALIGN,
+        Some(offset) => offset / 8 * 8,
+        None => {
+            return Err(BootError::TooLarge {
+                image: "device tree",
+                size,
+            })
+        }
+    };
+    layout.fdt = (MEMORY_BASE + offset, MEMORY_BASE + offset + size);
+    check_overlap(
+        "device tree",
+        layout.fdt,
+        &[
+            ("firmware", layout.firmware),
+            ("kernel", layout.kernel),
+            ("initial ramdisk", layout.initrd),
+        ],
+    )?;
+    bus.memory().write_bytes(offset, &layout.dtb);
+
+    bus.with_device(BOOT_ROM_BASE, |rom: &mut BootRom| {
+        *rom = BootRom::new(MEMORY_BASE, layout.fdt.0, layout.kernel_entry)
+    })
+    .expect("failed to get the boot ROM");
+    Ok(layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn machine(memory_size: u64) -> Machine {
+        Machine::new(Vec::new(), Vec::new(), None, memory_size, 1)
+    }
+
+    fn images(kernel: u64) -> BootImages {
+        BootImages {
+            kernel: Some(vec![0; kernel as usize]),
+            ..BootImages::default()
+        }
+    }
+
+    fn is_overlap(result: Result<BootLayout, BootError>, image: &str, other: &str) -> bool {
+        matches!(result, Err(BootError::Overlap { image: i, other: o }) if (i, o) == (image, other))
+    }
+
+    #[test]
+    fn images_are_placed_above_the_firmware() {
+        let machine = machine(64 * MIB);
+        let layout = load_boot_images(&machine, &[0x13; 4096], images(4096)).unwrap();
+        assert_eq!(layout.firmware, Some((MEMORY_BASE, MEMORY_BASE + 4096)));
+        assert_eq!(
+            layout.kernel,
+            Some((
+                MEMORY_BASE + KERNEL_OFFSET,
+                MEMORY_BASE + KERNEL_OFFSET + 4096
+            ))
+        );
+        let mut data = [0; 4];
+        machine.bus.memory().read_bytes(0, &mut data);
+        assert_eq!(data, [0x13; 4]);
+    }
+
+    #[test]
+    fn firmware_over_the_kernel_is_rejected() {
+        let machine = machine(64 * MIB);
+        let firmware = vec![0; KERNEL_OFFSET as usize + 1];
+        assert!(is_overlap(
+            load_boot_images(&machine, &firmware, images(4096)),
+            "kernel",
+            "firmware"
+        ));
+    }
+
+    #[test]
+    fn firmware_over_the_device_tree_is_rejected() {
+        // The device tree is placed at 2 MiB, the top 2 MiB page of the memory.
+        let machine = machine(4 * MIB);
+        let firmware = vec![0; 3 * MIB as usize];
+        assert!(is_overlap(
+            load_boot_images(&machine, &firmware, BootImages::default()),
+            "device tree",
+            "firmware"
+        ));
+    }
+
+    #[test]
+    fn firmware_larger_than_the_memory_is_rejected() {
+        let machine = machine(4 * MIB);
+        let firmware = vec![0; 4 * MIB as usize + 1];
+        assert!(matches!(
+            load_boot_images(&machine, &firmware, BootImages::default()),
+            Err(BootError::TooLarge {
+                image: "firmware",
+                ..
+            })
+        ));
+    }
+}
@@ -1,13 +1,49 @@
 //! The bus module contains the system bus which can access the memroy or memory-mapped peripheral
-//! devices.
+//! devices. Devices are mapped to regions of the physical address space, and more devices can be
+//! registered with `Bus::register`. Devices are advanced by `Bus::tick` and can access the memory
+//! directly through a `DmaContext` while they are ticked.
+//!
+//! The bus is shared by all harts of the machine, so it's accessed through `&self`. Each device is
+//! behind its own lock, and the memory is accessed without a lock.
 
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::sync::{atomic::Ordering, Mutex, MutexGuard, PoisonError};
+
+use crate::boot::*;
+use crate::chardev::*;
 use crate::clint::*;
+use crate::fdt::*;
+use crate::framebuffer::*;
+use crate::irq::*;
 use crate::memory::*;
 use crate::plic::*;
+use crate::power::*;
+use crate::rom::*;
+use crate::rtc::*;
+use crate::snapshot::*;
+use crate::syscon::*;
 use crate::trap::*;
 use crate::uart::*;
 use crate::virtio::*;
 
+/// The address which the boot ROM starts, same as QEMU virt machine. A hart starts executing here.
+pub const BOOT_ROM_BASE: u64 = 0x1000;
+/// The size of the boot ROM.
+pub const BOOT_ROM_SIZE: u64 = 0xf000;
+
+/// The address which the SiFive test device starts, same as QEMU virt machine. Writing to it
+/// shuts down or reboots the machine.
+pub const TEST_BASE: u64 = 0x10_0000;
+/// The size of the SiFive test device.
+pub const TEST_SIZE: u64 = 0x1000;
+
+/// The address which the Goldfish real-time clock (RTC) starts, same as QEMU virt machine.
+pub const RTC_BASE: u64 = 0x10_1000;
+/// The size of the RTC.
+pub const RTC_SIZE: u64 = 0x1000;
+
 /// The address which the core-local interruptor (CLINT) starts. It contains the timer and
 /// generates per-hart software interrupts and timer
 /// interrupts.
@@ -26,75 +62,594 @@ pub const UART_BASE: u64 = 0x1000_0000;
 /// The size of UART.
 pub const UART_SIZE: u64 = 0x100;
 
-/// The address which virtio starts.
+/// The address which the first virtio device starts. The device in the slot `n` starts at
+/// `VIRTIO_BASE + n * VIRTIO_SIZE`, same as QEMU virt machine.
 pub const VIRTIO_BASE: u64 = 0x1000_1000;
-/// The size of virtio.
+/// The size of a virtio device.
 pub const VIRTIO_SIZE: u64 = 0x1000;
+/// The number of slots for virtio devices.
+pub const VIRTIO_SLOTS: usize = 8;
+
+/// The address which the framebuffer starts, if the machine has one. The region is unused in
+/// QEMU virt machine without PCI.
+pub const FRAMEBUFFER_BASE: u64 = 0x3000_0000;
+/// The largest size of the framebuffer, so that it ends before the memory.
+pub const FRAMEBUFFER_MAX_SIZE: u64 = 0x1000_0000;
 
 /// The address which memory starts, same as QEMU virt machine.
 pub const MEMORY_BASE: u64 = 0x8000_0000;
 
-pub trait Device {
+/// A device that can be mapped to a region of the physical address space. `addr` is the offset
+/// from the start of the region and `size` is the access size in bits.
+///
+/// A device that requests interrupts holds an `IrqLine` connected to the PLIC.
+pub trait Device: Any + Send {
     fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception>;
     fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception>;
+
+    /// Put the device back to its power-on state.
+    fn reset(&mut self) {}
+
+    /// Advance the device by `cycles` cycles. Work that needs the memory, such as processing a
+    /// virtqueue, is done here through `dma`.
+    fn tick(&mut self, _cycles: u64, _dma: &mut DmaContext) {}
+
+    /// Add a node for the device mapped to `[base, base + size)` to the device tree. Devices
+    /// unknown to guests add nothing.
+    fn describe(&self, _base: u64, _size: u64, _fdt: &mut Fdt) {}
+
+    /// Save the internal state of the device to `snapshot`. Devices without state save nothing.
+    fn save(&mut self, _snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Restore the state saved by `save`. The device has the same configuration as the device
+    /// that saved it.
+    fn restore(&mut self, _snapshot: &mut SnapshotReader) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Direct memory access (DMA) to the guest physical memory for a device. Addresses are guest
+/// physical addresses, and accesses outside the memory fail with an access fault.
+pub struct DmaContext<'a> {
+    memory: &'a Memory,
 }
 
-/// The system bus.
+impl<'a> DmaContext<'a> {
+    /// Create a context to access `memory` mapped at `MEMORY_BASE`.
+    pub fn new(memory: &'a Memory) -> Self {
+        Self { memory }
+    }
+
+    /// Return the offset in the memory of `len` bytes at `addr`, or None if they are outside of
+    /// the memory.
+    fn offset(&self, addr: u64, len: u64) -> Option<u64> {
+        let offset = addr.checked_sub(MEMORY_BASE)?;
+        if self.memory.contains_bytes(offset, len) {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    /// Load `size` bits at `addr`.
+    pub fn read(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        match self.offset(addr, size / 8) {
+            Some(offset) => self
+                .memory
+                .load(offset, size)
+                .map_err(|_| Exception::LoadAccessFault(addr)),
+            None => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
+    /// Store `size` bits of `value` at `addr`.
+    pub fn write(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        match self.offset(addr, size / 8) {
+            Some(offset) => self
+                .memory
+                .store(offset, size, value)
+                .map_err(|_| Exception::StoreAMOAccessFault(addr)),
+            None => Err(Exception::StoreAMOAccessFault(addr)),
+        }
+    }
+
+    /// Copy the memory starting at `addr` to `data`.
+    pub fn read_bytes(&mut self, addr: u64, data: &mut [u8]) -> Result<(), Exception> {
+        let offset = self
+            .offset(addr, data.len() as u64)
+            .ok_or(Exception::LoadAccessFault(addr))?;
+        self.memory.read_bytes(offset, data);
+        Ok(())
+    }
+
+    /// Copy `data` to the memory starting at `addr`.
+    pub fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Result<(), Exception> {
+        let offset = self
+            .offset(addr, data.len() as u64)
+            .ok_or(Exception::StoreAMOAccessFault(addr))?;
+        self.memory.write_bytes(offset, data);
+        Ok(())
+    }
+}
+
+/// An error returned when a device can't be mapped to the bus.
+#[derive(Debug)]
+pub enum BusError {
+    /// The region has size 0 or wraps around the end of the address space.
+    InvalidRegion { base: u64, size: u64 },
+    /// The region overlaps the region of a device that has already been registered.
+    Overlap {
+        base: u64,
+        size: u64,
+        existing_base: u64,
+        existing_size: u64,
+    },
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusError::InvalidRegion { base, size } => {
+                write!(f, "invalid region {:#x} with size {:#x}", base, size)
+            }
+            BusError::Overlap {
+                base,
+                size,
+                existing_base,
+                existing_size,
+            } => write!(
+                f,
+                "region {:#x}..{:#x} overlaps region {:#x}..{:#x}",
+                base,
+                base + size,
+                existing_base,
+                existing_base + existing_size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+/// A device mapped to `[base, base + size)`.
+struct Region {
+    base: u64,
+    size: u64,
+    device: Mutex<Box<dyn Device>>,
+}
+
+impl Region {
+    /// Lock the device. A device is left as it is if a hart panicked while accessing it.
+    fn lock(&self) -> MutexGuard<'_, Box<dyn Device>> {
+        self.device.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The system bus. It holds the memory at `MEMORY_BASE` and an address map of device regions
+/// sorted by their base addresses. The memory is kept out of the map so that devices can access it
+/// while they are ticked.
 pub struct Bus {
-    clint: Clint,
-    plic: Plic,
-    pub uart: Uart,
-    pub virtio: Virtio,
     memory: Memory,
+    /// Requests to shut down or reboot the machine.
+    power: PowerControl,
+    regions: Vec<Region>,
 }
 
 impl Bus {
-    /// Create a new system bus object.
-    pub fn new(binary: Vec<u8>, disk_image: Vec<u8>) -> Bus {
-        Self {
-            clint: Clint::new(),
-            plic: Plic::new(),
-            uart: Uart::new(),
-            virtio: Virtio::new(disk_image),
-            memory: Memory::new(binary),
+    /// Create a new system bus object with the memory and the default devices mapped. The virtio
+    /// devices in `virtio_devices` are mapped to the virtio slots in order, and `framebuffer` is
+    /// mapped at `FRAMEBUFFER_BASE`. The interrupt controllers drive the interrupts of each hart
+    /// through `harts`, indexed by the hart ID.
+    pub fn new(
+        binary: Vec<u8>,
+        virtio_devices: Vec<Box<dyn VirtioDevice>>,
+        framebuffer: Option<Framebuffer>,
+        memory_size: u64,
+        harts: &[HartInterrupts],
+    ) -> Bus {
+        assert!(
+            virtio_devices.len() <= VIRTIO_SLOTS,
+            "too many virtio devices"
+        );
+        let mut bus = Self {
+            memory: Memory::new(binary, memory_size),
+            power: PowerControl::new(),
+            regions: Vec::new(),
+        };
+        let plic = Plic::new(harts);
+        let uart = Uart::new(Box::new(StdioBackend::new()), plic.irq_line(UART_IRQ));
+        let rtc = GoldfishRtc::new(plic.irq_line(RTC_IRQ));
+        let test = SifiveTest::new(bus.power.clone());
+        let virtio: Vec<Virtio> = virtio_devices
+            .into_iter()
+            .enumerate()
+            .map(|(slot, device)| Virtio::new(device, plic.irq_line(VIRTIO_IRQ + slot as u64)))
+            .collect();
+        let rom = BootRom::new(MEMORY_BASE, 0, MEMORY_BASE + KERNEL_OFFSET);
+        let default_devices: [(u64, u64, Box<dyn Device>); 6] = [
+            (BOOT_ROM_BASE, BOOT_ROM_SIZE, Box::new(rom)),
+            (TEST_BASE, TEST_SIZE, Box::new(test)),
+            (RTC_BASE, RTC_SIZE, Box::new(rtc)),
+            (CLINT_BASE, CLINT_SIZE, Box::new(Clint::new(harts.to_vec()))),
+            (PLIC_BASE, PLIC_SIZE, Box::new(plic)),
+            (UART_BASE, UART_SIZE, Box::new(uart)),
+        ];
+        for (base, size, device) in default_devices {
+            bus.register(base, size, device)
+                .expect("failed to map a default device");
+        }
+        for (slot, virtio) in virtio.into_iter().enumerate() {
+            let base = VIRTIO_BASE + slot as u64 * VIRTIO_SIZE;
+            bus.register(base, VIRTIO_SIZE, Box::new(virtio))
+                .expect("failed to map a virtio device");
+        }
+        if let Some(framebuffer) = framebuffer {
+            assert!(
+                framebuffer.size() <= FRAMEBUFFER_MAX_SIZE,
+                "the framebuffer is too large"
+            );
+            bus.register(FRAMEBUFFER_BASE, framebuffer.size(), Box::new(framebuffer))
+                .expect("failed to map the framebuffer");
+        }
+        bus
+    }
+
+    /// Map `device` to the region `[base, base + size)`. The region must not overlap any region
+    /// that has already been registered.
+    pub fn register(
+        &mut self,
+        base: u64,
+        size: u64,
+        device: Box<dyn Device>,
+    ) -> Result<(), BusError> {
+        if size == 0 || base.checked_add(size).is_none() {
+            return Err(BusError::InvalidRegion { base, size });
+        }
+        if base < MEMORY_BASE + self.memory.size() && MEMORY_BASE < base + size {
+            return Err(BusError::Overlap {
+                base,
+                size,
+                existing_base: MEMORY_BASE,
+                existing_size: self.memory.size(),
+            });
+        }
+
+        // The first region that starts after `base`. Only it and the region before it can
+        // overlap the new region because the regions are sorted and disjoint.
+        let index = self.regions.partition_point(|region| region.base <= base);
+        let neighbors = self.regions[index.saturating_sub(1)..]
+            .iter()
+            .take(if index == 0 { 1 } else { 2 });
+        for region in neighbors {
+            if base < region.base + region.size && region.base < base + size {
+                return Err(BusError::Overlap {
+                    base,
+                    size,
+                    existing_base: region.base,
+                    existing_size: region.size,
+                });
+            }
+        }
+
+        let device = Mutex::new(device);
+        self.regions.insert(index, Region { base, size, device });
+        Ok(())
+    }
+
+    /// Return the memory.
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// Return the power control of the machine.
+    pub fn power(&self) -> &PowerControl {
+        &self.power
+    }
+
+    /// Return the memory size in bytes.
+    pub fn memory_size(&self) -> u64 {
+        self.memory.size()
+    }
+
+    /// Add the nodes of all devices to the device tree.
+    pub fn describe(&self, fdt: &mut Fdt) {
+        for region in &self.regions {
+            region.lock().describe(region.base, region.size, fdt);
         }
     }
 
-    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        if CLINT_BASE <= addr && addr < CLINT_BASE + CLINT_SIZE {
-            return self.clint.load(addr, size);
+    /// Call `f` with the device of type `T` mapped at `base` and return its result, or None if
+    /// there is no such device.
+    pub fn with_device<T: Device, R>(&self, base: u64, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let region = self.regions.iter().find(|region| region.base == base)?;
+        let mut device = region.lock();
+        let device: &mut dyn Any = device.as_mut();
+        device.downcast_mut::<T>().map(f)
+    }
+
+    /// Find the region that contains `addr` by a binary search.
+    fn find(&self, addr: u64) -> Option<&Region> {
+        let index = self.regions.partition_point(|region| region.base <= addr);
+        let region = self.regions.get(index.checked_sub(1)?)?;
+        if addr - region.base < region.size {
+            Some(region)
+        } else {
+            None
         }
-        if PLIC_BASE <= addr && addr < PLIC_BASE + PLIC_SIZE {
-            return self.plic.load(addr, size);
+    }
+
+    /// Advance every device by `cycles` cycles.
+    pub fn tick(&self, cycles: u64) {
+        let mut dma = DmaContext::new(&self.memory);
+        for region in &self.regions {
+            region.lock().tick(cycles, &mut dma);
         }
-        if UART_BASE <= addr && addr < UART_BASE + UART_SIZE {
-            return self.uart.load(addr, size);
+    }
+
+    /// Save the state of every device to `snapshot`. The memory isn't saved.
+    pub fn save(&self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        snapshot.u64(self.regions.len() as u64);
+        for region in &self.regions {
+            snapshot.u64(region.base).u64(region.size);
+            snapshot.section(|snapshot| region.lock().save(snapshot))?;
         }
-        if VIRTIO_BASE <= addr && addr < VIRTIO_BASE + VIRTIO_SIZE {
-            return self.virtio.load(addr, size);
+        Ok(())
+    }
+
+    /// Restore the state of every device saved by `save`. The devices must be mapped to the same
+    /// regions as when the snapshot was taken.
+    pub fn restore(&self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        if snapshot.u64()? != self.regions.len() as u64 {
+            return Err(snapshot_error("the devices don't match"));
         }
-        if MEMORY_BASE <= addr {
-            return self.memory.load(addr, size);
+        for region in &self.regions {
+            let (base, size) = (snapshot.u64()?, snapshot.u64()?);
+            if (base, size) != (region.base, region.size) {
+                return Err(snapshot_error(&format!(
+                    "no device is mapped to {:#x}..{:#x}",
+                    base,
+                    base.wrapping_add(size)
+                )));
+            }
+            snapshot.section(|snapshot| region.lock().restore(snapshot))?;
         }
-        Err(Exception::LoadAccessFault)
+        Ok(())
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        if CLINT_BASE <= addr && addr < CLINT_BASE + CLINT_SIZE {
-            return self.clint.store(addr, size, value);
+    /// Put every device back to its power-on state. The memory is kept.
+    pub fn reset(&self) {
+        for region in &self.regions {
+            region.lock().reset();
         }
-        if PLIC_BASE <= addr && addr < PLIC_BASE + PLIC_SIZE {
-            return self.plic.store(addr, size, value);
+    }
+
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if addr.wrapping_sub(MEMORY_BASE) < self.memory.size() {
+            return self
+                .memory
+                .load(addr - MEMORY_BASE, size)
+                .map_err(|_| Exception::LoadAccessFault(addr));
         }
-        if UART_BASE <= addr && addr < UART_BASE + UART_SIZE {
-            return self.uart.store(addr, size, value);
+        match self.find(addr) {
+            Some(region) => region
+                .lock()
+                .load(addr - region.base, size)
+                .map_err(|_| Exception::LoadAccessFault(addr)),
+            None => Err(Exception::LoadAccessFault(addr)),
         }
-        if VIRTIO_BASE <= addr && addr < VIRTIO_BASE + VIRTIO_SIZE {
-            return self.virtio.store(addr, size, value);
+    }
+
+    pub fn store(&self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if addr.wrapping_sub(MEMORY_BASE) < self.memory.size() {
+            return self
+                .memory
+                .store(addr - MEMORY_BASE, size, value)
+                .map_err(|_| Exception::StoreAMOAccessFault(addr));
         }
-        if MEMORY_BASE <= addr {
-            return self.memory.store(addr, size, value);
+        match self.find(addr) {
+            Some(region) => region
+                .lock()
+                .store(addr - region.base, size, value)
+                .map_err(|_| Exception::StoreAMOAccessFault(addr)),
+            None => Err(Exception::StoreAMOAccessFault(addr)),
         }
-        Err(Exception::StoreAMOAccessFault)
+    }
+
+    /// Atomically apply `op` with `value` to `size` bits at `addr`, and return the old value. An
+    /// atomic access to a device is atomic because the device is locked during the access.
+    pub fn atomic(
+        &self,
+        addr: u64,
+        size: u64,
+        op: AmoOp,
+        value: u64,
+        ordering: Ordering,
+    ) -> Result<u64, Exception> {
+        if addr.wrapping_sub(MEMORY_BASE) < self.memory.size() {
+            return self
+                .memory
+                .atomic(addr - MEMORY_BASE, size, op, value, ordering)
+                .map_err(|_| Exception::StoreAMOAccessFault(addr));
+        }
+        let region = self
+            .find(addr)
+            .ok_or(Exception::StoreAMOAccessFault(addr))?;
+        let mut device = region.lock();
+        let old = device
+            .load(addr - region.base, size)
+            .map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+        device
+            .store(addr - region.base, size, op.apply(old, value, size))
+            .map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+        Ok(old)
+    }
+
+    /// Atomically store `new` to `size` bits at `addr` if they hold `current`. Return true if
+    /// `new` is stored.
+    pub fn compare_exchange(
+        &self,
+        addr: u64,
+        size: u64,
+        current: u64,
+        new: u64,
+        ordering: Ordering,
+    ) -> Result<bool, Exception> {
+        if addr.wrapping_sub(MEMORY_BASE) < self.memory.size() {
+            return self
+                .memory
+                .compare_exchange(addr - MEMORY_BASE, size, current, new, ordering)
+                .map_err(|_| Exception::StoreAMOAccessFault(addr));
+        }
+        let region = self
+            .find(addr)
+            .ok_or(Exception::StoreAMOAccessFault(addr))?;
+        let mut device = region.lock();
+        let old = device
+            .load(addr - region.base, size)
+            .map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+        if old != current {
+            return Ok(false);
+        }
+        device
+            .store(addr - region.base, size, new)
+            .map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A device that returns its tag and the offset of a load.
+    struct Tagged(u64);
+
+    impl Device for Tagged {
+        fn load(&mut self, addr: u64, _size: u64) -> Result<u64, Exception> {
+            Ok((self.0 << 32) | addr)
+        }
+
+        fn store(&mut self, _addr: u64, _size: u64, _value: u64) -> Result<(), Exception> {
+            Ok(())
+        }
+    }
+
+    const BASE: u64 = 0x4000_0000;
+
+    fn bus() -> Bus {
+        Bus::new(
+            Vec::new(),
+            Vec::new(),
+            None,
+            MEMORY_SIZE,
+            &[HartInterrupts::new()],
+        )
+    }
+
+    fn is_overlap(result: Result<(), BusError>, base: u64) -> bool {
+        matches!(result, Err(BusError::Overlap { existing_base, .. }) if existing_base == base)
+    }
+
+    #[test]
+    fn adjacent_regions_are_mapped() {
+        let mut bus = bus();
+        bus.register(BASE + 0x1000, 0x1000, Box::new(Tagged(2)))
+            .unwrap();
+        bus.register(BASE, 0x1000, Box::new(Tagged(1))).unwrap();
+        bus.register(BASE + 0x2000, 0x1000, Box::new(Tagged(3)))
+            .unwrap();
+        assert_eq!(bus.load(BASE + 0xfff, 8).unwrap(), (1 << 32) | 0xfff);
+        assert_eq!(bus.load(BASE + 0x1000, 8).unwrap(), 2 << 32);
+        assert_eq!(bus.load(BASE + 0x2010, 8).unwrap(), (3 << 32) | 0x10);
+        assert!(bus.load(BASE + 0x3000, 8).is_err());
+    }
+
+    #[test]
+    fn overlapping_regions_are_rejected() {
+        let mut bus = bus();
+        bus.register(BASE + 0x1000, 0x1000, Box::new(Tagged(1)))
+            .unwrap();
+        // The end, the start, the inside and the whole of the existing region.
+        for (base, size) in [
+            (BASE, 0x1001),
+            (BASE + 0x1fff, 0x1000),
+            (BASE + 0x1100, 0x100),
+            (BASE, 0x3000),
+        ] {
+            assert!(is_overlap(
+                bus.register(base, size, Box::new(Tagged(2))),
+                BASE + 0x1000
+            ));
+        }
+        // A region over the default devices.
+        assert!(is_overlap(
+            bus.register(UART_BASE + 0x80, 0x100, Box::new(Tagged(2))),
+            UART_BASE
+        ));
+        assert_eq!(bus.load(BASE + 0x1100, 8).unwrap(), (1 << 32) | 0x100);
+    }
+
+    #[test]
+    fn regions_over_the_memory_are_rejected() {
+        let mut bus = bus();
+        let end = MEMORY_BASE + MEMORY_SIZE;
+        assert!(is_overlap(
+            bus.register(MEMORY_BASE - 0x1000, 0x1001, Box::new(Tagged(1))),
+            MEMORY_BASE
+        ));
+        assert!(is_overlap(
+            bus.register(end - 1, 0x1000, Box::new(Tagged(1))),
+            MEMORY_BASE
+        ));
+        bus.register(MEMORY_BASE - 0x1000, 0x1000, Box::new(Tagged(1)))
+            .unwrap();
+        bus.register(end, 0x1000, Box::new(Tagged(2))).unwrap();
+        assert_eq!(bus.load(end, 8).unwrap(), 2 << 32);
+    }
+
+    #[test]
+    fn invalid_regions_are_rejected() {
+        let mut bus = bus();
+        assert!(matches!(
+            bus.register(BASE, 0, Box::new(Tagged(1))),
+            Err(BusError::InvalidRegion { .. })
+        ));
+        assert!(matches!(
+            bus.register(u64::MAX - 0xfff, 0x2000, Box::new(Tagged(1))),
+            Err(BusError::InvalidRegion { .. })
+        ));
+    }
+
+    #[test]
+    fn accesses_past_the_end_of_the_memory_fault() {
+        let bus = bus();
+        let end = MEMORY_BASE + MEMORY_SIZE;
+        bus.store(end - 8, 64, 0x0102_0304_0506_0708).unwrap();
+        assert_eq!(bus.load(end - 8, 64).unwrap(), 0x0102_0304_0506_0708);
+
+        // Just past the end, and straddling the end.
+        for (addr, size) in [(end, 8), (end, 64), (end - 4, 64), (end - 1, 16)] {
+            assert!(matches!(
+                bus.load(addr, size),
+                Err(Exception::LoadAccessFault(a)) if a == addr
+            ));
+            assert!(matches!(
+                bus.store(addr, size, 0),
+                Err(Exception::StoreAMOAccessFault(a)) if a == addr
+            ));
+        }
+        assert!(matches!(
+            bus.atomic(end - 4, 64, AmoOp::Add, 1, Ordering::SeqCst),
+            Err(Exception::StoreAMOAccessFault(_))
+        ));
+        // The bytes before the end are untouched by the failed stores.
+        assert_eq!(bus.load(end - 8, 64).unwrap(), 0x0102_0304_0506_0708);
+
+        let mut dma = DmaContext::new(bus.memory());
+        let mut data = [0; 16];
+        assert!(dma.read_bytes(end - 8, &mut data).is_err());
+        assert!(dma.write_bytes(end - 8, &data).is_err());
     }
 }
@@ -0,0 +1,113 @@
+//! The chardev module contains the backends that connect a character device, e.g. the UART or a
+//! port of the virtio console, to the host. The standard input is read on its own thread, so that
+//! devices can check for input without blocking.
+
+use std::fs::File;
+use std::io::{self, prelude::*};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::thread;
+
+/// The number of bytes read from the standard input and not taken by a device yet.
+const STDIN_BUFFER_SIZE: usize = 4096;
+
+/// A backend that sends and receives bytes.
+pub trait CharBackend: Send {
+    /// Send `data` to the host.
+    fn write(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Receive a byte from the host without blocking, or None if no byte has arrived.
+    fn read(&mut self) -> Option<u8>;
+}
+
+/// Return the bytes read from the standard input. The reading thread is started on the first
+/// call, and the bytes are shared by all stdio backends.
+fn stdin() -> &'static Mutex<Receiver<u8>> {
+    static STDIN: OnceLock<Mutex<Receiver<u8>>> = OnceLock::new();
+    STDIN.get_or_init(|| {
+        let (sender, receiver) = mpsc::sync_channel(STDIN_BUFFER_SIZE);
+        thread::spawn(move || {
+            let mut byte = [0; 1];
+            loop {
+                match io::stdin().read(&mut byte) {
+                    // Stop reading when the standard input reaches EOF.
+                    Ok(0) => return,
+                    Ok(_) => {
+                        // Wait while the buffer is full.
+                        if sender.send(byte[0]).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        println!("{}", e);
+                    }
+                }
+            }
+        });
+        Mutex::new(receiver)
+    })
+}
+
+/// A backend on the standard input and output.
+pub struct StdioBackend;
+
+impl StdioBackend {
+    pub fn new() -> Self {
+        stdin();
+        Self
+    }
+}
+
+impl CharBackend for StdioBackend {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        stdout.write_all(data)?;
+        stdout.flush()
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        stdin()
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .try_recv()
+            .ok()
+    }
+}
+
+/// A backend that writes to a file and never receives a byte.
+pub struct FileBackend {
+    file: File,
+}
+
+impl FileBackend {
+    /// Create the file at `path`, or truncate it if it exists.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: File::create(path)?,
+        })
+    }
+}
+
+impl CharBackend for FileBackend {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+/// A backend that connects nothing. Sent bytes are dropped and no byte arrives.
+pub struct NullCharBackend;
+
+impl CharBackend for NullCharBackend {
+    fn write(&mut self, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        None
+    }
+}
@@ -2,60 +2,294 @@
 //! block holds memory-mapped control and status registers associated with
 //! software and timer interrupts. It generates per-hart software interrupts and timer.
 
+use std::io;
+
 use crate::bus::*;
+use crate::cpu::*;
+use crate::fdt::*;
+use crate::irq::*;
+use crate::snapshot::*;
 use crate::trap::*;
 
-/// The address of a mtimecmp register starts. A mtimecmp is a memory mapped machine mode timer
-/// compare register, used to trigger an interrupt when mtimecmp is greater than or equal to mtime.
-pub const CLINT_MTIMECMP: u64 = CLINT_BASE + 0x4000;
-/// The address of a timer register. A mtime is a machine mode timer register which runs at a
-/// constant frequency.
-pub const CLINT_MTIME: u64 = CLINT_BASE + 0xbff8;
+/// The offset of the msip registers, 4 bytes per hart. Writing 1 to the lowest bit raises a
+/// machine-mode software interrupt, and writing 0 clears it.
+pub const CLINT_MSIP: u64 = 0x0;
+/// The offset of the mtimecmp registers, 8 bytes per hart. A mtimecmp is a memory mapped machine
+/// mode timer compare register, used to trigger an interrupt when mtimecmp is greater than or
+/// equal to mtime.
+pub const CLINT_MTIMECMP: u64 = 0x4000;
+/// The offset of a timer register. A mtime is a machine mode timer register which runs at a
+/// constant frequency. It's shared by all harts.
+pub const CLINT_MTIME: u64 = 0xbff8;
+
+/// The frequency of mtime advertised to the guest. Same as QEMU virt machine.
+pub const CLINT_TIMEBASE_FREQUENCY: u64 = 10_000_000;
 
 /// The core-local interruptor (CLINT).
 pub struct Clint {
     mtime: u64,
-    mtimecmp: u64,
+    /// The timer comparators, indexed by the hart ID.
+    mtimecmp: Vec<u64>,
+    /// The software interrupt bits, indexed by the hart ID.
+    msip: Vec<u64>,
+    /// The wires to the harts, indexed by the hart ID.
+    harts: Vec<HartInterrupts>,
 }
 
 impl Device for Clint {
     fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
         match size {
+            32 => Ok(self.load32(addr)),
             64 => Ok(self.load64(addr)),
-            _ => Err(Exception::LoadAccessFault),
+            _ => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
     fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         match size {
-            64 => Ok(self.store64(addr, value)),
-            _ => Err(Exception::StoreAMOAccessFault),
+            32 => {
+                self.store32(addr, value);
+                Ok(())
+            }
+            64 => {
+                self.store64(addr, value);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.mtime = 0;
+        self.mtimecmp.fill(u64::MAX);
+        self.msip.fill(0);
+        self.update();
+    }
+
+    fn tick(&mut self, cycles: u64, _dma: &mut DmaContext) {
+        // The timer runs at the frequency of instructions.
+        self.mtime = self.mtime.wrapping_add(cycles);
+        self.update();
+    }
+
+    fn describe(&self, base: u64, size: u64, fdt: &mut Fdt) {
+        fdt.begin_node(&format!("clint@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_reg(base, size);
+        // The machine software interrupt and the machine timer interrupt of each hart.
+        let interrupts: Vec<u32> = (0..self.harts.len())
+            .flat_map(|hart| [cpu_intc_phandle(hart), 3, cpu_intc_phandle(hart), 7])
+            .collect();
+        fdt.property_u32s("interrupts-extended", &interrupts);
+        fdt.end_node();
+    }
+
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        snapshot.u64(self.mtime).u64(self.harts.len() as u64);
+        for (mtimecmp, msip) in self.mtimecmp.iter().zip(&self.msip) {
+            snapshot.u64(*mtimecmp).u64(*msip);
         }
+        Ok(())
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        self.mtime = snapshot.u64()?;
+        if snapshot.u64()? != self.harts.len() as u64 {
+            return Err(snapshot_error("the number of harts doesn't match"));
+        }
+        for hart in 0..self.harts.len() {
+            self.mtimecmp[hart] = snapshot.u64()?;
+            self.msip[hart] = snapshot.u64()?;
+        }
+        self.update();
+        Ok(())
     }
 }
 
 impl Clint {
-    /// Create a new `Clint` object.
-    pub fn new() -> Self {
-        Self {
+    /// Create a new `Clint` object that drives the software and timer interrupts of `harts`,
+    /// indexed by the hart ID.
+    pub fn new(harts: Vec<HartInterrupts>) -> Self {
+        let mut clint = Self {
             mtime: 0,
-            mtimecmp: 0,
+            // No timer interrupt until software programs the comparator.
+            mtimecmp: vec![u64::MAX; harts.len()],
+            msip: vec![0; harts.len()],
+            harts,
+        };
+        clint.update();
+        clint
+    }
+
+    /// Return the current time.
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    /// Drive MSIP and MTIP of every hart.
+    fn update(&mut self) {
+        for (hart, wires) in self.harts.iter().enumerate() {
+            wires.set(MIP_MSIP, self.msip[hart] & 1 != 0);
+            wires.set(MIP_MTIP, self.mtime >= self.mtimecmp[hart]);
+        }
+    }
+
+    /// Return the hart whose msip register contains `addr`.
+    fn msip_hart(&self, addr: u64) -> Option<usize> {
+        let hart = (addr.checked_sub(CLINT_MSIP)? / 4) as usize;
+        (hart < self.msip.len()).then_some(hart)
+    }
+
+    /// Return the hart whose mtimecmp register starts at `addr`.
+    fn mtimecmp_hart(&self, addr: u64) -> Option<usize> {
+        let hart = (addr.checked_sub(CLINT_MTIMECMP)? / 8) as usize;
+        (hart < self.mtimecmp.len()).then_some(hart)
+    }
+
+    /// Load 4 bytes. Registers wider than 4 bytes are accessed by halves.
+    fn load32(&self, addr: u64) -> u64 {
+        if let Some(hart) = self.msip_hart(addr) {
+            return self.msip[hart];
+        }
+        let value = self.load64(addr & !0x7);
+        if addr & 0x4 == 0 {
+            value & 0xffff_ffff
+        } else {
+            value >> 32
         }
     }
 
     fn load64(&self, addr: u64) -> u64 {
+        if let Some(hart) = self.msip_hart(addr) {
+            // Two msip registers in one access.
+            let high = self.msip.get(hart + 1).copied().unwrap_or(0);
+            return self.msip[hart] | (high << 32);
+        }
+        if let Some(hart) = self.mtimecmp_hart(addr) {
+            return self.mtimecmp[hart];
+        }
         match addr {
-            CLINT_MTIMECMP => self.mtimecmp,
             CLINT_MTIME => self.mtime,
             _ => 0,
         }
     }
 
+    /// Store 4 bytes. Registers wider than 4 bytes are accessed by halves.
+    fn store32(&mut self, addr: u64, value: u64) {
+        let value = value & 0xffff_ffff;
+        if let Some(hart) = self.msip_hart(addr) {
+            self.msip[hart] = value & 1;
+            self.update();
+            return;
+        }
+        let old = self.load64(addr & !0x7);
+        let new = if addr & 0x4 == 0 {
+            (old & !0xffff_ffff) | value
+        } else {
+            (old & 0xffff_ffff) | (value << 32)
+        };
+        self.store64(addr & !0x7, new);
+    }
+
     fn store64(&mut self, addr: u64, value: u64) {
-        match addr {
-            CLINT_MTIMECMP => self.mtimecmp = value,
-            CLINT_MTIME => self.mtime = value,
-            _ => {}
+        if self.msip_hart(addr).is_some() {
+            // Two msip registers in one access.
+            self.store32(addr, value);
+            if self.msip_hart(addr + 4).is_some() {
+                self.store32(addr + 4, value >> 32);
+            }
+            return;
+        }
+        if let Some(hart) = self.mtimecmp_hart(addr) {
+            self.mtimecmp[hart] = value;
+        } else if addr == CLINT_MTIME {
+            self.mtime = value;
+        }
+        self.update();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::*;
+
+    /// Return a CLINT for `harts` harts, and their wires.
+    fn clint(harts: usize) -> (Clint, Vec<HartInterrupts>) {
+        let harts: Vec<HartInterrupts> = (0..harts).map(|_| HartInterrupts::new()).collect();
+        (Clint::new(harts.clone()), harts)
+    }
+
+    fn tick(clint: &mut Clint, cycles: u64) {
+        let memory = Memory::new(Vec::new(), 0x1000);
+        clint.tick(cycles, &mut DmaContext::new(&memory));
+    }
+
+    #[test]
+    fn tick_advances_mtime_and_raises_mtip() {
+        let (mut clint, harts) = clint(1);
+        tick(&mut clint, 5);
+        assert_eq!(clint.load(CLINT_MTIME, 64).unwrap(), 5);
+        assert_eq!(harts[0].pending(), 0);
+
+        clint.store(CLINT_MTIMECMP, 64, 10).unwrap();
+        tick(&mut clint, 4);
+        assert_eq!(harts[0].pending() & MIP_MTIP, 0);
+        tick(&mut clint, 1);
+        assert_eq!(harts[0].pending() & MIP_MTIP, MIP_MTIP);
+
+        // A new comparator in the future lowers MTIP.
+        clint.store(CLINT_MTIMECMP + 4, 32, 1).unwrap();
+        assert_eq!(clint.load(CLINT_MTIMECMP, 64).unwrap(), 1 << 32 | 10);
+        assert_eq!(harts[0].pending() & MIP_MTIP, 0);
+    }
+
+    #[test]
+    fn msip_raises_the_software_interrupt() {
+        let (mut clint, harts) = clint(1);
+        clint.store(CLINT_MSIP, 32, 0xff).unwrap();
+        assert_eq!(clint.load(CLINT_MSIP, 32).unwrap(), 1);
+        assert_eq!(harts[0].pending(), MIP_MSIP);
+        clint.store(CLINT_MSIP, 32, 0).unwrap();
+        assert_eq!(harts[0].pending(), 0);
+    }
+
+    #[test]
+    fn reset_stops_the_timer_interrupt() {
+        let (mut clint, harts) = clint(1);
+        clint.store(CLINT_MTIMECMP, 64, 0).unwrap();
+        clint.store(CLINT_MSIP, 32, 1).unwrap();
+        assert_eq!(harts[0].pending(), MIP_MSIP | MIP_MTIP);
+        tick(&mut clint, 100);
+        clint.reset();
+        assert_eq!(clint.mtime(), 0);
+        assert_eq!(harts[0].pending(), 0);
+    }
+
+    #[test]
+    fn registers_are_routed_to_their_hart() {
+        let (mut clint, harts) = clint(3);
+        clint.store(CLINT_MSIP + 4, 32, 1).unwrap();
+        assert_eq!(clint.load(CLINT_MSIP, 32).unwrap(), 0);
+        assert_eq!(clint.load(CLINT_MSIP + 4, 32).unwrap(), 1);
+        let pending: Vec<u64> = harts.iter().map(HartInterrupts::pending).collect();
+        assert_eq!(pending, [0, MIP_MSIP, 0]);
+
+        for hart in 0..3 {
+            clint
+                .store(CLINT_MTIMECMP + 8 * hart, 64, 100 * (hart + 1))
+                .unwrap();
         }
+        assert_eq!(clint.load(CLINT_MTIMECMP + 16, 64).unwrap(), 300);
+        tick(&mut clint, 200);
+        let mtip: Vec<bool> = harts.iter().map(|h| h.pending() & MIP_MTIP != 0).collect();
+        assert_eq!(mtip, [true, true, false]);
+        assert_eq!(harts[1].pending() & MIP_MSIP, MIP_MSIP);
+
+        // The registers of a hart that doesn't exist are read as zero and ignored.
+        assert_eq!(clint.load(CLINT_MSIP + 12, 32).unwrap(), 0);
+        clint.store(CLINT_MSIP + 12, 32, 1).unwrap();
+        clint.store(CLINT_MTIMECMP + 24, 64, 0).unwrap();
+        assert_eq!(harts[2].pending() & MIP_MTIP, 0);
     }
 }
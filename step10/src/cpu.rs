@@ -2,12 +2,21 @@
 
 #![allow(dead_code)]
 
+use std::io;
+use std::sync::{
+    atomic::{self, Ordering},
+    Arc,
+};
+
 use crate::bus::*;
+use crate::clint::*;
+use crate::framebuffer::*;
+use crate::irq::*;
 use crate::memory::*;
-use crate::plic::*;
+use crate::power::*;
+use crate::sbi::*;
+use crate::snapshot::*;
 use crate::trap::*;
-use crate::uart::*;
-use crate::virtio::*;
 
 /// The page size (4 KiB) for the virtual memory system.
 const PAGE_SIZE: u64 = 4096;
@@ -46,6 +55,40 @@ pub const MIP_MTIP: u64 = 1 << 7;
 pub const MIP_SEIP: u64 = 1 << 9;
 pub const MIP_MEIP: u64 = 1 << 11;
 
+// MSTATUS fields.
+pub const MSTATUS_SIE: u64 = 0x00000002;
+pub const MSTATUS_MIE: u64 = 0x00000008;
+pub const MSTATUS_SPIE: u64 = 0x00000020;
+pub const MSTATUS_MPIE: u64 = 0x00000080;
+pub const MSTATUS_SPP: u64 = 0x00000100;
+pub const MSTATUS_VS: u64 = 0x00000600;
+pub const MSTATUS_MPP: u64 = 0x00001800;
+pub const MSTATUS_FS: u64 = 0x00006000;
+pub const MSTATUS_XS: u64 = 0x00018000;
+pub const MSTATUS_MPRV: u64 = 0x00020000;
+pub const MSTATUS_SUM: u64 = 0x00040000;
+pub const MSTATUS_MXR: u64 = 0x00080000;
+pub const MSTATUS_TVM: u64 = 0x00100000;
+pub const MSTATUS_TW: u64 = 0x00200000;
+pub const MSTATUS_TSR: u64 = 0x00400000;
+pub const MSTATUS_UXL: u64 = 0x3_00000000;
+pub const MSTATUS_SXL: u64 = 0xc_00000000;
+pub const MSTATUS_SD: u64 = 0x80000000_00000000;
+
+/// The position of the MPP field in the MSTATUS register.
+const MSTATUS_MPP_SHIFT: u64 = 11;
+/// The value of the UXL and SXL fields. 2 means XLEN is 64 bits.
+const XLEN_64: u64 = 2;
+
+// MTVEC and STVEC fields.
+pub const TVEC_MODE: u64 = 0b11;
+pub const TVEC_MODE_DIRECT: u64 = 0;
+pub const TVEC_MODE_VECTORED: u64 = 1;
+
+// Unprivileged counters.
+/// Timer for RDTIME instruction. It's a read-only shadow of the mtime register of the CLINT.
+pub const TIME: usize = 0xc01;
+
 // Supervisor-level CSRs.
 /// Supervisor status register.
 pub const SSTATUS: usize = 0x100;
@@ -76,6 +119,7 @@ pub const SSTATUS_XS: u64 = 0x00018000;
 pub const SSTATUS_SUM: u64 = 0x00040000;
 pub const SSTATUS_MXR: u64 = 0x00080000;
 pub const SSTATUS_UXL: u64 = 0x3_00000000;
+pub const SSTATUS_SD: u64 = 0x80000000_00000000;
 
 /// The privileged mode.
 #[derive(Debug, PartialEq, PartialOrd, Eq, Copy, Clone)]
@@ -85,9 +129,21 @@ pub enum Mode {
     Machine = 0b11,
 }
 
+impl Mode {
+    /// Decode a privilege mode held in a 2-bit field such as MSTATUS.MPP. The reserved encoding
+    /// 0b10 never reaches here because MPP is a WARL field and rejects it.
+    pub fn from_bits(bits: u64) -> Mode {
+        match bits & 0b11 {
+            0b11 => Mode::Machine,
+            0b01 => Mode::Supervisor,
+            _ => Mode::User,
+        }
+    }
+}
+
 /// Access type that is used in the virtual address translation process. It decides which exception
 /// should raises (InstructionPageFault, LoadPageFault or StoreAMOPageFault).
-#[derive(Debug, PartialEq, PartialOrd)]
+#[derive(Debug, PartialEq, PartialOrd, Copy, Clone)]
 pub enum AccessType {
     /// Raises the exception InstructionPageFault. It is used for an instruction fetch.
     Instruction,
@@ -97,8 +153,76 @@ pub enum AccessType {
     Store,
 }
 
-/// The `Cpu` struct that contains registers, a program coutner, system bus that connects
-/// peripheral devices, and control and status registers.
+impl AccessType {
+    /// Return the page-fault exception corresponding to the access type.
+    fn page_fault(&self, addr: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionPageFault(addr),
+            AccessType::Load => Exception::LoadPageFault(addr),
+            AccessType::Store => Exception::StoreAMOPageFault(addr),
+        }
+    }
+
+    /// Return the access-fault exception corresponding to the access type.
+    fn access_fault(&self, addr: u64) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionAccessFault(addr),
+            AccessType::Load => Exception::LoadAccessFault(addr),
+            AccessType::Store => Exception::StoreAMOAccessFault(addr),
+        }
+    }
+}
+
+/// How a load or store to an address that is not aligned to its size is handled.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum MisalignedAccess {
+    /// Raise LoadAddressMisaligned or StoreAMOAddressMisaligned.
+    Trap,
+    /// Split the access into byte accesses, translating each page separately.
+    Emulate,
+}
+
+// The bits of the predecessor and successor sets of a fence instruction.
+const FENCE_W: u64 = 1 << 0;
+const FENCE_R: u64 = 1 << 1;
+const FENCE_O: u64 = 1 << 2;
+const FENCE_I: u64 = 1 << 3;
+
+/// Return the host fence of a fence instruction with the predecessor set `pred` and the successor
+/// set `succ`, or None if nothing is ordered.
+fn fence_ordering(pred: u64, succ: u64) -> Option<Ordering> {
+    if pred == 0 || succ == 0 {
+        return None;
+    }
+    let writes = FENCE_W | FENCE_O;
+    let reads = FENCE_R | FENCE_I;
+    if pred & writes == 0 {
+        // Earlier reads before later accesses, e.g. `fence r,rw`.
+        Some(Ordering::Acquire)
+    } else if succ & reads == 0 {
+        // Earlier accesses before later writes, e.g. `fence rw,w`.
+        Some(Ordering::Release)
+    } else {
+        // Earlier writes before later reads need a full fence, e.g. `fence rw,rw`.
+        Some(Ordering::SeqCst)
+    }
+}
+
+/// A reservation made by a load-reserved (LR) instruction, checked by the next store-conditional
+/// (SC) instruction.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+struct Reservation {
+    /// The virtual address of the reserved word.
+    addr: u64,
+    /// The access size in bits.
+    size: u64,
+    /// The value read by the LR instruction.
+    value: u64,
+}
+
+/// The `Cpu` struct that contains the state of a hart: registers, a program coutner, control and
+/// status registers and the LR/SC reservation. The system bus that connects the memory and the
+/// peripheral devices is shared by all harts of the machine.
 pub struct Cpu {
     /// 32 64-bit integer registers.
     pub regs: [u64; 32],
@@ -106,8 +230,8 @@ pub struct Cpu {
     pub pc: u64,
     /// The current privilege mode.
     pub mode: Mode,
-    /// System bus that transfers data between CPU and peripheral devices.
-    pub bus: Bus,
+    /// System bus that transfers data between CPU and peripheral devices. It's shared by all harts.
+    pub bus: Arc<Bus>,
     /// Control and status registers. RISC-V ISA sets aside a 12-bit encoding space (csr[11:0]) for
     /// up to 4096 CSRs.
     pub csrs: Csr,
@@ -115,15 +239,119 @@ pub struct Cpu {
     pub enable_paging: bool,
     /// physical page number (PPN) × PAGE_SIZE (4096).
     pub page_table: u64,
+    /// The last traps taken by this hart.
+    pub trap_log: TrapLog,
+    /// How misaligned loads and stores are handled. Atomics always trap.
+    pub misaligned_access: MisalignedAccess,
+    /// The built-in SBI, shared by all harts. When it's set, an ecall from S-mode is handled by
+    /// the emulator.
+    pub sbi: Option<Arc<Sbi>>,
+    /// The reservation of the last LR instruction, if it hasn't been consumed by a SC instruction.
+    reservation: Option<Reservation>,
 }
 
 pub struct Csr {
     csrs: [u64; 4096],
+    /// The interrupt-pending bits driven by the CLINT and the PLIC. They are visible in MIP and
+    /// SIP on top of the bits written by software.
+    interrupts: HartInterrupts,
 }
 
 impl Csr {
-    fn new() -> Self {
-        Self { csrs: [0; 4096] }
+    /// Create the CSRs of the hart `hartid`, whose interrupts are driven through `interrupts`.
+    pub fn new(hartid: u64, interrupts: HartInterrupts) -> Self {
+        let mut csrs = [0; 4096];
+        csrs[MHARTID] = hartid;
+        // UXL and SXL are read-only and always report a 64-bit XLEN.
+        csrs[MSTATUS] = (XLEN_64 << 32) | (XLEN_64 << 34);
+        Self { csrs, interrupts }
+    }
+
+    /// Clear all CSRs to their values after reset. The hart ID and the wires are kept.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.csrs[MHARTID], self.interrupts.clone());
+    }
+
+    /// Save the CSRs that aren't zero to `snapshot`.
+    pub fn save(&self, snapshot: &mut SnapshotWriter) {
+        let csrs: Vec<(usize, u64)> = self
+            .csrs
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, value)| *value != 0)
+            .collect();
+        snapshot.u64(csrs.len() as u64);
+        for (addr, value) in csrs {
+            snapshot.u16(addr as u16).u64(value);
+        }
+    }
+
+    /// Restore the CSRs saved by `save`. The CSRs are written as they are, without the checks of
+    /// `store`, and the hart ID must not change.
+    pub fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        let mut csrs = [0; 4096];
+        for _ in 0..snapshot.u64()? {
+            let addr = snapshot.u16()? as usize;
+            let value = snapshot.u64()?;
+            *csrs
+                .get_mut(addr)
+                .ok_or_else(|| snapshot_error("invalid CSR"))? = value;
+        }
+        if csrs[MHARTID] != self.csrs[MHARTID] {
+            return Err(snapshot_error("the hart IDs don't match"));
+        }
+        self.csrs = csrs;
+        Ok(())
+    }
+
+    /// Return the wires that drive the external, timer and software interrupts of the hart.
+    pub fn interrupts(&self) -> &HartInterrupts {
+        &self.interrupts
+    }
+
+    /// Return a legal MSTATUS value for a write of `value`. Read-only fields keep their current
+    /// value and WARL fields reject illegal values.
+    fn legalize_mstatus(&self, value: u64) -> u64 {
+        let writable = MSTATUS_SIE
+            | MSTATUS_MIE
+            | MSTATUS_SPIE
+            | MSTATUS_MPIE
+            | MSTATUS_SPP
+            | MSTATUS_MPP
+            | MSTATUS_FS
+            | MSTATUS_MPRV
+            | MSTATUS_SUM
+            | MSTATUS_MXR
+            | MSTATUS_TVM
+            | MSTATUS_TW
+            | MSTATUS_TSR;
+        let old = self.csrs[MSTATUS];
+        let mut new = (old & !writable) | (value & writable);
+
+        // 3.1.6.1 Privilege and Global Interrupt-Enable Stack in mstatus register
+        // "xPP fields are WARL fields that can hold only privilege mode x and any implemented
+        // privilege mode lower than x." The encoding 2 is reserved, so keep the old MPP.
+        if (new & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT == 0b10 {
+            new = (new & !MSTATUS_MPP) | (old & MSTATUS_MPP);
+        }
+
+        // 3.1.6.2 Base ISA Control in mstatus Register
+        // UXL and SXL are hardwired because this emulator supports only RV64.
+        new = (new & !(MSTATUS_UXL | MSTATUS_SXL)) | (XLEN_64 << 32) | (XLEN_64 << 34);
+
+        // 3.1.6.6 Extension Context Status in mstatus Register
+        // "The SD bit is a read-only bit that summarizes whether either the FS, VS, or XS fields
+        // signal the presence of some dirty state."
+        if (new & MSTATUS_FS) == MSTATUS_FS
+            || (new & MSTATUS_VS) == MSTATUS_VS
+            || (new & MSTATUS_XS) == MSTATUS_XS
+        {
+            new |= MSTATUS_SD;
+        } else {
+            new &= !MSTATUS_SD;
+        }
+        new
     }
 
     pub fn load(&self, addr: usize) -> u64 {
@@ -136,11 +364,13 @@ impl Csr {
                     | SSTATUS_XS
                     | SSTATUS_SUM
                     | SSTATUS_MXR
-                    | SSTATUS_UXL;
+                    | SSTATUS_UXL
+                    | SSTATUS_SD;
                 self.csrs[MSTATUS] & mask
             }
             SIE => self.csrs[MIE] & self.csrs[MIDELEG],
-            SIP => self.csrs[MIP] & self.csrs[MIDELEG],
+            SIP => self.load(MIP) & self.csrs[MIDELEG],
+            MIP => self.csrs[MIP] | self.interrupts.pending(),
             _ => self.csrs[addr],
         }
     }
@@ -155,7 +385,20 @@ impl Csr {
                     | SSTATUS_XS
                     | SSTATUS_SUM
                     | SSTATUS_MXR;
-                self.csrs[MSTATUS] = (self.csrs[MSTATUS] & !mask) | (value & mask);
+                let value = (self.csrs[MSTATUS] & !mask) | (value & mask);
+                self.csrs[MSTATUS] = self.legalize_mstatus(value);
+            }
+            MSTATUS => self.csrs[MSTATUS] = self.legalize_mstatus(value),
+            MTVEC | STVEC => {
+                // 3.1.7 Machine Trap-Vector Base-Address Register (mtvec)
+                // The MODE field is WARL and the values greater than or equal to 2 are reserved,
+                // so keep the previous mode for them.
+                let mode = match value & TVEC_MODE {
+                    TVEC_MODE_DIRECT => TVEC_MODE_DIRECT,
+                    TVEC_MODE_VECTORED => TVEC_MODE_VECTORED,
+                    _ => self.csrs[addr] & TVEC_MODE,
+                };
+                self.csrs[addr] = (value & !TVEC_MODE) | mode;
             }
             SIE => {
                 self.csrs[MIE] =
@@ -171,21 +414,102 @@ impl Csr {
 }
 
 impl Cpu {
-    /// Create a new `Cpu` object.
-    pub fn new(binary: Vec<u8>, disk_image: Vec<u8>) -> Self {
+    /// Create a new `Cpu` object for the hart `hartid` on `bus`. The interrupt controllers of the
+    /// bus drive the interrupts of the hart through `interrupts`.
+    pub fn new(hartid: u64, bus: Arc<Bus>, interrupts: HartInterrupts) -> Self {
         // The stack pointer (SP) must be set up at first.
         let mut regs = [0; 32];
-        regs[2] = MEMORY_BASE + MEMORY_SIZE;
+        regs[2] = MEMORY_BASE + bus.memory_size();
+        let csrs = Csr::new(hartid, interrupts);
 
         Self {
             regs,
-            // The program counter starts from the start address of a memory.
-            pc: MEMORY_BASE,
+            // The program counter starts from the reset vector in the boot ROM, which jumps to the
+            // start address of a memory.
+            pc: BOOT_ROM_BASE,
             mode: Mode::Machine,
-            bus: Bus::new(binary, disk_image),
-            csrs: Csr::new(),
+            bus,
+            csrs,
             enable_paging: false,
             page_table: 0,
+            trap_log: TrapLog::new(),
+            misaligned_access: MisalignedAccess::Emulate,
+            sbi: None,
+            reservation: None,
+        }
+    }
+
+    /// Put the hart back to its power-on state. The configuration, such as `misaligned_access`,
+    /// is kept.
+    pub fn reset(&mut self) {
+        let memory_size = self.bus.memory_size();
+        self.regs = [0; 32];
+        self.regs[2] = MEMORY_BASE + memory_size;
+        self.pc = BOOT_ROM_BASE;
+        self.mode = Mode::Machine;
+        self.csrs.reset();
+        self.enable_paging = false;
+        self.page_table = 0;
+        self.trap_log = TrapLog::new();
+        self.reservation = None;
+    }
+
+    /// Save the state of the hart to `snapshot`: the registers, the CSRs, the privilege mode, the
+    /// paging state and the reservation. The trap log isn't saved.
+    pub fn save(&self, snapshot: &mut SnapshotWriter) {
+        for reg in self.regs.iter() {
+            snapshot.u64(*reg);
+        }
+        snapshot.u64(self.pc).u8(self.mode as u8);
+        self.csrs.save(snapshot);
+        snapshot.bool(self.enable_paging).u64(self.page_table);
+        snapshot.bool(self.reservation.is_some());
+        if let Some(reservation) = self.reservation {
+            snapshot
+                .u64(reservation.addr)
+                .u64(reservation.size)
+                .u64(reservation.value);
+        }
+    }
+
+    /// Restore the state of the hart saved by `save`. The trap log starts over.
+    pub fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        for reg in self.regs.iter_mut() {
+            *reg = snapshot.u64()?;
+        }
+        self.pc = snapshot.u64()?;
+        self.mode = match snapshot.u8()? {
+            0b00 => Mode::User,
+            0b01 => Mode::Supervisor,
+            0b11 => Mode::Machine,
+            _ => return Err(snapshot_error("invalid privilege mode")),
+        };
+        self.csrs.restore(snapshot)?;
+        self.enable_paging = snapshot.bool()?;
+        self.page_table = snapshot.u64()?;
+        self.reservation = if snapshot.bool()? {
+            Some(Reservation {
+                addr: snapshot.u64()?,
+                size: snapshot.u64()?,
+                value: snapshot.u64()?,
+            })
+        } else {
+            None
+        };
+        self.trap_log = TrapLog::new();
+        Ok(())
+    }
+
+    /// Return the hart ID.
+    pub fn hartid(&self) -> usize {
+        self.csrs.load(MHARTID) as usize
+    }
+
+    /// Return true if the hart has been stopped and doesn't execute instructions.
+    pub fn is_stopped(&self) -> bool {
+        match &self.sbi {
+            Some(sbi) => sbi.is_stopped(self.hartid()),
+            None => false,
         }
     }
 
@@ -199,23 +523,20 @@ impl Cpu {
         ];
         for i in (0..32).step_by(4) {
             output = format!(
-                "{}\n{}",
+                "{}\nx{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x}",
                 output,
-                format!(
-                    "x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x}",
-                    i,
-                    abi[i],
-                    self.regs[i],
-                    i + 1,
-                    abi[i + 1],
-                    self.regs[i + 1],
-                    i + 2,
-                    abi[i + 2],
-                    self.regs[i + 2],
-                    i + 3,
-                    abi[i + 3],
-                    self.regs[i + 3],
-                )
+                i,
+                abi[i],
+                self.regs[i],
+                i + 1,
+                abi[i + 1],
+                self.regs[i + 1],
+                i + 2,
+                abi[i + 2],
+                self.regs[i + 2],
+                i + 3,
+                abi[i + 3],
+                self.regs[i + 3],
             );
         }
         println!("{}", output);
@@ -224,65 +545,26 @@ impl Cpu {
     /// Print values in some csrs.
     pub fn dump_csrs(&self) {
         let output = format!(
-            "{}\n{}",
-            format!(
-                "mstatus={:>#18x} mtvec={:>#18x} mepc={:>#18x} mcause={:>#18x}",
-                self.csrs.load(MSTATUS),
-                self.csrs.load(MTVEC),
-                self.csrs.load(MEPC),
-                self.csrs.load(MCAUSE),
-            ),
-            format!(
-                "sstatus={:>#18x} stvec={:>#18x} sepc={:>#18x} scause={:>#18x}",
-                self.csrs.load(SSTATUS),
-                self.csrs.load(STVEC),
-                self.csrs.load(SEPC),
-                self.csrs.load(SCAUSE),
-            ),
+            "mstatus={:>#18x} mtvec={:>#18x} mepc={:>#18x} mcause={:>#18x}\n\
+             sstatus={:>#18x} stvec={:>#18x} sepc={:>#18x} scause={:>#18x}",
+            self.csrs.load(MSTATUS),
+            self.csrs.load(MTVEC),
+            self.csrs.load(MEPC),
+            self.csrs.load(MCAUSE),
+            self.csrs.load(SSTATUS),
+            self.csrs.load(STVEC),
+            self.csrs.load(SEPC),
+            self.csrs.load(SCAUSE),
         );
         println!("{}", output);
     }
 
     pub fn check_pending_interrupt(&mut self) -> Option<Interrupt> {
-        // 3.1.6.1 Privilege and Global Interrupt-Enable Stack in mstatus register
-        // "When a hart is executing in privilege mode x, interrupts are globally enabled when x
-        // IE=1 and globally disabled when x IE=0."
-        match self.mode {
-            Mode::Machine => {
-                // Check if the MIE bit is enabled.
-                if (self.csrs.load(MSTATUS) >> 3) & 1 == 0 {
-                    return None;
-                }
-            }
-            Mode::Supervisor => {
-                // Check if the SIE bit is enabled.
-                if (self.csrs.load(SSTATUS) >> 1) & 1 == 0 {
-                    return None;
-                }
-            }
-            _ => {}
-        }
-
-        // Check external interrupt for uart and virtio.
-        let irq;
-        if self.bus.uart.is_interrupting() {
-            irq = UART_IRQ;
-        } else if self.bus.virtio.is_interrupting() {
-            // Access disk by direct memory access (DMA). An interrupt is raised after a disk
-            // access is done.
-            Virtio::disk_access(self);
-            irq = VIRTIO_IRQ;
-        } else {
-            irq = 0;
-        }
-
-        if irq != 0 {
-            self.bus
-                .store(PLIC_SCLAIM, 32, irq)
-                .expect("failed to write an IRQ to the PLIC_SCLAIM");
-            self.csrs.store(MIP, self.csrs.load(MIP) | MIP_SEIP);
+        if self.sbi.is_some() {
+            forward_interrupts(self);
         }
 
+        // 3.1.9 Machine Interrupt Registers (mip and mie)
         // "An interrupt i will be taken if bit i is set in both mip and mie, and if interrupts are globally enabled.
         // By default, M-mode interrupts are globally enabled if the hart’s current privilege mode is less than
         // M, or if the current privilege mode is M and the MIE bit in the mstatus register is set. If bit i
@@ -290,38 +572,47 @@ impl Cpu {
         // privilege mode equals the delegated privilege mode (S or U) and that mode’s interrupt enable bit
         // (SIE or UIE in mstatus) is set, or if the current privilege mode is less than the delegated privilege
         // mode."
+        let mstatus = self.csrs.load(MSTATUS);
+        let m_enabled = self.mode < Mode::Machine || (mstatus & MSTATUS_MIE) != 0;
+        let s_enabled = self.mode < Mode::Supervisor
+            || (self.mode == Mode::Supervisor && (mstatus & MSTATUS_SIE) != 0);
 
         let pending = self.csrs.load(MIE) & self.csrs.load(MIP);
-
-        if (pending & MIP_MEIP) != 0 {
-            self.csrs.store(MIP, self.csrs.load(MIP) & !MIP_MEIP);
-            return Some(Interrupt::MachineExternalInterrupt);
-        }
-        if (pending & MIP_MSIP) != 0 {
-            self.csrs.store(MIP, self.csrs.load(MIP) & !MIP_MSIP);
-            return Some(Interrupt::MachineSoftwareInterrupt);
+        let mideleg = self.csrs.load(MIDELEG);
+        let mut enabled = 0;
+        if m_enabled {
+            enabled |= pending & !mideleg;
         }
-        if (pending & MIP_MTIP) != 0 {
-            self.csrs.store(MIP, self.csrs.load(MIP) & !MIP_MTIP);
-            return Some(Interrupt::MachineTimerInterrupt);
+        if s_enabled {
+            enabled |= pending & mideleg;
         }
-        if (pending & MIP_SEIP) != 0 {
-            self.csrs.store(MIP, self.csrs.load(MIP) & !MIP_SEIP);
-            return Some(Interrupt::SupervisorExternalInterrupt);
-        }
-        if (pending & MIP_SSIP) != 0 {
-            self.csrs.store(MIP, self.csrs.load(MIP) & !MIP_SSIP);
-            return Some(Interrupt::SupervisorSoftwareInterrupt);
-        }
-        if (pending & MIP_STIP) != 0 {
-            self.csrs.store(MIP, self.csrs.load(MIP) & !MIP_STIP);
-            return Some(Interrupt::SupervisorTimerInterrupt);
+
+        // "Multiple simultaneous interrupts destined for M-mode are handled in the following
+        // decreasing priority order: MEI, MSI, MTI, SEI, SSI, STI." Interrupts for M-mode are
+        // handled before the ones delegated to S-mode.
+        let order = [
+            (MIP_MEIP, Interrupt::MachineExternalInterrupt),
+            (MIP_MSIP, Interrupt::MachineSoftwareInterrupt),
+            (MIP_MTIP, Interrupt::MachineTimerInterrupt),
+            (MIP_SEIP, Interrupt::SupervisorExternalInterrupt),
+            (MIP_SSIP, Interrupt::SupervisorSoftwareInterrupt),
+            (MIP_STIP, Interrupt::SupervisorTimerInterrupt),
+        ];
+        for targets in [enabled & !mideleg, enabled & mideleg] {
+            for &(bit, interrupt) in &order {
+                if targets & bit != 0 {
+                    // The bits driven by the CLINT and the PLIC stay pending until the source is
+                    // cleared. The software-writable bits are consumed here.
+                    self.csrs.store(MIP, self.csrs.csrs[MIP] & !bit);
+                    return Some(interrupt);
+                }
+            }
         }
         None
     }
 
     /// Update the physical page number (PPN) and the addressing mode.
-    fn update_paging(&mut self, csr_addr: usize) {
+    pub(crate) fn update_paging(&mut self, csr_addr: usize) {
         if csr_addr != SATP {
             return;
         }
@@ -334,16 +625,27 @@ impl Cpu {
         let mode = self.csrs.load(SATP) >> 60;
 
         // Enable the SV39 paging if the value of the mode field is 8.
-        if mode == 8 {
-            self.enable_paging = true;
-        } else {
-            self.enable_paging = false;
+        self.enable_paging = mode == 8;
+    }
+
+    /// Return the privilege mode used for a memory access. Loads and stores in M-mode are
+    /// translated and protected as though the current mode were MPP when MPRV is set.
+    fn effective_mode(&self, access_type: &AccessType) -> Mode {
+        let mstatus = self.csrs.load(MSTATUS);
+        if self.mode == Mode::Machine
+            && *access_type != AccessType::Instruction
+            && (mstatus & MSTATUS_MPRV) != 0
+        {
+            return Mode::from_bits((mstatus & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT);
         }
+        self.mode
     }
 
     /// Translate a virtual address to a physical address for the paged virtual-memory system.
     pub fn translate(&mut self, addr: u64, access_type: AccessType) -> Result<u64, Exception> {
-        if !self.enable_paging {
+        // M-mode accesses are never translated.
+        let mode = self.effective_mode(&access_type);
+        if !self.enable_paging || mode == Mode::Machine {
             return Ok(addr);
         }
 
@@ -366,7 +668,10 @@ impl Cpu {
             // 2. Let pte be the value of the PTE at address a+va.vpn[i]×PTESIZE. (For Sv32,
             //    PTESIZE=4.) If accessing pte violates a PMA or PMP check, raise an access
             //    exception corresponding to the original access type.
-            pte = self.bus.load(a + vpn[i as usize] * 8, 64)?;
+            pte = self
+                .bus
+                .load(a + vpn[i as usize] * 8, 64)
+                .map_err(|_| access_type.access_fault(addr))?;
 
             // 3. If pte.v = 0, or if pte.r = 0 and pte.w = 1, stop and raise a page-fault
             //    exception corresponding to the original access type.
@@ -375,11 +680,7 @@ impl Cpu {
             let w = (pte >> 2) & 1;
             let x = (pte >> 3) & 1;
             if v == 0 || (r == 0 && w == 1) {
-                match access_type {
-                    AccessType::Instruction => return Err(Exception::InstructionPageFault),
-                    AccessType::Load => return Err(Exception::LoadPageFault),
-                    AccessType::Store => return Err(Exception::StoreAMOPageFault),
-                }
+                return Err(access_type.page_fault(addr));
             }
 
             // 4. Otherwise, the PTE is valid. If pte.r = 1 or pte.x = 1, go to step 5.
@@ -394,15 +695,39 @@ impl Cpu {
             let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
             a = ppn * PAGE_SIZE;
             if i < 0 {
-                match access_type {
-                    AccessType::Instruction => return Err(Exception::InstructionPageFault),
-                    AccessType::Load => return Err(Exception::LoadPageFault),
-                    AccessType::Store => return Err(Exception::StoreAMOPageFault),
-                }
+                return Err(access_type.page_fault(addr));
+            }
+        }
+
+        // 5. A leaf PTE has been found. Determine if the requested memory access is allowed by
+        //    the pte.r, pte.w, pte.x, and pte.u bits, given the current privilege mode and the
+        //    value of the SUM and MXR fields of the mstatus register. If not, stop and raise a
+        //    page-fault exception corresponding to the original access type.
+        let mstatus = self.csrs.load(MSTATUS);
+        let r = (pte >> 1) & 1 == 1;
+        let w = (pte >> 2) & 1 == 1;
+        let x = (pte >> 3) & 1 == 1;
+        let u = (pte >> 4) & 1 == 1;
+        let permitted = match access_type {
+            AccessType::Instruction => x,
+            // "When MXR=1, loads from pages marked either readable or executable (R=1 or X=1)
+            // will succeed."
+            AccessType::Load => r || ((mstatus & MSTATUS_MXR) != 0 && x),
+            AccessType::Store => w,
+        };
+        let privileged = match mode {
+            Mode::User => u,
+            // "When SUM=1, these accesses are permitted. SUM has no effect when page-based
+            // virtual memory is not in effect." S-mode can never execute code on U pages.
+            Mode::Supervisor => {
+                !u || ((mstatus & MSTATUS_SUM) != 0 && access_type != AccessType::Instruction)
             }
+            Mode::Machine => true,
+        };
+        if !permitted || !privileged {
+            return Err(access_type.page_fault(addr));
         }
 
-        // A leaf PTE has been found.
         let ppn = [
             (pte >> 10) & 0x1ff,
             (pte >> 19) & 0x1ff,
@@ -431,30 +756,158 @@ impl Cpu {
                 // ordinary page (4 KiB). It reduces TLB misses and improves performance.
                 Ok((ppn[2] << 30) | (vpn[1] << 21) | (vpn[0] << 12) | offset)
             }
-            _ => match access_type {
-                AccessType::Instruction => return Err(Exception::InstructionPageFault),
-                AccessType::Load => return Err(Exception::LoadPageFault),
-                AccessType::Store => return Err(Exception::StoreAMOPageFault),
-            },
+            _ => Err(access_type.page_fault(addr)),
         }
     }
 
+    /// Load a value from the virtual address `addr`.
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if addr % (size / 8) != 0 {
+            return match self.misaligned_access {
+                MisalignedAccess::Trap => Err(Exception::LoadAddressMisaligned(addr)),
+                MisalignedAccess::Emulate => self.load_misaligned(addr, size),
+            };
+        }
         let p_addr = self.translate(addr, AccessType::Load)?;
         self.bus.load(p_addr, size)
     }
 
+    /// Store a value to the virtual address `addr`.
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if addr % (size / 8) != 0 {
+            return match self.misaligned_access {
+                MisalignedAccess::Trap => Err(Exception::StoreAMOAddressMisaligned(addr)),
+                MisalignedAccess::Emulate => self.store_misaligned(addr, size, value),
+            };
+        }
         let p_addr = self.translate(addr, AccessType::Store)?;
         self.bus.store(p_addr, size, value)
     }
 
+    /// Translate every byte of a misaligned access. An access that crosses a page boundary is
+    /// translated page by page, so that a fault on either page is raised before any byte is
+    /// accessed.
+    fn translate_misaligned(
+        &mut self,
+        addr: u64,
+        size: u64,
+        access_type: AccessType,
+    ) -> Result<Vec<u64>, Exception> {
+        let bytes = size / 8;
+        let first_page_bytes = bytes.min(PAGE_SIZE - addr % PAGE_SIZE);
+        let first = self.translate(addr, access_type)?;
+        let mut p_addrs: Vec<u64> = (0..first_page_bytes).map(|i| first + i).collect();
+        if first_page_bytes < bytes {
+            let second = self.translate(addr.wrapping_add(first_page_bytes), access_type)?;
+            p_addrs.extend((0..bytes - first_page_bytes).map(|i| second + i));
+        }
+        Ok(p_addrs)
+    }
+
+    /// Load a misaligned value byte by byte in little-endian order.
+    fn load_misaligned(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let p_addrs = self.translate_misaligned(addr, size, AccessType::Load)?;
+        let mut value = 0;
+        for (i, p_addr) in p_addrs.into_iter().enumerate() {
+            let byte = self
+                .bus
+                .load(p_addr, 8)
+                .map_err(|_| Exception::LoadAccessFault(addr))?;
+            value |= byte << (i * 8);
+        }
+        Ok(value)
+    }
+
+    /// Store a misaligned value byte by byte in little-endian order.
+    fn store_misaligned(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let p_addrs = self.translate_misaligned(addr, size, AccessType::Store)?;
+        for (i, p_addr) in p_addrs.into_iter().enumerate() {
+            self.bus
+                .store(p_addr, 8, (value >> (i * 8)) & 0xff)
+                .map_err(|_| Exception::StoreAMOAccessFault(addr))?;
+        }
+        Ok(())
+    }
+
+    /// Check if the current privilege mode may access the CSR at `csr_addr`, and if the CSR is
+    /// writable when `write` is set. Raise an IllegalInstruction exception otherwise.
+    fn check_csr_access(&self, csr_addr: usize, write: bool, inst: u64) -> Result<(), Exception> {
+        // 2.1 CSR Address Mapping Conventions
+        // "The next two bits (csr[9:8]) encode the lowest privilege level that can access the
+        // CSR."
+        if (csr_addr >> 8) & 0b11 > self.mode as usize {
+            return Err(Exception::IllegalInstruction(inst));
+        }
+        // "The top two bits (csr[11:10]) indicate whether the register is read/write (00, 01, or
+        // 10) or read-only (11)." "Attempts to write a read-only register raise an
+        // illegal-instruction exception."
+        if write && (csr_addr >> 10) & 0b11 == 0b11 {
+            return Err(Exception::IllegalInstruction(inst));
+        }
+        // "When TVM=1, attempts to read or write the satp CSR or execute an SFENCE.VMA
+        // instruction while executing in S-mode will raise an illegal instruction exception."
+        if csr_addr == SATP
+            && self.mode == Mode::Supervisor
+            && (self.csrs.load(MSTATUS) & MSTATUS_TVM) != 0
+        {
+            return Err(Exception::IllegalInstruction(inst));
+        }
+        Ok(())
+    }
+
+    /// Read a CSR for a CSR instruction. TIME reads the timer of the CLINT.
+    fn load_csr(&mut self, csr_addr: usize) -> u64 {
+        match csr_addr {
+            TIME => self
+                .bus
+                .with_device(CLINT_BASE, |clint: &mut Clint| clint.mtime())
+                .unwrap_or(0),
+            _ => self.csrs.load(csr_addr),
+        }
+    }
+
+    /// Execute an instruction and take a pending interrupt. A stopped hart does nothing. The traps
+    /// taken are recorded in `trap_log`, which reports a double fault.
+    pub fn step(&mut self) {
+        if self.sbi.is_some() {
+            start_if_pending(self);
+        }
+        if self.is_stopped() {
+            return;
+        }
+
+        // 1. Fetch.
+        let inst = match self.fetch() {
+            Ok(inst) => inst,
+            Err(exception) => {
+                // Move the program counter as if the instruction had been fetched, so that the
+                // trap handler sees the address of the faulting instruction.
+                self.pc = self.pc.wrapping_add(4);
+                exception.take_trap(self);
+                return;
+            }
+        };
+
+        // 2. Add 4 to the program counter.
+        self.pc += 4;
+
+        // 3. Decode.
+        // 4. Execute.
+        if let Err(exception) = self.execute(inst) {
+            exception.take_trap(self);
+        }
+
+        if let Some(interrupt) = self.check_pending_interrupt() {
+            interrupt.take_trap(self);
+        }
+    }
+
     /// Get an instruction from the memory.
     pub fn fetch(&mut self) -> Result<u64, Exception> {
         let p_pc = self.translate(self.pc, AccessType::Instruction)?;
         match self.bus.load(p_pc, 32) {
             Ok(inst) => Ok(inst),
-            Err(_e) => Err(Exception::InstructionAccessFault),
+            Err(_e) => Err(Exception::InstructionAccessFault(self.pc)),
         }
     }
 
@@ -515,11 +968,27 @@ impl Cpu {
                 }
             }
             0x0f => {
-                // A fence instruction does nothing because this emulator executes an
-                // instruction sequentially on a single thread.
                 match funct3 {
-                    0x0 => {} // fence
-                    _ => {}
+                    0x0 => {
+                        // fence
+                        // Order the memory accesses of the hart as seen by the other harts with a
+                        // host fence. The accesses to the memory are relaxed host atomics, so a
+                        // host fence gives the ordering of the predecessor and successor sets.
+                        // The device accesses are ordered anyway because each device is locked.
+                        let pred = (inst >> 24) & 0xf;
+                        let succ = (inst >> 20) & 0xf;
+                        if let Some(ordering) = fence_ordering(pred, succ) {
+                            atomic::fence(ordering);
+                        }
+                    }
+                    0x1 => {
+                        // fence.i
+                        // Zifencei: synchronize the instruction and data streams. Nothing to
+                        // do because `fetch` reads every instruction from the bus and the
+                        // emulator keeps no decoded-instruction cache, so stores to code pages
+                        // are always visible to the next fetch.
+                    }
+                    _ => return Err(Exception::IllegalInstruction(inst)),
                 }
             }
             0x13 => {
@@ -538,6 +1007,17 @@ impl Cpu {
                     }
                     0x2 => {
                         // slti
+                        if rd == 0 && rs1 == 0 && imm == SNAPSHOT_HINT {
+                            // A hint for custom use, which requests a snapshot of the machine.
+                            self.bus.power().request(PowerRequest::Snapshot);
+                        }
+                        if rd == 0 && rs1 == 0 && imm == FRAME_DUMP_HINT {
+                            // A hint for custom use, which requests a dump of the framebuffer.
+                            self.bus
+                                .with_device(FRAMEBUFFER_BASE, |fb: &mut Framebuffer| {
+                                    fb.request_dump()
+                                });
+                        }
                         self.regs[rd] = if (self.regs[rs1] as i64) < (imm as i64) {
                             1
                         } else {
@@ -620,41 +1100,99 @@ impl Cpu {
                 // RV64A: “A” standard extension for atomic
                 // instructions
                 let funct5 = (funct7 & 0b1111100) >> 2;
-                let _aq = (funct7 & 0b0000010) >> 1; // acquire access
-                let _rl = funct7 & 0b0000001; // release access
-                match (funct3, funct5) {
-                    (0x2, 0x00) => {
-                        // amoadd.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, t.wrapping_add(self.regs[rs2]))?;
-                        self.regs[rd] = t;
-                    }
-                    (0x3, 0x00) => {
-                        // amoadd.d
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, t.wrapping_add(self.regs[rs2]))?;
-                        self.regs[rd] = t;
+                // "Setting both the aq and the rl bit on an AMO makes the sequence sequentially
+                // consistent."
+                let aq = (funct7 & 0b0000010) >> 1; // acquire access
+                let rl = funct7 & 0b0000001; // release access
+                let ordering = match (aq, rl) {
+                    (1, 1) => Ordering::SeqCst,
+                    (1, 0) => Ordering::Acquire,
+                    (0, 1) => Ordering::Release,
+                    _ => Ordering::Relaxed,
+                };
+                // "For LR and SC, the A extension requires that the address held in rs1 be
+                // naturally aligned to the size of the operand" and AMOs raise an address-
+                // misaligned exception instead of being emulated. LR is a load, so it raises a
+                // load address-misaligned exception.
+                let size = match funct3 {
+                    0x2 => 32,
+                    0x3 => 64,
+                    _ => return Err(Exception::IllegalInstruction(inst)),
+                };
+                let addr = self.regs[rs1];
+                if addr % (size / 8) != 0 {
+                    return Err(if funct5 == 0x02 {
+                        Exception::LoadAddressMisaligned(addr)
+                    } else {
+                        Exception::StoreAMOAddressMisaligned(addr)
+                    });
+                }
+                let value = match funct5 {
+                    0x02 => {
+                        // lr.w, lr.d
+                        let value = self.load(addr, size)?;
+                        if aq == 1 {
+                            atomic::fence(Ordering::Acquire);
+                        }
+                        self.reservation = Some(Reservation { addr, size, value });
+                        value
                     }
-                    (0x2, 0x01) => {
-                        // amoswap.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, self.regs[rs2])?;
-                        self.regs[rd] = t;
+                    0x03 => {
+                        // sc.w, sc.d
+                        // "SC.W conditionally writes a word in rs2 to the address in rs1: the
+                        // SC.W succeeds only if the reservation is still valid and the reservation
+                        // set contains the bytes being written." The reservation is broken when
+                        // another hart has changed the reserved value since the LR, which is
+                        // checked by a host compare-and-exchange. Same as QEMU, a change that
+                        // restores the reserved value isn't noticed.
+                        let success = match self.reservation.take() {
+                            Some(reservation)
+                                if reservation.addr == addr && reservation.size == size =>
+                            {
+                                let p_addr = self.translate(addr, AccessType::Store)?;
+                                self.bus.compare_exchange(
+                                    p_addr,
+                                    size,
+                                    reservation.value,
+                                    self.regs[rs2],
+                                    ordering,
+                                )?
+                            }
+                            _ => false,
+                        };
+                        // "SC.W writes zero to rd on success or a nonzero code on failure."
+                        !success as u64
                     }
-                    (0x3, 0x01) => {
-                        // amoswap.d
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, self.regs[rs2])?;
-                        self.regs[rd] = t;
+                    _ => {
+                        let op = match funct5 {
+                            0x00 => AmoOp::Add,
+                            0x01 => AmoOp::Swap,
+                            0x04 => AmoOp::Xor,
+                            0x08 => AmoOp::Or,
+                            0x0c => AmoOp::And,
+                            0x10 => AmoOp::Min,
+                            0x14 => AmoOp::Max,
+                            0x18 => AmoOp::Minu,
+                            0x1c => AmoOp::Maxu,
+                            _ => return Err(Exception::IllegalInstruction(inst)),
+                        };
+                        // amo<op>.w, amo<op>.d
+                        let p_addr = self.translate(addr, AccessType::Store)?;
+                        self.bus
+                            .atomic(p_addr, size, op, self.regs[rs2], ordering)?
                     }
-                    _ => {}
-                }
+                };
+                // The 32-bit instructions sign-extend the value to 64 bits.
+                self.regs[rd] = match size {
+                    32 => value as i32 as i64 as u64,
+                    _ => value,
+                };
             }
             0x33 => {
                 // "SLL, SRL, and SRA perform logical left, logical right, and arithmetic right
                 // shifts on the value in register rs1 by the shift amount held in register rs2.
                 // In RV64I, only the low 6 bits of rs2 are considered for the shift amount."
-                let shamt = ((self.regs[rs2] & 0x3f) as u64) as u32;
+                let shamt = (self.regs[rs2] & 0x3f) as u32;
                 match (funct3, funct7) {
                     (0x0, 0x00) => {
                         // add
@@ -664,6 +1202,57 @@ impl Cpu {
                         // mul
                         self.regs[rd] = self.regs[rs1].wrapping_mul(self.regs[rs2]);
                     }
+                    (0x1, 0x01) => {
+                        // mulh
+                        self.regs[rd] = ((self.regs[rs1] as i64 as i128)
+                            .wrapping_mul(self.regs[rs2] as i64 as i128)
+                            >> 64) as u64;
+                    }
+                    (0x2, 0x01) => {
+                        // mulhsu
+                        self.regs[rd] = ((self.regs[rs1] as i64 as i128)
+                            .wrapping_mul(self.regs[rs2] as i128)
+                            >> 64) as u64;
+                    }
+                    (0x3, 0x01) => {
+                        // mulhu
+                        self.regs[rd] = ((self.regs[rs1] as u128)
+                            .wrapping_mul(self.regs[rs2] as u128)
+                            >> 64) as u64;
+                    }
+                    // 7.2 Division Operations
+                    // "The quotient of division by zero has all bits set, and the remainder of
+                    // division by zero equals the dividend. Signed division overflow occurs only
+                    // when the most-negative integer is divided by -1. The quotient of a signed
+                    // division with overflow is equal to the dividend, and the remainder is zero."
+                    (0x4, 0x01) => {
+                        // div
+                        self.regs[rd] = match self.regs[rs2] {
+                            0 => u64::MAX,
+                            divisor => (self.regs[rs1] as i64).wrapping_div(divisor as i64) as u64,
+                        };
+                    }
+                    (0x5, 0x01) => {
+                        // divu
+                        self.regs[rd] = match self.regs[rs2] {
+                            0 => u64::MAX,
+                            divisor => self.regs[rs1] / divisor,
+                        };
+                    }
+                    (0x6, 0x01) => {
+                        // rem
+                        self.regs[rd] = match self.regs[rs2] {
+                            0 => self.regs[rs1],
+                            divisor => (self.regs[rs1] as i64).wrapping_rem(divisor as i64) as u64,
+                        };
+                    }
+                    (0x7, 0x01) => {
+                        // remu
+                        self.regs[rd] = match self.regs[rs2] {
+                            0 => self.regs[rs1],
+                            divisor => self.regs[rs1] % divisor,
+                        };
+                    }
                     (0x0, 0x20) => {
                         // sub
                         self.regs[rd] = self.regs[rs1].wrapping_sub(self.regs[rs2]);
@@ -708,7 +1297,7 @@ impl Cpu {
                         // and
                         self.regs[rd] = self.regs[rs1] & self.regs[rs2];
                     }
-                    _ => {}
+                    _ => return Err(Exception::IllegalInstruction(inst)),
                 }
             }
             0x37 => {
@@ -724,6 +1313,11 @@ impl Cpu {
                         self.regs[rd] =
                             self.regs[rs1].wrapping_add(self.regs[rs2]) as i32 as i64 as u64;
                     }
+                    (0x0, 0x01) => {
+                        // mulw
+                        self.regs[rd] = (self.regs[rs1] as i32).wrapping_mul(self.regs[rs2] as i32)
+                            as i64 as u64;
+                    }
                     (0x0, 0x20) => {
                         // subw
                         self.regs[rd] =
@@ -741,7 +1335,35 @@ impl Cpu {
                         // sraw
                         self.regs[rd] = ((self.regs[rs1] as i32) >> (shamt as i32)) as u64;
                     }
-                    _ => {}
+                    (0x4, 0x01) => {
+                        // divw
+                        self.regs[rd] = match self.regs[rs2] as i32 {
+                            0 => u64::MAX,
+                            divisor => (self.regs[rs1] as i32).wrapping_div(divisor) as i64 as u64,
+                        };
+                    }
+                    (0x5, 0x01) => {
+                        // divuw
+                        self.regs[rd] = match self.regs[rs2] as u32 {
+                            0 => u64::MAX,
+                            divisor => ((self.regs[rs1] as u32) / divisor) as i32 as i64 as u64,
+                        };
+                    }
+                    (0x6, 0x01) => {
+                        // remw
+                        self.regs[rd] = match self.regs[rs2] as i32 {
+                            0 => self.regs[rs1] as i32 as i64 as u64,
+                            divisor => (self.regs[rs1] as i32).wrapping_rem(divisor) as i64 as u64,
+                        };
+                    }
+                    (0x7, 0x01) => {
+                        // remuw
+                        self.regs[rd] = match self.regs[rs2] as u32 {
+                            0 => self.regs[rs1] as i32 as i64 as u64,
+                            divisor => ((self.regs[rs1] as u32) % divisor) as i32 as i64 as u64,
+                        };
+                    }
+                    _ => return Err(Exception::IllegalInstruction(inst)),
                 }
             }
             0x63 => {
@@ -751,44 +1373,23 @@ impl Cpu {
                     | ((inst >> 20) & 0x7e0) // imm[10:5]
                     | ((inst >> 7) & 0x1e); // imm[4:1]
 
-                match funct3 {
-                    0x0 => {
-                        // beq
-                        if self.regs[rs1] == self.regs[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
-                        }
-                    }
-                    0x1 => {
-                        // bne
-                        if self.regs[rs1] != self.regs[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
-                        }
-                    }
-                    0x4 => {
-                        // blt
-                        if (self.regs[rs1] as i64) < (self.regs[rs2] as i64) {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
-                        }
-                    }
-                    0x5 => {
-                        // bge
-                        if (self.regs[rs1] as i64) >= (self.regs[rs2] as i64) {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
-                        }
-                    }
-                    0x6 => {
-                        // bltu
-                        if self.regs[rs1] < self.regs[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
-                        }
-                    }
-                    0x7 => {
-                        // bgeu
-                        if self.regs[rs1] >= self.regs[rs2] {
-                            self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
-                        }
-                    }
-                    _ => {}
+                let taken = match funct3 {
+                    // beq
+                    0x0 => self.regs[rs1] == self.regs[rs2],
+                    // bne
+                    0x1 => self.regs[rs1] != self.regs[rs2],
+                    // blt
+                    0x4 => (self.regs[rs1] as i64) < (self.regs[rs2] as i64),
+                    // bge
+                    0x5 => (self.regs[rs1] as i64) >= (self.regs[rs2] as i64),
+                    // bltu
+                    0x6 => self.regs[rs1] < self.regs[rs2],
+                    // bgeu
+                    0x7 => self.regs[rs1] >= self.regs[rs2],
+                    _ => false,
+                };
+                if taken {
+                    self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
                 }
             }
             0x67 => {
@@ -814,6 +1415,12 @@ impl Cpu {
             }
             0x73 => {
                 let csr_addr = ((inst & 0xfff00000) >> 20) as usize;
+                if funct3 != 0x0 {
+                    // csrrw and csrrwi always write the CSR, and the others write it only if rs1
+                    // or uimm is not zero.
+                    let write = matches!(funct3, 0x1 | 0x5) || rs1 != 0;
+                    self.check_csr_access(csr_addr, write, inst)?;
+                }
                 match funct3 {
                     0x0 => {
                         match (rs2, funct7) {
@@ -825,6 +1432,9 @@ impl Cpu {
                                     Mode::User => {
                                         return Err(Exception::EnvironmentCallFromUMode);
                                     }
+                                    Mode::Supervisor if self.sbi.is_some() => {
+                                        return handle_ecall(self);
+                                    }
                                     Mode::Supervisor => {
                                         return Err(Exception::EnvironmentCallFromSMode);
                                     }
@@ -848,6 +1458,16 @@ impl Cpu {
                                 // - Sets CSRs[sstatus].SIE to CSRs[sstatus].SPIE.
                                 // - Sets CSRs[sstatus].SPIE to 1.
                                 // - Sets CSRs[sstatus].SPP to 0.
+                                // - Sets CSRs[mstatus].MPRV to 0.
+                                // "When TSR=1, attempts to execute SRET while executing in S-mode
+                                // will raise an illegal instruction exception."
+                                let mstatus = self.csrs.load(MSTATUS);
+                                if self.mode == Mode::User
+                                    || (self.mode == Mode::Supervisor
+                                        && (mstatus & MSTATUS_TSR) != 0)
+                                {
+                                    return Err(Exception::IllegalInstruction(inst));
+                                }
                                 self.pc = self.csrs.load(SEPC);
                                 // When the SRET instruction is executed to return from the trap
                                 // handler, the privilege level is set to user mode if the SPP
@@ -870,6 +1490,9 @@ impl Cpu {
                                 self.csrs.store(SSTATUS, self.csrs.load(SSTATUS) | (1 << 5));
                                 self.csrs
                                     .store(SSTATUS, self.csrs.load(SSTATUS) & !(1 << 8));
+                                // "If xPP≠M, xRET also sets MPRV=0." SPP can never hold M.
+                                self.csrs
+                                    .store(MSTATUS, self.csrs.load(MSTATUS) & !MSTATUS_MPRV);
                             }
                             (0x2, 0x18) => {
                                 // mret
@@ -880,13 +1503,15 @@ impl Cpu {
                                 // - Sets CSRs[mstatus].MIE to CSRs[mstatus].MPIE.
                                 // - Sets CSRs[mstatus].MPIE to 1.
                                 // - Sets CSRs[mstatus].MPP to 0.
+                                // - Sets CSRs[mstatus].MPRV to 0 if the new mode is not M.
+                                if self.mode != Mode::Machine {
+                                    return Err(Exception::IllegalInstruction(inst));
+                                }
                                 self.pc = self.csrs.load(MEPC);
                                 // MPP is two bits wide at [11..12] of the MSTATUS csr.
-                                self.mode = match (self.csrs.load(MSTATUS) >> 11) & 0b11 {
-                                    2 => Mode::Machine,
-                                    1 => Mode::Supervisor,
-                                    _ => Mode::User,
-                                };
+                                self.mode = Mode::from_bits(
+                                    (self.csrs.load(MSTATUS) & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT,
+                                );
                                 // The MPIE bit is the 7th and the MIE bit is the 3rd of the
                                 // MSTATUS csr.
                                 self.csrs.store(
@@ -899,18 +1524,43 @@ impl Cpu {
                                 );
                                 self.csrs.store(MSTATUS, self.csrs.load(MSTATUS) | (1 << 7));
                                 self.csrs
-                                    .store(MSTATUS, self.csrs.load(MSTATUS) & !(0b11 << 11));
+                                    .store(MSTATUS, self.csrs.load(MSTATUS) & !MSTATUS_MPP);
+                                if self.mode != Mode::Machine {
+                                    self.csrs
+                                        .store(MSTATUS, self.csrs.load(MSTATUS) & !MSTATUS_MPRV);
+                                }
+                            }
+                            (0x5, 0x8) => {
+                                // wfi
+                                // Do nothing because interrupts are checked after every
+                                // instruction.
+                                // "When TW=1, then if WFI is executed in any less-privileged
+                                // mode, and it does not complete within an implementation-specific,
+                                // bounded time limit, the WFI instruction causes an illegal
+                                // instruction exception."
+                                let tw = (self.csrs.load(MSTATUS) & MSTATUS_TW) != 0;
+                                if self.mode != Mode::Machine && tw {
+                                    return Err(Exception::IllegalInstruction(inst));
+                                }
                             }
                             (_, 0x9) => {
                                 // sfence.vma
-                                // Do nothing.
+                                // Do nothing because this emulator has no TLB.
+                                // "When TVM=1, attempts to read or write the satp CSR or execute
+                                // an SFENCE.VMA instruction while executing in S-mode will raise
+                                // an illegal instruction exception."
+                                let tvm = (self.csrs.load(MSTATUS) & MSTATUS_TVM) != 0;
+                                if self.mode == Mode::User || (self.mode == Mode::Supervisor && tvm)
+                                {
+                                    return Err(Exception::IllegalInstruction(inst));
+                                }
                             }
                             _ => {}
                         }
                     }
                     0x1 => {
                         // csrrw
-                        let t = self.csrs.load(csr_addr);
+                        let t = self.load_csr(csr_addr);
                         self.csrs.store(csr_addr, self.regs[rs1]);
                         self.regs[rd] = t;
 
@@ -918,7 +1568,7 @@ impl Cpu {
                     }
                     0x2 => {
                         // csrrs
-                        let t = self.csrs.load(csr_addr);
+                        let t = self.load_csr(csr_addr);
                         self.csrs.store(csr_addr, t | self.regs[rs1]);
                         self.regs[rd] = t;
 
@@ -926,7 +1576,7 @@ impl Cpu {
                     }
                     0x3 => {
                         // csrrc
-                        let t = self.csrs.load(csr_addr);
+                        let t = self.load_csr(csr_addr);
                         self.csrs.store(csr_addr, t & (!self.regs[rs1]));
                         self.regs[rd] = t;
 
@@ -943,7 +1593,7 @@ impl Cpu {
                     0x6 => {
                         // csrrsi
                         let zimm = rs1 as u64;
-                        let t = self.csrs.load(csr_addr);
+                        let t = self.load_csr(csr_addr);
                         self.csrs.store(csr_addr, t | zimm);
                         self.regs[rd] = t;
 
@@ -952,7 +1602,7 @@ impl Cpu {
                     0x7 => {
                         // csrrci
                         let zimm = rs1 as u64;
-                        let t = self.csrs.load(csr_addr);
+                        let t = self.load_csr(csr_addr);
                         self.csrs.store(csr_addr, t & (!zimm));
                         self.regs[rd] = t;
 
@@ -963,9 +1613,293 @@ impl Cpu {
             }
             _ => {
                 dbg!(format!("not implemented yet: opcode {:#x}", opcode));
-                return Err(Exception::IllegalInstruction);
+                return Err(Exception::IllegalInstruction(inst));
             }
         }
-        return Ok(());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRET: u64 = 0x10200073;
+    const WFI: u64 = 0x10500073;
+    const SFENCE_VMA: u64 = 0x12000073;
+
+    /// Return hart 0 of a machine with the memory of the default size and no devices.
+    fn cpu() -> Cpu {
+        let interrupts = HartInterrupts::new();
+        let bus = Arc::new(Bus::new(
+            Vec::new(),
+            Vec::new(),
+            None,
+            MEMORY_SIZE,
+            std::slice::from_ref(&interrupts),
+        ));
+        Cpu::new(0, bus, interrupts)
+    }
+
+    /// Return a CSR instruction of `funct3` on `csr` with `rs1` and `rd`.
+    fn csr_inst(funct3: u64, csr: usize, rs1: usize, rd: usize) -> u64 {
+        ((csr as u64) << 20) | ((rs1 as u64) << 15) | (funct3 << 12) | ((rd as u64) << 7) | 0x73
+    }
+
+    fn is_illegal(result: Result<(), Exception>) -> bool {
+        matches!(result, Err(Exception::IllegalInstruction(_)))
+    }
+
+    /// Map the virtual pages in `pages` to physical pages in S-mode with Sv39, through page
+    /// tables at `MEMORY_BASE + 0x10_0000`. The virtual pages must be in the first 2 MiB.
+    fn map_pages(cpu: &mut Cpu, pages: &[(u64, u64)]) {
+        let root = MEMORY_BASE + 0x10_0000;
+        let (l1, l0) = (root + PAGE_SIZE, root + 2 * PAGE_SIZE);
+        // V, R, W, A and D.
+        let leaf = 0x1 | 0x2 | 0x4 | 0x40 | 0x80;
+        cpu.bus
+            .store(root, 64, ((l1 / PAGE_SIZE) << 10) | 1)
+            .unwrap();
+        cpu.bus.store(l1, 64, ((l0 / PAGE_SIZE) << 10) | 1).unwrap();
+        for (va, pa) in pages {
+            let pte = ((pa / PAGE_SIZE) << 10) | leaf;
+            cpu.bus.store(l0 + (va / PAGE_SIZE) * 8, 64, pte).unwrap();
+        }
+        cpu.regs[1] = (8 << 60) | (root / PAGE_SIZE);
+        cpu.execute(csr_inst(0x1, SATP, 1, 0)).unwrap();
+        cpu.mode = Mode::Supervisor;
+    }
+
+    /// Execute the M-extension instruction of `opcode` and `funct3` on `a` and `b`, and return rd.
+    fn muldiv(opcode: u64, funct3: u64, a: u64, b: u64) -> u64 {
+        let mut cpu = cpu();
+        cpu.regs[1] = a;
+        cpu.regs[2] = b;
+        // rd = x3, rs1 = x1, rs2 = x2, funct7 = 1.
+        let inst = (1 << 25) | (2 << 20) | (1 << 15) | (funct3 << 12) | (3 << 7) | opcode;
+        cpu.execute(inst).unwrap();
+        cpu.regs[3]
+    }
+
+    #[test]
+    fn multiplication_returns_the_high_bits() {
+        let minus_two = -2i64 as u64;
+        assert_eq!(muldiv(0x33, 0x0, minus_two, 3), -6i64 as u64);
+        assert_eq!(muldiv(0x33, 0x1, minus_two, 3), u64::MAX);
+        assert_eq!(muldiv(0x33, 0x2, minus_two, u64::MAX), -2i64 as u64);
+        assert_eq!(muldiv(0x33, 0x3, minus_two, 3), 2);
+        // mulw sign-extends the low 32 bits.
+        assert_eq!(
+            muldiv(0x3b, 0x0, 0x1_0000_0002, 0x4000_0000),
+            0xffff_ffff_8000_0000
+        );
+    }
+
+    #[test]
+    fn division_by_zero_and_overflow() {
+        let min = i64::MIN as u64;
+        assert_eq!(muldiv(0x33, 0x4, -7i64 as u64, 2), -3i64 as u64);
+        assert_eq!(muldiv(0x33, 0x4, 7, 0), u64::MAX);
+        assert_eq!(muldiv(0x33, 0x4, min, -1i64 as u64), min);
+        assert_eq!(muldiv(0x33, 0x5, 7, 0), u64::MAX);
+        assert_eq!(muldiv(0x33, 0x6, -7i64 as u64, 2), -1i64 as u64);
+        assert_eq!(muldiv(0x33, 0x6, 7, 0), 7);
+        assert_eq!(muldiv(0x33, 0x6, min, -1i64 as u64), 0);
+        assert_eq!(muldiv(0x33, 0x7, 7, 3), 1);
+
+        let min = i32::MIN as i64 as u64;
+        assert_eq!(muldiv(0x3b, 0x4, min, -1i64 as u64), min);
+        // Only the low 32 bits of the divisor are used.
+        assert_eq!(muldiv(0x3b, 0x4, 7, 1 << 32), u64::MAX);
+        assert_eq!(muldiv(0x3b, 0x5, 0xffff_fffe, 1), -2i64 as u64);
+        assert_eq!(muldiv(0x3b, 0x6, 0x1_8000_0000, 0), min);
+        assert_eq!(muldiv(0x3b, 0x6, min, -1i64 as u64), 0);
+        assert_eq!(muldiv(0x3b, 0x7, 0xffff_ffff, 0x10), 0xf);
+    }
+
+    #[test]
+    fn unknown_register_operation_is_illegal() {
+        let mut cpu = cpu();
+        // funct7 = 0x02 in OP and OP-32.
+        for opcode in [0x33, 0x3b] {
+            assert!(is_illegal(cpu.execute((0x02 << 25) | (3 << 7) | opcode)));
+        }
+    }
+
+    #[test]
+    fn restore_rejects_an_invalid_mode() {
+        let mut cpu = cpu();
+        cpu.mode = Mode::Supervisor;
+        let mut snapshot = SnapshotWriter::new();
+        cpu.save(&mut snapshot);
+        let mut data = snapshot.into_bytes();
+        // The mode follows the 32 registers and the pc.
+        let mode = 33 * 8;
+        assert_eq!(data[mode], Mode::Supervisor as u8);
+
+        cpu.restore(&mut SnapshotReader::new(&data)).unwrap();
+        assert_eq!(cpu.mode, Mode::Supervisor);
+        for invalid in [0b10, 0b100] {
+            data[mode] = invalid;
+            assert!(cpu.restore(&mut SnapshotReader::new(&data)).is_err());
+        }
+    }
+
+    #[test]
+    fn misaligned_access_crosses_a_page_boundary() {
+        let mut cpu = cpu();
+        cpu.misaligned_access = MisalignedAccess::Emulate;
+        // The virtual pages are contiguous, but the physical pages aren't.
+        let (first, second) = (MEMORY_BASE + 0x20_0000, MEMORY_BASE + 0x30_0000);
+        map_pages(&mut cpu, &[(0x1000, first), (0x2000, second)]);
+
+        cpu.store(0x1ffc, 64, 0x0807_0605_0403_0201).unwrap();
+        assert_eq!(cpu.bus.load(first + 0xffc, 32).unwrap(), 0x0403_0201);
+        assert_eq!(cpu.bus.load(second, 32).unwrap(), 0x0807_0605);
+        assert_eq!(cpu.load(0x1ffe, 32).unwrap(), 0x0605_0403);
+
+        cpu.misaligned_access = MisalignedAccess::Trap;
+        assert!(matches!(
+            cpu.load(0x1ffc, 64),
+            Err(Exception::LoadAddressMisaligned(0x1ffc))
+        ));
+    }
+
+    #[test]
+    fn misaligned_access_faults_on_the_second_page() {
+        let mut cpu = cpu();
+        cpu.misaligned_access = MisalignedAccess::Emulate;
+        let first = MEMORY_BASE + 0x20_0000;
+        map_pages(&mut cpu, &[(0x1000, first)]);
+
+        // The fault address is the start of the part on the unmapped page.
+        assert!(matches!(
+            cpu.load(0x1ffc, 64),
+            Err(Exception::LoadPageFault(0x2000))
+        ));
+        assert!(matches!(
+            cpu.store(0x1ffe, 32, u64::MAX),
+            Err(Exception::StoreAMOPageFault(0x2000))
+        ));
+        // No byte is written to the first page when the second page faults.
+        assert_eq!(cpu.bus.load(first + 0xff8, 64).unwrap(), 0);
+    }
+
+    #[test]
+    fn mstatus_keeps_a_legal_mpp() {
+        let mut csrs = Csr::new(0, HartInterrupts::new());
+        csrs.store(MSTATUS, MSTATUS_MPP);
+        assert_eq!(csrs.load(MSTATUS) & MSTATUS_MPP, MSTATUS_MPP);
+        // 0b10 is reserved, so the previous MPP is kept.
+        csrs.store(MSTATUS, 0b10 << MSTATUS_MPP_SHIFT);
+        assert_eq!(csrs.load(MSTATUS) & MSTATUS_MPP, MSTATUS_MPP);
+        csrs.store(MSTATUS, 0b01 << MSTATUS_MPP_SHIFT);
+        assert_eq!(
+            Mode::from_bits((csrs.load(MSTATUS) & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT),
+            Mode::Supervisor
+        );
+    }
+
+    #[test]
+    fn mstatus_xlen_is_hardwired() {
+        let mut csrs = Csr::new(0, HartInterrupts::new());
+        let xlen = MSTATUS_UXL | MSTATUS_SXL;
+        assert_eq!(csrs.load(MSTATUS) & xlen, (XLEN_64 << 32) | (XLEN_64 << 34));
+        csrs.store(MSTATUS, 0);
+        assert_eq!(csrs.load(MSTATUS) & xlen, (XLEN_64 << 32) | (XLEN_64 << 34));
+        csrs.store(MSTATUS, xlen);
+        assert_eq!(csrs.load(MSTATUS) & xlen, (XLEN_64 << 32) | (XLEN_64 << 34));
+    }
+
+    #[test]
+    fn mstatus_sd_summarizes_dirty_state() {
+        let mut csrs = Csr::new(0, HartInterrupts::new());
+        csrs.store(MSTATUS, MSTATUS_FS);
+        assert_ne!(csrs.load(MSTATUS) & MSTATUS_SD, 0);
+        assert_ne!(csrs.load(SSTATUS) & SSTATUS_SD, 0);
+        // FS is only initial, and SD can't be written.
+        csrs.store(MSTATUS, (1 << 13) | MSTATUS_SD);
+        assert_eq!(csrs.load(MSTATUS) & MSTATUS_SD, 0);
+    }
+
+    #[test]
+    fn tsr_traps_sret_in_s_mode() {
+        let mut cpu = cpu();
+        cpu.mode = Mode::Supervisor;
+        cpu.csrs.store(SEPC, MEMORY_BASE);
+        assert!(cpu.execute(SRET).is_ok());
+        assert_eq!(cpu.mode, Mode::User);
+
+        cpu.mode = Mode::Supervisor;
+        cpu.csrs.store(MSTATUS, MSTATUS_TSR);
+        assert!(is_illegal(cpu.execute(SRET)));
+        // TSR doesn't affect M-mode.
+        cpu.mode = Mode::Machine;
+        assert!(cpu.execute(SRET).is_ok());
+    }
+
+    #[test]
+    fn tvm_traps_satp_and_sfence_vma_in_s_mode() {
+        let mut cpu = cpu();
+        cpu.mode = Mode::Supervisor;
+        assert!(cpu.execute(csr_inst(0x2, SATP, 0, 1)).is_ok());
+        assert!(cpu.execute(SFENCE_VMA).is_ok());
+
+        cpu.csrs.store(MSTATUS, MSTATUS_TVM);
+        assert!(is_illegal(cpu.execute(csr_inst(0x2, SATP, 0, 1))));
+        assert!(is_illegal(cpu.execute(SFENCE_VMA)));
+        cpu.mode = Mode::Machine;
+        assert!(cpu.execute(csr_inst(0x2, SATP, 0, 1)).is_ok());
+        assert!(cpu.execute(SFENCE_VMA).is_ok());
+    }
+
+    #[test]
+    fn tw_traps_wfi_below_m_mode() {
+        let mut cpu = cpu();
+        cpu.mode = Mode::Supervisor;
+        assert!(cpu.execute(WFI).is_ok());
+        cpu.csrs.store(MSTATUS, MSTATUS_TW);
+        assert!(is_illegal(cpu.execute(WFI)));
+        cpu.mode = Mode::Machine;
+        assert!(cpu.execute(WFI).is_ok());
+    }
+
+    #[test]
+    fn csr_access_checks_the_privilege_level() {
+        let mut cpu = cpu();
+        cpu.mode = Mode::Supervisor;
+        assert!(is_illegal(cpu.execute(csr_inst(0x2, MSTATUS, 0, 1))));
+        assert!(cpu.execute(csr_inst(0x2, SSTATUS, 0, 1)).is_ok());
+        cpu.mode = Mode::User;
+        assert!(is_illegal(cpu.execute(csr_inst(0x2, SSTATUS, 0, 1))));
+    }
+
+    #[test]
+    fn mprv_translates_loads_and_stores_with_mpp() {
+        let mut cpu = cpu();
+        // An empty root page table, so that every translated access faults.
+        let root = MEMORY_BASE + 0x10_0000;
+        cpu.regs[1] = (8 << 60) | (root / PAGE_SIZE);
+        cpu.execute(csr_inst(0x1, SATP, 1, 0)).unwrap();
+        let addr = MEMORY_BASE + 0x20_0000;
+        assert!(cpu.load(addr, 64).is_ok());
+
+        cpu.csrs
+            .store(MSTATUS, MSTATUS_MPRV | (0b01 << MSTATUS_MPP_SHIFT));
+        assert!(matches!(
+            cpu.load(addr, 64),
+            Err(Exception::LoadPageFault(a)) if a == addr
+        ));
+        assert!(matches!(
+            cpu.store(addr, 64, 0),
+            Err(Exception::StoreAMOPageFault(a)) if a == addr
+        ));
+        // Instruction fetches are never affected.
+        cpu.pc = addr;
+        assert!(cpu.fetch().is_ok());
+
+        // With MPP = M, the accesses aren't translated.
+        cpu.csrs.store(MSTATUS, MSTATUS_MPRV | MSTATUS_MPP);
+        assert!(cpu.load(addr, 64).is_ok());
     }
 }
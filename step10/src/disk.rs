@@ -0,0 +1,538 @@
+//! The disk module contains the storage behind the block device. A `Disk` is a sequence of bytes
+//! accessed at arbitrary offsets: a disk image in the host memory, a raw or qcow2 image file, or
+//! an image file with the writes redirected to a copy-on-write overlay file.
+//!
+//! A snapshot of a machine holds the contents of its disks written by the guest. An overlay saves
+//! only the written blocks, so it keeps snapshots of a large disk small.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use crate::qcow2::*;
+use crate::snapshot::*;
+
+/// The unit in which an overlay tracks which parts of the disk have been written.
+const OVERLAY_BLOCK_SIZE: u64 = 512;
+/// The unit in which the contents of a disk are saved to a snapshot.
+const SNAPSHOT_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// The storage of a block device.
+pub trait Disk: Send {
+    /// Return the size of the disk in bytes.
+    fn capacity(&self) -> u64;
+
+    /// Return true if the disk can't be written.
+    fn is_readonly(&self) -> bool {
+        false
+    }
+
+    /// Fill `data` with the bytes at `offset`. The range must be inside of the disk.
+    fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()>;
+
+    /// Write `data` to `offset`. The range must be inside of the disk.
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Make the writes done so far persistent.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Save the contents of the disk to `snapshot`. By default, the whole disk is saved unless
+    /// it's read-only, and the blocks of zeros are skipped.
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        save_contents(self, snapshot)
+    }
+
+    /// Restore the contents saved by `save`. The writes done after the snapshot was taken are
+    /// undone.
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        restore_contents(self, snapshot)
+    }
+}
+
+/// Save the capacity of `disk` and the blocks that aren't all zeros to `snapshot`. Only the
+/// capacity is saved if the disk is read-only.
+fn save_contents<D: Disk + ?Sized>(disk: &mut D, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+    let capacity = disk.capacity();
+    let saved = !disk.is_readonly();
+    snapshot.u64(capacity).bool(saved);
+    if saved {
+        let mut block = vec![0; SNAPSHOT_BLOCK_SIZE as usize];
+        for offset in (0..capacity).step_by(SNAPSHOT_BLOCK_SIZE as usize) {
+            let block = &mut block[..(capacity - offset).min(SNAPSHOT_BLOCK_SIZE) as usize];
+            disk.read_at(offset, block)?;
+            if block.iter().any(|byte| *byte != 0) {
+                snapshot.u64(offset).compressed(block);
+            }
+        }
+    }
+    // The end of the blocks.
+    snapshot.u64(u64::MAX);
+    Ok(())
+}
+
+/// Restore the contents of `disk` saved by `save_contents`. Only the blocks that have changed are
+/// written.
+fn restore_contents<D: Disk + ?Sized>(
+    disk: &mut D,
+    snapshot: &mut SnapshotReader,
+) -> io::Result<()> {
+    let capacity = disk.capacity();
+    if snapshot.u64()? != capacity {
+        return Err(snapshot_error("the disk size doesn't match"));
+    }
+    let saved = snapshot.bool()?;
+    // The next saved block, as its offset and data.
+    let next_block = |snapshot: &mut SnapshotReader| -> io::Result<Option<(u64, Vec<u8>)>> {
+        match snapshot.u64()? {
+            u64::MAX => Ok(None),
+            offset => Ok(Some((offset, snapshot.compressed()?))),
+        }
+    };
+    let mut next = next_block(snapshot)?;
+    if !saved {
+        return match next {
+            None => Ok(()),
+            Some(_) => Err(snapshot_error("invalid disk contents")),
+        };
+    }
+    let mut current = vec![0; SNAPSHOT_BLOCK_SIZE as usize];
+    for offset in (0..capacity).step_by(SNAPSHOT_BLOCK_SIZE as usize) {
+        let len = (capacity - offset).min(SNAPSHOT_BLOCK_SIZE) as usize;
+        let expected = match next.take() {
+            Some((start, data)) if start == offset && data.len() == len => {
+                next = next_block(snapshot)?;
+                data
+            }
+            Some((start, _)) if start <= offset => {
+                return Err(snapshot_error("invalid disk contents"));
+            }
+            other => {
+                next = other;
+                vec![0; len]
+            }
+        };
+        let current = &mut current[..len];
+        disk.read_at(offset, current)?;
+        if *current != *expected {
+            disk.write_at(offset, &expected)?;
+        }
+    }
+    match next {
+        None => Ok(()),
+        Some(_) => Err(snapshot_error("invalid disk contents")),
+    }
+}
+
+/// Return the error for a write to a read-only disk.
+pub(crate) fn readonly_error() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "the disk is read-only")
+}
+
+/// A disk image in the host memory. The writes are lost when the machine exits.
+impl Disk for Vec<u8> {
+    fn capacity(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        let offset = offset as usize;
+        data.copy_from_slice(&self[offset..offset + data.len()]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let offset = offset as usize;
+        self[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/// A disk backed by a raw image file. The writes go straight to the file.
+pub struct FileDisk {
+    file: File,
+    capacity: u64,
+    readonly: bool,
+}
+
+impl FileDisk {
+    /// Open the file at `path` as a disk. The file is never written if `readonly` is true.
+    pub fn open(path: &Path, readonly: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(!readonly).open(path)?;
+        let capacity = file.metadata()?.len();
+        Ok(Self {
+            file,
+            capacity,
+            readonly,
+        })
+    }
+}
+
+impl Disk for FileDisk {
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    fn read_at(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        self.file.read_exact_at(data, offset)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        if self.readonly {
+            return Err(readonly_error());
+        }
+        self.file.write_all_at(data, offset)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// A disk that reads from a base disk and writes to a separate overlay file, so that the base is
+/// never modified. The overlay is a sparse file with the written blocks at their offsets in the
+/// disk, followed by a bitmap of the written blocks. An existing overlay is reused, so the writes
+/// persist across runs as long as the base isn't changed.
+pub struct OverlayDisk {
+    base: Box<dyn Disk>,
+    overlay: File,
+    /// Whether each block has been written to the overlay, a bit for each block.
+    written: Vec<u8>,
+    /// The offset of the bitmap in the overlay file.
+    bitmap_offset: u64,
+}
+
+impl OverlayDisk {
+    /// Create an overlay of `base` in the file at `path`, or reuse the overlay if the file
+    /// already exists.
+    pub fn open(base: Box<dyn Disk>, path: &Path) -> io::Result<Self> {
+        let overlay = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let blocks = base.capacity().div_ceil(OVERLAY_BLOCK_SIZE);
+        let bitmap_offset = blocks * OVERLAY_BLOCK_SIZE;
+        let mut written = vec![0; blocks.div_ceil(8) as usize];
+        let len = overlay.metadata()?.len();
+        if len == 0 {
+            // Make the overlay as large as the disk without allocating the blocks.
+            overlay.set_len(bitmap_offset + written.len() as u64)?;
+        } else if len == bitmap_offset + written.len() as u64 {
+            overlay.read_exact_at(&mut written, bitmap_offset)?;
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the overlay doesn't match the size of the disk",
+            ));
+        }
+        Ok(Self {
+            base,
+            overlay,
+            written,
+            bitmap_offset,
+        })
+    }
+
+    fn is_written(&self, block: u64) -> bool {
+        self.written[(block / 8) as usize] & (1 << (block % 8)) != 0
+    }
+
+    /// Return the blocks that have been written, in order.
+    fn written_blocks(&self) -> Vec<u64> {
+        let blocks = self.bitmap_offset / OVERLAY_BLOCK_SIZE;
+        (0..blocks)
+            .filter(|block| self.is_written(*block))
+            .collect()
+    }
+
+    /// Mark `block` as written, in the memory and in the overlay file.
+    fn mark_written(&mut self, block: u64) -> io::Result<()> {
+        let index = (block / 8) as usize;
+        self.written[index] |= 1 << (block % 8);
+        self.overlay.write_all_at(
+            &self.written[index..index + 1],
+            self.bitmap_offset + index as u64,
+        )
+    }
+}
+
+impl Disk for OverlayDisk {
+    fn capacity(&self) -> u64 {
+        self.base.capacity()
+    }
+
+    fn read_at(&mut self, mut offset: u64, mut data: &mut [u8]) -> io::Result<()> {
+        // Read block by block, each from the overlay if it has been written.
+        while !data.is_empty() {
+            let block = offset / OVERLAY_BLOCK_SIZE;
+            let len = ((block + 1) * OVERLAY_BLOCK_SIZE - offset).min(data.len() as u64) as usize;
+            let (part, rest) = data.split_at_mut(len);
+            if self.is_written(block) {
+                self.overlay.read_exact_at(part, offset)?;
+            } else {
+                self.base.read_at(offset, part)?;
+            }
+            offset += len as u64;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, mut offset: u64, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let block = offset / OVERLAY_BLOCK_SIZE;
+            let start = block * OVERLAY_BLOCK_SIZE;
+            let len = (start + OVERLAY_BLOCK_SIZE - offset).min(data.len() as u64) as usize;
+            if !self.is_written(block) && len as u64 != OVERLAY_BLOCK_SIZE {
+                // Copy the rest of the block from the base before a partial write.
+                let end = (start + OVERLAY_BLOCK_SIZE).min(self.capacity());
+                let mut copy = vec![0; (end - start) as usize];
+                self.base.read_at(start, &mut copy)?;
+                self.overlay.write_all_at(&copy, start)?;
+            }
+            self.overlay.write_all_at(&data[..len], offset)?;
+            if !self.is_written(block) {
+                self.mark_written(block)?;
+            }
+            offset += len as u64;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.overlay.sync_data()
+    }
+
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        // The base is never written, so only the written blocks are saved.
+        let mut data = Vec::new();
+        let mut block = [0; OVERLAY_BLOCK_SIZE as usize];
+        for index in self.written_blocks() {
+            self.overlay
+                .read_exact_at(&mut block, index * OVERLAY_BLOCK_SIZE)?;
+            data.extend_from_slice(&block);
+        }
+        snapshot.bytes(&self.written).compressed(&data);
+        Ok(())
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        let written = snapshot.bytes()?;
+        let data = snapshot.compressed()?;
+        let count: u32 = written.iter().map(|byte| byte.count_ones()).sum();
+        if written.len() != self.written.len()
+            || data.len() as u64 != count as u64 * OVERLAY_BLOCK_SIZE
+        {
+            return Err(snapshot_error("the overlay doesn't match"));
+        }
+        // The blocks written after the snapshot was taken are read from the base again.
+        self.written = written.to_vec();
+        let blocks = self.written_blocks();
+        for (index, block) in blocks
+            .into_iter()
+            .zip(data.chunks(OVERLAY_BLOCK_SIZE as usize))
+        {
+            self.overlay
+                .write_all_at(block, index * OVERLAY_BLOCK_SIZE)?;
+        }
+        self.overlay.write_all_at(&self.written, self.bitmap_offset)
+    }
+}
+
+/// Open the image file at `path`, a qcow2 image or a raw image detected from its header. The
+/// image is never written if `readonly` is true.
+pub fn open_image(path: &Path, readonly: bool) -> io::Result<Box<dyn Disk>> {
+    if is_qcow2(path)? {
+        Ok(Box::new(Qcow2Disk::open(path, readonly)?))
+    } else {
+        Ok(Box::new(FileDisk::open(path, readonly)?))
+    }
+}
+
+/// Open the disk image at `path`. The image is never written if `readonly` is true, and the writes
+/// go to the overlay file at `overlay` if it's given.
+pub fn open_disk(path: &Path, readonly: bool, overlay: Option<&Path>) -> io::Result<Box<dyn Disk>> {
+    let base = open_image(path, readonly || overlay.is_some())?;
+    match overlay {
+        Some(overlay) if !readonly => Ok(Box::new(OverlayDisk::open(base, overlay)?)),
+        _ => Ok(base),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// Return an empty directory for the test `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rvemu-disk-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Return `len` bytes that differ from block to block.
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i / 7) as u8).collect()
+    }
+
+    /// Return the whole contents of `disk`.
+    fn contents(disk: &mut dyn Disk) -> Vec<u8> {
+        let mut data = vec![0; disk.capacity() as usize];
+        disk.read_at(0, &mut data).unwrap();
+        data
+    }
+
+    fn snapshot(disk: &mut dyn Disk) -> Vec<u8> {
+        let mut snapshot = SnapshotWriter::new();
+        disk.save(&mut snapshot).unwrap();
+        snapshot.into_bytes()
+    }
+
+    fn restore(disk: &mut dyn Disk, data: &[u8]) -> io::Result<()> {
+        let mut snapshot = SnapshotReader::new(data);
+        disk.restore(&mut snapshot)?;
+        snapshot.finish()
+    }
+
+    #[test]
+    fn readonly_file_isnt_written() {
+        let dir = temp_dir("readonly");
+        let path = dir.join("disk.img");
+        fs::write(&path, pattern(2048)).unwrap();
+
+        let mut disk = FileDisk::open(&path, true).unwrap();
+        assert!(disk.is_readonly());
+        assert_eq!(disk.capacity(), 2048);
+        let mut data = [0; 16];
+        disk.read_at(700, &mut data).unwrap();
+        assert_eq!(data[..], pattern(2048)[700..716]);
+        let error = disk.write_at(0, &[1; 16]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(fs::read(&path).unwrap(), pattern(2048));
+
+        let mut disk = FileDisk::open(&path, false).unwrap();
+        disk.write_at(700, &[1; 16]).unwrap();
+        assert_eq!(fs::read(&path).unwrap()[700..716], [1; 16]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn partial_write_copies_the_rest_of_the_block() {
+        let dir = temp_dir("overlay");
+        let (base, path) = (dir.join("disk.img"), dir.join("disk.overlay"));
+        fs::write(&base, pattern(2048)).unwrap();
+        let mut disk =
+            OverlayDisk::open(Box::new(FileDisk::open(&base, true).unwrap()), &path).unwrap();
+        assert!(!disk.is_readonly());
+        // The bitmap of the 4 blocks follows the blocks.
+        assert_eq!(disk.bitmap_offset, 2048);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 2048 + 1);
+
+        // A write across blocks 0 and 1, and a whole block 3.
+        disk.write_at(500, &[0xaa; 20]).unwrap();
+        disk.write_at(1536, &[0xbb; 512]).unwrap();
+        let mut expected = pattern(2048);
+        expected[500..520].fill(0xaa);
+        expected[1536..].fill(0xbb);
+        assert_eq!(contents(&mut disk), expected);
+        assert_eq!(disk.written_blocks(), [0, 1, 3]);
+
+        let overlay = fs::read(&path).unwrap();
+        assert_eq!(overlay[2048], 0b1011);
+        assert_eq!(overlay[..1024], expected[..1024]);
+        assert_eq!(overlay[1024..1536], [0; 512]);
+        assert_eq!(fs::read(&base).unwrap(), pattern(2048));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn existing_overlay_is_reused() {
+        let dir = temp_dir("reuse");
+        let (base, path) = (dir.join("disk.img"), dir.join("disk.overlay"));
+        fs::write(&base, pattern(1000)).unwrap();
+        let open =
+            |path: &Path| OverlayDisk::open(Box::new(FileDisk::open(&base, true).unwrap()), path);
+        let mut disk = open(&path).unwrap();
+        disk.write_at(990, &[0xcc; 10]).unwrap();
+        drop(disk);
+
+        let mut disk = open(&path).unwrap();
+        assert_eq!(disk.written_blocks(), [1]);
+        let mut expected = pattern(1000);
+        expected[990..].fill(0xcc);
+        assert_eq!(contents(&mut disk), expected);
+
+        // An overlay of another disk is rejected.
+        let other = OverlayDisk::open(Box::new(vec![0; 4096]), &path);
+        assert_eq!(other.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restored_contents_undo_the_later_writes() {
+        // The last snapshot block is partial.
+        let capacity = 2 * SNAPSHOT_BLOCK_SIZE as usize + 1000;
+        let mut disk = vec![0; capacity];
+        disk.write_at(SNAPSHOT_BLOCK_SIZE + 5, b"saved").unwrap();
+        disk.write_at(capacity as u64 - 1, b"!").unwrap();
+        let saved = snapshot(&mut disk);
+        let expected = disk.clone();
+
+        disk.write_at(0, b"later").unwrap();
+        disk.write_at(SNAPSHOT_BLOCK_SIZE + 5, b"LATER").unwrap();
+        restore(&mut disk, &saved).unwrap();
+        assert_eq!(disk, expected);
+
+        // The size of the disk must match.
+        let mut other = vec![0; capacity - 1];
+        assert!(restore(&mut other, &saved).is_err());
+
+        // Only the capacity of a read-only disk is saved, and its contents are kept.
+        let dir = temp_dir("snapshot");
+        let path = dir.join("disk.img");
+        fs::write(&path, pattern(capacity)).unwrap();
+        let mut readonly = FileDisk::open(&path, true).unwrap();
+        let saved = snapshot(&mut readonly);
+        assert!(saved.len() < 32);
+        restore(&mut readonly, &saved).unwrap();
+        assert!(restore(&mut disk, &saved).is_ok());
+        assert_eq!(disk, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restored_overlay_reads_the_base_again() {
+        let dir = temp_dir("overlay-snapshot");
+        let path = dir.join("disk.overlay");
+        let mut disk = OverlayDisk::open(Box::new(pattern(4096)), &path).unwrap();
+        disk.write_at(600, b"saved").unwrap();
+        let saved = snapshot(&mut disk);
+        let expected = contents(&mut disk);
+
+        disk.write_at(600, b"LATER").unwrap();
+        disk.write_at(3000, b"later").unwrap();
+        restore(&mut disk, &saved).unwrap();
+        assert_eq!(disk.written_blocks(), [1]);
+        assert_eq!(contents(&mut disk), expected);
+        // The bitmap in the file is restored too.
+        assert_eq!(fs::read(&path).unwrap()[4096], 0b10);
+
+        let mut other = OverlayDisk::open(Box::new(pattern(8192)), &dir.join("other")).unwrap();
+        assert!(restore(&mut other, &saved).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
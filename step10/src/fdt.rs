@@ -0,0 +1,354 @@
+//! The fdt module generates a flattened device tree (FDT), also known as a device tree blob
+//! (DTB), which describes the machine to the guest. The tree is built from the devices mapped to
+//! the bus, so it always matches the running configuration.
+//! See the spec: https://github.com/devicetree-org/devicetree-specification/releases
+
+use std::collections::HashMap;
+
+use crate::boot::*;
+use crate::bus::*;
+use crate::clint::*;
+
+/// The magic number in the header.
+const FDT_MAGIC: u32 = 0xd00dfeed;
+/// The version of the format.
+const FDT_VERSION: u32 = 17;
+/// The oldest version which is compatible with `FDT_VERSION`.
+const FDT_LAST_COMP_VERSION: u32 = 16;
+/// The size of the header.
+const FDT_HEADER_SIZE: u32 = 40;
+
+// Tokens of the structure block.
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_END: u32 = 0x9;
+
+/// The phandle of the PLIC.
+pub const PHANDLE_PLIC: u32 = 1;
+/// The phandle of the system controller that powers off and reboots the machine.
+pub const PHANDLE_SYSCON: u32 = 2;
+
+/// Return the phandle of the local interrupt controller of a hart.
+pub fn cpu_intc_phandle(hart: usize) -> u32 {
+    3 + hart as u32
+}
+
+/// The ISA string advertised to the guest.
+pub const RISCV_ISA: &str = "rv64ima_zicsr_zifencei";
+/// The MMU type advertised to the guest.
+pub const RISCV_MMU_TYPE: &str = "riscv,sv39";
+
+/// A builder of a flattened device tree. Nodes and properties are written in order, between
+/// `begin_node` and `end_node`.
+pub struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    /// The offsets of property names in `strings`.
+    string_offsets: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl Fdt {
+    /// Create an empty device tree.
+    pub fn new() -> Self {
+        Self {
+            structure: Vec::new(),
+            strings: Vec::new(),
+            string_offsets: HashMap::new(),
+            depth: 0,
+        }
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend(value.to_be_bytes());
+    }
+
+    /// Append `data` to the structure block, padded to a multiple of 4 bytes.
+    fn push_padded(&mut self, data: &[u8]) {
+        self.structure.extend(data);
+        let padding = (4 - self.structure.len() % 4) % 4;
+        self.structure.resize(self.structure.len() + padding, 0);
+    }
+
+    /// Return the offset of `name` in the strings block, adding it if necessary.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        if let Some(offset) = self.string_offsets.get(name) {
+            return *offset;
+        }
+        let offset = self.strings.len() as u32;
+        self.strings.extend(name.as_bytes());
+        self.strings.push(0);
+        self.string_offsets.insert(name.to_string(), offset);
+        offset
+    }
+
+    /// Start a node named `name`, e.g. `serial@10000000`. The root node is named "".
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        let mut data = name.as_bytes().to_vec();
+        data.push(0);
+        self.push_padded(&data);
+        self.depth += 1;
+    }
+
+    /// End the current node.
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "no node to end");
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    /// Add a property with the raw `value`.
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let name_offset = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(name_offset);
+        self.push_padded(value);
+    }
+
+    /// Add a property without a value, e.g. `interrupt-controller`.
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    /// Add a property with a 32-bit cell.
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    /// Add a property with 32-bit cells.
+    pub fn property_u32s(&mut self, name: &str, values: &[u32]) {
+        let data: Vec<u8> = values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        self.property(name, &data);
+    }
+
+    /// Add a property with a 64-bit value in two cells.
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    /// Add a property with a string.
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    /// Add a property with a list of strings.
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let mut data = Vec::new();
+        for value in values {
+            data.extend(value.as_bytes());
+            data.push(0);
+        }
+        self.property(name, &data);
+    }
+
+    /// Add a `reg` property of a region, with 2 address cells and 2 size cells.
+    pub fn property_reg(&mut self, base: u64, size: u64) {
+        let data: Vec<u8> = [base, size]
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        self.property("reg", &data);
+    }
+
+    /// Return the device tree blob.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert!(self.depth == 0, "a node is not ended");
+        self.push_u32(FDT_END);
+
+        // An empty memory reservation block follows the header.
+        let off_mem_rsvmap = FDT_HEADER_SIZE;
+        let off_dt_struct = off_mem_rsvmap + 16;
+        let off_dt_strings = off_dt_struct + self.structure.len() as u32;
+        let totalsize = off_dt_strings + self.strings.len() as u32;
+        let header = [
+            FDT_MAGIC,
+            totalsize,
+            off_dt_struct,
+            off_dt_strings,
+            off_mem_rsvmap,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            // boot_cpuid_phys
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+
+        let mut blob: Vec<u8> = header.iter().flat_map(|word| word.to_be_bytes()).collect();
+        blob.extend([0; 16]);
+        blob.extend(self.structure);
+        blob.extend(self.strings);
+        blob
+    }
+}
+
+/// Generate the device tree of a machine with `harts` harts and the devices mapped to `bus`.
+/// `/chosen` holds the kernel command line and the initial ramdisk of `layout`.
+pub fn generate(bus: &Bus, harts: usize, layout: &BootLayout) -> Vec<u8> {
+    let mut fdt = Fdt::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", "rvemu-for-book");
+
+    fdt.begin_node("chosen");
+    if let Some(bootargs) = &layout.bootargs {
+        fdt.property_string("bootargs", bootargs);
+    }
+    fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", UART_BASE));
+    if let Some((start, end)) = layout.initrd {
+        fdt.property_u64("linux,initrd-start", start);
+        fdt.property_u64("linux,initrd-end", end);
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", MEMORY_BASE));
+    fdt.property_string("device_type", "memory");
+    fdt.property_reg(MEMORY_BASE, bus.memory_size());
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", CLINT_TIMEBASE_FREQUENCY as u32);
+    for hart in 0..harts {
+        fdt.begin_node(&format!("cpu@{}", hart));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", hart as u32);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", RISCV_ISA);
+        fdt.property_string("mmu-type", RISCV_MMU_TYPE);
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", cpu_intc_phandle(hart));
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_null("ranges");
+    bus.describe(&mut fdt);
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return the big-endian 32-bit word at `offset` in `blob`.
+    fn be32(blob: &[u8], offset: u32) -> u32 {
+        let offset = offset as usize;
+        u32::from_be_bytes([
+            blob[offset],
+            blob[offset + 1],
+            blob[offset + 2],
+            blob[offset + 3],
+        ])
+    }
+
+    fn small_tree() -> Vec<u8> {
+        let mut fdt = Fdt::new();
+        fdt.begin_node("");
+        fdt.property_u32("#address-cells", 2);
+        fdt.begin_node("cpu@0");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_null("interrupt-controller");
+        fdt.property_u32("#address-cells", 1);
+        fdt.end_node();
+        fdt.end_node();
+        fdt.finish()
+    }
+
+    #[test]
+    fn header_describes_blocks() {
+        let blob = small_tree();
+        let off_dt_struct = be32(&blob, 8);
+        let off_dt_strings = be32(&blob, 12);
+        let off_mem_rsvmap = be32(&blob, 16);
+        let size_dt_strings = be32(&blob, 32);
+        let size_dt_struct = be32(&blob, 36);
+
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4), blob.len() as u32);
+        assert_eq!(be32(&blob, 20), FDT_VERSION);
+        assert_eq!(be32(&blob, 24), FDT_LAST_COMP_VERSION);
+        assert_eq!(off_mem_rsvmap, FDT_HEADER_SIZE);
+        // The memory reservation block only has its terminating entry.
+        assert!(blob[off_mem_rsvmap as usize..off_dt_struct as usize]
+            .iter()
+            .all(|byte| *byte == 0));
+        assert_eq!(off_dt_struct, off_mem_rsvmap + 16);
+        assert_eq!(off_dt_strings, off_dt_struct + size_dt_struct);
+        assert_eq!(blob.len() as u32, off_dt_strings + size_dt_strings);
+        assert_eq!(off_dt_struct % 8, 0);
+        assert_eq!(size_dt_struct % 4, 0);
+    }
+
+    #[test]
+    fn structure_has_aligned_tokens() {
+        let blob = small_tree();
+        let off_dt_struct = be32(&blob, 8) as usize;
+        let off_dt_strings = be32(&blob, 12) as usize;
+
+        let mut expected = Vec::new();
+        let mut words = |words: &[u32]| {
+            for word in words {
+                expected.extend(word.to_be_bytes());
+            }
+        };
+        words(&[FDT_BEGIN_NODE, 0]);
+        words(&[FDT_PROP, 4, 0, 2]);
+        words(&[FDT_BEGIN_NODE, 0x6370_7540, 0x3000_0000]); // "cpu@0"
+        words(&[FDT_PROP, 6, 15, 0x7269_7363, 0x7600_0000]); // "riscv"
+        words(&[FDT_PROP, 0, 26]);
+        words(&[FDT_PROP, 4, 0, 1]);
+        words(&[FDT_END_NODE, FDT_END_NODE, FDT_END]);
+        assert_eq!(&blob[off_dt_struct..off_dt_strings], &expected[..]);
+    }
+
+    #[test]
+    fn strings_are_deduplicated() {
+        let blob = small_tree();
+        let off_dt_strings = be32(&blob, 12) as usize;
+        assert_eq!(
+            &blob[off_dt_strings..],
+            &b"#address-cells\0compatible\0interrupt-controller\0"[..]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "a node is not ended")]
+    fn unended_node_is_rejected() {
+        let mut fdt = Fdt::new();
+        fdt.begin_node("");
+        fdt.begin_node("soc");
+        fdt.end_node();
+        fdt.finish();
+    }
+
+    #[test]
+    #[should_panic(expected = "no node to end")]
+    fn extra_end_node_is_rejected() {
+        let mut fdt = Fdt::new();
+        fdt.begin_node("");
+        fdt.end_node();
+        fdt.end_node();
+    }
+}
@@ -0,0 +1,469 @@
+//! The framebuffer module contains a simple framebuffer, a region of pixels that the guest draws to
+//! and the host saves as an image. There is no display: the pixels are written to a PPM or PNG
+//! file on exit, every few frames, or when the guest executes the `FRAME_DUMP_HINT` hint, so that
+//! it works without a window system.
+//! The device tree binding:
+//! https://www.kernel.org/doc/Documentation/devicetree/bindings/display/simple-framebuffer.yaml
+
+use std::fs::File;
+use std::io::{self, prelude::*, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::bus::*;
+use crate::clint::*;
+use crate::fdt::*;
+use crate::snapshot::*;
+use crate::trap::*;
+
+/// The number of frames per second, used to count frames for periodic dumps.
+pub const FRAMES_PER_SECOND: u64 = 60;
+
+/// The immediate of `slti x0, x0, imm`, a hint for custom use that requests a dump of the
+/// framebuffer. It does nothing on other machines.
+pub const FRAME_DUMP_HINT: u64 = 0x535;
+
+/// The layout of a pixel in the memory, named as in the device tree binding. The components are
+/// listed from the most significant bits of a little-endian word.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum PixelFormat {
+    R5G6B5,
+    R8G8B8,
+    X8R8G8B8,
+    A8R8G8B8,
+    X8B8G8R8,
+    A8B8G8R8,
+}
+
+impl PixelFormat {
+    /// Return the format named `name`, e.g. `x8r8g8b8`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            PixelFormat::R5G6B5,
+            PixelFormat::R8G8B8,
+            PixelFormat::X8R8G8B8,
+            PixelFormat::A8R8G8B8,
+            PixelFormat::X8B8G8R8,
+            PixelFormat::A8B8G8R8,
+        ]
+        .iter()
+        .copied()
+        .find(|format| format.name() == name)
+    }
+
+    /// Return the name in the device tree.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PixelFormat::R5G6B5 => "r5g6b5",
+            PixelFormat::R8G8B8 => "r8g8b8",
+            PixelFormat::X8R8G8B8 => "x8r8g8b8",
+            PixelFormat::A8R8G8B8 => "a8r8g8b8",
+            PixelFormat::X8B8G8R8 => "x8b8g8r8",
+            PixelFormat::A8B8G8R8 => "a8b8g8r8",
+        }
+    }
+
+    /// Return the size of a pixel in bytes.
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::R5G6B5 => 2,
+            PixelFormat::R8G8B8 => 3,
+            _ => 4,
+        }
+    }
+
+    /// Return the red, green and blue components of `pixel`. Alpha is ignored.
+    fn rgb(&self, pixel: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::R5G6B5 => {
+                let value = u16::from_le_bytes([pixel[0], pixel[1]]);
+                let (r, g, b) = (value >> 11, (value >> 5) & 0x3f, value & 0x1f);
+                // Scale the components to 8 bits.
+                [
+                    ((r << 3) | (r >> 2)) as u8,
+                    ((g << 2) | (g >> 4)) as u8,
+                    ((b << 3) | (b >> 2)) as u8,
+                ]
+            }
+            PixelFormat::R8G8B8 | PixelFormat::X8R8G8B8 | PixelFormat::A8R8G8B8 => {
+                [pixel[2], pixel[1], pixel[0]]
+            }
+            PixelFormat::X8B8G8R8 | PixelFormat::A8B8G8R8 => [pixel[0], pixel[1], pixel[2]],
+        }
+    }
+}
+
+/// A simple framebuffer. Lines are stored one after another without padding.
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    pixels: Vec<u8>,
+    /// The file written on request and every `dump_every` frames, or None if the pixels aren't
+    /// dumped while running.
+    dump: Option<PathBuf>,
+    /// The interval of periodic dumps in frames, or 0 if the pixels are dumped only on request.
+    dump_every: u64,
+    /// True if a dump has been requested by the guest and not written yet.
+    dump_requested: bool,
+    /// The number of frames since the machine was created.
+    frames: u64,
+    /// The number of cycles since the last frame.
+    cycles: u64,
+}
+
+impl Device for Framebuffer {
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let bytes = self
+            .bytes(addr, size)
+            .ok_or(Exception::LoadAccessFault(addr))?;
+        let mut value = [0; 8];
+        value[..bytes.len()].copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        let (start, len) = match self.bytes(addr, size) {
+            Some(bytes) => (addr as usize, bytes.len()),
+            None => return Err(Exception::StoreAMOAccessFault(addr)),
+        };
+        self.pixels[start..start + len].copy_from_slice(&value.to_le_bytes()[..len]);
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.pixels.fill(0);
+        self.dump_requested = false;
+    }
+
+    fn tick(&mut self, cycles: u64, _dma: &mut DmaContext) {
+        if self.dump.is_none() {
+            return;
+        }
+        if self.dump_requested {
+            self.dump_requested = false;
+            self.dump_frame();
+        }
+        self.cycles += cycles;
+        if self.cycles < CLINT_TIMEBASE_FREQUENCY / FRAMES_PER_SECOND {
+            return;
+        }
+        self.cycles = 0;
+        self.frames += 1;
+        if self.dump_every != 0 && self.frames % self.dump_every == 0 {
+            self.dump_frame();
+        }
+    }
+
+    fn describe(&self, base: u64, size: u64, fdt: &mut Fdt) {
+        fdt.begin_node(&format!("framebuffer@{:x}", base));
+        fdt.property_string("compatible", "simple-framebuffer");
+        fdt.property_reg(base, size);
+        fdt.property_u32("width", self.width as u32);
+        fdt.property_u32("height", self.height as u32);
+        fdt.property_u32("stride", self.stride() as u32);
+        fdt.property_string("format", self.format.name());
+        fdt.end_node();
+    }
+
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        snapshot
+            .u64(self.width as u64)
+            .u64(self.height as u64)
+            .bytes(self.format.name().as_bytes())
+            .compressed(&self.pixels)
+            .u64(self.frames)
+            .u64(self.cycles);
+        Ok(())
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        let (width, height) = (snapshot.u64()?, snapshot.u64()?);
+        let format = snapshot.bytes()?;
+        if (width, height) != (self.width as u64, self.height as u64)
+            || format != self.format.name().as_bytes()
+        {
+            return Err(snapshot_error("the framebuffer doesn't match"));
+        }
+        let pixels = snapshot.compressed()?;
+        if pixels.len() != self.pixels.len() {
+            return Err(snapshot_error("invalid framebuffer pixels"));
+        }
+        self.pixels = pixels;
+        self.frames = snapshot.u64()?;
+        self.cycles = snapshot.u64()?;
+        Ok(())
+    }
+}
+
+impl Framebuffer {
+    /// Create a new black framebuffer of `width` x `height` pixels in `format`.
+    pub fn new(width: usize, height: usize, format: PixelFormat) -> Self {
+        assert!(width > 0 && height > 0, "the framebuffer is empty");
+        Self {
+            width,
+            height,
+            format,
+            pixels: vec![0; width * height * format.bytes_per_pixel()],
+            dump: None,
+            dump_every: 0,
+            dump_requested: false,
+            frames: 0,
+            cycles: 0,
+        }
+    }
+
+    /// Save the pixels to `path` when the guest requests it, and also every `every` frames if
+    /// it's given. `{}` in the path is replaced with the frame number.
+    pub fn set_dump(&mut self, path: &Path, every: Option<u64>) {
+        assert!(every != Some(0), "the interval must be at least one frame");
+        self.dump = Some(path.to_path_buf());
+        self.dump_every = every.unwrap_or(0);
+    }
+
+    /// Request a dump of the pixels to the file given to `set_dump`. It's written on the next
+    /// tick, outside of the hart that requested it. Without a file, it's ignored.
+    pub fn request_dump(&mut self) {
+        self.dump_requested = self.dump.is_some();
+    }
+
+    /// Save the pixels to the file given to `set_dump` and report an error.
+    fn dump_frame(&self) {
+        if let Some(dump) = &self.dump {
+            if let Err(e) = self.save_frame(dump) {
+                eprintln!("framebuffer: {}: {}", dump.display(), e);
+            }
+        }
+    }
+
+    /// Return the size of the region to map the framebuffer, rounded up to a page.
+    pub fn size(&self) -> u64 {
+        (self.pixels.len() as u64 + 0xfff) & !0xfff
+    }
+
+    /// Return the number of bytes of a line.
+    pub fn stride(&self) -> usize {
+        self.width * self.format.bytes_per_pixel()
+    }
+
+    /// Return the bytes accessed by a load or a store of `size` bits at `addr`, or None if they
+    /// are outside of the pixels.
+    fn bytes(&self, addr: u64, size: u64) -> Option<&[u8]> {
+        let start = addr as usize;
+        self.pixels
+            .get(start..start.checked_add(size as usize / 8)?)
+    }
+
+    /// Return the pixels as red, green and blue bytes, line by line.
+    fn rgb(&self) -> Vec<u8> {
+        self.pixels
+            .chunks_exact(self.format.bytes_per_pixel())
+            .flat_map(|pixel| self.format.rgb(pixel))
+            .collect()
+    }
+
+    /// Save the pixels to `path` with `{}` replaced with the current frame number, so that every
+    /// dump is kept.
+    pub fn save_frame(&self, path: &Path) -> io::Result<()> {
+        let path = path
+            .to_string_lossy()
+            .replace("{}", &self.frames.to_string());
+        self.save(Path::new(&path))
+    }
+
+    /// Save the pixels to `path`, as a PNG file if the extension is `png` and as a binary PPM
+    /// file otherwise.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let rgb = self.rgb();
+        if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("png"))
+        {
+            write_png(&mut file, self.width, self.height, &rgb)?;
+        } else {
+            write!(file, "P6\n{} {}\n255\n", self.width, self.height)?;
+            file.write_all(&rgb)?;
+        }
+        file.flush()
+    }
+}
+
+/// Write an RGB image to `out` as a PNG file. The image data is stored without compression, so
+/// that no deflate encoder is needed.
+fn write_png(out: &mut impl Write, width: usize, height: usize, rgb: &[u8]) -> io::Result<()> {
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut header = Vec::new();
+    header.extend((width as u32).to_be_bytes());
+    header.extend((height as u32).to_be_bytes());
+    // 8 bits per component, truecolor, deflate, adaptive filtering and no interlace.
+    header.extend([8, 2, 0, 0, 0]);
+    write_png_chunk(out, b"IHDR", &header)?;
+
+    // Each line starts with the filter type 0, which means no filter.
+    let mut lines = Vec::with_capacity((width * 3 + 1) * height);
+    for line in rgb.chunks_exact(width * 3) {
+        lines.push(0);
+        lines.extend(line);
+    }
+    // A zlib stream of stored deflate blocks.
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = lines.chunks(0xffff);
+    let count = blocks.len();
+    for (i, block) in blocks.enumerate() {
+        zlib.push((i + 1 == count) as u8);
+        zlib.extend((block.len() as u16).to_le_bytes());
+        zlib.extend((!(block.len() as u16)).to_le_bytes());
+        zlib.extend(block);
+    }
+    zlib.extend(adler32(&lines).to_be_bytes());
+    write_png_chunk(out, b"IDAT", &zlib)?;
+
+    write_png_chunk(out, b"IEND", &[])
+}
+
+/// Write a PNG chunk of `kind` with `data`.
+fn write_png_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc32(kind.iter().chain(data)).to_be_bytes())
+}
+
+/// Return the CRC-32 of `bytes`, which ends a PNG chunk.
+fn crc32<'a>(bytes: impl IntoIterator<Item = &'a u8>) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Return the Adler-32 checksum of `data`, which ends a zlib stream.
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    /// Return the kind and the data of each chunk of a PNG file after the signature, checking
+    /// their CRCs.
+    fn png_chunks(mut png: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut chunks = Vec::new();
+        while !png.is_empty() {
+            let len = u32::from_be_bytes(png[..4].try_into().unwrap()) as usize;
+            let (kind, data) = (&png[4..8], &png[8..8 + len]);
+            let crc = u32::from_be_bytes(png[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32(kind.iter().chain(data)));
+            chunks.push((kind.try_into().unwrap(), data));
+            png = &png[12 + len..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn checksums_match_known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        // The sums are reduced between chunks of long inputs.
+        assert_eq!(adler32(&[0xff; 100_000]), 0x149a_302c);
+        assert_eq!(adler32(&vec![0; 1 << 20]), 0x00f0_0001);
+    }
+
+    #[test]
+    fn large_png_is_split_into_stored_blocks() {
+        // 601 bytes a line with the filter type, more than 64 KiB in total.
+        let (width, height) = (200, 120);
+        let rgb: Vec<u8> = (0..width * height * 3).map(|i| (i % 253) as u8).collect();
+        let mut png = Vec::new();
+        write_png(&mut png, width, height, &rgb).unwrap();
+        assert_eq!(png[..8], *b"\x89PNG\r\n\x1a\n");
+        let chunks = png_chunks(&png[8..]);
+        let kinds: Vec<&[u8; 4]> = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 200, 0, 0, 0, 120, 8, 2, 0, 0, 0]);
+
+        let zlib = chunks[1].1;
+        assert_eq!(zlib[..2], [0x78, 0x01]);
+        assert_eq!(u16::from_be_bytes([zlib[0], zlib[1]]) % 31, 0);
+        let (mut pos, mut lines, mut lens) = (2, Vec::new(), Vec::new());
+        loop {
+            let last = zlib[pos];
+            let len = u16::from_le_bytes([zlib[pos + 1], zlib[pos + 2]]);
+            let nlen = u16::from_le_bytes([zlib[pos + 3], zlib[pos + 4]]);
+            assert_eq!(nlen, !len);
+            lines.extend_from_slice(&zlib[pos + 5..pos + 5 + len as usize]);
+            lens.push(len as usize);
+            pos += 5 + len as usize;
+            if last == 1 {
+                break;
+            }
+            assert_eq!(last, 0);
+        }
+        assert_eq!(lens, [0xffff, 601 * 120 - 0xffff]);
+        assert_eq!(zlib[pos..], adler32(&lines).to_be_bytes());
+        for (line, pixels) in lines.chunks(601).zip(rgb.chunks(600)) {
+            assert_eq!(line[0], 0);
+            assert_eq!(line[1..], *pixels);
+        }
+    }
+
+    #[test]
+    fn pixels_are_converted_to_rgb() {
+        // The components are scaled so that the largest value is 255.
+        for (value, rgb) in [
+            (0xf800u16, [255, 0, 0]),
+            (0x07e0, [0, 255, 0]),
+            (0x001f, [0, 0, 255]),
+            (0x8410, [132, 130, 132]),
+            (0xffff, [255, 255, 255]),
+        ] {
+            assert_eq!(PixelFormat::R5G6B5.rgb(&value.to_le_bytes()), rgb);
+        }
+        // Red is in the most significant bits, the last byte of a little-endian word.
+        for format in [
+            PixelFormat::R8G8B8,
+            PixelFormat::X8R8G8B8,
+            PixelFormat::A8R8G8B8,
+        ] {
+            assert_eq!(format.rgb(&[0x33, 0x22, 0x11, 0xff]), [0x11, 0x22, 0x33]);
+        }
+        for format in [PixelFormat::X8B8G8R8, PixelFormat::A8B8G8R8] {
+            assert_eq!(format.rgb(&[0x11, 0x22, 0x33, 0xff]), [0x11, 0x22, 0x33]);
+        }
+
+        // A red and a blue pixel saved as PPM.
+        let mut fb = Framebuffer::new(2, 1, PixelFormat::R5G6B5);
+        fb.store(0, 32, 0x001f_f800).unwrap();
+        let path = env::temp_dir().join(format!("rvemu-fb-{}.ppm", process::id()));
+        fb.save(&path).unwrap();
+        let ppm = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(ppm, b"P6\n2 1\n255\n\xff\x00\x00\x00\x00\xff");
+    }
+}
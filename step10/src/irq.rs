@@ -0,0 +1,131 @@
+//! The irq module contains interrupt wires. An `IrqLine` connects a device to an interrupt source
+//! of the PLIC, and `HartInterrupts` connects the interrupt controllers (CLINT and PLIC) to the
+//! interrupt-pending bits of a hart. Both are shared flags, so a device can raise an interrupt
+//! from any thread, e.g. the thread reading the standard input for the UART.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+/// The number of interrupt sources of the PLIC. Source 0 is reserved and means "no interrupt".
+pub const IRQ_COUNT: u64 = 64;
+
+/// The level of an interrupt source of the PLIC. A device raises the line while it requests an
+/// interrupt and lowers it once the request has been served.
+#[derive(Debug, Clone)]
+pub struct IrqLine {
+    /// The interrupt source number.
+    irq: u64,
+    /// The levels of all interrupt sources, shared with the PLIC.
+    levels: Arc<AtomicU64>,
+}
+
+impl IrqLine {
+    /// Create a line for the source `irq` whose levels are read from `levels`.
+    pub(crate) fn new(irq: u64, levels: Arc<AtomicU64>) -> Self {
+        assert!(
+            irq > 0 && irq < IRQ_COUNT,
+            "invalid interrupt source {}",
+            irq
+        );
+        Self { irq, levels }
+    }
+
+    /// Return the interrupt source number.
+    pub fn irq(&self) -> u64 {
+        self.irq
+    }
+
+    /// Drive the line high.
+    pub fn raise(&self) {
+        self.levels.fetch_or(1 << self.irq, Ordering::AcqRel);
+    }
+
+    /// Drive the line low.
+    pub fn lower(&self) {
+        self.levels.fetch_and(!(1 << self.irq), Ordering::AcqRel);
+    }
+
+    /// Drive the line to `level`.
+    pub fn set(&self, level: bool) {
+        if level {
+            self.raise();
+        } else {
+            self.lower();
+        }
+    }
+
+    /// Return true if the line is high.
+    pub fn is_raised(&self) -> bool {
+        self.levels.load(Ordering::Acquire) & (1 << self.irq) != 0
+    }
+}
+
+/// The interrupt-pending bits driven into a hart from outside (MEIP, SEIP, MTIP and MSIP). A hart
+/// sees them in its MIP register in addition to the bits written by software.
+#[derive(Debug, Clone, Default)]
+pub struct HartInterrupts {
+    pending: Arc<AtomicU64>,
+}
+
+impl HartInterrupts {
+    /// Create wires with no interrupt pending.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drive the MIP bits in `mask` to `level`.
+    pub fn set(&self, mask: u64, level: bool) {
+        // Most updates don't change anything, so avoid the read-modify-write in that case.
+        let pending = self.pending();
+        if (pending & mask == mask && level) || (pending & mask == 0 && !level) {
+            return;
+        }
+        if level {
+            self.pending.fetch_or(mask, Ordering::AcqRel);
+        } else {
+            self.pending.fetch_and(!mask, Ordering::AcqRel);
+        }
+    }
+
+    /// Return the MIP bits driven high.
+    pub fn pending(&self) -> u64 {
+        self.pending.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_drive_their_own_level() {
+        let levels = Arc::new(AtomicU64::new(0));
+        let (a, b) = (
+            IrqLine::new(3, levels.clone()),
+            IrqLine::new(5, levels.clone()),
+        );
+        a.raise();
+        // Raising twice is the same as raising once: the line is a level, not a counter.
+        a.raise();
+        b.set(true);
+        assert!(a.is_raised() && b.is_raised());
+        assert_eq!(levels.load(Ordering::Acquire), 1 << 3 | 1 << 5);
+
+        a.lower();
+        assert!(!a.is_raised());
+        assert!(b.is_raised());
+        b.set(false);
+        assert_eq!(levels.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn hart_interrupts_are_set_by_mask() {
+        let hart = HartInterrupts::new();
+        let clone = hart.clone();
+        hart.set(0x888, true);
+        hart.set(0x8, false);
+        assert_eq!(clone.pending(), 0x880);
+    }
+}
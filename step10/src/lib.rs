@@ -0,0 +1,36 @@
+//! A RISC-V emulator. A `Machine` has harts, each of which is a `Cpu` that executes instructions
+//! and accesses the memory and the devices mapped to the shared `Bus`. More devices can be mapped
+//! with `Bus::register` from the closure given to `Machine::with_devices`.
+
+#![allow(clippy::new_without_default)]
+
+pub mod boot;
+pub mod bus;
+pub mod chardev;
+pub mod clint;
+pub mod cpu;
+pub mod disk;
+pub mod fdt;
+pub mod framebuffer;
+pub mod irq;
+pub mod machine;
+pub mod memory;
+pub mod net;
+pub mod plic;
+pub mod power;
+pub mod qcow2;
+pub mod rom;
+pub mod rtc;
+pub mod sbi;
+pub mod snapshot;
+pub mod syscon;
+pub mod trap;
+pub mod uart;
+pub mod virtio;
+#[cfg(unix)]
+pub mod virtio_9p;
+pub mod virtio_blk;
+pub mod virtio_console;
+pub mod virtio_input;
+pub mod virtio_net;
+pub mod virtio_rng;
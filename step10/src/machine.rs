@@ -0,0 +1,548 @@
+//! The machine module contains `Machine`, which connects harts to a shared bus. By default, the
+//! harts run interleaved on one host thread: every running hart executes one instruction in the
+//! order of the hart IDs, and then the devices are advanced by one cycle. The execution is
+//! deterministic.
+//!
+//! In the parallel mode, each hart runs on its own host thread and the devices are advanced on the
+//! calling thread. The memory is shared as host atomics, so the guest sees the RISC-V weak memory
+//! ordering (RVWMO) as long as it uses fences and atomic instructions.
+//!
+//! The complete state of a machine can be saved to a snapshot and restored later, to a machine
+//! created with the same configuration.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
+use std::thread;
+use std::time::Duration;
+
+use crate::bus::*;
+use crate::clint::*;
+use crate::cpu::*;
+use crate::framebuffer::*;
+use crate::irq::*;
+use crate::power::*;
+use crate::sbi::*;
+use crate::snapshot::*;
+use crate::virtio::*;
+
+/// The default number of harts. Same as xv6, which expects 3 harts.
+pub const DEFAULT_HARTS: usize = 3;
+/// The largest number of harts. The mtimecmp registers of the CLINT end at mtime.
+pub const MAX_HARTS: usize = ((CLINT_MTIME - CLINT_MTIMECMP) / 8) as usize;
+
+/// The number of instructions a hart executes between checks of the shared state in the parallel
+/// mode.
+const PARALLEL_BATCH: u64 = 1024;
+
+/// Why the machine stopped running.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Exit {
+    /// A shutdown has been requested, with the exit status.
+    Shutdown(u16),
+    /// A reboot has been requested.
+    Reboot,
+    /// A snapshot has been requested. The machine can go on running after it has been taken.
+    Snapshot,
+    /// No hart executes instructions anymore.
+    Stopped,
+    /// The hart with the ID takes the same trap over and over again.
+    DoubleFault(usize),
+}
+
+/// A machine with harts that share the memory and the devices.
+pub struct Machine {
+    /// The bus shared by all harts.
+    pub bus: Arc<Bus>,
+    /// The harts, indexed by the hart ID.
+    pub harts: Vec<Cpu>,
+    /// The number of cycles the devices have been advanced by since the machine was created.
+    cycles: u64,
+    /// The cycle when a snapshot is requested, if any.
+    snapshot_at: Option<u64>,
+}
+
+impl Machine {
+    /// Create a machine with `harts` harts and `memory_size` bytes of memory, copy `binary` to the
+    /// start of the memory, map `virtio_devices` to the virtio slots in order, and map
+    /// `framebuffer` if it's given.
+    pub fn new(
+        binary: Vec<u8>,
+        virtio_devices: Vec<Box<dyn VirtioDevice>>,
+        framebuffer: Option<Framebuffer>,
+        memory_size: u64,
+        harts: usize,
+    ) -> Self {
+        Self::with_devices(
+            binary,
+            virtio_devices,
+            framebuffer,
+            memory_size,
+            harts,
+            |_| Ok(()),
+        )
+        .expect("failed to map the default devices")
+    }
+
+    /// Create a machine like `new`, and call `map_devices` to register more devices with
+    /// `Bus::register` before the bus is shared by the harts. Return the error of `map_devices`,
+    /// e.g. when a device overlaps another one.
+    pub fn with_devices(
+        binary: Vec<u8>,
+        virtio_devices: Vec<Box<dyn VirtioDevice>>,
+        framebuffer: Option<Framebuffer>,
+        memory_size: u64,
+        harts: usize,
+        map_devices: impl FnOnce(&mut Bus) -> Result<(), BusError>,
+    ) -> Result<Self, BusError> {
+        assert!(
+            (1..=MAX_HARTS).contains(&harts),
+            "the number of harts must be between 1 and {}",
+            MAX_HARTS
+        );
+        let interrupts: Vec<HartInterrupts> = (0..harts).map(|_| HartInterrupts::new()).collect();
+        let mut bus = Bus::new(
+            binary,
+            virtio_devices,
+            framebuffer,
+            memory_size,
+            &interrupts,
+        );
+        map_devices(&mut bus)?;
+        let bus = Arc::new(bus);
+        let harts = interrupts
+            .into_iter()
+            .enumerate()
+            .map(|(hartid, interrupts)| Cpu::new(hartid as u64, bus.clone(), interrupts))
+            .collect();
+        Ok(Self {
+            bus,
+            harts,
+            cycles: 0,
+            snapshot_at: None,
+        })
+    }
+
+    /// Enable the built-in SBI on all harts. Only hart 0 runs, and the others wait for
+    /// `sbi_hart_start`.
+    pub fn enable_sbi(&mut self) {
+        let sbi = Arc::new(Sbi::new(self.harts.len()));
+        for hart in &mut self.harts {
+            hart.sbi = Some(sbi.clone());
+        }
+    }
+
+    /// Execute one instruction on every running hart, and then advance the devices by one cycle.
+    /// Return a hart that took the same trap over and over again, if any.
+    pub fn step(&mut self) -> Option<&Cpu> {
+        for hart in &mut self.harts {
+            hart.step();
+        }
+        self.bus.tick(1);
+        self.cycles += 1;
+        self.check_snapshot_at();
+        self.harts
+            .iter()
+            .find(|hart| hart.trap_log.double_fault().is_some())
+    }
+
+    /// Return the number of cycles the devices have been advanced by since the machine was
+    /// created. A reboot doesn't reset it.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Request a snapshot when the devices have been advanced by `cycles` cycles since the machine
+    /// was created. `run` returns `Exit::Snapshot` then.
+    pub fn request_snapshot_at(&mut self, cycles: u64) {
+        self.snapshot_at = Some(cycles);
+    }
+
+    /// Request a snapshot if the cycle given to `request_snapshot_at` has come.
+    fn check_snapshot_at(&mut self) {
+        if self.snapshot_at.is_some_and(|at| self.cycles >= at) {
+            self.snapshot_at = None;
+            self.bus.power().request(PowerRequest::Snapshot);
+        }
+    }
+
+    /// Return a snapshot of the complete state of the machine: the harts, the SBI, every device
+    /// and the memory. It's taken between instructions, e.g. after `run` has returned.
+    pub fn snapshot(&self) -> io::Result<Vec<u8>> {
+        let mut snapshot = SnapshotWriter::new();
+        snapshot
+            .u64(u64::from_le_bytes(SNAPSHOT_MAGIC))
+            .u32(SNAPSHOT_VERSION)
+            .u64(self.cycles)
+            .u64(self.bus.memory_size())
+            .u64(self.harts.len() as u64);
+        for hart in &self.harts {
+            snapshot.section(|snapshot| {
+                hart.save(snapshot);
+                Ok(())
+            })?;
+        }
+        let sbi = &self.harts[0].sbi;
+        snapshot.bool(sbi.is_some());
+        if let Some(sbi) = sbi {
+            snapshot.section(|snapshot| {
+                sbi.save(snapshot);
+                Ok(())
+            })?;
+        }
+        snapshot.section(|snapshot| self.bus.save(snapshot))?;
+        self.bus.memory().save(&mut snapshot);
+        Ok(snapshot.into_bytes())
+    }
+
+    /// Restore a snapshot taken by `snapshot`. The machine must have the same memory size, harts
+    /// and devices as the machine the snapshot was taken on. If it fails, the state of the
+    /// machine is left partly restored.
+    pub fn restore(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut snapshot = SnapshotReader::new(data);
+        if snapshot.u64()? != u64::from_le_bytes(SNAPSHOT_MAGIC) {
+            return Err(snapshot_error("not a snapshot"));
+        }
+        let version = snapshot.u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(snapshot_error(&format!("unsupported version {}", version)));
+        }
+        let cycles = snapshot.u64()?;
+        // Check the configuration before anything is restored.
+        if snapshot.u64()? != self.bus.memory_size() {
+            return Err(snapshot_error("the memory size doesn't match"));
+        }
+        if snapshot.u64()? != self.harts.len() as u64 {
+            return Err(snapshot_error("the number of harts doesn't match"));
+        }
+        for hart in &mut self.harts {
+            snapshot.section(|snapshot| hart.restore(snapshot))?;
+        }
+        if snapshot.bool()? {
+            if self.harts[0].sbi.is_none() {
+                self.enable_sbi();
+            }
+            if let Some(sbi) = &self.harts[0].sbi {
+                snapshot.section(|snapshot| sbi.restore(snapshot))?;
+            }
+        } else {
+            for hart in &mut self.harts {
+                hart.sbi = None;
+            }
+        }
+        snapshot.section(|snapshot| self.bus.restore(snapshot))?;
+        self.bus.memory().restore(&mut snapshot)?;
+        snapshot.finish()?;
+        self.cycles = cycles;
+        Ok(())
+    }
+
+    /// Save a snapshot to the file at `path`.
+    pub fn save_snapshot(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.snapshot()?)
+    }
+
+    /// Restore the snapshot in the file at `path`.
+    pub fn load_snapshot(&mut self, path: &Path) -> io::Result<()> {
+        self.restore(&fs::read(path)?)
+    }
+
+    /// Return true if no hart executes instructions anymore.
+    pub fn is_stopped(&self) -> bool {
+        self.harts.iter().all(|hart| hart.is_stopped())
+    }
+
+    /// Put the harts and all devices back to their power-on state. The memory is kept.
+    pub fn reset(&mut self) {
+        for hart in &mut self.harts {
+            hart.reset();
+        }
+        if self.harts[0].sbi.is_some() {
+            self.enable_sbi();
+        }
+        self.bus.reset();
+    }
+
+    /// Run the harts interleaved on the current thread until the machine stops.
+    pub fn run(&mut self) -> Exit {
+        loop {
+            if let Some(cpu) = self.step() {
+                return Exit::DoubleFault(cpu.hartid());
+            }
+            if let Some(exit) = self.check_exit() {
+                return exit;
+            }
+        }
+    }
+
+    /// Run each hart on its own host thread until the machine stops. The devices are advanced on
+    /// the current thread, by the number of instructions executed by the fastest hart.
+    pub fn run_parallel(&mut self) -> Exit {
+        let running = AtomicBool::new(true);
+        // The number of instructions executed by each hart, indexed by the hart ID.
+        let retired: Vec<AtomicU64> = self.harts.iter().map(|_| AtomicU64::new(0)).collect();
+        let faulted: Vec<AtomicBool> = self.harts.iter().map(|_| AtomicBool::new(false)).collect();
+        let sbi = self.harts[0].sbi.clone();
+        let (start, snapshot_at) = (self.cycles, self.snapshot_at);
+        let bus = &self.bus;
+        let harts = &mut self.harts;
+
+        let (exit, ticked) = thread::scope(|scope| {
+            for hart in harts.iter_mut() {
+                let (running, retired, faulted) = (&running, &retired, &faulted);
+                scope.spawn(move || {
+                    let hartid = hart.hartid();
+                    while running.load(Ordering::Relaxed) {
+                        if hart.is_stopped() {
+                            // Wait for `sbi_hart_start` without spinning.
+                            hart.step();
+                            thread::sleep(Duration::from_micros(100));
+                            continue;
+                        }
+                        // Stop right after a shutdown or a reboot has been requested, as a hart
+                        // does on the single thread.
+                        let mut stepped = 0;
+                        while stepped < PARALLEL_BATCH && !bus.power().is_pending() {
+                            hart.step();
+                            stepped += 1;
+                        }
+                        retired[hartid].fetch_add(stepped, Ordering::Relaxed);
+                        if hart.trap_log.double_fault().is_some() {
+                            faulted[hartid].store(true, Ordering::Release);
+                            break;
+                        }
+                    }
+                });
+            }
+
+            let mut ticked = 0;
+            let exit = loop {
+                let cycles = retired
+                    .iter()
+                    .map(|retired| retired.load(Ordering::Relaxed))
+                    .max()
+                    .unwrap_or(0);
+                if cycles > ticked {
+                    bus.tick(cycles - ticked);
+                    ticked = cycles;
+                    if snapshot_at.is_some_and(|at| start + ticked >= at) {
+                        bus.power().request(PowerRequest::Snapshot);
+                    }
+                } else {
+                    thread::yield_now();
+                }
+
+                // A power request is taken after all harts have stopped, so that no hart runs on
+                // until it notices the request.
+                if bus.power().is_pending() {
+                    break None;
+                }
+                if let Some(hartid) = faulted
+                    .iter()
+                    .position(|faulted| faulted.load(Ordering::Acquire))
+                {
+                    break Some(Exit::DoubleFault(hartid));
+                }
+                let stopped = match &sbi {
+                    Some(sbi) => (0..sbi.harts()).all(|hartid| sbi.is_stopped(hartid)),
+                    None => false,
+                };
+                if stopped {
+                    break Some(Exit::Stopped);
+                }
+            };
+            running.store(false, Ordering::Relaxed);
+            (exit, ticked)
+        });
+        // The last batches may have been counted after the loop has stopped.
+        let cycles = retired
+            .iter()
+            .map(|retired| retired.load(Ordering::Relaxed))
+            .max()
+            .unwrap_or(0);
+        self.bus.tick(cycles - ticked);
+        self.cycles += cycles;
+        self.check_snapshot_at();
+        match exit {
+            Some(exit) => exit,
+            None => self.check_exit().expect("no power request is pending"),
+        }
+    }
+
+    /// Return why the machine has to stop, if it has to.
+    fn check_exit(&self) -> Option<Exit> {
+        match self.bus.power().take() {
+            Some(PowerRequest::Shutdown(status)) => Some(Exit::Shutdown(status)),
+            Some(PowerRequest::Reboot) => Some(Exit::Reboot),
+            Some(PowerRequest::Snapshot) => Some(Exit::Snapshot),
+            None if self.is_stopped() => Some(Exit::Stopped),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::*;
+    use crate::trap::*;
+
+    /// Return a machine with the memory of the default size and 2 harts.
+    fn machine(binary: Vec<u8>) -> Machine {
+        Machine::new(binary, Vec::new(), None, MEMORY_SIZE, 2)
+    }
+
+    #[test]
+    fn restored_snapshot_is_the_same() {
+        // addi ra, zero, 5; addi sp, ra, 1
+        let binary = [0x00500093u32, 0x00108113]
+            .iter()
+            .flat_map(|inst| inst.to_le_bytes())
+            .collect();
+        let mut machine = machine(binary);
+        machine.enable_sbi();
+        // Skip the boot ROM, which is empty without a device tree.
+        machine.harts[0].pc = MEMORY_BASE;
+        machine.step();
+        machine.step();
+        machine
+            .bus
+            .store(MEMORY_BASE + 0x10_0000, 64, 0x0123_4567_89ab_cdef)
+            .unwrap();
+        machine.harts[1].regs[10] = 42;
+        let snapshot = machine.snapshot().unwrap();
+
+        let mut restored = self::machine(Vec::new());
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.snapshot().unwrap(), snapshot);
+        assert_eq!(restored.cycles(), 2);
+        assert!(restored.harts[0].sbi.is_some());
+        assert_eq!(restored.harts[0].regs[2], 6);
+        assert_eq!(restored.harts[1].regs[10], 42);
+        assert_eq!(
+            restored.bus.load(MEMORY_BASE + 0x10_0000, 64).unwrap(),
+            0x0123_4567_89ab_cdef
+        );
+    }
+
+    #[test]
+    fn snapshot_of_another_machine_is_rejected() {
+        let snapshot = machine(Vec::new()).snapshot().unwrap();
+        let mut other = Machine::new(Vec::new(), Vec::new(), None, MEMORY_SIZE, 1);
+        assert!(other.restore(&snapshot).is_err());
+        let mut truncated = self::machine(Vec::new());
+        assert!(truncated.restore(&snapshot[..snapshot.len() - 1]).is_err());
+    }
+
+    /// A device that returns a constant.
+    struct Constant(u64);
+
+    impl Device for Constant {
+        fn load(&mut self, _addr: u64, _size: u64) -> Result<u64, Exception> {
+            Ok(self.0)
+        }
+
+        fn store(&mut self, _addr: u64, _size: u64, _value: u64) -> Result<(), Exception> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn harts_access_registered_devices() {
+        // lui t0, 0x40000; ld t1, 0(t0)
+        let binary = [0x400002b7u32, 0x0002b303]
+            .iter()
+            .flat_map(|inst| inst.to_le_bytes())
+            .collect();
+        let mut machine = Machine::with_devices(binary, Vec::new(), None, MEMORY_SIZE, 1, |bus| {
+            bus.register(0x4000_0000, 0x1000, Box::new(Constant(42)))
+        })
+        .unwrap();
+        machine.harts[0].pc = MEMORY_BASE;
+        machine.step();
+        machine.step();
+        assert_eq!(machine.harts[0].regs[6], 42);
+    }
+
+    #[test]
+    fn overlapping_registered_device_is_rejected() {
+        let result = Machine::with_devices(Vec::new(), Vec::new(), None, MEMORY_SIZE, 1, |bus| {
+            bus.register(MEMORY_BASE, 0x1000, Box::new(Constant(0)))
+        });
+        assert!(matches!(result, Err(BusError::Overlap { .. })));
+    }
+
+    #[test]
+    fn each_hart_reads_its_own_hart_id() {
+        // csrr a0, mhartid
+        let mut machine = machine(0xf1402573u32.to_le_bytes().to_vec());
+        for hart in &mut machine.harts {
+            hart.pc = MEMORY_BASE;
+        }
+        machine.step();
+        for (hartid, hart) in machine.harts.iter().enumerate() {
+            assert_eq!(hart.hartid(), hartid);
+            assert_eq!(hart.regs[10], hartid as u64);
+            assert_eq!(hart.pc, MEMORY_BASE + 4);
+        }
+    }
+
+    /// Return a machine with `harts` harts that all start to execute `program` at the start of
+    /// the memory in M-mode.
+    fn parallel_machine(program: &[u32], harts: usize) -> Machine {
+        let binary = program.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        let mut machine = Machine::new(binary, Vec::new(), None, MEMORY_SIZE, harts);
+        for hart in &mut machine.harts {
+            hart.pc = MEMORY_BASE;
+        }
+        machine
+    }
+
+    #[test]
+    fn parallel_harts_update_a_shared_counter_atomically() {
+        let program = [
+            0x00001517, // auipc a0, 1
+            0x00850593, // addi a1, a0, 8
+            0x01050613, // addi a2, a0, 16
+            0x3e800293, // addi t0, zero, 1000
+            0x00100313, // addi t1, zero, 1
+            0x0065202f, // loop: amoadd.w zero, t1, (a0)
+            0x1005a3af, // retry: lr.w t2, (a1)
+            0x00138393, // addi t2, t2, 1
+            0x1875ae2f, // sc.w t3, t2, (a1)
+            0xfe0e1ae3, // bnez t3, retry
+            0xfff28293, // addi t0, t0, -1
+            0xfe0294e3, // bnez t0, loop
+            0x00662eaf, // amoadd.w t4, t1, (a2)
+            0x00300f13, // addi t5, zero, 3
+            0x01ee9a63, // bne t4, t5, spin
+            0x00100f37, // lui t5, 0x100 (TEST_BASE)
+            0x00005fb7, // lui t6, 5
+            0x555f8f93, // addi t6, t6, 0x555 (SYSCON_POWEROFF)
+            0x01ff2023, // sw t6, 0(t5)
+            0x0000006f, // spin: j spin
+        ];
+        // The last of the 4 harts to finish its 1000 iterations powers off the machine.
+        let mut machine = parallel_machine(&program, 4);
+        assert_eq!(machine.run_parallel(), Exit::Shutdown(0));
+        let counters = MEMORY_BASE + 0x1000;
+        assert_eq!(machine.bus.load(counters, 32).unwrap(), 4000);
+        assert_eq!(machine.bus.load(counters + 8, 32).unwrap(), 4000);
+        assert_eq!(machine.bus.load(counters + 16, 32).unwrap(), 4);
+        assert!(machine.cycles() > 0);
+    }
+
+    #[test]
+    fn parallel_harts_stop_on_a_fault() {
+        // Hart 0 spins, while hart 1 executes the zeroed memory after the loop. The trap vector
+        // is 0, where nothing can be fetched.
+        let mut machine = parallel_machine(&[0x0000006f], 2);
+        machine.harts[1].pc = MEMORY_BASE + 0x100;
+        assert_eq!(machine.run_parallel(), Exit::DoubleFault(1));
+        assert!(machine.harts[0].trap_log.double_fault().is_none());
+    }
+}
@@ -1,71 +1,480 @@
-mod bus;
-mod clint;
-mod cpu;
-mod memory;
-mod plic;
-mod trap;
-mod uart;
-mod virtio;
+//! The rvemu-for-book command runs a firmware or a kernel on an emulated RISC-V machine.
+//!
+//! ```text
+//! rvemu-for-book [options] <filename> <(option) image>
+//! ```
+//!
+//! `<filename>` is the firmware, which runs in M-mode from the start of the memory. It's omitted
+//! when `--bios` or `--kernel` is given. `<image>` is the disk of the virtio block device, a raw or
+//! qcow2 image, which is written in place unless `--readonly` or `--overlay` is given.
+//!
+//! The exit status is the failure code the guest has written to the SiFive test device, or 0, as
+//! in QEMU.
+//!
+//! The machine:
+//! - `--memory <size>`: the memory size, e.g. `512M` or `2G`. 128 MiB by default.
+//! - `--smp <harts>`: the number of harts, 3 by default.
+//! - `--dump-harts`: print the registers of every hart on exit, not only those of hart 0.
+//! - `--parallel`: run each hart on its own host thread.
+//! - `--misaligned trap|emulate`: trap or emulate misaligned loads and stores. They're emulated by
+//!   default.
+//! - `--rtc-epoch <seconds>`: start the real-time clock at `<seconds>` since the Unix epoch and
+//!   advance it with the instructions, for deterministic runs.
+//!
+//! Booting:
+//! - `--bios <firmware>`: the firmware, instead of `<filename>`.
+//! - `--kernel <kernel>`: the kernel started by the firmware in S-mode. Without a firmware, it's
+//!   booted on the built-in SBI.
+//! - `--initrd <initrd>`: the initial ramdisk.
+//! - `--append <cmdline>`: the kernel command line.
+//! - `--dump-dtb <file>`: save the generated device tree to `<file>`.
+//!
+//! The disk:
+//! - `--virtio-modern`: expose the virtio devices through the modern virtio-mmio interface instead
+//!   of the legacy one, which xv6 expects.
+//! - `--readonly`: don't write to the image.
+//! - `--overlay <file>`: keep the writes to the image in `<file>`.
+//!
+//! Other devices:
+//! - `--net unix:<local>:<peer>`: add a network card on a Unix datagram socket bound to `<local>`
+//!   that sends frames to `<peer>`.
+//! - `--net-dump <file>`: record the frames of the network card to a pcap file.
+//! - `--mac <mac>`: the MAC address of the network card, e.g. `52:54:00:12:34:56`.
+//! - `--console stdio|null|file:<path>`: add a port to a virtio console. The first port is the
+//!   console, and the others are named `port1`, `port2` and so on.
+//! - `--rng`: add an entropy device.
+//! - `--rng-seed <seed>`: add an entropy device seeded with `<seed>`.
+//! - `--share <dir>`: export `<dir>` to the guest over 9P with the mount tag `rvemu`.
+//! - `--share-readonly`: export the directory read-only.
+//! - `--fb <width>x<height>[:<format>]`: add a simple framebuffer, in `x8r8g8b8` unless
+//!   `<format>` is given.
+//! - `--fb-dump <file>`: save the framebuffer to a PNG or PPM file on exit and when the guest
+//!   executes `slti zero, zero, 0x535`, with `{}` in the file name replaced by the frame number.
+//!   It requires `--fb`.
+//! - `--fb-dump-every <frames>`: also save it every `<frames>` frames at 60 frames per second.
+//!   It requires `--fb-dump`.
+//! - `--keyboard <script>`: add a keyboard whose keys are pressed and released by `<script>`.
+//!
+//! Snapshots:
+//! - `--snapshot <file>`: save the state of the machine to `<file>`, with `{}` replaced by the
+//!   cycle count, when the guest executes `slti zero, zero, 0x534`.
+//! - `--snapshot-after <cycles>`: also save it after `<cycles>` cycles.
+//! - `--restore <file>`: resume from a snapshot taken with the same options.
 
 use std::env;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::path::Path;
+use std::process;
 
-use crate::cpu::*;
-use crate::trap::*;
+use step10_rvemu_for_book::boot::*;
+use step10_rvemu_for_book::bus::*;
+use step10_rvemu_for_book::chardev::*;
+use step10_rvemu_for_book::cpu::*;
+use step10_rvemu_for_book::disk::*;
+use step10_rvemu_for_book::framebuffer::*;
+use step10_rvemu_for_book::machine::*;
+use step10_rvemu_for_book::memory::*;
+use step10_rvemu_for_book::net::*;
+use step10_rvemu_for_book::rtc::*;
+use step10_rvemu_for_book::sbi::*;
+use step10_rvemu_for_book::virtio::*;
+#[cfg(unix)]
+use step10_rvemu_for_book::virtio_9p::*;
+use step10_rvemu_for_book::virtio_blk::*;
+use step10_rvemu_for_book::virtio_console::*;
+use step10_rvemu_for_book::virtio_input::*;
+use step10_rvemu_for_book::virtio_net::*;
+use step10_rvemu_for_book::virtio_rng::*;
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
+/// The command-line usage. The options are described in the documentation of this module.
+const USAGE: &str = "Usage: rvemu-for-book [options] <filename> <(option) image>";
+
+/// Print `message` with the usage and exit.
+fn usage_error(message: &str) -> ! {
+    eprintln!("rvemu-for-book: {}\n{}", message, USAGE);
+    process::exit(2);
+}
+
+/// Return the value of `option`, which is the next argument.
+fn option_value(args: &mut impl Iterator<Item = String>, option: &str) -> String {
+    args.next()
+        .unwrap_or_else(|| usage_error(&format!("{} requires a value", option)))
+}
+
+/// Parse the value of `option` with `parse`.
+fn parse_option<T>(
+    args: &mut impl Iterator<Item = String>,
+    option: &str,
+    parse: impl FnOnce(&str) -> Option<T>,
+) -> T {
+    let value = option_value(args, option);
+    parse(&value)
+        .unwrap_or_else(|| usage_error(&format!("invalid value for {}: {}", option, value)))
+}
 
-    if (args.len() != 2) && (args.len() != 3) {
-        panic!("Usage: rvemu-for-book <filename> <(option) image>");
+/// Read the whole file at `path`.
+fn read_file(path: &str) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Load `firmware` and `images` into the memory and get the harts ready to boot. The boot hart
+/// boots the kernel directly when the built-in SBI is enabled.
+fn boot(
+    machine: &mut Machine,
+    firmware: &[u8],
+    images: &BootImages,
+) -> Result<BootLayout, BootError> {
+    let layout = load_boot_images(machine, firmware, images.clone())?;
+    let boot_hart = &mut machine.harts[0];
+    if boot_hart.sbi.is_some() {
+        enter_kernel(boot_hart, &layout);
     }
-    let mut file = File::open(&args[1])?;
-    let mut binary = Vec::new();
-    file.read_to_end(&mut binary)?;
+    Ok(layout)
+}
 
-    let mut disk_image = Vec::new();
-    if args.len() == 3 {
-        let mut file = File::open(&args[2])?;
-        file.read_to_end(&mut disk_image)?;
+/// Parse a memory size such as `4096`, `64K`, `512M` or `2G` into bytes. The size must be a
+/// non-zero multiple of 4 KiB.
+fn parse_memory_size(size: &str) -> Option<u64> {
+    let (number, unit) = match size.char_indices().last()? {
+        (i, 'K') | (i, 'k') => (&size[..i], 1 << 10),
+        (i, 'M') | (i, 'm') => (&size[..i], 1 << 20),
+        (i, 'G') | (i, 'g') => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    let bytes = number.parse::<u64>().ok()?.checked_mul(unit)?;
+    if bytes == 0 || bytes % 4096 != 0 || bytes > (1 << 40) {
+        return None;
     }
+    Some(bytes)
+}
 
-    let mut cpu = Cpu::new(binary, disk_image);
+/// Parse a MAC address such as `52:54:00:12:34:56`.
+fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let mut bytes = [0; 6];
+    let mut parts = mac.split(':');
+    for byte in &mut bytes {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    match parts.next() {
+        Some(_) => None,
+        None => Some(bytes),
+    }
+}
 
-    loop {
-        // 1. Fetch.
-        let inst = match cpu.fetch() {
-            // Break the loop if an error occurs.
-            Ok(inst) => inst,
-            Err(exception) => {
-                exception.take_trap(&mut cpu);
-                println!("exception: {:?}", exception);
-                break;
-            }
-        };
+/// Create the backend of the network card from `net`, e.g. `unix:<local>:<peer>`, recording the
+/// frames to `dump` if it's given.
+fn net_backend(net: Option<&str>, dump: Option<&str>) -> io::Result<Box<dyn NetBackend>> {
+    let backend: Box<dyn NetBackend> = match net.map(|net| net.split(':').collect::<Vec<_>>()) {
+        Some(args) => match args.as_slice() {
+            #[cfg(unix)]
+            ["unix", local, peer] => Box::new(UnixDatagramBackend::bind(
+                Path::new(local),
+                Path::new(peer),
+            )?),
+            _ => usage_error(&format!(
+                "invalid value for --net: {}",
+                net.unwrap_or_default()
+            )),
+        },
+        None => Box::new(NullBackend),
+    };
+    match dump {
+        Some(dump) => Ok(Box::new(PcapBackend::create(Path::new(dump), backend)?)),
+        None => Ok(backend),
+    }
+}
+
+/// Parse a framebuffer such as `640x480` or `640x480:r5g6b5`. The default format is
+/// `x8r8g8b8`.
+fn parse_framebuffer(fb: &str) -> Option<Framebuffer> {
+    let (size, format) = match fb.split_once(':') {
+        Some((size, format)) => (size, PixelFormat::from_name(format)?),
+        None => (fb, PixelFormat::X8R8G8B8),
+    };
+    let (width, height) = size.split_once('x')?;
+    let (width, height) = (width.parse::<usize>().ok()?, height.parse::<usize>().ok()?);
+    let bytes = width
+        .checked_mul(height)?
+        .checked_mul(format.bytes_per_pixel())?;
+    if width == 0 || height == 0 || bytes as u64 > FRAMEBUFFER_MAX_SIZE {
+        return None;
+    }
+    Some(Framebuffer::new(width, height, format))
+}
 
-        // 2. Add 4 to the program counter.
-        cpu.pc += 4;
+/// Create the backend of a console port from `console`, e.g. `stdio` or `file:<path>`.
+fn char_backend(console: &str) -> io::Result<Box<dyn CharBackend>> {
+    match console.split_once(':') {
+        Some(("file", path)) => Ok(Box::new(FileBackend::create(Path::new(path))?)),
+        None if console == "stdio" => Ok(Box::new(StdioBackend::new())),
+        None if console == "null" => Ok(Box::new(NullCharBackend)),
+        _ => usage_error(&format!("invalid value for --console: {}", console)),
+    }
+}
 
-        // 3. Decode.
-        // 4. Execute.
-        match cpu.execute(inst) {
-            // Break the loop if an error occurs.
-            Ok(_) => {}
-            Err(exception) => {
-                exception.take_trap(&mut cpu);
+fn main() -> io::Result<()> {
+    let mut files = Vec::new();
+    let mut misaligned_access = MisalignedAccess::Emulate;
+    let mut memory_size = MEMORY_SIZE;
+    let mut harts = DEFAULT_HARTS;
+    let mut parallel = false;
+    let mut dump_harts = false;
+    let mut virtio_modern = false;
+    let mut readonly = false;
+    let mut overlay = None;
+    let mut net = None;
+    let mut net_dump = None;
+    let mut mac = DEFAULT_MAC;
+    let mut consoles = Vec::new();
+    let mut rng = false;
+    let mut rng_seed = None;
+    let mut share = None;
+    let mut rtc_epoch = None;
+    let mut framebuffer = None;
+    let mut fb_dump = None;
+    let mut fb_dump_every = None;
+    let mut keyboard = None;
+    let mut snapshot = None;
+    let mut snapshot_after = None;
+    let mut restore = None;
+    let mut share_readonly = false;
+    let mut bios = None;
+    let mut kernel = None;
+    let mut initrd = None;
+    let mut bootargs = None;
+    let mut dump_dtb = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let args = &mut args;
+        match arg.as_str() {
+            "--memory" => memory_size = parse_option(args, &arg, parse_memory_size),
+            "--smp" => {
+                harts = parse_option(args, &arg, |harts| {
+                    harts
+                        .parse()
+                        .ok()
+                        .filter(|harts| (1..=MAX_HARTS).contains(harts))
+                })
+            }
+            "--parallel" => parallel = true,
+            "--dump-harts" => dump_harts = true,
+            "--virtio-modern" => virtio_modern = true,
+            "--readonly" => readonly = true,
+            "--overlay" => overlay = Some(option_value(args, &arg)),
+            "--net" => net = Some(option_value(args, &arg)),
+            "--net-dump" => net_dump = Some(option_value(args, &arg)),
+            "--mac" => mac = parse_option(args, &arg, parse_mac),
+            "--console" => consoles.push(option_value(args, &arg)),
+            "--rtc-epoch" => rtc_epoch = Some(parse_option(args, &arg, |e| e.parse::<u64>().ok())),
+            "--fb" => framebuffer = Some(parse_option(args, &arg, parse_framebuffer)),
+            "--fb-dump" => fb_dump = Some(option_value(args, &arg)),
+            "--fb-dump-every" => {
+                fb_dump_every = Some(parse_option(args, &arg, |every| {
+                    every.parse::<u64>().ok().filter(|every| *every > 0)
+                }))
+            }
+            "--keyboard" => keyboard = Some(option_value(args, &arg)),
+            "--snapshot" => snapshot = Some(option_value(args, &arg)),
+            "--snapshot-after" => {
+                snapshot_after = Some(parse_option(args, &arg, |c| c.parse::<u64>().ok()))
             }
+            "--restore" => restore = Some(option_value(args, &arg)),
+            "--share" => share = Some(option_value(args, &arg)),
+            "--share-readonly" => share_readonly = true,
+            "--rng" => rng = true,
+            "--rng-seed" => {
+                rng = true;
+                rng_seed = Some(parse_option(args, &arg, |seed| seed.parse::<u64>().ok()));
+            }
+            "--misaligned" => {
+                misaligned_access = parse_option(args, &arg, |misaligned| match misaligned {
+                    "trap" => Some(MisalignedAccess::Trap),
+                    "emulate" => Some(MisalignedAccess::Emulate),
+                    _ => None,
+                })
+            }
+            "--bios" => bios = Some(option_value(args, &arg)),
+            "--kernel" => kernel = Some(option_value(args, &arg)),
+            "--initrd" => initrd = Some(option_value(args, &arg)),
+            "--append" => bootargs = Some(option_value(args, &arg)),
+            "--dump-dtb" => dump_dtb = Some(option_value(args, &arg)),
+            _ if arg.starts_with("--") => usage_error(&format!("unknown option: {}", arg)),
+            _ => files.push(arg),
         }
+    }
 
-        match cpu.check_pending_interrupt() {
-            Some(interrupt) => interrupt.take_trap(&mut cpu),
-            None => {}
+    // The firmware is the first file unless it's given by --bios. Without a firmware, the kernel
+    // runs on the built-in SBI.
+    if bios.is_none() && kernel.is_none() {
+        if files.is_empty() {
+            usage_error("no firmware is given");
         }
+        bios = Some(files.remove(0));
+    }
+    if files.len() > 1 {
+        usage_error("more than one disk image is given");
+    }
+    if fb_dump.is_some() && framebuffer.is_none() {
+        usage_error("--fb-dump requires --fb");
+    }
+    if fb_dump_every.is_some() && fb_dump.is_none() {
+        usage_error("--fb-dump-every requires --fb-dump");
+    }
+    let mut images = BootImages {
+        kernel: None,
+        initrd: initrd.as_deref().map(read_file).transpose()?,
+        bootargs,
+    };
+    images.kernel = kernel.as_deref().map(read_file).transpose()?;
+    let firmware = match &bios {
+        Some(bios) => read_file(bios)?,
+        None => Vec::new(),
+    };
+    let disk = match files.first() {
+        Some(image) => open_disk(
+            Path::new(image),
+            readonly,
+            overlay.as_deref().map(Path::new),
+        )?,
+        None => Box::new(Vec::new()),
+    };
+    let mut virtio_devices: Vec<Box<dyn VirtioDevice>> = vec![Box::new(VirtioBlk::new(disk))];
+    if net.is_some() || net_dump.is_some() {
+        let backend = net_backend(net.as_deref(), net_dump.as_deref())?;
+        virtio_devices.push(Box::new(VirtioNet::new(mac, backend)));
+    }
+    if !consoles.is_empty() {
+        let mut ports = Vec::new();
+        for (i, console) in consoles.iter().enumerate() {
+            ports.push((format!("port{}", i), char_backend(console)?));
+        }
+        virtio_devices.push(Box::new(VirtioConsole::new(ports)));
+    }
+    if let Some(share) = &share {
+        #[cfg(unix)]
+        virtio_devices.push(Box::new(Virtio9p::new(
+            Path::new(share),
+            DEFAULT_MOUNT_TAG,
+            share_readonly,
+        )?));
+        #[cfg(not(unix))]
+        usage_error(&format!("--share {} is only supported on Unix", share));
+    }
+    if let Some(script) = &keyboard {
+        virtio_devices.push(Box::new(VirtioInput::from_script(Path::new(script))?));
+    }
+    if rng {
+        virtio_devices.push(Box::new(VirtioRng::new(rng_seed)));
     }
-    cpu.dump_registers();
-    println!("-----------------------------------------------------------------------------------------------------------");
-    cpu.dump_csrs();
 
+    if let (Some(fb), Some(dump)) = (&mut framebuffer, &fb_dump) {
+        fb.set_dump(Path::new(dump), fb_dump_every);
+    }
+    // The firmware is loaded by `boot`, which loads it again on a reboot.
+    let virtio_slots = virtio_devices.len() as u64;
+    let mut machine = Machine::new(Vec::new(), virtio_devices, framebuffer, memory_size, harts);
+    for hart in &mut machine.harts {
+        hart.misaligned_access = misaligned_access;
+    }
+    if bios.is_none() {
+        machine.enable_sbi();
+    }
+    if virtio_modern {
+        for slot in 0..virtio_slots {
+            machine
+                .bus
+                .with_device(VIRTIO_BASE + slot * VIRTIO_SIZE, |virtio: &mut Virtio| {
+                    virtio.set_legacy(false)
+                })
+                .expect("failed to get the virtio device");
+        }
+    }
+    if let Some(epoch) = rtc_epoch {
+        machine
+            .bus
+            .with_device(RTC_BASE, |rtc: &mut GoldfishRtc| {
+                rtc.set_clock(RtcClock::Fixed(epoch))
+            })
+            .expect("failed to get the RTC");
+    }
+    let layout = match boot(&mut machine, &firmware, &images) {
+        Ok(layout) => layout,
+        Err(e) => {
+            eprintln!("rvemu-for-book: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Some(path) = dump_dtb {
+        File::create(path)?.write_all(&layout.dtb)?;
+    }
+    // The images are still loaded for a reboot, and the snapshot replaces all of the state.
+    if let Some(path) = &restore {
+        machine.load_snapshot(Path::new(path))?;
+    }
+    if let Some(cycles) = snapshot_after {
+        machine.request_snapshot_at(machine.cycles() + cycles);
+    }
+
+    let status = loop {
+        let exit = if parallel {
+            machine.run_parallel()
+        } else {
+            machine.run()
+        };
+        match exit {
+            Exit::Reboot => {
+                machine.reset();
+                machine.bus.memory().clear();
+                // The same images have been loaded once, so they still fit.
+                boot(&mut machine, &firmware, &images).expect("failed to reboot");
+            }
+            Exit::Snapshot => match &snapshot {
+                Some(path) => {
+                    let path = path.replace("{}", &machine.cycles().to_string());
+                    if let Err(e) = machine.save_snapshot(Path::new(&path)) {
+                        eprintln!("{}: {}", path, e);
+                    }
+                }
+                None => eprintln!("a snapshot has been requested without --snapshot"),
+            },
+            Exit::DoubleFault(hartid) => {
+                // The same trap is taken over and over again.
+                let cpu = &machine.harts[hartid];
+                let double_fault = cpu.trap_log.double_fault().expect("no double fault");
+                eprintln!("hart {}: {}\n{}", hartid, double_fault, cpu.trap_log);
+                break 0;
+            }
+            Exit::Shutdown(status) => break status,
+            Exit::Stopped => break 0,
+        }
+    };
+    if let Some(path) = &fb_dump {
+        machine
+            .bus
+            .with_device(FRAMEBUFFER_BASE, |fb: &mut Framebuffer| {
+                fb.save_frame(Path::new(path))
+            })
+            .expect("failed to get the framebuffer")?;
+    }
+    // Only the boot hart is printed by default, as with a single hart.
+    let dumped = if dump_harts { machine.harts.len() } else { 1 };
+    for cpu in &machine.harts[..dumped] {
+        if dump_harts {
+            println!("hart {}:", cpu.hartid());
+        }
+        cpu.dump_registers();
+        println!("-----------------------------------------------------------------------------------------------------------");
+        cpu.dump_csrs();
+    }
+
+    if status != 0 {
+        process::exit(status as i32);
+    }
     Ok(())
 }
@@ -1,114 +1,457 @@
 //! The memory module contains a memory structure and implementation for memory access.
 
-use crate::bus::*;
+use std::io;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    OnceLock,
+};
+
+use crate::snapshot::*;
 use crate::trap::*;
 
 /// Default memory size (128MiB).
 pub const MEMORY_SIZE: u64 = 1024 * 1024 * 128;
 
-/// The dynamic random access memory (DRAM).
+/// The size of a chunk of host memory that backs the guest memory. A chunk is allocated the first
+/// time it's written.
+const CHUNK_SIZE: u64 = 4096;
+/// The number of 64-bit words in a chunk.
+const CHUNK_WORDS: usize = (CHUNK_SIZE / 8) as usize;
+/// The number of chunks in a table (2MiB of guest memory). A table is allocated the first time one
+/// of its chunks is written, so the index of a large memory doesn't cost host memory either.
+const TABLE_CHUNKS: u64 = 512;
+
+/// A chunk of `CHUNK_WORDS` words.
+type Chunk = Box<[AtomicU64]>;
+/// A table of `TABLE_CHUNKS` chunks. An uninitialized chunk has never been written.
+type Table = Box<[OnceLock<Chunk>]>;
+
+/// The operation of an atomic memory operation (AMO) instruction.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum AmoOp {
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    Minu,
+    Maxu,
+}
+
+impl AmoOp {
+    /// Return the value stored by the operation on the `size`-bit `old` value and `value`.
+    pub fn apply(self, old: u64, value: u64, size: u64) -> u64 {
+        // Signed comparisons are done on the sign-extended values.
+        let signed = |v: u64| match size {
+            32 => v as i32 as i64,
+            _ => v as i64,
+        };
+        let unsigned = |v: u64| match size {
+            32 => v & 0xffff_ffff,
+            _ => v,
+        };
+        match self {
+            AmoOp::Swap => value,
+            AmoOp::Add => old.wrapping_add(value),
+            AmoOp::Xor => old ^ value,
+            AmoOp::And => old & value,
+            AmoOp::Or => old | value,
+            AmoOp::Min => signed(old).min(signed(value)) as u64,
+            AmoOp::Max => signed(old).max(signed(value)) as u64,
+            AmoOp::Minu => unsigned(old).min(unsigned(value)),
+            AmoOp::Maxu => unsigned(old).max(unsigned(value)),
+        }
+    }
+}
+
+/// Return the ordering of the load of a failed compare-and-exchange with the `success` ordering.
+fn failure_ordering(success: Ordering) -> Ordering {
+    match success {
+        Ordering::SeqCst => Ordering::SeqCst,
+        Ordering::AcqRel | Ordering::Acquire => Ordering::Acquire,
+        _ => Ordering::Relaxed,
+    }
+}
+
+/// The dynamic random access memory (DRAM). The memory is sparse: untouched regions read as zero
+/// and don't cost any host memory, so a large guest memory is cheap until the guest uses it.
+///
+/// The memory is shared by all harts and the devices, so it's accessed through `&self`. It's
+/// backed by atomic 64-bit words: an access inside a word is a single atomic access, and an access
+/// that crosses a word is split into bytes. Loads and stores are relaxed, and the ordering required
+/// by the guest is given by its fences and by the orderings of `atomic` and `compare_exchange`.
 #[derive(Debug)]
 pub struct Memory {
-    pub memory: Vec<u8>,
+    /// Tables of chunks. An uninitialized table has no chunk that has been written.
+    tables: Vec<OnceLock<Table>>,
+    /// The memory size in bytes.
+    size: u64,
 }
 
-impl Device for Memory {
-    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+impl Memory {
+    /// Create a new `Memory` object of `size` bytes and copy `binary` to the start of it.
+    pub fn new(binary: Vec<u8>, size: u64) -> Memory {
+        if binary.len() as u64 > size {
+            panic!(
+                "the binary ({} bytes) doesn't fit in the memory ({} bytes)",
+                binary.len(),
+                size
+            );
+        }
+
+        let memory = Self {
+            tables: (0..size.div_ceil(CHUNK_SIZE * TABLE_CHUNKS))
+                .map(|_| OnceLock::new())
+                .collect(),
+            size,
+        };
+        memory.write_bytes(0, &binary);
+        memory
+    }
+
+    /// Clear the whole memory to zero.
+    pub fn clear(&self) {
+        for (_, chunk) in self.written_chunks() {
+            chunk
+                .iter()
+                .for_each(|word| word.store(0, Ordering::Relaxed));
+        }
+    }
+
+    /// Return the memory size in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Load `size` bits at the offset `addr`.
+    pub fn load(&self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if !self.contains(addr, size) {
+            return Err(Exception::LoadAccessFault(addr));
+        }
         match size {
-            8 => Ok(self.load8(addr)),
-            16 => Ok(self.load16(addr)),
-            32 => Ok(self.load32(addr)),
-            64 => Ok(self.load64(addr)),
-            _ => Err(Exception::LoadAccessFault),
+            8 | 16 | 32 | 64 => Ok(self.read(addr, size / 8)),
+            _ => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
-    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+    /// Store `size` bits of `value` at the offset `addr`.
+    pub fn store(&self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if !self.contains(addr, size) {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
         match size {
-            8 => Ok(self.store8(addr, value)),
-            16 => Ok(self.store16(addr, value)),
-            32 => Ok(self.store32(addr, value)),
-            64 => Ok(self.store64(addr, value)),
-            _ => Err(Exception::StoreAMOAccessFault),
+            8 | 16 | 32 | 64 => {
+                self.write(addr, size / 8, value);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
+        }
+    }
+
+    /// Atomically apply `op` with `value` to the naturally aligned `size` bits at the offset
+    /// `addr`, and return the old value.
+    pub fn atomic(
+        &self,
+        addr: u64,
+        size: u64,
+        op: AmoOp,
+        value: u64,
+        ordering: Ordering,
+    ) -> Result<u64, Exception> {
+        if !self.contains(addr, size) || !matches!(size, 32 | 64) || addr % (size / 8) != 0 {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        let word = self.word_mut(addr);
+        if size == 64 {
+            return Ok(match op {
+                AmoOp::Swap => word.swap(value, ordering),
+                AmoOp::Add => word.fetch_add(value, ordering),
+                AmoOp::Xor => word.fetch_xor(value, ordering),
+                AmoOp::And => word.fetch_and(value, ordering),
+                AmoOp::Or => word.fetch_or(value, ordering),
+                AmoOp::Minu => word.fetch_min(value, ordering),
+                AmoOp::Maxu => word.fetch_max(value, ordering),
+                AmoOp::Min | AmoOp::Max => word
+                    .fetch_update(ordering, failure_ordering(ordering), |old| {
+                        Some(op.apply(old, value, size))
+                    })
+                    .unwrap_or_else(|old| old),
+            });
+        }
+
+        // Update the lane of the access in its word.
+        let (shift, mask) = Self::lane(addr, size / 8);
+        let old = word
+            .fetch_update(ordering, failure_ordering(ordering), |old| {
+                let new = op.apply((old & mask) >> shift, value, size);
+                Some((old & !mask) | ((new << shift) & mask))
+            })
+            .unwrap_or_else(|old| old);
+        Ok((old & mask) >> shift)
+    }
+
+    /// Atomically store `new` to the naturally aligned `size` bits at the offset `addr` if they
+    /// hold `current`. Return true if `new` is stored.
+    pub fn compare_exchange(
+        &self,
+        addr: u64,
+        size: u64,
+        current: u64,
+        new: u64,
+        ordering: Ordering,
+    ) -> Result<bool, Exception> {
+        if !self.contains(addr, size) || !matches!(size, 32 | 64) || addr % (size / 8) != 0 {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        let word = self.word_mut(addr);
+        let (shift, mask) = Self::lane(addr, size / 8);
+        let result = word.fetch_update(ordering, failure_ordering(ordering), |old| {
+            if (old & mask) >> shift != current & (mask >> shift) {
+                return None;
+            }
+            Some((old & !mask) | ((new << shift) & mask))
+        });
+        Ok(result.is_ok())
+    }
+
+    /// Return true if an access of `size` bits at the offset `addr` is entirely inside the memory.
+    fn contains(&self, addr: u64, size: u64) -> bool {
+        addr < self.size && size / 8 <= self.size - addr
+    }
+
+    /// Copy `data` to the memory starting at the offset `addr`.
+    pub fn write_bytes(&self, addr: u64, data: &[u8]) {
+        let mut i = 0;
+        while i < data.len() {
+            let offset = addr + i as u64;
+            if offset % 8 == 0 && data.len() - i >= 8 {
+                let mut word = [0; 8];
+                word.copy_from_slice(&data[i..i + 8]);
+                self.write(offset, 8, u64::from_le_bytes(word));
+                i += 8;
+            } else {
+                self.write(offset, 1, data[i] as u64);
+                i += 1;
+            }
+        }
+    }
+
+    /// Copy the memory starting at the offset `addr` to `data`.
+    pub fn read_bytes(&self, addr: u64, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.read(addr + i as u64, 1) as u8;
         }
     }
+
+    /// Save the memory to `snapshot`. Only the chunks that hold non-zero bytes are saved, each of
+    /// them compressed.
+    pub fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.u64(self.size);
+        for (index, chunk) in self.written_chunks() {
+            let words: Vec<u64> = chunk
+                .iter()
+                .map(|word| word.load(Ordering::Relaxed))
+                .collect();
+            if words.iter().all(|word| *word == 0) {
+                continue;
+            }
+            let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+            snapshot.u64(index).compressed(&bytes);
+        }
+        // The end of the chunks.
+        snapshot.u64(u64::MAX);
+    }
+
+    /// Restore the memory saved by `save`. The bytes that weren't saved are cleared.
+    pub fn restore(&self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        if snapshot.u64()? != self.size {
+            return Err(snapshot_error("the memory size doesn't match"));
+        }
+        self.clear();
+        loop {
+            let index = snapshot.u64()?;
+            if index == u64::MAX {
+                return Ok(());
+            }
+            let bytes = snapshot.compressed()?;
+            if index >= self.size.div_ceil(CHUNK_SIZE) || bytes.len() as u64 != CHUNK_SIZE {
+                return Err(snapshot_error("invalid memory chunk"));
+            }
+            self.write_bytes(index * CHUNK_SIZE, &bytes);
+        }
+    }
+
+    /// Return true if `len` bytes at the offset `addr` are entirely inside the memory.
+    pub fn contains_bytes(&self, addr: u64, len: u64) -> bool {
+        addr <= self.size && len <= self.size - addr
+    }
+
+    /// Return the chunks that have been written with their indexes.
+    fn written_chunks(&self) -> impl Iterator<Item = (u64, &Chunk)> {
+        self.tables
+            .iter()
+            .enumerate()
+            .filter_map(|(table_index, table)| Some((table_index as u64, table.get()?)))
+            .flat_map(|(table_index, table)| {
+                table.iter().enumerate().filter_map(move |(i, chunk)| {
+                    Some((table_index * TABLE_CHUNKS + i as u64, chunk.get()?))
+                })
+            })
+    }
+
+    /// Return the word that contains the offset `addr`, or None if its chunk has never been
+    /// written.
+    fn word(&self, addr: u64) -> Option<&AtomicU64> {
+        let index = addr / CHUNK_SIZE;
+        let table = self.tables[(index / TABLE_CHUNKS) as usize].get()?;
+        let chunk = table[(index % TABLE_CHUNKS) as usize].get()?;
+        Some(&chunk[(addr % CHUNK_SIZE / 8) as usize])
+    }
+
+    /// Return the word that contains the offset `addr`, allocating its table and its chunk if
+    /// necessary.
+    fn word_mut(&self, addr: u64) -> &AtomicU64 {
+        let index = addr / CHUNK_SIZE;
+        let table = self.tables[(index / TABLE_CHUNKS) as usize]
+            .get_or_init(|| (0..TABLE_CHUNKS).map(|_| OnceLock::new()).collect());
+        let chunk = table[(index % TABLE_CHUNKS) as usize]
+            .get_or_init(|| (0..CHUNK_WORDS).map(|_| AtomicU64::new(0)).collect());
+        &chunk[(addr % CHUNK_SIZE / 8) as usize]
+    }
+
+    /// Return the shift and the mask of `bytes` bytes at the offset `addr` in their word.
+    fn lane(addr: u64, bytes: u64) -> (u64, u64) {
+        let shift = (addr % 8) * 8;
+        let mask = if bytes == 8 {
+            u64::MAX
+        } else {
+            ((1 << (bytes * 8)) - 1) << shift
+        };
+        (shift, mask)
+    }
+
+    /// Load `bytes` bytes at the offset `addr` from the little-endian memory.
+    fn read(&self, addr: u64, bytes: u64) -> u64 {
+        if addr % 8 + bytes > 8 {
+            // The access crosses a word.
+            return (0..bytes).fold(0, |value, i| value | (self.read(addr + i, 1) << (i * 8)));
+        }
+
+        let (shift, mask) = Self::lane(addr, bytes);
+        match self.word(addr) {
+            Some(word) => (word.load(Ordering::Relaxed) & mask) >> shift,
+            None => 0,
+        }
+    }
+
+    /// Store `bytes` bytes at the offset `addr` to the little-endian memory.
+    fn write(&self, addr: u64, bytes: u64, value: u64) {
+        if addr % 8 + bytes > 8 {
+            // The access crosses a word.
+            for i in 0..bytes {
+                self.write(addr + i, 1, value >> (i * 8));
+            }
+            return;
+        }
+
+        let word = self.word_mut(addr);
+        if bytes == 8 {
+            word.store(value, Ordering::Relaxed);
+            return;
+        }
+        // Replace only the bytes of the access, so that a concurrent store to the other bytes
+        // of the word isn't lost.
+        let (shift, mask) = Self::lane(addr, bytes);
+        let _ = word.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| {
+            Some((old & !mask) | ((value << shift) & mask))
+        });
+    }
 }
 
-impl Memory {
-    /// Create a new `Memory` object with default memory size.
-    pub fn new(binary: Vec<u8>) -> Memory {
-        let mut memory = vec![0; MEMORY_SIZE as usize];
-        memory.splice(..binary.len(), binary.iter().cloned());
-
-        Self { memory }
-    }
-
-    /// Load a byte from the little-endian memory.
-    fn load8(&self, addr: u64) -> u64 {
-        let index = (addr - MEMORY_BASE) as usize;
-        self.memory[index] as u64
-    }
-
-    /// Load 2 bytes from the little-endian memory.
-    fn load16(&self, addr: u64) -> u64 {
-        let index = (addr - MEMORY_BASE) as usize;
-        return (self.memory[index] as u64) | ((self.memory[index + 1] as u64) << 8);
-    }
-
-    /// Load 4 bytes from the little-endian memory.
-    fn load32(&self, addr: u64) -> u64 {
-        let index = (addr - MEMORY_BASE) as usize;
-        return (self.memory[index] as u64)
-            | ((self.memory[index + 1] as u64) << 8)
-            | ((self.memory[index + 2] as u64) << 16)
-            | ((self.memory[index + 3] as u64) << 24);
-    }
-
-    /// Load 8 bytes from the little-endian memory.
-    fn load64(&self, addr: u64) -> u64 {
-        let index = (addr - MEMORY_BASE) as usize;
-        return (self.memory[index] as u64)
-            | ((self.memory[index + 1] as u64) << 8)
-            | ((self.memory[index + 2] as u64) << 16)
-            | ((self.memory[index + 3] as u64) << 24)
-            | ((self.memory[index + 4] as u64) << 32)
-            | ((self.memory[index + 5] as u64) << 40)
-            | ((self.memory[index + 6] as u64) << 48)
-            | ((self.memory[index + 7] as u64) << 56);
-    }
-
-    /// Store a byte to the little-endian memory.
-    fn store8(&mut self, addr: u64, value: u64) {
-        let index = (addr - MEMORY_BASE) as usize;
-        self.memory[index] = value as u8
-    }
-
-    /// Store 2 bytes to the little-endian memory.
-    fn store16(&mut self, addr: u64, value: u64) {
-        let index = (addr - MEMORY_BASE) as usize;
-        self.memory[index] = (value & 0xff) as u8;
-        self.memory[index + 1] = ((value >> 8) & 0xff) as u8;
-    }
-
-    /// Store 4 bytes to the little-endian memory.
-    fn store32(&mut self, addr: u64, value: u64) {
-        let index = (addr - MEMORY_BASE) as usize;
-        self.memory[index] = (value & 0xff) as u8;
-        self.memory[index + 1] = ((value >> 8) & 0xff) as u8;
-        self.memory[index + 2] = ((value >> 16) & 0xff) as u8;
-        self.memory[index + 3] = ((value >> 24) & 0xff) as u8;
-    }
-
-    /// Store 8 bytes to the little-endian memory.
-    fn store64(&mut self, addr: u64, value: u64) {
-        let index = (addr - MEMORY_BASE) as usize;
-        self.memory[index] = (value & 0xff) as u8;
-        self.memory[index + 1] = ((value >> 8) & 0xff) as u8;
-        self.memory[index + 2] = ((value >> 16) & 0xff) as u8;
-        self.memory[index + 3] = ((value >> 24) & 0xff) as u8;
-        self.memory[index + 4] = ((value >> 32) & 0xff) as u8;
-        self.memory[index + 5] = ((value >> 40) & 0xff) as u8;
-        self.memory[index + 6] = ((value >> 48) & 0xff) as u8;
-        self.memory[index + 7] = ((value >> 56) & 0xff) as u8;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return the number of tables allocated in `memory`.
+    fn tables(memory: &Memory) -> usize {
+        memory
+            .tables
+            .iter()
+            .filter(|table| table.get().is_some())
+            .count()
+    }
+
+    #[test]
+    fn fresh_memory_allocates_nothing() {
+        let memory = Memory::new(Vec::new(), 1 << 40);
+        assert_eq!(tables(&memory), 0);
+        assert_eq!(memory.load(0x12_3456_7000, 64).unwrap(), 0);
+        // Loads don't allocate either.
+        assert_eq!(tables(&memory), 0);
+        assert_eq!(memory.written_chunks().count(), 0);
+    }
+
+    #[test]
+    fn write_allocates_one_chunk() {
+        let memory = Memory::new(Vec::new(), 1 << 40);
+        memory.store(0x12_3456_7ff8, 64, 0xdead_beef).unwrap();
+        memory.store(0x12_3456_7000, 8, 0x42).unwrap();
+        assert_eq!(tables(&memory), 1);
+        let chunks: Vec<u64> = memory.written_chunks().map(|(index, _)| index).collect();
+        assert_eq!(chunks, [0x12_3456_7000 / CHUNK_SIZE]);
+        assert_eq!(memory.load(0x12_3456_7ff8, 64).unwrap(), 0xdead_beef);
+
+        // A write that crosses a chunk allocates the next chunk too.
+        memory.store(0x12_3456_7ffc, 64, u64::MAX).unwrap();
+        assert_eq!(memory.written_chunks().count(), 2);
+        assert_eq!(memory.load(0x12_3456_8000, 32).unwrap(), 0xffff_ffff);
+    }
+
+    #[test]
+    fn clear_zeroes_the_written_chunks() {
+        let memory = Memory::new(vec![0xff; 16], 1 << 24);
+        memory.store(0x80_0000, 64, 1).unwrap();
+        memory.clear();
+        assert_eq!(memory.load(0, 64).unwrap(), 0);
+        assert_eq!(memory.load(0x80_0000, 64).unwrap(), 0);
+    }
+
+    #[test]
+    fn restored_memory_has_the_same_sparse_contents() {
+        let memory = Memory::new(b"binary".to_vec(), 1 << 30);
+        memory.store(0x3000_0000, 32, 0x1234_5678).unwrap();
+        memory.store(0x1000, 64, 7).unwrap();
+        // A written chunk that holds only zeros isn't saved.
+        memory.store(0x2000_0000, 64, 0).unwrap();
+        let mut snapshot = SnapshotWriter::new();
+        memory.save(&mut snapshot);
+        let data = snapshot.into_bytes();
+
+        let restored = Memory::new(Vec::new(), 1 << 30);
+        restored.store(0x1008, 64, 9).unwrap();
+        restored.store(0x800_0000, 64, 9).unwrap();
+        let mut reader = SnapshotReader::new(&data);
+        restored.restore(&mut reader).unwrap();
+        reader.finish().unwrap();
+        let mut binary = [0; 6];
+        restored.read_bytes(0, &mut binary);
+        assert_eq!(&binary, b"binary");
+        assert_eq!(restored.load(0x3000_0000, 32).unwrap(), 0x1234_5678);
+        assert_eq!(restored.load(0x1000, 64).unwrap(), 7);
+        // The bytes that weren't saved are cleared.
+        assert_eq!(restored.load(0x1008, 64).unwrap(), 0);
+        assert_eq!(restored.load(0x800_0000, 64).unwrap(), 0);
+        assert_eq!(
+            restored
+                .written_chunks()
+                .filter(|(index, _)| *index == 0x2000_0000 / CHUNK_SIZE)
+                .count(),
+            0
+        );
+
+        let other = Memory::new(Vec::new(), 1 << 29);
+        assert!(other.restore(&mut SnapshotReader::new(&data)).is_err());
     }
 }
@@ -0,0 +1,238 @@
+//! The net module contains the backends that connect a network device to the host. A backend
+//! sends and receives Ethernet frames without root privileges or a real network: a Unix datagram
+//! socket connects two emulator instances, or two machines in one process with a socket pair, and
+//! a pcap file records the frames for tools like Wireshark.
+
+use std::fs::File;
+use std::io::{self, prelude::*, BufWriter};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The largest size of an Ethernet frame handled by the backends, without the frame check
+/// sequence. It's large enough for the frames of a guest that uses TSO.
+pub const MAX_FRAME_SIZE: usize = 65536;
+
+/// A backend that sends and receives Ethernet frames.
+pub trait NetBackend: Send {
+    /// Send `frame`. A frame that can't be delivered, e.g. because the peer isn't running, is
+    /// dropped as a real network would.
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    /// Receive a frame into `buf` without blocking. Return the size of the frame, or None if no
+    /// frame has arrived.
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>>;
+}
+
+/// A backend that connects nothing. Sent frames are dropped and no frame arrives.
+pub struct NullBackend;
+
+impl NetBackend for NullBackend {
+    fn send(&mut self, _frame: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn recv(&mut self, _buf: &mut [u8]) -> io::Result<Option<usize>> {
+        Ok(None)
+    }
+}
+
+/// A backend on a Unix datagram socket. Each frame is a datagram.
+#[cfg(unix)]
+pub struct UnixDatagramBackend {
+    socket: UnixDatagram,
+    /// The path of the peer socket, or None if the socket is connected.
+    peer: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+impl UnixDatagramBackend {
+    /// Create a backend bound to `local` that sends frames to `peer`. Another emulator instance
+    /// bound to `peer` that sends frames to `local` is on the same network. The peer doesn't have
+    /// to be running yet.
+    pub fn bind(local: &Path, peer: &Path) -> io::Result<Self> {
+        // A socket file left by a previous run would make the bind fail.
+        match std::fs::remove_file(local) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let socket = UnixDatagram::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            peer: Some(peer.to_path_buf()),
+        })
+    }
+
+    /// Create two backends connected to each other, e.g. for two machines in one process.
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let (a, b) = UnixDatagram::pair()?;
+        a.set_nonblocking(true)?;
+        b.set_nonblocking(true)?;
+        Ok((
+            Self {
+                socket: a,
+                peer: None,
+            },
+            Self {
+                socket: b,
+                peer: None,
+            },
+        ))
+    }
+}
+
+#[cfg(unix)]
+impl NetBackend for UnixDatagramBackend {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let result = match &self.peer {
+            Some(peer) => self.socket.send_to(frame, peer),
+            None => self.socket.send(frame),
+        };
+        match result {
+            Ok(_) => Ok(()),
+            // The peer isn't running or can't keep up.
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::NotFound
+                        | io::ErrorKind::ConnectionRefused
+                        | io::ErrorKind::WouldBlock
+                ) =>
+            {
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match self.socket.recv(buf) {
+            Ok(len) => Ok(Some(len)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A backend that records the frames sent and received through another backend to a pcap file.
+pub struct PcapBackend {
+    inner: Box<dyn NetBackend>,
+    file: BufWriter<File>,
+}
+
+impl PcapBackend {
+    /// Create a pcap file at `path` that records the frames of `inner`.
+    pub fn create(path: &Path, inner: Box<dyn NetBackend>) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        // struct pcap_hdr {
+        //   uint32 magic_number;
+        //   uint16 version_major;
+        //   uint16 version_minor;
+        //   int32  thiszone;
+        //   uint32 sigfigs;
+        //   uint32 snaplen;
+        //   uint32 network;
+        // };
+        file.write_all(&0xa1b2_c3d4u32.to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&4u16.to_le_bytes())?;
+        file.write_all(&[0; 8])?;
+        file.write_all(&(MAX_FRAME_SIZE as u32).to_le_bytes())?;
+        // LINKTYPE_ETHERNET
+        file.write_all(&1u32.to_le_bytes())?;
+        file.flush()?;
+        Ok(Self { inner, file })
+    }
+
+    /// Append `frame` to the file with the current time.
+    fn record(&mut self, frame: &[u8]) -> io::Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // struct pcaprec_hdr {
+        //   uint32 ts_sec;
+        //   uint32 ts_usec;
+        //   uint32 incl_len;
+        //   uint32 orig_len;
+        // };
+        self.file.write_all(&(now.as_secs() as u32).to_le_bytes())?;
+        self.file.write_all(&now.subsec_micros().to_le_bytes())?;
+        self.file.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.file.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.file.write_all(frame)?;
+        // Keep the file readable while the machine is running.
+        self.file.flush()
+    }
+}
+
+impl NetBackend for PcapBackend {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.record(frame)?;
+        self.inner.send(frame)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        let len = self.inner.recv(buf)?;
+        if let Some(len) = len {
+            self.record(&buf[..len])?;
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn pair_delivers_frames_to_each_other() {
+        let (mut a, mut b) = UnixDatagramBackend::pair().unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(a.recv(&mut buf).unwrap(), None);
+        a.send(b"to b").unwrap();
+        b.send(b"to a!").unwrap();
+        assert_eq!(b.recv(&mut buf).unwrap(), Some(4));
+        assert_eq!(&buf[..4], b"to b");
+        assert_eq!(a.recv(&mut buf).unwrap(), Some(5));
+        assert_eq!(b.recv(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn pcap_records_sent_and_received_frames() {
+        let path = env::temp_dir().join(format!("rvemu-net-{}.pcap", process::id()));
+        let (a, mut b) = UnixDatagramBackend::pair().unwrap();
+        let mut pcap = PcapBackend::create(&path, Box::new(a)).unwrap();
+        pcap.send(&[1, 2, 3]).unwrap();
+        b.send(&[4, 5]).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(pcap.recv(&mut buf).unwrap(), Some(2));
+        assert_eq!(pcap.recv(&mut buf).unwrap(), None);
+        assert_eq!(b.recv(&mut buf).unwrap(), Some(3));
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        // The global header: the magic, version 2.4, no time zone or accuracy, the snapshot
+        // length and Ethernet.
+        assert_eq!(data.len(), 24 + (16 + 3) + (16 + 2));
+        assert_eq!(data[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(data[4..8], [2, 0, 4, 0]);
+        assert_eq!(data[8..16], [0; 8]);
+        assert_eq!(u32_at(16), MAX_FRAME_SIZE as u32);
+        assert_eq!(u32_at(20), 1);
+        // A record header has the time, the captured length and the original length.
+        assert!(u32_at(24) > 0);
+        assert!(u32_at(28) < 1_000_000);
+        assert_eq!((u32_at(32), u32_at(36)), (3, 3));
+        assert_eq!(data[40..43], [1, 2, 3]);
+        assert_eq!((u32_at(51), u32_at(55)), (2, 2));
+        assert_eq!(data[59..], [4, 5]);
+    }
+}
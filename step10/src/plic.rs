@@ -2,71 +2,467 @@
 //! The plic connects all external interrupts in the system to all hart
 //! contexts in the system, via the external interrupt source in each hart.
 //! It's the global interrupt controller in a RISC-V system.
+//!
+//! The register layout is the same as the PLIC of the QEMU virt machine. Context `2 * hart` is the
+//! M-mode context of a hart and context `2 * hart + 1` is its S-mode context.
+//! See the spec: https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc
+
+use std::io;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 
 use crate::bus::*;
+use crate::cpu::*;
+use crate::fdt::*;
+use crate::irq::*;
+use crate::snapshot::*;
 use crate::trap::*;
 
-/// The address of interrupt pending bits.
-pub const PLIC_PENDING: u64 = PLIC_BASE + 0x1000;
-/// The address of the regsiters to enable interrupts for S-mode.
-pub const PLIC_SENABLE: u64 = PLIC_BASE + 0x2080;
-/// The address of the registers to set a priority for S-mode.
-pub const PLIC_SPRIORITY: u64 = PLIC_BASE + 0x201000;
-/// The address of the claim/complete registers for S-mode.
-pub const PLIC_SCLAIM: u64 = PLIC_BASE + 0x201004;
+/// The offset of the interrupt source priorities, 4 bytes per source.
+pub const PLIC_PRIORITY: u64 = 0x0;
+/// The offset of interrupt pending bits.
+pub const PLIC_PENDING: u64 = 0x1000;
+/// The offset of the interrupt enable bits of context 0. Each context has 0x80 bytes.
+pub const PLIC_ENABLE: u64 = 0x2000;
+/// The offset of the priority threshold of context 0. Each context has 0x1000 bytes.
+pub const PLIC_THRESHOLD: u64 = 0x200000;
+/// The offset of the claim/complete register of context 0. Each context has 0x1000 bytes.
+pub const PLIC_CLAIM: u64 = 0x200004;
+/// The offset of the regsiters to enable interrupts for S-mode.
+pub const PLIC_SENABLE: u64 = 0x2080;
+/// The offset of the registers to set a priority for S-mode.
+pub const PLIC_SPRIORITY: u64 = 0x201000;
+/// The offset of the claim/complete registers for S-mode.
+pub const PLIC_SCLAIM: u64 = 0x201004;
+
+/// The stride of the enable bits between contexts.
+const ENABLE_STRIDE: u64 = 0x80;
+/// The stride of the threshold and claim/complete registers between contexts.
+const CONTEXT_STRIDE: u64 = 0x1000;
+/// The largest priority. Priorities are WARL and hold 3 bits, same as QEMU.
+const MAX_PRIORITY: u32 = 7;
+
+/// An interrupt target: the external interrupt of a hart in M-mode or S-mode.
+struct Context {
+    /// Enable bits of the interrupt sources.
+    enable: u64,
+    /// Interrupts with a priority less than or equal to the threshold are masked.
+    threshold: u32,
+    /// The wires to the hart.
+    hart: HartInterrupts,
+    /// MIP_MEIP or MIP_SEIP.
+    mip: u64,
+}
 
 /// The platform-level-interrupt controller (PLIC).
 pub struct Plic {
+    priority: [u32; IRQ_COUNT as usize],
+    /// Interrupts latched by the gateways and not claimed yet.
     pending: u64,
-    senable: u64,
-    spriority: u64,
-    sclaim: u64,
+    /// Interrupts claimed and not completed yet. The gateway doesn't forward a new request for
+    /// them until the completion.
+    claimed: u64,
+    /// The levels of the interrupt sources, driven by `IrqLine`s.
+    levels: Arc<AtomicU64>,
+    contexts: Vec<Context>,
 }
 
 impl Device for Plic {
     fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
         match size {
             32 => Ok(self.load32(addr)),
-            _ => Err(Exception::LoadAccessFault),
+            _ => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
     fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         match size {
-            32 => Ok(self.store32(addr, value)),
-            _ => Err(Exception::StoreAMOAccessFault),
+            32 => {
+                self.store32(addr, value);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
+
+    fn reset(&mut self) {
+        self.priority = [0; IRQ_COUNT as usize];
+        self.pending = 0;
+        self.claimed = 0;
+        for context in &mut self.contexts {
+            context.enable = 0;
+            context.threshold = 0;
+        }
+        self.update();
+    }
+
+    fn tick(&mut self, _cycles: u64, _dma: &mut DmaContext) {
+        self.update();
+    }
+
+    fn describe(&self, base: u64, size: u64, fdt: &mut Fdt) {
+        fdt.begin_node(&format!("plic@{:x}", base));
+        fdt.property_u32("phandle", PHANDLE_PLIC);
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_reg(base, size);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_u32("riscv,ndev", (IRQ_COUNT - 1) as u32);
+        // Each context is the external interrupt of a hart, MEIP (11) or SEIP (9).
+        let contexts: Vec<u32> = self
+            .contexts
+            .iter()
+            .enumerate()
+            .flat_map(|(index, context)| {
+                [cpu_intc_phandle(index / 2), context.mip.trailing_zeros()]
+            })
+            .collect();
+        fdt.property_u32s("interrupts-extended", &contexts);
+        fdt.end_node();
+    }
+
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        for priority in self.priority.iter() {
+            snapshot.u32(*priority);
+        }
+        snapshot
+            .u64(self.pending)
+            .u64(self.claimed)
+            .u64(self.levels.load(Ordering::Acquire))
+            .u64(self.contexts.len() as u64);
+        for context in &self.contexts {
+            snapshot.u64(context.enable).u32(context.threshold);
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        for priority in self.priority.iter_mut() {
+            *priority = snapshot.u32()?;
+        }
+        self.pending = snapshot.u64()?;
+        self.claimed = snapshot.u64()?;
+        // The levels of the interrupt lines are restored here, and the devices drive them as
+        // usual from now on.
+        self.levels.store(snapshot.u64()?, Ordering::Release);
+        if snapshot.u64()? != self.contexts.len() as u64 {
+            return Err(snapshot_error("the number of harts doesn't match"));
+        }
+        for context in &mut self.contexts {
+            context.enable = snapshot.u64()?;
+            context.threshold = snapshot.u32()?;
+        }
+        self.update();
+        Ok(())
+    }
 }
 
 impl Plic {
-    /// Create a new `Plic` object.
-    pub fn new() -> Self {
+    /// Create a new `Plic` object with an M-mode and an S-mode context for each hart.
+    pub fn new(harts: &[HartInterrupts]) -> Self {
+        let contexts = harts
+            .iter()
+            .flat_map(|hart| {
+                [MIP_MEIP, MIP_SEIP].map(|mip| Context {
+                    enable: 0,
+                    threshold: 0,
+                    hart: hart.clone(),
+                    mip,
+                })
+            })
+            .collect();
         Self {
+            priority: [0; IRQ_COUNT as usize],
             pending: 0,
-            senable: 0,
-            spriority: 0,
-            sclaim: 0,
+            claimed: 0,
+            levels: Arc::new(AtomicU64::new(0)),
+            contexts,
+        }
+    }
+
+    /// Return the line connected to the interrupt source `irq`.
+    pub fn irq_line(&self, irq: u64) -> IrqLine {
+        IrqLine::new(irq, self.levels.clone())
+    }
+
+    /// Latch the raised lines into the pending bits and drive the external interrupt of each
+    /// context.
+    fn update(&mut self) {
+        // Source 0 doesn't exist.
+        let levels = self.levels.load(Ordering::Acquire) & !1;
+        self.pending |= levels & !self.claimed;
+        for index in 0..self.contexts.len() {
+            let level = self.best(index) != 0;
+            let context = &self.contexts[index];
+            context.hart.set(context.mip, level);
         }
     }
 
-    fn load32(&self, addr: u64) -> u64 {
+    /// Return the pending and enabled interrupt with the highest priority above the threshold of
+    /// the context, or 0 if there is none. Ties are broken by the lowest source number.
+    fn best(&self, index: usize) -> u64 {
+        let context = &self.contexts[index];
+        let mut candidates = self.pending & context.enable;
+        let mut best = 0;
+        let mut best_priority = context.threshold;
+        while candidates != 0 {
+            let irq = candidates.trailing_zeros() as u64;
+            candidates &= candidates - 1;
+            if self.priority[irq as usize] > best_priority {
+                best = irq;
+                best_priority = self.priority[irq as usize];
+            }
+        }
+        best
+    }
+
+    /// Return the context of a threshold or claim/complete register at `addr`.
+    fn context_of(&self, addr: u64, base: u64) -> Option<usize> {
+        let index = ((addr - base) / CONTEXT_STRIDE) as usize;
+        if index < self.contexts.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn load32(&mut self, addr: u64) -> u64 {
         match addr {
-            PLIC_PENDING => self.pending,
-            PLIC_SENABLE => self.senable,
-            PLIC_SPRIORITY => self.spriority,
-            PLIC_SCLAIM => self.sclaim,
+            PLIC_PRIORITY..=0xfff => {
+                let irq = addr / 4;
+                if irq < IRQ_COUNT {
+                    self.priority[irq as usize] as u64
+                } else {
+                    0
+                }
+            }
+            PLIC_PENDING..=0x1fff => match (addr - PLIC_PENDING) / 4 {
+                0 => self.pending & 0xffff_ffff,
+                1 => self.pending >> 32,
+                _ => 0,
+            },
+            PLIC_ENABLE..=0x1f_ffff => {
+                let index = ((addr - PLIC_ENABLE) / ENABLE_STRIDE) as usize;
+                match (self.contexts.get(index), (addr % ENABLE_STRIDE) / 4) {
+                    (Some(context), 0) => context.enable & 0xffff_ffff,
+                    (Some(context), 1) => context.enable >> 32,
+                    _ => 0,
+                }
+            }
+            PLIC_THRESHOLD.. if addr % CONTEXT_STRIDE == 0 => {
+                match self.context_of(addr, PLIC_THRESHOLD) {
+                    Some(index) => self.contexts[index].threshold as u64,
+                    None => 0,
+                }
+            }
+            PLIC_CLAIM.. if addr % CONTEXT_STRIDE == 4 => match self.context_of(addr, PLIC_CLAIM) {
+                Some(index) => self.claim(index),
+                None => 0,
+            },
             _ => 0,
         }
     }
 
     fn store32(&mut self, addr: u64, value: u64) {
+        let value = value & 0xffff_ffff;
         match addr {
-            PLIC_PENDING => self.pending = value,
-            PLIC_SENABLE => self.senable = value,
-            PLIC_SPRIORITY => self.spriority = value,
-            PLIC_SCLAIM => self.sclaim = value,
+            PLIC_PRIORITY..=0xfff => {
+                let irq = addr / 4;
+                if irq > 0 && irq < IRQ_COUNT {
+                    self.priority[irq as usize] = (value as u32).min(MAX_PRIORITY);
+                }
+            }
+            // The pending bits are read-only.
+            PLIC_PENDING..=0x1fff => {}
+            PLIC_ENABLE..=0x1f_ffff => {
+                let index = ((addr - PLIC_ENABLE) / ENABLE_STRIDE) as usize;
+                if let Some(context) = self.contexts.get_mut(index) {
+                    match (addr % ENABLE_STRIDE) / 4 {
+                        // Source 0 can't be enabled.
+                        0 => context.enable = (context.enable & !0xffff_ffff) | (value & !1),
+                        1 => context.enable = (context.enable & 0xffff_ffff) | (value << 32),
+                        _ => {}
+                    }
+                }
+            }
+            PLIC_THRESHOLD.. if addr % CONTEXT_STRIDE == 0 => {
+                if let Some(index) = self.context_of(addr, PLIC_THRESHOLD) {
+                    self.contexts[index].threshold = (value as u32).min(MAX_PRIORITY);
+                }
+            }
+            PLIC_CLAIM.. if addr % CONTEXT_STRIDE == 4 => {
+                if let Some(index) = self.context_of(addr, PLIC_CLAIM) {
+                    self.complete(index, value);
+                }
+            }
             _ => {}
         }
+        self.update();
+    }
+
+    /// Claim the best interrupt for the context and return its source number, or 0 if there is
+    /// none.
+    fn claim(&mut self, index: usize) -> u64 {
+        let irq = self.best(index);
+        if irq != 0 {
+            self.pending &= !(1 << irq);
+            self.claimed |= 1 << irq;
+        }
+        self.update();
+        irq
+    }
+
+    /// Complete the interrupt `irq`. The gateway forwards the next request of the source after
+    /// that. A completion for a source that is not enabled for the context is ignored.
+    fn complete(&mut self, index: usize, irq: u64) {
+        if irq < IRQ_COUNT && self.contexts[index].enable & (1 << irq) != 0 {
+            self.claimed &= !(1 << irq);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return a PLIC with the interrupts of `harts` harts, and their wires.
+    fn plic(harts: usize) -> (Plic, Vec<HartInterrupts>) {
+        let harts: Vec<HartInterrupts> = (0..harts).map(|_| HartInterrupts::new()).collect();
+        (Plic::new(&harts), harts)
+    }
+
+    fn store(plic: &mut Plic, addr: u64, value: u64) {
+        plic.store(addr, 32, value).unwrap();
+    }
+
+    fn load(plic: &mut Plic, addr: u64) -> u64 {
+        plic.load(addr, 32).unwrap()
+    }
+
+    fn meip(hart: &HartInterrupts) -> bool {
+        hart.pending() & MIP_MEIP != 0
+    }
+
+    #[test]
+    fn claimed_source_isnt_forwarded_until_completed() {
+        let (mut plic, harts) = plic(1);
+        store(&mut plic, PLIC_PRIORITY + 4 * 5, 1);
+        store(&mut plic, PLIC_ENABLE, 1 << 5);
+        let line = plic.irq_line(5);
+        line.raise();
+        plic.update();
+        assert!(meip(&harts[0]));
+        assert_eq!(load(&mut plic, PLIC_PENDING), 1 << 5);
+
+        assert_eq!(load(&mut plic, PLIC_CLAIM), 5);
+        assert!(!meip(&harts[0]));
+        // The line is still high, but the source is claimed.
+        plic.update();
+        assert!(!meip(&harts[0]));
+        assert_eq!(load(&mut plic, PLIC_CLAIM), 0);
+
+        store(&mut plic, PLIC_CLAIM, 5);
+        assert!(meip(&harts[0]));
+        line.lower();
+        assert_eq!(load(&mut plic, PLIC_CLAIM), 5);
+        store(&mut plic, PLIC_CLAIM, 5);
+        assert!(!meip(&harts[0]));
+    }
+
+    #[test]
+    fn completion_of_a_disabled_source_is_ignored() {
+        let (mut plic, harts) = plic(1);
+        store(&mut plic, PLIC_PRIORITY + 4 * 5, 1);
+        store(&mut plic, PLIC_ENABLE, 1 << 5);
+        plic.irq_line(5).raise();
+        plic.update();
+        assert_eq!(load(&mut plic, PLIC_CLAIM), 5);
+        store(&mut plic, PLIC_ENABLE, 0);
+        store(&mut plic, PLIC_CLAIM, 5);
+        store(&mut plic, PLIC_ENABLE, 1 << 5);
+        assert!(!meip(&harts[0]));
+    }
+
+    #[test]
+    fn highest_priority_above_the_threshold_is_claimed() {
+        let (mut plic, harts) = plic(1);
+        for (irq, priority) in [(3, 2), (4, 5), (6, 5)] {
+            store(&mut plic, PLIC_PRIORITY + 4 * irq, priority);
+            plic.irq_line(irq).raise();
+        }
+        store(&mut plic, PLIC_ENABLE, 1 << 3 | 1 << 4 | 1 << 6);
+        store(&mut plic, PLIC_THRESHOLD, 5);
+        assert!(!meip(&harts[0]));
+        assert_eq!(load(&mut plic, PLIC_CLAIM), 0);
+
+        store(&mut plic, PLIC_THRESHOLD, 2);
+        // A tie is broken by the lowest source number.
+        assert_eq!(load(&mut plic, PLIC_CLAIM), 4);
+        assert_eq!(load(&mut plic, PLIC_CLAIM), 6);
+        assert_eq!(load(&mut plic, PLIC_CLAIM), 0);
+        store(&mut plic, PLIC_THRESHOLD, 1);
+        assert_eq!(load(&mut plic, PLIC_CLAIM), 3);
+
+        // Priorities and thresholds hold 3 bits, and source 0 has no priority.
+        store(&mut plic, PLIC_PRIORITY + 4, 100);
+        store(&mut plic, PLIC_PRIORITY, 1);
+        store(&mut plic, PLIC_THRESHOLD, 100);
+        assert_eq!(load(&mut plic, PLIC_PRIORITY + 4), MAX_PRIORITY as u64);
+        assert_eq!(load(&mut plic, PLIC_PRIORITY), 0);
+        assert_eq!(load(&mut plic, PLIC_THRESHOLD), MAX_PRIORITY as u64);
+    }
+
+    #[test]
+    fn reset_clears_the_contexts() {
+        let (mut plic, harts) = plic(1);
+        store(&mut plic, PLIC_PRIORITY + 4 * 5, 1);
+        store(&mut plic, PLIC_ENABLE, 1 << 5);
+        plic.irq_line(5).raise();
+        plic.update();
+        assert_eq!(load(&mut plic, PLIC_CLAIM), 5);
+        store(&mut plic, PLIC_THRESHOLD, 3);
+
+        plic.reset();
+        assert_eq!(load(&mut plic, PLIC_ENABLE), 0);
+        assert_eq!(load(&mut plic, PLIC_THRESHOLD), 0);
+        assert_eq!(load(&mut plic, PLIC_PRIORITY + 4 * 5), 0);
+        // The source is no longer claimed, but it's disabled.
+        assert_eq!(load(&mut plic, PLIC_PENDING), 1 << 5);
+        assert!(!meip(&harts[0]));
+    }
+
+    #[test]
+    fn contexts_drive_the_interrupts_of_their_hart() {
+        let (mut plic, harts) = plic(2);
+        store(&mut plic, PLIC_PRIORITY + 4 * 3, 1);
+        let line = plic.irq_line(3);
+        line.raise();
+        plic.update();
+        assert!(harts.iter().all(|hart| hart.pending() == 0));
+
+        // Context 2 * hart is the M-mode context and 2 * hart + 1 the S-mode one.
+        for (context, hart, mip) in [
+            (0, 0, MIP_MEIP),
+            (1, 0, MIP_SEIP),
+            (2, 1, MIP_MEIP),
+            (3, 1, MIP_SEIP),
+        ] {
+            let enable = PLIC_ENABLE + ENABLE_STRIDE * context;
+            store(&mut plic, enable, 1 << 3);
+            let pending: Vec<u64> = harts.iter().map(HartInterrupts::pending).collect();
+            let mut expected = [0, 0];
+            expected[hart] = mip;
+            assert_eq!(pending, expected, "context {}", context);
+
+            let claim = PLIC_CLAIM + CONTEXT_STRIDE * context;
+            assert_eq!(load(&mut plic, claim), 3);
+            store(&mut plic, claim, 3);
+            store(&mut plic, enable, 0);
+        }
+        assert_eq!(PLIC_SENABLE, PLIC_ENABLE + ENABLE_STRIDE);
+        assert_eq!(PLIC_SCLAIM, PLIC_CLAIM + CONTEXT_STRIDE);
     }
 }
@@ -0,0 +1,103 @@
+//! The power module contains requests to shut down, reboot or take a snapshot of the machine. A
+//! request can come from the SBI, a device or a hint instruction, and the main loop acts on it
+//! after the current instruction.
+
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+
+/// No request is pending. The request is in the low 8 bits, and the exit status of a shutdown in
+/// the upper bits.
+const POWER_NONE: u32 = 0;
+const POWER_SHUTDOWN: u32 = 1;
+const POWER_REBOOT: u32 = 2;
+const POWER_SNAPSHOT: u32 = 3;
+
+/// A request to change the power state of the machine.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum PowerRequest {
+    /// Power off the machine and stop the emulator with the exit status, which isn't 0 when the
+    /// guest reports a failure.
+    Shutdown(u16),
+    /// Put the harts and all devices back to their power-on state and boot again.
+    Reboot,
+    /// Save a snapshot of the machine and continue running.
+    Snapshot,
+}
+
+/// The shared power control of the machine. Clones refer to the same pending request.
+#[derive(Debug, Clone, Default)]
+pub struct PowerControl {
+    request: Arc<AtomicU32>,
+}
+
+impl PowerControl {
+    /// Create a power control with no pending request.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request to shut down, reboot or take a snapshot of the machine. A shutdown takes precedence
+    /// over a reboot, and a reboot over a snapshot.
+    pub fn request(&self, request: PowerRequest) {
+        match request {
+            PowerRequest::Shutdown(status) => self
+                .request
+                .store(POWER_SHUTDOWN | (status as u32) << 8, Ordering::Release),
+            PowerRequest::Reboot => {
+                // Keep a pending shutdown.
+                let _ = self
+                    .request
+                    .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| {
+                        (pending & 0xff != POWER_SHUTDOWN).then_some(POWER_REBOOT)
+                    });
+            }
+            PowerRequest::Snapshot => {
+                let _ = self.request.compare_exchange(
+                    POWER_NONE,
+                    POWER_SNAPSHOT,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                );
+            }
+        }
+    }
+
+    /// Return true if a request is pending.
+    pub fn is_pending(&self) -> bool {
+        self.request.load(Ordering::Relaxed) != POWER_NONE
+    }
+
+    /// Return the pending request and clear it.
+    pub fn take(&self) -> Option<PowerRequest> {
+        let request = self.request.swap(POWER_NONE, Ordering::AcqRel);
+        match request & 0xff {
+            POWER_SHUTDOWN => Some(PowerRequest::Shutdown((request >> 8) as u16)),
+            POWER_REBOOT => Some(PowerRequest::Reboot),
+            POWER_SNAPSHOT => Some(PowerRequest::Snapshot),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shutdown_keeps_its_status_over_later_requests() {
+        let power = PowerControl::new();
+        power.request(PowerRequest::Snapshot);
+        power.request(PowerRequest::Shutdown(0x1234));
+        power.request(PowerRequest::Reboot);
+        power.request(PowerRequest::Snapshot);
+        assert!(power.is_pending());
+        assert_eq!(power.take(), Some(PowerRequest::Shutdown(0x1234)));
+        assert_eq!(power.take(), None);
+
+        power.request(PowerRequest::Snapshot);
+        power.request(PowerRequest::Reboot);
+        assert_eq!(power.take(), Some(PowerRequest::Reboot));
+    }
+}
@@ -0,0 +1,753 @@
+//! The qcow2 module contains a disk in the QEMU copy-on-write (qcow2) image format. The guest
+//! disk is split into clusters, which are mapped to the clusters of the image file through a
+//! two-level table (the L1 table and L2 tables). Clusters are allocated at the end of the image
+//! file when they are written for the first time, and their reference counts are kept up to date
+//! so that the image stays consistent for other tools. Unallocated clusters are read from the
+//! backing file, if any.
+//!
+//! The qcow2 spec:
+//! https://gitlab.com/qemu-project/qemu/-/blob/master/docs/interop/qcow2.txt
+
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, prelude::*, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::disk::*;
+
+/// The magic at the start of a qcow2 image, "QFI\xfb".
+pub const QCOW2_MAGIC: u32 = 0x5146_49fb;
+
+// The offsets of the header fields. All fields are big-endian.
+const HEADER_VERSION: u64 = 4;
+const HEADER_BACKING_FILE_OFFSET: u64 = 8;
+const HEADER_BACKING_FILE_SIZE: u64 = 16;
+const HEADER_CLUSTER_BITS: u64 = 20;
+const HEADER_SIZE: u64 = 24;
+const HEADER_CRYPT_METHOD: u64 = 32;
+const HEADER_L1_SIZE: u64 = 36;
+const HEADER_L1_TABLE_OFFSET: u64 = 40;
+const HEADER_REFCOUNT_TABLE_OFFSET: u64 = 48;
+const HEADER_REFCOUNT_TABLE_CLUSTERS: u64 = 56;
+const HEADER_NB_SNAPSHOTS: u64 = 60;
+// Version 3 only.
+const HEADER_INCOMPATIBLE_FEATURES: u64 = 72;
+const HEADER_AUTOCLEAR_FEATURES: u64 = 88;
+const HEADER_REFCOUNT_ORDER: u64 = 96;
+
+// Incompatible feature bits.
+const INCOMPATIBLE_DIRTY: u64 = 1 << 0;
+const INCOMPATIBLE_CORRUPT: u64 = 1 << 1;
+/// The compression type only matters for compressed clusters, which are rejected when accessed.
+const INCOMPATIBLE_COMPRESSION_TYPE: u64 = 1 << 3;
+
+/// The largest size of the L1 table and the refcount table in bytes, same as QEMU.
+const MAX_TABLE_SIZE: u64 = 32 << 20;
+/// The largest number of images in a chain of backing files, including the top image.
+const MAX_BACKING_CHAIN: usize = 16;
+
+/// The bits of an L1 or L2 entry that hold the offset of a cluster in the image file.
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The entry points to a cluster whose reference count is exactly 1, so it can be written in place.
+const FLAG_COPIED: u64 = 1 << 63;
+/// The L2 entry points to a compressed cluster.
+const FLAG_COMPRESSED: u64 = 1 << 62;
+/// The L2 entry reads as zeros. Version 3 only.
+const FLAG_ZERO: u64 = 1 << 0;
+
+/// Where the data of a guest cluster is.
+#[derive(Debug, Clone, Copy)]
+enum Cluster {
+    /// In the backing file, or zeros without a backing file.
+    Unallocated,
+    /// Zeros. The cluster at the offset, if any, is still allocated.
+    Zero(u64),
+    /// In the cluster at the offset of the image file. It can be written in place if `copied` is
+    /// true.
+    Data { offset: u64, copied: bool },
+    /// In a compressed cluster, which isn't supported.
+    Compressed,
+}
+
+/// A disk backed by a qcow2 image file.
+pub struct Qcow2Disk {
+    file: File,
+    readonly: bool,
+    version: u32,
+    capacity: u64,
+    cluster_bits: u32,
+    l1_table_offset: u64,
+    l1_table: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    /// The disk read for the unallocated clusters.
+    backing: Option<Box<dyn Disk>>,
+    /// The end of the image file, where new clusters are allocated.
+    end: u64,
+}
+
+/// Return an error for an image that can't be opened.
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("qcow2: {}", message))
+}
+
+/// Return an error for a feature that isn't supported.
+fn unsupported(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, format!("qcow2: {}", message))
+}
+
+/// Return true if the file at `path` starts with the qcow2 magic.
+pub fn is_qcow2(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(u32::from_be_bytes(magic) == QCOW2_MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+impl Qcow2Disk {
+    /// Open the qcow2 image at `path`. The image is never written if `readonly` is true. The
+    /// backing file, if any, is opened read-only.
+    pub fn open(path: &Path, readonly: bool) -> io::Result<Self> {
+        Self::open_in_chain(path, readonly, &mut Vec::new())
+    }
+
+    /// Open the qcow2 image at `path` as a backing file of the images in `chain`, which are the
+    /// canonical paths of the images opened so far. A chain that is too long or that refers to an
+    /// image twice is an error, so that a malicious image can't make the opening recurse forever.
+    fn open_in_chain(path: &Path, readonly: bool, chain: &mut Vec<PathBuf>) -> io::Result<Self> {
+        let canonical = path.canonicalize()?;
+        if chain.contains(&canonical) {
+            return Err(invalid("the chain of backing files has a loop"));
+        }
+        if chain.len() >= MAX_BACKING_CHAIN {
+            return Err(invalid("the chain of backing files is too long"));
+        }
+        chain.push(canonical);
+
+        let file = OpenOptions::new().read(true).write(!readonly).open(path)?;
+        let end = file.metadata()?.len();
+        let mut disk = Self {
+            file,
+            readonly,
+            version: 0,
+            capacity: 0,
+            cluster_bits: 0,
+            l1_table_offset: 0,
+            l1_table: Vec::new(),
+            refcount_table_offset: 0,
+            refcount_table: Vec::new(),
+            backing: None,
+            end,
+        };
+
+        if disk.read_be(0, 4)? as u32 != QCOW2_MAGIC {
+            return Err(invalid("not a qcow2 image"));
+        }
+        disk.version = disk.read_be(HEADER_VERSION, 4)? as u32;
+        if disk.version != 2 && disk.version != 3 {
+            return Err(unsupported(&format!("version {}", disk.version)));
+        }
+        disk.cluster_bits = disk.read_be(HEADER_CLUSTER_BITS, 4)? as u32;
+        if !(9..=21).contains(&disk.cluster_bits) {
+            return Err(invalid("invalid cluster size"));
+        }
+        if disk.read_be(HEADER_CRYPT_METHOD, 4)? != 0 {
+            return Err(unsupported("encrypted images are not supported"));
+        }
+        if disk.version == 3 {
+            disk.check_features()?;
+        }
+        if disk.read_be(HEADER_NB_SNAPSHOTS, 4)? != 0 && !readonly {
+            // Clusters shared with snapshots would need their tables copied before a write.
+            return Err(unsupported(
+                "images with internal snapshots can only be opened read-only",
+            ));
+        }
+        disk.capacity = disk.read_be(HEADER_SIZE, 8)?;
+
+        let l1_size = disk.read_be(HEADER_L1_SIZE, 4)?;
+        if l1_size * 8 > MAX_TABLE_SIZE {
+            return Err(invalid("the L1 table is too large"));
+        }
+        if l1_size.saturating_mul(disk.l2_entries() << disk.cluster_bits) < disk.capacity {
+            return Err(invalid("the L1 table is too small for the disk"));
+        }
+        disk.l1_table_offset = disk.read_be(HEADER_L1_TABLE_OFFSET, 8)?;
+        disk.l1_table = disk.read_table(disk.l1_table_offset, l1_size)?;
+
+        disk.refcount_table_offset = disk.read_be(HEADER_REFCOUNT_TABLE_OFFSET, 8)?;
+        let clusters = disk.read_be(HEADER_REFCOUNT_TABLE_CLUSTERS, 4)?;
+        if clusters << disk.cluster_bits > MAX_TABLE_SIZE {
+            return Err(invalid("the refcount table is too large"));
+        }
+        disk.refcount_table = disk.read_table(
+            disk.refcount_table_offset,
+            (clusters << disk.cluster_bits) / 8,
+        )?;
+
+        let backing_offset = disk.read_be(HEADER_BACKING_FILE_OFFSET, 8)?;
+        if backing_offset != 0 {
+            let mut name = vec![0; disk.read_be(HEADER_BACKING_FILE_SIZE, 4)? as usize];
+            disk.file.seek(SeekFrom::Start(backing_offset))?;
+            disk.file.read_exact(&mut name)?;
+            let name = String::from_utf8(name).map_err(|_| invalid("invalid backing file"))?;
+            // A relative path is relative to the directory of the image.
+            let backing = path.parent().unwrap_or_else(|| Path::new("")).join(name);
+            disk.backing = Some(if is_qcow2(&backing)? {
+                Box::new(Self::open_in_chain(&backing, true, chain)?)
+            } else {
+                open_image(&backing, true)?
+            });
+        }
+
+        // New clusters are allocated after everything in the file.
+        disk.end = disk.end.div_ceil(disk.cluster_size()) * disk.cluster_size();
+        Ok(disk)
+    }
+
+    /// Check the feature bits of a version 3 image.
+    fn check_features(&mut self) -> io::Result<()> {
+        let incompatible = self.read_be(HEADER_INCOMPATIBLE_FEATURES, 8)?;
+        if incompatible & INCOMPATIBLE_DIRTY != 0 {
+            return Err(invalid(
+                "the reference counts are dirty; repair the image with `qemu-img check -r all`",
+            ));
+        }
+        if incompatible & INCOMPATIBLE_CORRUPT != 0 {
+            return Err(invalid("the image is marked as corrupt"));
+        }
+        if incompatible & !INCOMPATIBLE_COMPRESSION_TYPE != 0 {
+            return Err(unsupported(&format!(
+                "incompatible features {:#x} are not supported",
+                incompatible
+            )));
+        }
+        if self.read_be(HEADER_REFCOUNT_ORDER, 4)? != 4 {
+            return Err(unsupported("only 16-bit reference counts are supported"));
+        }
+        // Features that are valid only while the image is modified by software that knows them
+        // have to be cleared before the image is modified.
+        if !self.readonly && self.read_be(HEADER_AUTOCLEAR_FEATURES, 8)? != 0 {
+            self.write_be(HEADER_AUTOCLEAR_FEATURES, 8, 0)?;
+        }
+        Ok(())
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Return the number of entries of an L2 table, which is a cluster.
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    /// Return the number of entries of a refcount block, which is a cluster of 16-bit entries.
+    fn refcount_block_entries(&self) -> u64 {
+        self.cluster_size() / 2
+    }
+
+    /// Read a big-endian integer of `len` bytes at `offset` of the image file.
+    fn read_be(&mut self, offset: u64, len: usize) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut bytes[8 - len..])?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    /// Write `value` as a big-endian integer of `len` bytes at `offset` of the image file.
+    fn write_be(&mut self, offset: u64, len: usize, value: u64) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&value.to_be_bytes()[8 - len..])
+    }
+
+    /// Read a table of `entries` 64-bit entries at `offset` of the image file.
+    fn read_table(&mut self, offset: u64, entries: u64) -> io::Result<Vec<u64>> {
+        let mut bytes = vec![0; (entries * 8) as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut bytes)?;
+        Ok(bytes
+            .chunks_exact(8)
+            .map(|entry| u64::from_be_bytes(entry.try_into().unwrap()))
+            .collect())
+    }
+
+    /// Return the offset in the image file of the L2 entry for the guest cluster at `offset`, or
+    /// None if the L2 table isn't allocated.
+    fn l2_entry_offset(&self, offset: u64) -> Option<u64> {
+        let cluster = offset >> self.cluster_bits;
+        let l2_table = self.l1_table[(cluster / self.l2_entries()) as usize] & OFFSET_MASK;
+        if l2_table == 0 {
+            return None;
+        }
+        Some(l2_table + cluster % self.l2_entries() * 8)
+    }
+
+    /// Return where the data of the guest cluster at `offset` is.
+    fn lookup(&mut self, offset: u64) -> io::Result<Cluster> {
+        let entry = match self.l2_entry_offset(offset) {
+            Some(entry_offset) => self.read_be(entry_offset, 8)?,
+            None => return Ok(Cluster::Unallocated),
+        };
+        if entry & FLAG_COMPRESSED != 0 {
+            return Ok(Cluster::Compressed);
+        }
+        let host = entry & OFFSET_MASK;
+        if self.version >= 3 && entry & FLAG_ZERO != 0 {
+            return Ok(Cluster::Zero(host));
+        }
+        if host == 0 {
+            return Ok(Cluster::Unallocated);
+        }
+        Ok(Cluster::Data {
+            offset: host,
+            copied: entry & FLAG_COPIED != 0,
+        })
+    }
+
+    /// Fill `data` with the bytes at `offset` inside of a single guest cluster.
+    fn read_cluster(&mut self, offset: u64, data: &mut [u8]) -> io::Result<()> {
+        match self.lookup(offset)? {
+            Cluster::Data { offset: host, .. } => {
+                let host = host + (offset & (self.cluster_size() - 1));
+                self.file.seek(SeekFrom::Start(host))?;
+                self.file.read_exact(data)
+            }
+            Cluster::Unallocated => {
+                data.fill(0);
+                if let Some(backing) = &mut self.backing {
+                    // The backing file can be smaller than the disk, and reads as zeros after
+                    // its end.
+                    let capacity = backing.capacity();
+                    if offset < capacity {
+                        let len = (capacity - offset).min(data.len() as u64) as usize;
+                        backing.read_at(offset, &mut data[..len])?;
+                    }
+                }
+                Ok(())
+            }
+            Cluster::Zero(_) => {
+                data.fill(0);
+                Ok(())
+            }
+            Cluster::Compressed => Err(unsupported("compressed clusters are not supported")),
+        }
+    }
+
+    /// Return the offset in the image file of the cluster to write the guest cluster at `offset`
+    /// in place, allocating it if needed. The current data of the guest cluster is copied to a
+    /// new cluster unless `overwrite` is true, i.e. the whole cluster is going to be written.
+    fn writable_cluster(&mut self, offset: u64, overwrite: bool) -> io::Result<u64> {
+        let old = self.lookup(offset)?;
+        match old {
+            Cluster::Data {
+                offset: host,
+                copied: true,
+            } => return Ok(host),
+            Cluster::Compressed => {
+                return Err(unsupported("compressed clusters are not supported"))
+            }
+            _ => {}
+        }
+
+        let start = offset & !(self.cluster_size() - 1);
+        let mut data = vec![0; self.cluster_size() as usize];
+        if !overwrite {
+            self.read_cluster(start, &mut data)?;
+        }
+        let entry_offset = self.l2_entry_offset_alloc(start)?;
+        let host = self.alloc_cluster()?;
+        self.file.seek(SeekFrom::Start(host))?;
+        self.file.write_all(&data)?;
+        self.write_be(entry_offset, 8, host | FLAG_COPIED)?;
+
+        // The old cluster isn't referred to by this entry anymore.
+        match old {
+            Cluster::Data { offset: old, .. } | Cluster::Zero(old) if old != 0 => {
+                let refcount = self.refcount(old)?;
+                self.set_refcount(old, refcount.saturating_sub(1))?;
+            }
+            _ => {}
+        }
+        Ok(host)
+    }
+
+    /// Return the offset in the image file of the L2 entry for the guest cluster at `offset`,
+    /// allocating the L2 table if needed.
+    fn l2_entry_offset_alloc(&mut self, offset: u64) -> io::Result<u64> {
+        if let Some(entry_offset) = self.l2_entry_offset(offset) {
+            let index = ((offset >> self.cluster_bits) / self.l2_entries()) as usize;
+            if self.l1_table[index] & FLAG_COPIED == 0 {
+                return Err(unsupported("shared L2 tables can't be written"));
+            }
+            return Ok(entry_offset);
+        }
+        let index = ((offset >> self.cluster_bits) / self.l2_entries()) as usize;
+        let l2_table = self.alloc_cluster()?;
+        self.l1_table[index] = l2_table | FLAG_COPIED;
+        self.write_be(
+            self.l1_table_offset + index as u64 * 8,
+            8,
+            self.l1_table[index],
+        )?;
+        Ok(self
+            .l2_entry_offset(offset)
+            .expect("the L2 table has been allocated"))
+    }
+
+    /// Allocate a zeroed cluster at the end of the image file, with the reference count 1.
+    fn alloc_cluster(&mut self) -> io::Result<u64> {
+        let offset = self.append_cluster()?;
+        self.set_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    /// Append a zeroed cluster to the image file without updating the reference counts.
+    fn append_cluster(&mut self) -> io::Result<u64> {
+        let offset = self.end;
+        self.end += self.cluster_size();
+        self.file.set_len(self.end)?;
+        Ok(offset)
+    }
+
+    /// Return the reference count of the cluster at `offset` of the image file.
+    fn refcount(&mut self, offset: u64) -> io::Result<u64> {
+        let cluster = offset >> self.cluster_bits;
+        let index = (cluster / self.refcount_block_entries()) as usize;
+        let block = match self.refcount_table.get(index) {
+            Some(entry) if entry & OFFSET_MASK != 0 => entry & OFFSET_MASK,
+            _ => return Ok(0),
+        };
+        self.read_be(block + cluster % self.refcount_block_entries() * 2, 2)
+    }
+
+    /// Set the reference count of the cluster at `offset` of the image file, allocating the
+    /// refcount block and growing the refcount table if needed.
+    fn set_refcount(&mut self, offset: u64, refcount: u64) -> io::Result<()> {
+        let cluster = offset >> self.cluster_bits;
+        let index = (cluster / self.refcount_block_entries()) as usize;
+        if index >= self.refcount_table.len() {
+            self.grow_refcount_table(index + 1)?;
+        }
+        if self.refcount_table[index] & OFFSET_MASK == 0 {
+            let block = self.append_cluster()?;
+            self.refcount_table[index] = block;
+            self.write_be(self.refcount_table_offset + index as u64 * 8, 8, block)?;
+            // The new block can describe itself, or it needs another block.
+            self.set_refcount(block, 1)?;
+        }
+        let block = self.refcount_table[index] & OFFSET_MASK;
+        let entry = block + cluster % self.refcount_block_entries() * 2;
+        self.write_be(entry, 2, refcount.min(u16::MAX as u64))
+    }
+
+    /// Move the refcount table to the end of the image file with at least `entries` entries.
+    fn grow_refcount_table(&mut self, entries: usize) -> io::Result<()> {
+        let entries_per_cluster = self.l2_entries() as usize;
+        let entries = entries.max(self.refcount_table.len() * 2);
+        let clusters = entries.div_ceil(entries_per_cluster);
+        let old_offset = self.refcount_table_offset;
+        let old_clusters = self.refcount_table.len() / entries_per_cluster;
+
+        let offset = self.end;
+        for _ in 0..clusters {
+            self.append_cluster()?;
+        }
+        self.refcount_table
+            .resize(clusters * entries_per_cluster, 0);
+        let bytes: Vec<u8> = self
+            .refcount_table
+            .iter()
+            .flat_map(|entry| entry.to_be_bytes())
+            .collect();
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&bytes)?;
+        self.write_be(HEADER_REFCOUNT_TABLE_OFFSET, 8, offset)?;
+        self.write_be(HEADER_REFCOUNT_TABLE_CLUSTERS, 4, clusters as u64)?;
+        self.refcount_table_offset = offset;
+
+        // The new table is in use, and the old one is free.
+        for i in 0..clusters as u64 {
+            self.set_refcount(offset + (i << self.cluster_bits), 1)?;
+        }
+        for i in 0..old_clusters as u64 {
+            self.set_refcount(old_offset + (i << self.cluster_bits), 0)?;
+        }
+        Ok(())
+    }
+}
+
+impl Disk for Qcow2Disk {
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    fn read_at(&mut self, mut offset: u64, mut data: &mut [u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let len = (self.cluster_size() - (offset & (self.cluster_size() - 1)))
+                .min(data.len() as u64) as usize;
+            let (part, rest) = data.split_at_mut(len);
+            self.read_cluster(offset, part)?;
+            offset += len as u64;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, mut offset: u64, mut data: &[u8]) -> io::Result<()> {
+        if self.readonly {
+            return Err(readonly_error());
+        }
+        while !data.is_empty() {
+            let within = offset & (self.cluster_size() - 1);
+            let len = (self.cluster_size() - within).min(data.len() as u64) as usize;
+            let host = self.writable_cluster(offset, len as u64 == self.cluster_size())?;
+            self.file.seek(SeekFrom::Start(host + within))?;
+            self.file.write_all(&data[..len])?;
+            offset += len as u64;
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    /// The cluster size of the test images, the smallest one.
+    const CLUSTER_BITS: u32 = 9;
+    const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+
+    /// Return an empty directory for the test `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rvemu-qcow2-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Create a version 3 image of `capacity` bytes at `path`. The clusters are the header, the
+    /// refcount table, a refcount block and the L1 table.
+    fn create_image(path: &Path, capacity: u64, backing: Option<&str>) {
+        let mut image = vec![0; 4 * CLUSTER_SIZE as usize];
+        let mut put = |offset: u64, len: usize, value: u64| {
+            let offset = offset as usize;
+            image[offset..offset + len].copy_from_slice(&value.to_be_bytes()[8 - len..]);
+        };
+        let l1_size = capacity.div_ceil((CLUSTER_SIZE / 8) * CLUSTER_SIZE);
+        put(0, 4, QCOW2_MAGIC as u64);
+        put(HEADER_VERSION, 4, 3);
+        put(HEADER_CLUSTER_BITS, 4, CLUSTER_BITS as u64);
+        put(HEADER_SIZE, 8, capacity);
+        put(HEADER_L1_SIZE, 4, l1_size);
+        put(HEADER_L1_TABLE_OFFSET, 8, 3 * CLUSTER_SIZE);
+        put(HEADER_REFCOUNT_TABLE_OFFSET, 8, CLUSTER_SIZE);
+        put(HEADER_REFCOUNT_TABLE_CLUSTERS, 4, 1);
+        put(HEADER_REFCOUNT_ORDER, 4, 4);
+        // header_length
+        put(100, 4, 104);
+        put(CLUSTER_SIZE, 8, 2 * CLUSTER_SIZE);
+        for cluster in 0..4 {
+            put(2 * CLUSTER_SIZE + cluster * 2, 2, 1);
+        }
+        if let Some(backing) = backing {
+            // After the end of the header extensions.
+            put(HEADER_BACKING_FILE_OFFSET, 8, 112);
+            put(HEADER_BACKING_FILE_SIZE, 4, backing.len() as u64);
+            image[112..112 + backing.len()].copy_from_slice(backing.as_bytes());
+        }
+        fs::write(path, image).unwrap();
+    }
+
+    #[test]
+    fn written_cluster_is_mapped_through_l1_and_l2() {
+        let dir = temp_dir("lookup");
+        let path = dir.join("disk.qcow2");
+        create_image(&path, 64 * 1024, None);
+
+        let mut disk = Qcow2Disk::open(&path, false).unwrap();
+        let offset = 40 * 1024 + 100;
+        assert!(matches!(disk.lookup(offset).unwrap(), Cluster::Unallocated));
+        // A write across two clusters.
+        let data: Vec<u8> = (0..CLUSTER_SIZE as u32).map(|i| i as u8).collect();
+        disk.write_at(offset, &data).unwrap();
+        drop(disk);
+
+        let mut disk = Qcow2Disk::open(&path, true).unwrap();
+        // The second L1 entry points to the L2 table of the second 32 KiB.
+        assert_eq!(disk.l1_table[0], 0);
+        assert_eq!(disk.l1_table[1] & FLAG_COPIED, FLAG_COPIED);
+        let first = match disk.lookup(offset).unwrap() {
+            Cluster::Data { offset, copied } => {
+                assert!(copied);
+                offset
+            }
+            cluster => panic!("unexpected cluster {:?}", cluster),
+        };
+        match disk.lookup(offset + CLUSTER_SIZE).unwrap() {
+            Cluster::Data { offset, .. } => assert_eq!(offset, first + CLUSTER_SIZE),
+            cluster => panic!("unexpected cluster {:?}", cluster),
+        }
+        let mut read = vec![0; data.len() + 8];
+        disk.read_at(offset - 4, &mut read).unwrap();
+        assert_eq!(&read[..4], &[0; 4]);
+        assert_eq!(&read[4..4 + data.len()], &data[..]);
+        assert_eq!(&read[4 + data.len()..], &[0; 4]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn allocated_clusters_are_counted() {
+        let dir = temp_dir("alloc");
+        let path = dir.join("disk.qcow2");
+        create_image(&path, 64 * 1024, None);
+
+        let mut disk = Qcow2Disk::open(&path, false).unwrap();
+        disk.write_at(0, &[1; 16]).unwrap();
+        // An L2 table and a data cluster are appended to the image.
+        assert_eq!(disk.end, 6 * CLUSTER_SIZE);
+        let l2_table = disk.l1_table[0] & OFFSET_MASK;
+        assert_eq!(disk.refcount(l2_table).unwrap(), 1);
+        let data = match disk.lookup(0).unwrap() {
+            Cluster::Data { offset, .. } => offset,
+            cluster => panic!("unexpected cluster {:?}", cluster),
+        };
+        assert_eq!(disk.refcount(data).unwrap(), 1);
+        assert_eq!(disk.refcount(disk.end).unwrap(), 0);
+
+        // A cluster is written in place once it's allocated.
+        disk.write_at(8, &[2; 16]).unwrap();
+        assert_eq!(disk.end, 6 * CLUSTER_SIZE);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 6 * CLUSTER_SIZE);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refcount_table_grows() {
+        let dir = temp_dir("grow");
+        let path = dir.join("disk.qcow2");
+        create_image(&path, 64 * 1024, None);
+
+        let mut disk = Qcow2Disk::open(&path, false).unwrap();
+        // A cluster beyond what the 64 entries of the table describe.
+        let entries = disk.refcount_table.len() as u64;
+        let far = entries * disk.refcount_block_entries() * CLUSTER_SIZE;
+        disk.set_refcount(far, 1).unwrap();
+
+        assert_eq!(disk.refcount_table.len() as u64, 2 * entries);
+        let offset = disk.refcount_table_offset;
+        assert_ne!(offset, CLUSTER_SIZE);
+        assert_eq!(disk.refcount(offset).unwrap(), 1);
+        assert_eq!(disk.refcount(offset + CLUSTER_SIZE).unwrap(), 1);
+        // The old table is free.
+        assert_eq!(disk.refcount(CLUSTER_SIZE).unwrap(), 0);
+        assert_eq!(disk.refcount(far).unwrap(), 1);
+        drop(disk);
+
+        let mut disk = Qcow2Disk::open(&path, true).unwrap();
+        assert_eq!(disk.refcount_table_offset, offset);
+        assert_eq!(disk.refcount_table.len() as u64, 2 * entries);
+        assert_eq!(disk.refcount(far).unwrap(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compressed_cluster_is_unsupported() {
+        let dir = temp_dir("compressed");
+        let path = dir.join("disk.qcow2");
+        create_image(&path, 64 * 1024, None);
+
+        let mut disk = Qcow2Disk::open(&path, false).unwrap();
+        disk.write_at(0, &[1; 16]).unwrap();
+        let entry = disk.l2_entry_offset(0).unwrap();
+        disk.write_be(entry, 8, FLAG_COMPRESSED | (5 * CLUSTER_SIZE))
+            .unwrap();
+
+        let error = disk.read_at(0, &mut [0; 16]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+        let error = disk.write_at(0, &[2; 16]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unallocated_cluster_is_read_from_backing_file() {
+        let dir = temp_dir("backing");
+        let base: Vec<u8> = (0..16 * 1024).map(|i| (i / 7) as u8).collect();
+        fs::write(dir.join("base.raw"), &base).unwrap();
+        let path = dir.join("disk.qcow2");
+        create_image(&path, 64 * 1024, Some("base.raw"));
+
+        let mut disk = Qcow2Disk::open(&path, false).unwrap();
+        let mut read = vec![0; 1024];
+        disk.read_at(1000, &mut read).unwrap();
+        assert_eq!(read, &base[1000..2024]);
+        // The disk is larger than the backing file, which reads as zeros after its end.
+        let mut read = vec![1; 1024];
+        disk.read_at(16 * 1024 - 512, &mut read).unwrap();
+        assert_eq!(&read[..512], &base[16 * 1024 - 512..]);
+        assert_eq!(&read[512..], &[0; 512][..]);
+
+        // A partial write copies the rest of the cluster from the backing file.
+        disk.write_at(CLUSTER_SIZE + 10, &[0xff; 4]).unwrap();
+        let mut read = vec![0; CLUSTER_SIZE as usize];
+        disk.read_at(CLUSTER_SIZE, &mut read).unwrap();
+        let mut expected = base[CLUSTER_SIZE as usize..2 * CLUSTER_SIZE as usize].to_vec();
+        expected[10..14].copy_from_slice(&[0xff; 4]);
+        assert_eq!(read, expected);
+        assert_eq!(fs::read(dir.join("base.raw")).unwrap(), base);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn backing_file_loop_is_rejected() {
+        let dir = temp_dir("loop");
+        create_image(&dir.join("self.qcow2"), 64 * 1024, Some("self.qcow2"));
+        create_image(&dir.join("a.qcow2"), 64 * 1024, Some("b.qcow2"));
+        create_image(&dir.join("b.qcow2"), 64 * 1024, Some("./a.qcow2"));
+
+        for name in ["self.qcow2", "a.qcow2"].iter().copied() {
+            let error = Qcow2Disk::open(&dir.join(name), true).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(error.to_string().contains("loop"), "{}", error);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn long_backing_chain_is_rejected() {
+        let dir = temp_dir("chain");
+        fs::write(dir.join("base.raw"), [7; 512]).unwrap();
+        create_image(&dir.join("0.qcow2"), 64 * 1024, Some("base.raw"));
+        for i in 1..=MAX_BACKING_CHAIN {
+            let backing = format!("{}.qcow2", i - 1);
+            create_image(&dir.join(format!("{}.qcow2", i)), 64 * 1024, Some(&backing));
+        }
+
+        // The longest chain can be opened.
+        let top = format!("{}.qcow2", MAX_BACKING_CHAIN - 1);
+        let mut disk = Qcow2Disk::open(&dir.join(top), true).unwrap();
+        let mut read = [0; 4];
+        disk.read_at(0, &mut read).unwrap();
+        assert_eq!(read, [7; 4]);
+
+        let top = format!("{}.qcow2", MAX_BACKING_CHAIN);
+        let error = Qcow2Disk::open(&dir.join(top), true).err().unwrap();
+        assert!(error.to_string().contains("too long"), "{}", error);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
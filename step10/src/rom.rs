@@ -0,0 +1,86 @@
+//! The rom module contains the boot ROM (mask ROM) at the reset vector. It holds the same reset
+//! code as the QEMU virt machine: it passes the hart ID in a0, the address of the device tree in
+//! a1 and the address of a `fw_dynamic_info` structure in a2, and jumps to the firmware.
+//! See the OpenSBI documentation of the fw_dynamic firmware:
+//! https://github.com/riscv-software-src/opensbi/blob/master/docs/firmware/fw_dynamic.md
+
+use std::io;
+
+use crate::bus::*;
+use crate::snapshot::*;
+use crate::trap::*;
+
+/// The magic value of `fw_dynamic_info` ("OSBI").
+const FW_DYNAMIC_INFO_MAGIC: u64 = 0x4942534f;
+/// The version of `fw_dynamic_info`.
+const FW_DYNAMIC_INFO_VERSION: u64 = 2;
+/// The privilege mode of the next booting stage. 1 is S-mode.
+const FW_DYNAMIC_INFO_NEXT_MODE_S: u64 = 1;
+
+/// The reset code. The data words follow it.
+const RESET_CODE: [u32; 6] = [
+    0x00000297, // auipc t0, 0x0
+    0x02828613, // addi  a2, t0, 40     # a2 = &fw_dynamic_info
+    0xf1402573, // csrr  a0, mhartid
+    0x0202b583, // ld    a1, 32(t0)     # a1 = the device tree
+    0x0182b283, // ld    t0, 24(t0)     # t0 = the firmware
+    0x00028067, // jr    t0
+];
+
+/// The boot ROM. It's read-only.
+pub struct BootRom {
+    data: Vec<u8>,
+}
+
+impl Device for BootRom {
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let len = size / 8;
+        match size {
+            8 | 16 | 32 | 64 if addr + len <= self.data.len() as u64 => {
+                let mut buf = [0; 8];
+                buf[..len as usize]
+                    .copy_from_slice(&self.data[addr as usize..(addr + len) as usize]);
+                Ok(u64::from_le_bytes(buf))
+            }
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
+    fn store(&mut self, addr: u64, _size: u64, _value: u64) -> Result<(), Exception> {
+        Err(Exception::StoreAMOAccessFault(addr))
+    }
+
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        snapshot.bytes(&self.data);
+        Ok(())
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        // The boot ROM is set up for the boot images, so its contents are part of the state.
+        self.data = snapshot.bytes()?.to_vec();
+        Ok(())
+    }
+}
+
+impl BootRom {
+    /// Create a boot ROM that jumps to the firmware at `firmware` with the device tree at `fdt`.
+    /// The firmware is told to boot the next stage at `next` in S-mode.
+    pub fn new(firmware: u64, fdt: u64, next: u64) -> Self {
+        let mut data: Vec<u8> = RESET_CODE.iter().flat_map(|i| i.to_le_bytes()).collect();
+        let words = [
+            firmware,
+            fdt,
+            // struct fw_dynamic_info
+            FW_DYNAMIC_INFO_MAGIC,
+            FW_DYNAMIC_INFO_VERSION,
+            next,
+            FW_DYNAMIC_INFO_NEXT_MODE_S,
+            // options
+            0,
+            // boot_hart
+            0,
+        ];
+        data.extend(words.iter().flat_map(|word| word.to_le_bytes()));
+        Self { data }
+    }
+}
@@ -0,0 +1,322 @@
+//! The rtc module contains the Goldfish real-time clock (RTC), which is used in the QEMU virt
+//! machine. The clock counts nanoseconds since the Unix epoch and can raise an alarm interrupt.
+//! It follows the host time by default, or starts from a fixed time and advances with the
+//! instructions so that a run is deterministic.
+//! See the spec: https://android.googlesource.com/platform/external/qemu/+/master/docs/GOLDFISH-VIRTUAL-HARDWARE.TXT
+
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bus::*;
+use crate::clint::*;
+use crate::fdt::*;
+use crate::irq::*;
+use crate::snapshot::*;
+use crate::trap::*;
+
+/// The interrupt request of the RTC, same as QEMU virt machine.
+pub const RTC_IRQ: u64 = 11;
+
+/// The lower 32 bits of the time. Reading it latches the upper 32 bits in `RTC_TIME_HIGH`, and
+/// writing it sets the time with the upper 32 bits written to `RTC_TIME_HIGH` before.
+pub const RTC_TIME_LOW: u64 = 0x00;
+/// The upper 32 bits of the time.
+pub const RTC_TIME_HIGH: u64 = 0x04;
+/// The lower 32 bits of the alarm time. Writing it arms the alarm.
+pub const RTC_ALARM_LOW: u64 = 0x08;
+/// The upper 32 bits of the alarm time.
+pub const RTC_ALARM_HIGH: u64 = 0x0c;
+/// Whether the alarm raises an interrupt.
+pub const RTC_IRQ_ENABLED: u64 = 0x10;
+/// Writing any value disarms the alarm.
+pub const RTC_CLEAR_ALARM: u64 = 0x14;
+/// 1 if the alarm is armed.
+pub const RTC_ALARM_STATUS: u64 = 0x18;
+/// Writing any value clears the interrupt.
+pub const RTC_CLEAR_INTERRUPT: u64 = 0x1c;
+
+/// The number of nanoseconds in a second.
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// The source of the time of the RTC.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum RtcClock {
+    /// Follow the time of the host.
+    Host,
+    /// Start from the seconds since the Unix epoch when the machine is created, and advance with
+    /// the mtime of the CLINT.
+    Fixed(u64),
+}
+
+/// The Goldfish real-time clock.
+pub struct GoldfishRtc {
+    clock: RtcClock,
+    /// The number of cycles since the machine was created. A reboot doesn't stop the clock.
+    cycles: u64,
+    /// The difference between the time set by the guest and the time of the clock source.
+    offset: u64,
+    time_high: u32,
+    alarm_high: u32,
+    /// The alarm time, or None if the alarm is disarmed.
+    alarm: Option<u64>,
+    irq_enabled: bool,
+    /// Whether the alarm has gone off and the interrupt hasn't been cleared.
+    irq_pending: bool,
+    irq: IrqLine,
+}
+
+impl Device for GoldfishRtc {
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if size != 32 {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        let value = match addr {
+            RTC_TIME_LOW => {
+                let time = self.time();
+                self.time_high = (time >> 32) as u32;
+                time as u32
+            }
+            RTC_TIME_HIGH => self.time_high,
+            RTC_ALARM_LOW => self.alarm.unwrap_or(0) as u32,
+            RTC_ALARM_HIGH => self.alarm_high,
+            RTC_IRQ_ENABLED => self.irq_enabled as u32,
+            RTC_ALARM_STATUS => self.alarm.is_some() as u32,
+            _ => 0,
+        };
+        Ok(value as u64)
+    }
+
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        let value = value as u32;
+        match addr {
+            RTC_TIME_LOW => {
+                let time = (self.time_high as u64) << 32 | value as u64;
+                self.offset = time.wrapping_sub(self.source_time());
+            }
+            RTC_TIME_HIGH => self.time_high = value,
+            RTC_ALARM_LOW => self.alarm = Some((self.alarm_high as u64) << 32 | value as u64),
+            RTC_ALARM_HIGH => self.alarm_high = value,
+            RTC_IRQ_ENABLED => self.irq_enabled = value & 1 != 0,
+            RTC_CLEAR_ALARM => self.alarm = None,
+            RTC_CLEAR_INTERRUPT => self.irq_pending = false,
+            _ => {}
+        }
+        self.update();
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        // The time is kept as a battery-backed clock does.
+        self.time_high = 0;
+        self.alarm_high = 0;
+        self.alarm = None;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+        self.update();
+    }
+
+    fn tick(&mut self, cycles: u64, _dma: &mut DmaContext) {
+        self.cycles = self.cycles.wrapping_add(cycles);
+        if self.alarm.is_some() {
+            self.update();
+        }
+    }
+
+    fn describe(&self, base: u64, size: u64, fdt: &mut Fdt) {
+        fdt.begin_node(&format!("rtc@{:x}", base));
+        fdt.property_string("compatible", "google,goldfish-rtc");
+        fdt.property_reg(base, size);
+        fdt.property_u32("interrupt-parent", PHANDLE_PLIC);
+        fdt.property_u32("interrupts", self.irq.irq() as u32);
+        fdt.end_node();
+    }
+
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        match self.clock {
+            RtcClock::Host => snapshot.bool(false),
+            RtcClock::Fixed(epoch) => snapshot.bool(true).u64(epoch),
+        };
+        snapshot
+            .u64(self.cycles)
+            .u64(self.offset)
+            .u32(self.time_high)
+            .u32(self.alarm_high)
+            .bool(self.alarm.is_some())
+            .u64(self.alarm.unwrap_or(0))
+            .bool(self.irq_enabled)
+            .bool(self.irq_pending);
+        Ok(())
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        // The clock source is restored too, so that a fixed clock goes on from the same time.
+        self.clock = if snapshot.bool()? {
+            RtcClock::Fixed(snapshot.u64()?)
+        } else {
+            RtcClock::Host
+        };
+        self.cycles = snapshot.u64()?;
+        self.offset = snapshot.u64()?;
+        self.time_high = snapshot.u32()?;
+        self.alarm_high = snapshot.u32()?;
+        let armed = snapshot.bool()?;
+        let alarm = snapshot.u64()?;
+        self.alarm = armed.then_some(alarm);
+        self.irq_enabled = snapshot.bool()?;
+        self.irq_pending = snapshot.bool()?;
+        self.update();
+        Ok(())
+    }
+}
+
+impl GoldfishRtc {
+    /// Create a new RTC that follows the host time and raises `irq` when the alarm goes off.
+    pub fn new(irq: IrqLine) -> Self {
+        Self {
+            clock: RtcClock::Host,
+            cycles: 0,
+            offset: 0,
+            time_high: 0,
+            alarm_high: 0,
+            alarm: None,
+            irq_enabled: false,
+            irq_pending: false,
+            irq,
+        }
+    }
+
+    /// Change the source of the time. The time set by the guest is forgotten.
+    pub fn set_clock(&mut self, clock: RtcClock) {
+        self.clock = clock;
+        self.cycles = 0;
+        self.offset = 0;
+    }
+
+    /// Return the time of the clock source in nanoseconds since the Unix epoch.
+    fn source_time(&self) -> u64 {
+        match self.clock {
+            RtcClock::Host => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_nanos() as u64)
+                .unwrap_or(0),
+            RtcClock::Fixed(epoch) => epoch.wrapping_mul(NANOS_PER_SEC).wrapping_add(
+                self.cycles
+                    .wrapping_mul(NANOS_PER_SEC / CLINT_TIMEBASE_FREQUENCY),
+            ),
+        }
+    }
+
+    /// Return the time seen by the guest in nanoseconds since the Unix epoch.
+    pub fn time(&self) -> u64 {
+        self.source_time().wrapping_add(self.offset)
+    }
+
+    /// Fire the alarm if its time has come, and drive the interrupt line.
+    fn update(&mut self) {
+        if let Some(alarm) = self.alarm {
+            if self.time() >= alarm {
+                self.alarm = None;
+                self.irq_pending = true;
+            }
+        }
+        self.irq.set(self.irq_enabled && self.irq_pending);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::*;
+    use std::sync::Arc;
+
+    /// The number of nanoseconds in a cycle of a fixed clock.
+    const NANOS_PER_CYCLE: u64 = NANOS_PER_SEC / CLINT_TIMEBASE_FREQUENCY;
+
+    /// Return an RTC with a fixed clock at `epoch` seconds.
+    fn rtc(epoch: u64) -> GoldfishRtc {
+        let mut rtc = GoldfishRtc::new(IrqLine::new(RTC_IRQ, Arc::default()));
+        rtc.set_clock(RtcClock::Fixed(epoch));
+        rtc
+    }
+
+    fn tick(rtc: &mut GoldfishRtc, cycles: u64) {
+        let memory = Memory::new(Vec::new(), 0x1000);
+        rtc.tick(cycles, &mut DmaContext::new(&memory));
+    }
+
+    fn load(rtc: &mut GoldfishRtc, addr: u64) -> u64 {
+        rtc.load(addr, 32).unwrap()
+    }
+
+    fn store(rtc: &mut GoldfishRtc, addr: u64, value: u64) {
+        rtc.store(addr, 32, value).unwrap();
+    }
+
+    #[test]
+    fn fixed_clock_advances_with_the_cycles() {
+        let mut rtc = rtc(1_600_000_000);
+        let start = 1_600_000_000 * NANOS_PER_SEC;
+        assert_eq!(rtc.time(), start);
+        tick(&mut rtc, 10);
+        assert_eq!(rtc.time(), start + 10 * NANOS_PER_CYCLE);
+    }
+
+    #[test]
+    fn reading_time_low_latches_time_high() {
+        let mut rtc = rtc(0);
+        // Set the time just before the upper 32 bits change.
+        store(&mut rtc, RTC_TIME_HIGH, 5);
+        store(&mut rtc, RTC_TIME_LOW, 0xffff_ff00);
+        assert_eq!(rtc.time(), 5 << 32 | 0xffff_ff00);
+
+        assert_eq!(load(&mut rtc, RTC_TIME_LOW), 0xffff_ff00);
+        tick(&mut rtc, 10);
+        assert_eq!(load(&mut rtc, RTC_TIME_HIGH), 5);
+        assert_eq!(load(&mut rtc, RTC_TIME_LOW), 0x2e8);
+        assert_eq!(load(&mut rtc, RTC_TIME_HIGH), 6);
+    }
+
+    #[test]
+    fn alarm_raises_the_interrupt() {
+        let mut rtc = rtc(0);
+        store(&mut rtc, RTC_IRQ_ENABLED, 1);
+        store(&mut rtc, RTC_ALARM_HIGH, 0);
+        store(&mut rtc, RTC_ALARM_LOW, 10 * NANOS_PER_CYCLE);
+        assert_eq!(load(&mut rtc, RTC_ALARM_STATUS), 1);
+
+        tick(&mut rtc, 9);
+        assert!(!rtc.irq.is_raised());
+        tick(&mut rtc, 1);
+        assert!(rtc.irq.is_raised());
+        assert_eq!(load(&mut rtc, RTC_ALARM_STATUS), 0);
+
+        store(&mut rtc, RTC_CLEAR_INTERRUPT, 1);
+        assert!(!rtc.irq.is_raised());
+    }
+
+    #[test]
+    fn disabled_alarm_interrupt_is_raised_when_enabled() {
+        let mut rtc = rtc(0);
+        // An alarm in the past goes off at once.
+        tick(&mut rtc, 10);
+        store(&mut rtc, RTC_ALARM_LOW, 5 * NANOS_PER_CYCLE);
+        assert_eq!(load(&mut rtc, RTC_ALARM_STATUS), 0);
+        assert!(!rtc.irq.is_raised());
+        store(&mut rtc, RTC_IRQ_ENABLED, 1);
+        assert!(rtc.irq.is_raised());
+    }
+
+    #[test]
+    fn cleared_alarm_doesnt_go_off() {
+        let mut rtc = rtc(0);
+        store(&mut rtc, RTC_IRQ_ENABLED, 1);
+        store(&mut rtc, RTC_ALARM_LOW, 10 * NANOS_PER_CYCLE);
+        store(&mut rtc, RTC_CLEAR_ALARM, 1);
+        tick(&mut rtc, 20);
+        assert_eq!(load(&mut rtc, RTC_ALARM_STATUS), 0);
+        assert!(!rtc.irq.is_raised());
+    }
+}
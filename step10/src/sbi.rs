@@ -0,0 +1,797 @@
+//! The sbi module contains a built-in implementation of the RISC-V Supervisor Binary Interface
+//! (SBI) v2.0. When it's enabled, an `ecall` from S-mode is handled by the emulator instead of
+//! trapping to an M-mode firmware, so an S-mode kernel can boot without one.
+//! See the spec: https://github.com/riscv-non-isa/riscv-sbi-doc
+
+use std::io;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, MutexGuard, PoisonError,
+};
+
+use crate::boot::*;
+use crate::bus::*;
+use crate::clint::*;
+use crate::cpu::*;
+use crate::power::*;
+use crate::snapshot::*;
+use crate::trap::*;
+use crate::uart::*;
+
+/// The implemented version of the SBI specification, 2.0.
+const SBI_SPEC_VERSION: u64 = 2 << 24;
+/// The implementation ID. It's not registered to the SBI specification.
+const SBI_IMPL_ID: u64 = 0x7276_656d;
+/// The implementation version.
+const SBI_IMPL_VERSION: u64 = 1;
+
+// Extension IDs.
+const SBI_EXT_LEGACY_CONSOLE_PUTCHAR: u64 = 0x01;
+const SBI_EXT_LEGACY_CONSOLE_GETCHAR: u64 = 0x02;
+const SBI_EXT_BASE: u64 = 0x10;
+const SBI_EXT_TIME: u64 = 0x54494d45;
+const SBI_EXT_IPI: u64 = 0x735049;
+const SBI_EXT_RFENCE: u64 = 0x52464e43;
+const SBI_EXT_HSM: u64 = 0x48534d;
+const SBI_EXT_SRST: u64 = 0x53525354;
+const SBI_EXT_DBCN: u64 = 0x4442434e;
+
+/// The extensions reported by `sbi_probe_extension`.
+const SBI_EXTENSIONS: [u64; 9] = [
+    SBI_EXT_LEGACY_CONSOLE_PUTCHAR,
+    SBI_EXT_LEGACY_CONSOLE_GETCHAR,
+    SBI_EXT_BASE,
+    SBI_EXT_TIME,
+    SBI_EXT_IPI,
+    SBI_EXT_RFENCE,
+    SBI_EXT_HSM,
+    SBI_EXT_SRST,
+    SBI_EXT_DBCN,
+];
+
+// Standard SBI errors.
+const SBI_SUCCESS: i64 = 0;
+const SBI_ERR_FAILED: i64 = -1;
+const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+const SBI_ERR_INVALID_PARAM: i64 = -3;
+const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+// Hart states of the HSM extension.
+const SBI_HSM_STATE_STARTED: u64 = 0;
+const SBI_HSM_STATE_STOPPED: u64 = 1;
+const SBI_HSM_STATE_START_PENDING: u64 = 2;
+
+/// The default retentive suspend type of `sbi_hart_suspend`.
+const SBI_HSM_SUSPEND_RETENTIVE: u64 = 0;
+/// The default non-retentive suspend type of `sbi_hart_suspend`.
+const SBI_HSM_SUSPEND_NON_RETENTIVE: u64 = 0x8000_0000;
+
+// Reset types of the SRST extension.
+const SBI_SRST_SHUTDOWN: u64 = 0;
+const SBI_SRST_COLD_REBOOT: u64 = 1;
+const SBI_SRST_WARM_REBOOT: u64 = 2;
+
+/// The hart mask base which means all harts.
+const SBI_HART_MASK_ALL: u64 = u64::MAX;
+
+/// The exceptions delegated to S-mode. Everything except the environment calls from S-mode and
+/// M-mode, which are handled here or can't happen.
+const SBI_MEDELEG: u64 = 0xb1ff;
+/// The interrupts delegated to S-mode.
+const SBI_MIDELEG: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+/// The state of a hart managed by the HSM extension.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum HartState {
+    Started,
+    Stopped,
+    /// `sbi_hart_start` has been called and the hart starts at `start_addr` with a1 = `opaque`
+    /// before its next instruction.
+    StartPending {
+        start_addr: u64,
+        opaque: u64,
+    },
+}
+
+/// The SBI state of a hart.
+#[derive(Debug)]
+struct SbiHart {
+    state: HartState,
+    /// The resume address and the opaque value of a non-retentive suspend in progress.
+    resume: Option<(u64, u64)>,
+}
+
+/// The state of the built-in SBI, shared by all harts.
+#[derive(Debug)]
+pub struct Sbi {
+    /// The state of each hart, indexed by the hart ID.
+    harts: Vec<Mutex<SbiHart>>,
+    /// Whether an IPI has been sent to each hart and not delivered yet, indexed by the hart ID.
+    ipis: Vec<AtomicBool>,
+}
+
+/// The result of an SBI call, returned in a0 and a1.
+type SbiResult = Result<u64, i64>;
+
+impl Sbi {
+    /// Create the SBI for `harts` harts. Only the boot hart is started, and the others wait for
+    /// `sbi_hart_start`.
+    pub fn new(harts: usize) -> Self {
+        Self {
+            harts: (0..harts)
+                .map(|hartid| {
+                    Mutex::new(SbiHart {
+                        state: if hartid == 0 {
+                            HartState::Started
+                        } else {
+                            HartState::Stopped
+                        },
+                        resume: None,
+                    })
+                })
+                .collect(),
+            ipis: (0..harts).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    /// Return the number of harts.
+    pub fn harts(&self) -> usize {
+        self.harts.len()
+    }
+
+    /// Return true if the hart `hartid` is stopped and doesn't execute instructions.
+    pub fn is_stopped(&self, hartid: usize) -> bool {
+        self.hart(hartid).state != HartState::Started
+    }
+
+    /// Save the state of each hart and the pending IPIs to `snapshot`.
+    pub fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.u64(self.harts.len() as u64);
+        for (hartid, ipi) in self.ipis.iter().enumerate() {
+            let hart = self.hart(hartid);
+            match hart.state {
+                HartState::Started => snapshot.u64(SBI_HSM_STATE_STARTED),
+                HartState::Stopped => snapshot.u64(SBI_HSM_STATE_STOPPED),
+                HartState::StartPending { start_addr, opaque } => snapshot
+                    .u64(SBI_HSM_STATE_START_PENDING)
+                    .u64(start_addr)
+                    .u64(opaque),
+            };
+            snapshot.bool(hart.resume.is_some());
+            if let Some((resume_addr, opaque)) = hart.resume {
+                snapshot.u64(resume_addr).u64(opaque);
+            }
+            snapshot.bool(ipi.load(Ordering::Acquire));
+        }
+    }
+
+    /// Restore the state saved by `save`.
+    pub fn restore(&self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        if snapshot.u64()? != self.harts.len() as u64 {
+            return Err(snapshot_error("the number of harts doesn't match"));
+        }
+        for (hartid, ipi) in self.ipis.iter().enumerate() {
+            let state = match snapshot.u64()? {
+                SBI_HSM_STATE_STARTED => HartState::Started,
+                SBI_HSM_STATE_STOPPED => HartState::Stopped,
+                SBI_HSM_STATE_START_PENDING => HartState::StartPending {
+                    start_addr: snapshot.u64()?,
+                    opaque: snapshot.u64()?,
+                },
+                _ => return Err(snapshot_error("invalid hart state")),
+            };
+            let resume = if snapshot.bool()? {
+                Some((snapshot.u64()?, snapshot.u64()?))
+            } else {
+                None
+            };
+            *self.hart(hartid) = SbiHart { state, resume };
+            ipi.store(snapshot.bool()?, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Lock the state of the hart `hartid`.
+    fn hart(&self, hartid: usize) -> MutexGuard<'_, SbiHart> {
+        self.harts[hartid]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Return the harts selected by a hart mask.
+    fn selected_harts(&self, mask: u64, base: u64) -> Result<Vec<usize>, i64> {
+        let harts = self.harts.len();
+        if base == SBI_HART_MASK_ALL {
+            return Ok((0..harts).collect());
+        }
+        let mut selected = Vec::new();
+        for bit in 0..64 {
+            if mask & (1 << bit) == 0 {
+                continue;
+            }
+            match base.checked_add(bit) {
+                Some(hart) if hart < harts as u64 => selected.push(hart as usize),
+                _ => return Err(SBI_ERR_INVALID_PARAM),
+            }
+        }
+        Ok(selected)
+    }
+}
+
+/// Jump to `addr` in S-mode as the SBI firmware would: a0 holds the hart ID and a1 `arg`.
+fn enter_supervisor(cpu: &mut Cpu, addr: u64, arg: u64) {
+    cpu.csrs.store(MEDELEG, SBI_MEDELEG);
+    cpu.csrs.store(MIDELEG, SBI_MIDELEG);
+    cpu.csrs.store(MCOUNTEREN, 0b111);
+    cpu.regs[10] = cpu.csrs.load(MHARTID);
+    cpu.regs[11] = arg;
+    cpu.mode = Mode::Supervisor;
+    cpu.pc = addr;
+}
+
+/// Start the kernel in S-mode on the boot hart. a1 holds the address of the device tree.
+pub fn enter_kernel(cpu: &mut Cpu, layout: &BootLayout) {
+    enter_supervisor(cpu, layout.kernel_entry, layout.fdt.0);
+}
+
+/// Start the hart if `sbi_hart_start` has been called for it.
+pub fn start_if_pending(cpu: &mut Cpu) {
+    let hartid = cpu.hartid();
+    let start = match &cpu.sbi {
+        Some(sbi) => {
+            let mut hart = sbi.hart(hartid);
+            match hart.state {
+                HartState::StartPending { start_addr, opaque } => {
+                    hart.state = HartState::Started;
+                    Some((start_addr, opaque))
+                }
+                _ => None,
+            }
+        }
+        None => None,
+    };
+    if let Some((start_addr, opaque)) = start {
+        // "The hart will start executing at start_addr in S-mode with a0 = hartid,
+        // a1 = opaque, satp = 0 and sstatus.SIE = 0."
+        cpu.csrs.store(SATP, 0);
+        cpu.update_paging(SATP);
+        cpu.csrs
+            .store(SSTATUS, cpu.csrs.load(SSTATUS) & !SSTATUS_SIE);
+        enter_supervisor(cpu, start_addr, opaque);
+    }
+}
+
+/// Forward the machine timer interrupt and the IPIs sent by `sbi_send_ipi` to S-mode. The timer
+/// is programmed by `sbi_set_timer`, which clears STIP again.
+pub fn forward_interrupts(cpu: &mut Cpu) {
+    if cpu.csrs.interrupts().pending() & MIP_MTIP != 0 {
+        cpu.csrs.store(MIP, cpu.csrs.load(MIP) | MIP_STIP);
+    }
+    // The SBI isn't cloned here because this runs before every instruction.
+    let hartid = cpu.hartid();
+    if let Some(sbi) = &cpu.sbi {
+        let ipi = &sbi.ipis[hartid];
+        if ipi.load(Ordering::Relaxed) && ipi.swap(false, Ordering::AcqRel) {
+            cpu.csrs.store(MIP, cpu.csrs.load(MIP) | MIP_SSIP);
+        }
+    }
+}
+
+/// Handle an `ecall` from S-mode. The extension ID is in a7, the function ID in a6 and the
+/// arguments in a0-a5. The error is returned in a0 and the value in a1.
+pub fn handle_ecall(cpu: &mut Cpu) -> Result<(), Exception> {
+    let eid = cpu.regs[17];
+    let fid = cpu.regs[16];
+    let args = [
+        cpu.regs[10],
+        cpu.regs[11],
+        cpu.regs[12],
+        cpu.regs[13],
+        cpu.regs[14],
+        cpu.regs[15],
+    ];
+
+    // The legacy extensions return a single value in a0.
+    match eid {
+        SBI_EXT_LEGACY_CONSOLE_PUTCHAR => {
+            console_write_byte(cpu, args[0]);
+            cpu.regs[10] = 0;
+            return Ok(());
+        }
+        SBI_EXT_LEGACY_CONSOLE_GETCHAR => {
+            cpu.regs[10] = match console_read_byte(cpu) {
+                Some(byte) => byte as u64,
+                None => -1i64 as u64,
+            };
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let result = match eid {
+        SBI_EXT_BASE => base(fid, args),
+        SBI_EXT_TIME => time(cpu, fid, args),
+        SBI_EXT_IPI => ipi(cpu, fid, args),
+        SBI_EXT_RFENCE => rfence(cpu, fid, args),
+        SBI_EXT_HSM => hsm(cpu, fid, args),
+        SBI_EXT_SRST => srst(cpu, fid, args),
+        SBI_EXT_DBCN => dbcn(cpu, fid, args),
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    };
+    match result {
+        Ok(value) => {
+            cpu.regs[10] = SBI_SUCCESS as u64;
+            cpu.regs[11] = value;
+        }
+        Err(error) => {
+            cpu.regs[10] = error as u64;
+            cpu.regs[11] = 0;
+        }
+    }
+
+    // A non-retentive suspend doesn't return to the caller.
+    let resume = sbi(cpu).hart(cpu.hartid()).resume.take();
+    if let Some((resume_addr, opaque)) = resume {
+        // "the hart will resume at resume_addr in S-mode with a0 = hartid, a1 = opaque,
+        // satp = 0 and sstatus.SIE = 0."
+        cpu.regs[10] = cpu.csrs.load(MHARTID);
+        cpu.regs[11] = opaque;
+        cpu.csrs.store(SATP, 0);
+        cpu.update_paging(SATP);
+        cpu.csrs
+            .store(SSTATUS, cpu.csrs.load(SSTATUS) & !SSTATUS_SIE);
+        cpu.pc = resume_addr;
+    }
+    Ok(())
+}
+
+/// The base extension.
+fn base(fid: u64, args: [u64; 6]) -> SbiResult {
+    match fid {
+        // sbi_get_spec_version
+        0 => Ok(SBI_SPEC_VERSION),
+        // sbi_get_impl_id
+        1 => Ok(SBI_IMPL_ID),
+        // sbi_get_impl_version
+        2 => Ok(SBI_IMPL_VERSION),
+        // sbi_probe_extension
+        3 => Ok(SBI_EXTENSIONS.contains(&args[0]) as u64),
+        // sbi_get_mvendorid, sbi_get_marchid and sbi_get_mimpid. This emulator doesn't
+        // implement them, so they are 0.
+        4..=6 => Ok(0),
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// The timer extension.
+fn time(cpu: &mut Cpu, fid: u64, args: [u64; 6]) -> SbiResult {
+    match fid {
+        // sbi_set_timer
+        0 => {
+            let mtimecmp = CLINT_BASE + CLINT_MTIMECMP + 8 * cpu.csrs.load(MHARTID);
+            cpu.bus
+                .store(mtimecmp, 64, args[0])
+                .map_err(|_| SBI_ERR_FAILED)?;
+            cpu.csrs.store(MIP, cpu.csrs.load(MIP) & !MIP_STIP);
+            Ok(0)
+        }
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// The IPI extension.
+fn ipi(cpu: &mut Cpu, fid: u64, args: [u64; 6]) -> SbiResult {
+    match fid {
+        // sbi_send_ipi
+        0 => {
+            let sbi = sbi(cpu);
+            for hartid in sbi.selected_harts(args[0], args[1])? {
+                sbi.ipis[hartid].store(true, Ordering::Release);
+            }
+            Ok(0)
+        }
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// The remote fence extension.
+fn rfence(cpu: &mut Cpu, fid: u64, args: [u64; 6]) -> SbiResult {
+    match fid {
+        // sbi_remote_fence_i, sbi_remote_sfence_vma and sbi_remote_sfence_vma_asid. There is
+        // nothing to flush because this emulator has neither an instruction cache nor a TLB.
+        0..=2 => {
+            sbi(cpu).selected_harts(args[0], args[1])?;
+            Ok(0)
+        }
+        // The fences for the hypervisor extension, which isn't implemented.
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// The hart state management extension.
+fn hsm(cpu: &mut Cpu, fid: u64, args: [u64; 6]) -> SbiResult {
+    let sbi = sbi(cpu);
+    let hartid = cpu.hartid();
+    match fid {
+        // sbi_hart_start
+        0 => {
+            if args[0] >= sbi.harts() as u64 {
+                return Err(SBI_ERR_INVALID_PARAM);
+            }
+            let mut hart = sbi.hart(args[0] as usize);
+            match hart.state {
+                HartState::Stopped => {
+                    hart.state = HartState::StartPending {
+                        start_addr: args[1],
+                        opaque: args[2],
+                    };
+                    Ok(0)
+                }
+                _ => Err(SBI_ERR_ALREADY_AVAILABLE),
+            }
+        }
+        // sbi_hart_stop
+        1 => {
+            sbi.hart(hartid).state = HartState::Stopped;
+            Ok(0)
+        }
+        // sbi_hart_get_status
+        2 => {
+            if args[0] >= sbi.harts() as u64 {
+                return Err(SBI_ERR_INVALID_PARAM);
+            }
+            match sbi.hart(args[0] as usize).state {
+                HartState::Started => Ok(SBI_HSM_STATE_STARTED),
+                HartState::Stopped => Ok(SBI_HSM_STATE_STOPPED),
+                HartState::StartPending { .. } => Ok(SBI_HSM_STATE_START_PENDING),
+            }
+        }
+        // sbi_hart_suspend
+        3 => match args[0] {
+            // Resume right away as if an interrupt had woken the hart up.
+            SBI_HSM_SUSPEND_RETENTIVE => Ok(0),
+            // Resume right away at the resume address.
+            SBI_HSM_SUSPEND_NON_RETENTIVE => {
+                sbi.hart(hartid).resume = Some((args[1], args[2]));
+                Ok(0)
+            }
+            _ => Err(SBI_ERR_INVALID_PARAM),
+        },
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// The system reset extension.
+fn srst(cpu: &mut Cpu, fid: u64, args: [u64; 6]) -> SbiResult {
+    match fid {
+        // sbi_system_reset
+        0 => {
+            let request = match args[0] {
+                SBI_SRST_SHUTDOWN => PowerRequest::Shutdown(0),
+                SBI_SRST_COLD_REBOOT | SBI_SRST_WARM_REBOOT => PowerRequest::Reboot,
+                _ => return Err(SBI_ERR_INVALID_PARAM),
+            };
+            cpu.bus.power().request(request);
+            Ok(0)
+        }
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// The debug console extension.
+fn dbcn(cpu: &mut Cpu, fid: u64, args: [u64; 6]) -> SbiResult {
+    let addr = args[1] | (args[2] << 32);
+    match fid {
+        // sbi_debug_console_write
+        0 => {
+            for i in 0..args[0] {
+                let byte = cpu
+                    .bus
+                    .load(addr.wrapping_add(i), 8)
+                    .map_err(|_| SBI_ERR_INVALID_PARAM)?;
+                console_write_byte(cpu, byte);
+            }
+            Ok(args[0])
+        }
+        // sbi_debug_console_read
+        1 => {
+            let mut count = 0;
+            while count < args[0] {
+                let byte = match console_read_byte(cpu) {
+                    Some(byte) => byte,
+                    None => break,
+                };
+                cpu.bus
+                    .store(addr.wrapping_add(count), 8, byte as u64)
+                    .map_err(|_| SBI_ERR_INVALID_PARAM)?;
+                count += 1;
+            }
+            Ok(count)
+        }
+        // sbi_debug_console_write_byte
+        2 => {
+            console_write_byte(cpu, args[0]);
+            Ok(0)
+        }
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Return the SBI state. It exists because the SBI is called only when it's enabled.
+fn sbi(cpu: &Cpu) -> Arc<Sbi> {
+    cpu.sbi.clone().expect("the SBI is not enabled")
+}
+
+/// Write a byte to the UART.
+fn console_write_byte(cpu: &mut Cpu, byte: u64) {
+    cpu.bus
+        .store(UART_BASE + UART_THR, 8, byte & 0xff)
+        .expect("failed to write to the UART");
+}
+
+/// Read a byte from the UART if one has been received.
+fn console_read_byte(cpu: &mut Cpu) -> Option<u8> {
+    let lsr = cpu
+        .bus
+        .load(UART_BASE + UART_LSR, 8)
+        .expect("failed to read from the UART");
+    if lsr as u8 & UART_LSR_RX == 0 {
+        return None;
+    }
+    let byte = cpu
+        .bus
+        .load(UART_BASE + UART_RHR, 8)
+        .expect("failed to read from the UART");
+    Some(byte as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chardev::*;
+    use crate::irq::*;
+    use crate::machine::*;
+    use crate::memory::*;
+    use std::collections::VecDeque;
+
+    /// A console that records the bytes written to it and returns the bytes of `input`.
+    struct Console {
+        output: Arc<Mutex<Vec<u8>>>,
+        input: VecDeque<u8>,
+    }
+
+    impl CharBackend for Console {
+        fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            self.output.lock().unwrap().extend_from_slice(data);
+            Ok(())
+        }
+
+        fn read(&mut self) -> Option<u8> {
+            self.input.pop_front()
+        }
+    }
+
+    /// Return a machine with 2 harts on the built-in SBI, whose UART receives `input`, and the
+    /// bytes written to the UART.
+    fn machine(input: &[u8]) -> (Machine, Arc<Mutex<Vec<u8>>>) {
+        let mut machine = Machine::new(Vec::new(), Vec::new(), None, MEMORY_SIZE, 2);
+        machine.enable_sbi();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let console = Console {
+            output: output.clone(),
+            input: input.iter().copied().collect(),
+        };
+        machine
+            .bus
+            .with_device(UART_BASE, |uart: &mut Uart| {
+                *uart = Uart::new(Box::new(console), IrqLine::new(UART_IRQ, Arc::default()))
+            })
+            .unwrap();
+        for hart in &mut machine.harts {
+            hart.mode = Mode::Supervisor;
+        }
+        (machine, output)
+    }
+
+    /// Call the function `fid` of the extension `eid` with `args`, and return a0 and a1.
+    fn ecall(cpu: &mut Cpu, eid: u64, fid: u64, args: &[u64]) -> (i64, u64) {
+        cpu.regs[10..10 + args.len()].copy_from_slice(args);
+        cpu.regs[16] = fid;
+        cpu.regs[17] = eid;
+        handle_ecall(cpu).unwrap();
+        (cpu.regs[10] as i64, cpu.regs[11])
+    }
+
+    /// Let the UART take the next received byte.
+    fn receive(machine: &Machine) {
+        machine.bus.tick(1000);
+    }
+
+    #[test]
+    fn base_extension_reports_the_implementation() {
+        let (mut machine, _) = machine(&[]);
+        let cpu = &mut machine.harts[0];
+        assert_eq!(ecall(cpu, SBI_EXT_BASE, 0, &[]), (0, SBI_SPEC_VERSION));
+        assert_eq!(ecall(cpu, SBI_EXT_BASE, 3, &[SBI_EXT_HSM]), (0, 1));
+        assert_eq!(ecall(cpu, SBI_EXT_BASE, 3, &[0x1234]), (0, 0));
+        assert_eq!(ecall(cpu, SBI_EXT_BASE, 7, &[]).0, SBI_ERR_NOT_SUPPORTED);
+        assert_eq!(ecall(cpu, 0x1234, 0, &[]).0, SBI_ERR_NOT_SUPPORTED);
+    }
+
+    #[test]
+    fn hart_is_started_and_stopped() {
+        let (mut machine, _) = machine(&[]);
+        let sbi = machine.harts[0].sbi.clone().unwrap();
+        let status = |cpu: &mut Cpu, hartid| ecall(cpu, SBI_EXT_HSM, 2, &[hartid]);
+        assert_eq!(status(&mut machine.harts[0], 0), (0, SBI_HSM_STATE_STARTED));
+        assert_eq!(status(&mut machine.harts[0], 1), (0, SBI_HSM_STATE_STOPPED));
+        assert_eq!(status(&mut machine.harts[0], 2).0, SBI_ERR_INVALID_PARAM);
+        assert!(machine.harts[1].is_stopped());
+
+        let start = [1, MEMORY_BASE + 0x1000, 42];
+        assert_eq!(ecall(&mut machine.harts[0], SBI_EXT_HSM, 0, &start).0, 0);
+        assert_eq!(
+            status(&mut machine.harts[0], 1),
+            (0, SBI_HSM_STATE_START_PENDING)
+        );
+        assert_eq!(
+            ecall(&mut machine.harts[0], SBI_EXT_HSM, 0, &start).0,
+            SBI_ERR_ALREADY_AVAILABLE
+        );
+        assert_eq!(
+            ecall(&mut machine.harts[0], SBI_EXT_HSM, 0, &[2, 0, 0]).0,
+            SBI_ERR_INVALID_PARAM
+        );
+
+        let hart = &mut machine.harts[1];
+        hart.mode = Mode::Machine;
+        start_if_pending(hart);
+        assert_eq!(hart.pc, MEMORY_BASE + 0x1000);
+        assert_eq!(hart.mode, Mode::Supervisor);
+        assert_eq!((hart.regs[10], hart.regs[11]), (1, 42));
+        assert!(!sbi.is_stopped(1));
+
+        assert_eq!(ecall(&mut machine.harts[1], SBI_EXT_HSM, 1, &[]).0, 0);
+        assert!(sbi.is_stopped(1));
+        assert_eq!(status(&mut machine.harts[0], 1), (0, SBI_HSM_STATE_STOPPED));
+    }
+
+    #[test]
+    fn non_retentive_suspend_resumes_at_the_address() {
+        let (mut machine, _) = machine(&[]);
+        let cpu = &mut machine.harts[0];
+        cpu.pc = MEMORY_BASE + 4;
+        let retentive = [SBI_HSM_SUSPEND_RETENTIVE];
+        assert_eq!(ecall(cpu, SBI_EXT_HSM, 3, &retentive).0, 0);
+        assert_eq!(cpu.pc, MEMORY_BASE + 4);
+
+        cpu.csrs.store(SSTATUS, SSTATUS_SIE);
+        let non_retentive = [SBI_HSM_SUSPEND_NON_RETENTIVE, MEMORY_BASE + 0x2000, 7];
+        assert_eq!(ecall(cpu, SBI_EXT_HSM, 3, &non_retentive), (0, 7));
+        assert_eq!(cpu.pc, MEMORY_BASE + 0x2000);
+        assert_eq!(cpu.csrs.load(SSTATUS) & SSTATUS_SIE, 0);
+
+        assert_eq!(ecall(cpu, SBI_EXT_HSM, 3, &[1]).0, SBI_ERR_INVALID_PARAM);
+    }
+
+    #[test]
+    fn ipi_raises_ssip_on_the_selected_harts() {
+        let (mut machine, _) = machine(&[]);
+        assert_eq!(
+            ecall(&mut machine.harts[0], SBI_EXT_IPI, 0, &[0b10, 0]).0,
+            0
+        );
+        for hart in &mut machine.harts {
+            forward_interrupts(hart);
+        }
+        assert_eq!(machine.harts[0].csrs.load(MIP) & MIP_SSIP, 0);
+        assert_eq!(machine.harts[1].csrs.load(MIP) & MIP_SSIP, MIP_SSIP);
+
+        // All harts, and a mask beyond the last hart.
+        let all = [0, SBI_HART_MASK_ALL];
+        assert_eq!(ecall(&mut machine.harts[1], SBI_EXT_IPI, 0, &all).0, 0);
+        forward_interrupts(&mut machine.harts[0]);
+        assert_eq!(machine.harts[0].csrs.load(MIP) & MIP_SSIP, MIP_SSIP);
+        assert_eq!(
+            ecall(&mut machine.harts[0], SBI_EXT_IPI, 0, &[0b1, 2]).0,
+            SBI_ERR_INVALID_PARAM
+        );
+    }
+
+    #[test]
+    fn set_timer_drives_stip() {
+        let (mut machine, _) = machine(&[]);
+        assert_eq!(ecall(&mut machine.harts[1], SBI_EXT_TIME, 0, &[100]).0, 0);
+        let mtimecmp = CLINT_BASE + CLINT_MTIMECMP;
+        assert_eq!(machine.bus.load(mtimecmp + 8, 64).unwrap(), 100);
+        assert_eq!(machine.bus.load(mtimecmp, 64).unwrap(), u64::MAX);
+
+        machine.bus.tick(100);
+        for hart in &mut machine.harts {
+            forward_interrupts(hart);
+        }
+        assert_eq!(machine.harts[0].csrs.load(MIP) & MIP_STIP, 0);
+        assert_eq!(machine.harts[1].csrs.load(MIP) & MIP_STIP, MIP_STIP);
+
+        // Programming the timer again clears STIP.
+        ecall(&mut machine.harts[1], SBI_EXT_TIME, 0, &[u64::MAX]);
+        assert_eq!(machine.harts[1].csrs.load(MIP) & MIP_STIP, 0);
+    }
+
+    #[test]
+    fn remote_fences_check_the_hart_mask() {
+        let (mut machine, _) = machine(&[]);
+        let cpu = &mut machine.harts[0];
+        for fid in 0..=2 {
+            assert_eq!(ecall(cpu, SBI_EXT_RFENCE, fid, &[0b11, 0]).0, 0);
+            assert_eq!(
+                ecall(cpu, SBI_EXT_RFENCE, fid, &[0b1, 5]).0,
+                SBI_ERR_INVALID_PARAM
+            );
+        }
+        assert_eq!(
+            ecall(cpu, SBI_EXT_RFENCE, 3, &[0b1, 0]).0,
+            SBI_ERR_NOT_SUPPORTED
+        );
+    }
+
+    #[test]
+    fn system_reset_requests_a_power_change() {
+        let (mut machine, _) = machine(&[]);
+        let cpu = &mut machine.harts[0];
+        let power = cpu.bus.power().clone();
+        for (reset_type, request) in [
+            (SBI_SRST_SHUTDOWN, PowerRequest::Shutdown(0)),
+            (SBI_SRST_COLD_REBOOT, PowerRequest::Reboot),
+            (SBI_SRST_WARM_REBOOT, PowerRequest::Reboot),
+        ] {
+            assert_eq!(ecall(cpu, SBI_EXT_SRST, 0, &[reset_type, 0]).0, 0);
+            assert_eq!(power.take(), Some(request));
+        }
+        assert_eq!(
+            ecall(cpu, SBI_EXT_SRST, 0, &[3, 0]).0,
+            SBI_ERR_INVALID_PARAM
+        );
+        assert_eq!(power.take(), None);
+    }
+
+    #[test]
+    fn debug_console_reads_and_writes_the_uart() {
+        let (mut machine, output) = machine(b"ab");
+        let buffer = MEMORY_BASE + 0x1000;
+        machine.bus.memory().write_bytes(0x1000, b"hello");
+        let cpu = &mut machine.harts[0];
+        assert_eq!(ecall(cpu, SBI_EXT_DBCN, 0, &[5, buffer, 0]), (0, 5));
+        assert_eq!(ecall(cpu, SBI_EXT_DBCN, 2, &[b'!' as u64]).0, 0);
+        assert_eq!(*output.lock().unwrap(), b"hello!");
+        assert_eq!(
+            ecall(cpu, SBI_EXT_DBCN, 0, &[5, MEMORY_BASE + MEMORY_SIZE, 0]).0,
+            SBI_ERR_INVALID_PARAM
+        );
+
+        // Nothing has been received yet.
+        assert_eq!(ecall(cpu, SBI_EXT_DBCN, 1, &[4, buffer, 0]), (0, 0));
+        receive(&machine);
+        let cpu = &mut machine.harts[0];
+        assert_eq!(ecall(cpu, SBI_EXT_DBCN, 1, &[4, buffer, 0]), (0, 1));
+        assert_eq!(cpu.bus.load(buffer, 8).unwrap(), b'a' as u64);
+    }
+
+    #[test]
+    fn legacy_console_puts_and_gets_characters() {
+        let (mut machine, output) = machine(b"z");
+        let cpu = &mut machine.harts[0];
+        assert_eq!(
+            ecall(cpu, SBI_EXT_LEGACY_CONSOLE_PUTCHAR, 0, &[b'x' as u64]).0,
+            0
+        );
+        assert_eq!(*output.lock().unwrap(), b"x");
+        assert_eq!(ecall(cpu, SBI_EXT_LEGACY_CONSOLE_GETCHAR, 0, &[]).0, -1);
+        receive(&machine);
+        let cpu = &mut machine.harts[0];
+        assert_eq!(
+            ecall(cpu, SBI_EXT_LEGACY_CONSOLE_GETCHAR, 0, &[]).0,
+            b'z' as i64
+        );
+    }
+}
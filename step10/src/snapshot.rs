@@ -0,0 +1,345 @@
+//! The snapshot module contains the format of machine snapshots. A snapshot holds the complete
+//! state of a machine: the harts, the memory and the internal state of every device, so that a
+//! run can be resumed later, e.g. right after a guest has booted, instead of booting again.
+//!
+//! A snapshot starts with `SNAPSHOT_MAGIC` and `SNAPSHOT_VERSION`, and it can be restored only to
+//! a machine with the same memory size, harts and devices. Values are little-endian, and the state
+//! of each component is a section prefixed with its length. Large data such as memory pages is
+//! compressed with a run-length encoding.
+
+use std::convert::TryFrom;
+use std::io;
+
+/// The bytes at the start of a snapshot.
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"RVEMUSNP";
+/// The version of the format. A snapshot of another version is rejected.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// The immediate of `slti x0, x0, imm`, a hint for custom use that requests a snapshot. It does
+/// nothing on other machines.
+pub const SNAPSHOT_HINT: u64 = 0x534;
+
+/// The longest run of the run-length encoding.
+const MAX_RUN: usize = 128;
+
+/// Return the error for a snapshot that can't be restored.
+pub fn snapshot_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("snapshot: {}", message))
+}
+
+/// A snapshot being written.
+#[derive(Debug, Default)]
+pub struct SnapshotWriter {
+    data: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    /// Create an empty snapshot.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return the bytes written so far.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.data.push(value);
+        self
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn bool(&mut self, value: bool) -> &mut Self {
+        self.u8(value as u8)
+    }
+
+    /// Write `data` prefixed with its length.
+    pub fn bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.u64(data.len() as u64);
+        self.data.extend_from_slice(data);
+        self
+    }
+
+    /// Write `data` compressed, prefixed with its length.
+    pub fn compressed(&mut self, data: &[u8]) -> &mut Self {
+        self.u64(data.len() as u64);
+        self.bytes(&compress(data))
+    }
+
+    /// Write the section written by `f`, prefixed with its length, so that a reader can check that
+    /// the section has been read to the end.
+    pub fn section(
+        &mut self,
+        f: impl FnOnce(&mut SnapshotWriter) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut section = SnapshotWriter::new();
+        f(&mut section)?;
+        self.bytes(&section.data);
+        Ok(())
+    }
+}
+
+/// A snapshot being read. Reading past the end is an error.
+#[derive(Debug)]
+pub struct SnapshotReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    /// Create a reader of the snapshot `data`.
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    /// Return the next `len` bytes.
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.data.len() - self.pos {
+            return Err(snapshot_error("unexpected end of data"));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Return the next `N` bytes as an array.
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(self.take(N)?);
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        self.array().map(u16::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        self.array().map(u64::from_le_bytes)
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    /// Read data written by `SnapshotWriter::bytes`.
+    pub fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u64()?;
+        self.take(usize::try_from(len).map_err(|_| snapshot_error("invalid length"))?)
+    }
+
+    /// Read data written by `SnapshotWriter::compressed`.
+    pub fn compressed(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u64()?;
+        decompress(self.bytes()?, len)
+    }
+
+    /// Read a section written by `SnapshotWriter::section` with `f`. It's an error if `f` doesn't
+    /// read the section to the end.
+    pub fn section<T>(
+        &mut self,
+        f: impl FnOnce(&mut SnapshotReader<'a>) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut section = SnapshotReader::new(self.bytes()?);
+        let value = f(&mut section)?;
+        section.finish()?;
+        Ok(value)
+    }
+
+    /// Return an error if some data hasn't been read.
+    pub fn finish(&self) -> io::Result<()> {
+        if self.pos == self.data.len() {
+            Ok(())
+        } else {
+            Err(snapshot_error("unexpected data at the end of a section"))
+        }
+    }
+}
+
+/// Compress `data` with a run-length encoding. A control byte `n` below 128 is followed by
+/// `n + 1` literal bytes, and a control byte `n` from 128 is followed by a byte repeated
+/// `n - 126` times.
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut literals = 0..0;
+    let flush = |out: &mut Vec<u8>, literals: &mut std::ops::Range<usize>| {
+        for chunk in data[literals.clone()].chunks(MAX_RUN) {
+            out.push(chunk.len() as u8 - 1);
+            out.extend_from_slice(chunk);
+        }
+        *literals = literals.end..literals.end;
+    };
+    let mut i = 0;
+    while i < data.len() {
+        let run = data[i..]
+            .iter()
+            .take(MAX_RUN + 1)
+            .take_while(|byte| **byte == data[i])
+            .count();
+        if run >= 2 {
+            flush(&mut out, &mut literals);
+            out.push((run + 126) as u8);
+            out.push(data[i]);
+            i += run;
+            literals = i..i;
+        } else {
+            i += 1;
+            literals.end = i;
+        }
+    }
+    flush(&mut out, &mut literals);
+    out
+}
+
+/// Decompress `data` compressed by `compress`, which must give `len` bytes.
+fn decompress(data: &[u8], len: u64) -> io::Result<Vec<u8>> {
+    let invalid = || snapshot_error("invalid compressed data");
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i] as usize;
+        i += 1;
+        if control < 128 {
+            let literals = data.get(i..i + control + 1).ok_or_else(invalid)?;
+            out.extend_from_slice(literals);
+            i += control + 1;
+        } else {
+            let byte = *data.get(i).ok_or_else(invalid)?;
+            out.resize(out.len() + control - 126, byte);
+            i += 1;
+        }
+        if out.len() as u64 > len {
+            return Err(invalid());
+        }
+    }
+    if out.len() as u64 != len {
+        return Err(invalid());
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return `data` compressed, after checking that it decompresses to `data` again.
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let compressed = compress(data);
+        assert_eq!(decompress(&compressed, data.len() as u64).unwrap(), data);
+        compressed
+    }
+
+    /// Return `len` bytes that never repeat the previous byte.
+    fn literals(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn runs_are_encoded_up_to_the_longest_run() {
+        assert_eq!(round_trip(&[]), []);
+        assert_eq!(round_trip(&[7; 2]), [128, 7]);
+        assert_eq!(round_trip(&[7; 129]), [255, 7]);
+        // The last byte doesn't fit in the run and is a literal.
+        assert_eq!(round_trip(&[7; 130]), [255, 7, 0, 7]);
+        assert_eq!(round_trip(&[7; 131]), [255, 7, 128, 7]);
+    }
+
+    #[test]
+    fn literals_are_split_into_chunks() {
+        assert_eq!(round_trip(&[1]), [0, 1]);
+        let compressed = round_trip(&literals(300));
+        assert_eq!(compressed.len(), 300 + 3);
+        assert_eq!(compressed[0], 127);
+        assert_eq!(compressed[129], 127);
+        // The last 44 bytes.
+        assert_eq!(compressed[258], 43);
+    }
+
+    #[test]
+    fn runs_and_literals_are_mixed() {
+        let mut data = literals(200);
+        data.extend([0; 130]);
+        data.extend(literals(3));
+        data.extend([0xff; 2]);
+        round_trip(&data);
+    }
+
+    #[test]
+    fn invalid_compressed_data_is_rejected() {
+        // A run longer than the data.
+        assert!(decompress(&[255, 7], 128).is_err());
+        // Data shorter than the length.
+        assert!(decompress(&[128, 7], 3).is_err());
+        // Missing literals and a missing repeated byte.
+        assert!(decompress(&[3, 1, 2], 4).is_err());
+        assert!(decompress(&[128], 2).is_err());
+    }
+
+    #[test]
+    fn values_are_read_in_order() {
+        let mut writer = SnapshotWriter::new();
+        writer
+            .u8(1)
+            .u16(0x203)
+            .u32(0x4050607)
+            .u64(0x8090a0b0c0d0e0f)
+            .bool(true)
+            .bytes(b"abc")
+            .compressed(&[0; 1000]);
+        let data = writer.into_bytes();
+        let mut reader = SnapshotReader::new(&data);
+        assert_eq!(reader.u8().unwrap(), 1);
+        assert_eq!(reader.u16().unwrap(), 0x203);
+        assert_eq!(reader.u32().unwrap(), 0x4050607);
+        assert_eq!(reader.u64().unwrap(), 0x8090a0b0c0d0e0f);
+        assert!(reader.bool().unwrap());
+        assert_eq!(reader.bytes().unwrap(), b"abc");
+        assert_eq!(reader.compressed().unwrap(), [0; 1000]);
+        reader.finish().unwrap();
+        assert!(reader.u8().is_err());
+    }
+
+    #[test]
+    fn sections_must_be_read_to_the_end() {
+        let mut writer = SnapshotWriter::new();
+        writer
+            .section(|section| {
+                section.u32(1).u32(2);
+                Ok(())
+            })
+            .unwrap();
+        let data = writer.into_bytes();
+        let mut reader = SnapshotReader::new(&data);
+        assert!(reader.section(|section| section.u32()).is_err());
+        let mut reader = SnapshotReader::new(&data);
+        assert!(reader
+            .section(|section| section.u64().and(section.u32()))
+            .is_err());
+        let mut reader = SnapshotReader::new(&data);
+        let values = reader.section(|section| Ok((section.u32()?, section.u32()?)));
+        assert_eq!(values.unwrap(), (1, 2));
+        reader.finish().unwrap();
+    }
+}
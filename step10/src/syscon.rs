@@ -0,0 +1,113 @@
+//! The syscon module contains the SiFive test device, which is used in the QEMU virt machine as a
+//! system controller. Writing a magic value to it shuts down or reboots the machine, and the
+//! device tree tells the guest which values to write, e.g. for the `syscon-poweroff` and
+//! `syscon-reboot` drivers of Linux.
+
+use crate::bus::*;
+use crate::fdt::*;
+use crate::power::*;
+use crate::trap::*;
+
+/// The value that shuts down the machine with the exit status in the upper 16 bits, to report a
+/// failure.
+pub const SYSCON_FAIL: u64 = 0x3333;
+/// The value that shuts down the machine.
+pub const SYSCON_POWEROFF: u64 = 0x5555;
+/// The value that reboots the machine.
+pub const SYSCON_REBOOT: u64 = 0x7777;
+
+/// The SiFive test device.
+pub struct SifiveTest {
+    power: PowerControl,
+}
+
+impl Device for SifiveTest {
+    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        match size {
+            32 => Ok(0),
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
+    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if size != 32 {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        if addr != 0 {
+            return Ok(());
+        }
+        match value & 0xffff {
+            SYSCON_FAIL => self
+                .power
+                .request(PowerRequest::Shutdown((value >> 16) as u16)),
+            SYSCON_POWEROFF => self.power.request(PowerRequest::Shutdown(0)),
+            SYSCON_REBOOT => self.power.request(PowerRequest::Reboot),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn describe(&self, base: u64, size: u64, fdt: &mut Fdt) {
+        fdt.begin_node(&format!("test@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+        fdt.property_reg(base, size);
+        fdt.property_u32("phandle", PHANDLE_SYSCON);
+        fdt.end_node();
+
+        for (name, value) in [("poweroff", SYSCON_POWEROFF), ("reboot", SYSCON_REBOOT)] {
+            fdt.begin_node(name);
+            fdt.property_string("compatible", &format!("syscon-{}", name));
+            fdt.property_u32("regmap", PHANDLE_SYSCON);
+            fdt.property_u32("offset", 0);
+            fdt.property_u32("value", value as u32);
+            fdt.end_node();
+        }
+    }
+}
+
+impl SifiveTest {
+    /// Create a new test device that sends its requests to `power`.
+    pub fn new(power: PowerControl) -> Self {
+        Self { power }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Store `value` to `addr` and return the power request made.
+    fn request(addr: u64, value: u64) -> Option<PowerRequest> {
+        let power = PowerControl::new();
+        SifiveTest::new(power.clone())
+            .store(addr, 32, value)
+            .unwrap();
+        power.take()
+    }
+
+    #[test]
+    fn values_request_a_power_change() {
+        assert_eq!(request(0, SYSCON_POWEROFF), Some(PowerRequest::Shutdown(0)));
+        assert_eq!(request(0, SYSCON_REBOOT), Some(PowerRequest::Reboot));
+        // The failure code in the upper 16 bits is the exit status.
+        assert_eq!(
+            request(0, 3 << 16 | SYSCON_FAIL),
+            Some(PowerRequest::Shutdown(3))
+        );
+        assert_eq!(
+            request(0, 0xffff << 16 | SYSCON_FAIL),
+            Some(PowerRequest::Shutdown(0xffff))
+        );
+    }
+
+    #[test]
+    fn other_values_and_offsets_are_ignored() {
+        assert_eq!(request(0, 0x1234), None);
+        assert_eq!(request(4, SYSCON_POWEROFF), None);
+        let mut test = SifiveTest::new(PowerControl::new());
+        assert!(matches!(
+            test.store(0, 64, SYSCON_POWEROFF),
+            Err(Exception::StoreAMOAccessFault(0))
+        ));
+    }
+}
@@ -2,32 +2,41 @@
 
 #![allow(dead_code)]
 
+use std::collections::VecDeque;
+use std::fmt;
+
 use crate::cpu::*;
 
+/// The most significant bit of mcause and scause, set when a trap is caused by an interrupt.
+pub const INTERRUPT_BIT: u64 = 1 << 63;
+
 /// All kinds of exceptions, an unusual condition occurring at run
 /// time associated with an instruction in the current hardware thread.
+/// Each variant with a value holds what is written to mtval or stval: the faulting virtual
+/// address, or the instruction bits for an illegal instruction.
 #[derive(Debug)]
 pub enum Exception {
-    InstructionAddressMisaligned,
-    InstructionAccessFault,
-    IllegalInstruction,
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
+    IllegalInstruction(u64),
     Breakpoint,
-    LoadAddressMisaligned,
-    LoadAccessFault,
-    StoreAMOAddressMisaligned,
-    StoreAMOAccessFault,
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAMOAddressMisaligned(u64),
+    StoreAMOAccessFault(u64),
     EnvironmentCallFromUMode,
     EnvironmentCallFromSMode,
     EnvironmentCallFromMMode,
-    InstructionPageFault,
-    LoadPageFault,
-    StoreAMOPageFault,
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StoreAMOPageFault(u64),
 }
 
 /// All kinds of interrupts, an external asynchronous event that may
 /// cause a hardware thread to experience an unexpected transfer of
 /// control.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
+#[allow(clippy::enum_variant_names)]
 pub enum Interrupt {
     UserSoftwareInterrupt,
     SupervisorSoftwareInterrupt,
@@ -40,39 +49,66 @@ pub enum Interrupt {
     MachineExternalInterrupt,
 }
 
+/// Return the address of the trap handler for a trap-vector base-address register value (mtvec or
+/// stvec).
+fn trap_vector(tvec: u64, cause: u64, is_interrupt: bool) -> u64 {
+    // 3.1.7 Machine Trap-Vector Base-Address Register (mtvec)
+    // "The value in the BASE field must always be aligned on a 4-byte boundary."
+    let base = tvec & !TVEC_MODE;
+    match tvec & TVEC_MODE {
+        // "When MODE=Vectored, all synchronous exceptions into machine mode cause the pc to be
+        // set to the address in the BASE field, whereas interrupts cause the pc to be set to the
+        // address in the BASE field plus four times the interrupt cause number."
+        TVEC_MODE_VECTORED if is_interrupt => {
+            // Strip the interrupt bit so that only the exception code selects the vector.
+            base.wrapping_add(4 * (cause & !INTERRUPT_BIT))
+        }
+        _ => base,
+    }
+}
+
 /// The transfer of control to a trap handler caused by either an
 /// exception or an interrupt.
 pub trait Trap {
     /// Returns an exception code that identifys the last exception.
     fn exception_code(&self) -> u64;
+    /// Returns the exception-specific value written to mtval or stval.
+    fn trap_value(&self) -> u64 {
+        0
+    }
     /// Trap handler.
     fn take_trap(&self, cpu: &mut Cpu);
     /// Helper method for a trap handler.
     fn take_trap_helper(&self, cpu: &mut Cpu, is_interrupt: bool) {
-        let exception_pc = cpu.pc.wrapping_sub(4);
+        // An exception is raised after the program counter has moved past the instruction that
+        // caused it, whereas an interrupt is taken before the instruction at the program counter
+        // is executed.
+        let exception_pc = if is_interrupt {
+            cpu.pc
+        } else {
+            cpu.pc.wrapping_sub(4)
+        };
         let previous_mode = cpu.mode;
+        let tval = self.trap_value();
 
         let mut cause = self.exception_code();
         // Set an interrupt bit if a trap is an interrupt.
         if is_interrupt {
-            cause = (1 << 63) | cause;
+            cause |= INTERRUPT_BIT;
         }
-        if (previous_mode <= Mode::Supervisor)
-            && ((cpu.csrs.load(MEDELEG).wrapping_shr(cause as u32)) & 1 != 0)
-        {
+        // Interrupts are delegated by mideleg and synchronous exceptions by medeleg. Both are
+        // indexed by the exception code without the interrupt bit.
+        let deleg = if is_interrupt {
+            cpu.csrs.load(MIDELEG)
+        } else {
+            cpu.csrs.load(MEDELEG)
+        };
+        if (previous_mode <= Mode::Supervisor) && ((deleg >> self.exception_code()) & 1 != 0) {
             // Handle the trap in S-mode.
             cpu.mode = Mode::Supervisor;
 
             // Set the program counter to the supervisor trap-handler base address (stvec).
-            if is_interrupt {
-                let vector = match cpu.csrs.load(STVEC) & 1 {
-                    1 => 4 * cause, // vectored mode
-                    _ => 0,         // direct mode
-                };
-                cpu.pc = (cpu.csrs.load(STVEC) & !1) + vector;
-            } else {
-                cpu.pc = cpu.csrs.load(STVEC) & !1;
-            }
+            cpu.pc = trap_vector(cpu.csrs.load(STVEC), cause, is_interrupt);
 
             // 4.1.9 Supervisor Exception Program Counter (sepc)
             // "The low bit of sepc (sepc[0]) is always zero."
@@ -97,7 +133,7 @@ pub trait Trap {
             // written with the faulting virtual address. On an illegal instruction trap,
             // stval may be written with the first XLEN or ILEN bits of the faulting
             // instruction as described below. For other exceptions, stval is set to zero."
-            cpu.csrs.store(STVAL, 0);
+            cpu.csrs.store(STVAL, tval);
 
             // Set a privious interrupt-enable bit for supervisor mode (SPIE, 5) to the value
             // of a global interrupt-enable bit for supervisor mode (SIE, 1).
@@ -123,15 +159,7 @@ pub trait Trap {
             cpu.mode = Mode::Machine;
 
             // Set the program counter to the machine trap-handler base address (mtvec).
-            if is_interrupt {
-                let vector = match cpu.csrs.load(MTVEC) & 1 {
-                    1 => 4 * cause, // vectored mode
-                    _ => 0,         // direct mode
-                };
-                cpu.pc = (cpu.csrs.load(MTVEC) & !1) + vector;
-            } else {
-                cpu.pc = cpu.csrs.load(MTVEC) & !1;
-            }
+            cpu.pc = trap_vector(cpu.csrs.load(MTVEC), cause, is_interrupt);
 
             // 3.1.15 Machine Exception Program Counter (mepc)
             // "The low bit of mepc (mepc[0]) is always zero."
@@ -157,7 +185,7 @@ pub trait Trap {
             // written with the faulting virtual address. On an illegal instruction trap,
             // mtval may be written with the first XLEN or ILEN bits of the faulting
             // instruction as described below. For other traps, mtval is set to zero."
-            cpu.csrs.store(MTVAL, 0);
+            cpu.csrs.store(MTVAL, tval);
 
             // Set a privious interrupt-enable bit for supervisor mode (MPIE, 7) to the value
             // of a global interrupt-enable bit for supervisor mode (MIE, 3).
@@ -171,30 +199,58 @@ pub trait Trap {
             );
             // Set a global interrupt-enable bit for supervisor mode (MIE, 3) to 0.
             cpu.csrs.store(MSTATUS, cpu.csrs.load(MSTATUS) & !(1 << 3));
-            // Set a privious privilege mode for supervisor mode (MPP, 11..13) to 0.
-            cpu.csrs
-                .store(MSTATUS, cpu.csrs.load(MSTATUS) & !(0b11 << 11));
+            // Set a previous privilege mode for machine mode (MPP, 11..13) to the mode the trap
+            // was taken from.
+            cpu.csrs.store(
+                MSTATUS,
+                (cpu.csrs.load(MSTATUS) & !MSTATUS_MPP) | ((previous_mode as u64) << 11),
+            );
         }
+
+        cpu.trap_log.record(TrapRecord {
+            cause,
+            epc: exception_pc & !1,
+            tval,
+            from: previous_mode,
+            to: cpu.mode,
+            handler: cpu.pc,
+        });
     }
 }
 
 impl Trap for Exception {
     fn exception_code(&self) -> u64 {
         match self {
-            Exception::InstructionAddressMisaligned => 0,
-            Exception::InstructionAccessFault => 1,
-            Exception::IllegalInstruction => 2,
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
+            Exception::IllegalInstruction(_) => 2,
             Exception::Breakpoint => 3,
-            Exception::LoadAddressMisaligned => 4,
-            Exception::LoadAccessFault => 5,
-            Exception::StoreAMOAddressMisaligned => 6,
-            Exception::StoreAMOAccessFault => 7,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAMOAddressMisaligned(_) => 6,
+            Exception::StoreAMOAccessFault(_) => 7,
             Exception::EnvironmentCallFromUMode => 8,
             Exception::EnvironmentCallFromSMode => 9,
             Exception::EnvironmentCallFromMMode => 11,
-            Exception::InstructionPageFault => 12,
-            Exception::LoadPageFault => 13,
-            Exception::StoreAMOPageFault => 15,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StoreAMOPageFault(_) => 15,
+        }
+    }
+
+    fn trap_value(&self) -> u64 {
+        match self {
+            Exception::InstructionAddressMisaligned(value)
+            | Exception::InstructionAccessFault(value)
+            | Exception::IllegalInstruction(value)
+            | Exception::LoadAddressMisaligned(value)
+            | Exception::LoadAccessFault(value)
+            | Exception::StoreAMOAddressMisaligned(value)
+            | Exception::StoreAMOAccessFault(value)
+            | Exception::InstructionPageFault(value)
+            | Exception::LoadPageFault(value)
+            | Exception::StoreAMOPageFault(value) => *value,
+            _ => 0,
         }
     }
 
@@ -222,3 +278,385 @@ impl Trap for Interrupt {
         self.take_trap_helper(cpu, true);
     }
 }
+
+/// The number of traps kept in a `TrapLog`.
+const TRAP_LOG_SIZE: usize = 32;
+/// The number of times in a row the same exception may be taken at the same pc with the same tval
+/// before it is considered a trap storm. Ecalls and ebreaks never are.
+const TRAP_STORM_THRESHOLD: usize = 64;
+
+/// A trap recorded in a `TrapLog`.
+#[derive(Debug, Clone, Copy)]
+pub struct TrapRecord {
+    /// The value written to mcause or scause.
+    pub cause: u64,
+    /// The value written to mepc or sepc.
+    pub epc: u64,
+    /// The value written to mtval or stval.
+    pub tval: u64,
+    /// The privilege mode the trap was taken from.
+    pub from: Mode,
+    /// The privilege mode the trap was taken into.
+    pub to: Mode,
+    /// The address of the trap handler.
+    pub handler: u64,
+}
+
+impl TrapRecord {
+    fn is_interrupt(&self) -> bool {
+        self.cause & INTERRUPT_BIT != 0
+    }
+
+    /// Return true if the trap is requested by the instruction itself, i.e. an ecall or an
+    /// ebreak. Such a trap is expected at the same pc over and over again, e.g. a system call in a
+    /// loop.
+    fn is_requested(&self) -> bool {
+        !self.is_interrupt() && matches!(self.cause, 3 | 8 | 9 | 11)
+    }
+
+    /// Return true if the trap is caused by fetching an instruction.
+    fn is_fetch_fault(&self) -> bool {
+        !self.is_interrupt() && matches!(self.cause, 0 | 1 | 12)
+    }
+
+    /// Return the name of the trap cause.
+    fn cause_name(&self) -> &'static str {
+        if self.is_interrupt() {
+            return match self.cause & !INTERRUPT_BIT {
+                0 => "UserSoftwareInterrupt",
+                1 => "SupervisorSoftwareInterrupt",
+                3 => "MachineSoftwareInterrupt",
+                4 => "UserTimerInterrupt",
+                5 => "SupervisorTimerInterrupt",
+                7 => "MachineTimerInterrupt",
+                8 => "UserExternalInterrupt",
+                9 => "SupervisorExternalInterrupt",
+                11 => "MachineExternalInterrupt",
+                _ => "UnknownInterrupt",
+            };
+        }
+        match self.cause {
+            0 => "InstructionAddressMisaligned",
+            1 => "InstructionAccessFault",
+            2 => "IllegalInstruction",
+            3 => "Breakpoint",
+            4 => "LoadAddressMisaligned",
+            5 => "LoadAccessFault",
+            6 => "StoreAMOAddressMisaligned",
+            7 => "StoreAMOAccessFault",
+            8 => "EnvironmentCallFromUMode",
+            9 => "EnvironmentCallFromSMode",
+            11 => "EnvironmentCallFromMMode",
+            12 => "InstructionPageFault",
+            13 => "LoadPageFault",
+            15 => "StoreAMOPageFault",
+            _ => "UnknownException",
+        }
+    }
+}
+
+/// An unrecoverable trap loop detected by a `TrapLog`.
+#[derive(Debug)]
+pub enum DoubleFault {
+    /// The same exception was taken at the same pc with the same tval too many times in a row.
+    TrapStorm(TrapRecord),
+    /// Fetching the first instruction of a trap handler caused an exception.
+    HandlerFetchFault(TrapRecord),
+}
+
+impl fmt::Display for DoubleFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DoubleFault::TrapStorm(record) => write!(
+                f,
+                "trap storm: {} taken {} times in a row at pc {:#x}",
+                record.cause_name(),
+                TRAP_STORM_THRESHOLD,
+                record.epc
+            ),
+            DoubleFault::HandlerFetchFault(record) => write!(
+                f,
+                "double fault: {} while fetching the trap handler at {:#x}",
+                record.cause_name(),
+                record.epc
+            ),
+        }
+    }
+}
+
+/// A ring buffer of the last traps taken by a hart, used to detect trap storms and double faults.
+pub struct TrapLog {
+    records: VecDeque<TrapRecord>,
+    /// The number of times in a row the last trap has been taken with the same cause, pc and tval.
+    repeats: usize,
+    /// Set when a double fault is detected. No more traps are recorded after that.
+    double_fault: Option<DoubleFault>,
+}
+
+impl TrapLog {
+    /// Create a new empty `TrapLog` object.
+    pub fn new() -> Self {
+        Self {
+            records: VecDeque::with_capacity(TRAP_LOG_SIZE),
+            repeats: 0,
+            double_fault: None,
+        }
+    }
+
+    /// Record a trap and check if it forms a trap storm or a double fault.
+    pub fn record(&mut self, record: TrapRecord) {
+        if self.double_fault.is_some() {
+            return;
+        }
+
+        if let Some(last) = self.records.back() {
+            // A fault on another address, e.g. demand paging in a loop, makes progress.
+            if !record.is_interrupt()
+                && !record.is_requested()
+                && (record.cause, record.epc, record.tval) == (last.cause, last.epc, last.tval)
+            {
+                self.repeats += 1;
+            } else {
+                self.repeats = 1;
+            }
+
+            // The handler faulted before it executed a single instruction.
+            if record.is_fetch_fault() && record.epc == last.handler {
+                self.double_fault = Some(DoubleFault::HandlerFetchFault(record));
+            }
+        } else {
+            self.repeats = 1;
+        }
+        if self.repeats >= TRAP_STORM_THRESHOLD {
+            self.double_fault = Some(DoubleFault::TrapStorm(record));
+        }
+
+        if self.records.len() == TRAP_LOG_SIZE {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Return the detected double fault, if any.
+    pub fn double_fault(&self) -> Option<&DoubleFault> {
+        self.double_fault.as_ref()
+    }
+}
+
+impl fmt::Display for TrapLog {
+    /// Print the recorded traps from the oldest to the newest.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "last {} traps (oldest first):", self.records.len())?;
+        writeln!(
+            f,
+            "{:<30} {:>18} {:>18} {:>18}  mode",
+            "cause", "epc", "tval", "handler"
+        )?;
+        for record in &self.records {
+            writeln!(
+                f,
+                "{:<30} {:>#18x} {:>#18x} {:>#18x}  {:?} -> {:?}",
+                record.cause_name(),
+                record.epc,
+                record.tval,
+                record.handler,
+                record.from,
+                record.to
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::*;
+    use crate::irq::*;
+    use crate::machine::*;
+    use crate::memory::*;
+
+    #[test]
+    fn vectored_interrupt_strips_interrupt_bit() {
+        let cause = INTERRUPT_BIT | Interrupt::MachineTimerInterrupt.exception_code();
+        assert_eq!(trap_vector(0x8000_0001, cause, true), 0x8000_001c);
+    }
+
+    #[test]
+    fn vectored_exception_jumps_to_base() {
+        let cause = Exception::IllegalInstruction(0).exception_code();
+        assert_eq!(trap_vector(0x8000_0001, cause, false), 0x8000_0000);
+    }
+
+    #[test]
+    fn direct_interrupt_jumps_to_base() {
+        let cause = INTERRUPT_BIT | Interrupt::SupervisorExternalInterrupt.exception_code();
+        assert_eq!(trap_vector(0x8000_0000, cause, true), 0x8000_0000);
+    }
+
+    #[test]
+    fn reserved_tvec_modes_are_rejected() {
+        let mut csrs = Csr::new(0, HartInterrupts::new());
+        csrs.store(MTVEC, 0x8000_0002);
+        assert_eq!(csrs.load(MTVEC), 0x8000_0000);
+
+        csrs.store(STVEC, 0x8000_0101);
+        csrs.store(STVEC, 0x8000_0203);
+        assert_eq!(csrs.load(STVEC), 0x8000_0201);
+    }
+
+    /// Take a machine software interrupt with `mtvec` written with `tvecs` in order, and return
+    /// the pc of the handler.
+    fn software_interrupt_handler(tvecs: &[u64]) -> u64 {
+        let binary = 0x00000013u32.to_le_bytes().to_vec(); // nop
+        let mut machine = Machine::new(binary, Vec::new(), None, MEMORY_SIZE, 1);
+        let cpu = &mut machine.harts[0];
+        cpu.pc = MEMORY_BASE;
+        for &tvec in tvecs {
+            cpu.csrs.store(MTVEC, tvec);
+        }
+        cpu.csrs.store(MSTATUS, MSTATUS_MIE);
+        cpu.csrs.store(MIE, MIP_MSIP);
+        cpu.csrs.store(MIP, MIP_MSIP);
+        cpu.step();
+        assert_eq!(cpu.csrs.load(MCAUSE), INTERRUPT_BIT | 3);
+        cpu.pc
+    }
+
+    #[test]
+    fn reserved_tvec_mode_keeps_vectoring() {
+        // Mode 1, then the reserved modes 2 and 3 with a new base.
+        let handler = software_interrupt_handler(&[0x8000_1001, 0x8000_2002, 0x8000_3003]);
+        assert_eq!(handler, 0x8000_3000 + 4 * 3);
+    }
+
+    #[test]
+    fn reserved_tvec_mode_keeps_direct() {
+        let handler = software_interrupt_handler(&[0x8000_1000, 0x8000_2003]);
+        assert_eq!(handler, 0x8000_2000);
+    }
+
+    fn trap(cause: u64, epc: u64, handler: u64) -> TrapRecord {
+        TrapRecord {
+            cause,
+            epc,
+            tval: 0,
+            from: Mode::User,
+            to: Mode::Machine,
+            handler,
+        }
+    }
+
+    #[test]
+    fn repeated_trap_is_a_storm() {
+        let mut log = TrapLog::new();
+        for _ in 0..TRAP_STORM_THRESHOLD - 1 {
+            log.record(trap(2, 0x8000_0000, 0x8000_1000));
+        }
+        assert!(log.double_fault().is_none());
+
+        log.record(trap(2, 0x8000_0000, 0x8000_1000));
+        match log.double_fault() {
+            Some(DoubleFault::TrapStorm(record)) => assert_eq!(record.epc, 0x8000_0000),
+            _ => panic!("expected a trap storm"),
+        }
+        assert_eq!(log.records.len(), TRAP_LOG_SIZE);
+    }
+
+    #[test]
+    fn storm_count_resets_on_another_trap() {
+        let mut log = TrapLog::new();
+        for i in 0..TRAP_STORM_THRESHOLD * 2 {
+            // A different pc or cause every few traps breaks the run.
+            let epc = 0x8000_0000 + (i / (TRAP_STORM_THRESHOLD / 2)) as u64 * 4;
+            log.record(trap(2, epc, 0x8000_1000));
+        }
+        log.record(trap(5, 0x8000_0100, 0x8000_1000));
+        assert!(log.double_fault().is_none());
+    }
+
+    #[test]
+    fn faults_on_other_addresses_are_not_a_storm() {
+        let mut log = TrapLog::new();
+        for i in 0..TRAP_STORM_THRESHOLD as u64 * 2 {
+            let mut record = trap(13, 0x8000_0000, 0x8000_1000);
+            record.tval = 0x1000 * i;
+            log.record(record);
+        }
+        assert!(log.double_fault().is_none());
+    }
+
+    #[test]
+    fn repeated_ecalls_are_not_a_storm() {
+        let mut log = TrapLog::new();
+        for cause in [3, 8, 9, 11] {
+            for _ in 0..TRAP_STORM_THRESHOLD * 2 {
+                log.record(trap(cause, 0x8000_0000, 0x8000_1000));
+            }
+        }
+        assert!(log.double_fault().is_none());
+    }
+
+    #[test]
+    fn ecall_loop_keeps_running() {
+        let program = [
+            0x00000297u32, // auipc t0, 0
+            0x01428293,    // addi t0, t0, 20
+            0x30529073,    // csrw mtvec, t0
+            0x00000073,    // loop: ecall
+            0xffdff06f,    // j loop
+            0x34102373,    // csrr t1, mepc
+            0x00430313,    // addi t1, t1, 4
+            0x34131073,    // csrw mepc, t1
+            0x30200073,    // mret
+        ];
+        let binary = program.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        let mut machine = Machine::new(binary, Vec::new(), None, MEMORY_SIZE, 1);
+        machine.harts[0].pc = MEMORY_BASE;
+        // An iteration of the loop takes 6 steps, so the ecall is taken more often than the
+        // threshold of a storm.
+        for _ in 0..TRAP_STORM_THRESHOLD * 10 {
+            assert!(machine.step().is_none());
+        }
+        let log = &machine.harts[0].trap_log;
+        assert_eq!(log.records.len(), TRAP_LOG_SIZE);
+        assert!(log
+            .records
+            .iter()
+            .all(|record| (record.cause, record.epc) == (11, MEMORY_BASE + 12)));
+    }
+
+    #[test]
+    fn repeated_interrupts_are_not_a_storm() {
+        let mut log = TrapLog::new();
+        let cause = INTERRUPT_BIT | Interrupt::MachineTimerInterrupt.exception_code();
+        for _ in 0..TRAP_STORM_THRESHOLD * 2 {
+            log.record(trap(cause, 0x8000_0000, 0x8000_1000));
+        }
+        assert!(log.double_fault().is_none());
+    }
+
+    #[test]
+    fn handler_fetch_fault_is_a_double_fault() {
+        let mut log = TrapLog::new();
+        log.record(trap(8, 0x8000_0000, 0x9000_0000));
+        log.record(trap(1, 0x9000_0000, 0x9000_0000));
+        match log.double_fault() {
+            Some(DoubleFault::HandlerFetchFault(record)) => assert_eq!(record.cause, 1),
+            _ => panic!("expected a handler fetch fault"),
+        }
+
+        // No more traps are recorded after a double fault.
+        log.record(trap(2, 0x8000_0004, 0x9000_0000));
+        assert_eq!(log.records.len(), 2);
+    }
+
+    #[test]
+    fn fetch_fault_outside_handler_is_not_a_double_fault() {
+        let mut log = TrapLog::new();
+        log.record(trap(8, 0x8000_0000, 0x9000_0000));
+        log.record(trap(12, 0x8000_2000, 0x9000_0000));
+        log.record(trap(2, 0x9000_0000, 0x9000_0000));
+        assert!(log.double_fault().is_none());
+    }
+}
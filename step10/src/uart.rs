@@ -1,29 +1,28 @@
 //! The uart module contains the implementation of a universal asynchronous receiver-transmitter
-//! (UART). The device is 16550a UART, which is used in the QEMU virt machine.
+//! (UART). The device is 16550a UART, which is used in the QEMU virt machine. The bytes are sent
+//! and received through a `CharBackend`.
 //! See the spec: http://byterunner.com/16550.html
 
 #![allow(dead_code)]
 
 use std::io;
-use std::io::prelude::*;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Condvar, Mutex,
-};
-use std::thread;
 
 use crate::bus::*;
+use crate::chardev::*;
+use crate::fdt::*;
+use crate::irq::*;
+use crate::snapshot::*;
 use crate::trap::*;
 
 /// The interrupt request of UART.
 pub const UART_IRQ: u64 = 10;
 
 /// Receive holding register (for input bytes).
-pub const UART_RHR: u64 = UART_BASE + 0;
+pub const UART_RHR: u64 = 0;
 /// Transmit holding register (for output bytes).
-pub const UART_THR: u64 = UART_BASE + 0;
+pub const UART_THR: u64 = 0;
 /// Line control register.
-pub const UART_LCR: u64 = UART_BASE + 3;
+pub const UART_LCR: u64 = 3;
 /// Line status register.
 /// LSR BIT 0:
 ///     0 = no data in receive holding register or FIFO.
@@ -31,7 +30,10 @@ pub const UART_LCR: u64 = UART_BASE + 3;
 /// LSR BIT 5:
 ///     0 = transmit holding register is full. 16550 will not accept any data for transmission.
 ///     1 = transmitter hold register (or FIFO) is empty. CPU can load the next character.
-pub const UART_LSR: u64 = UART_BASE + 5;
+pub const UART_LSR: u64 = 5;
+
+/// The number of cycles between checks for bytes received by the backend.
+const POLL_INTERVAL: u64 = 256;
 
 /// The receiver (RX) bit.
 pub const UART_LSR_RX: u8 = 1;
@@ -39,93 +41,119 @@ pub const UART_LSR_RX: u8 = 1;
 pub const UART_LSR_TX: u8 = 1 << 5;
 
 pub struct Uart {
-    /// Pair of an array for UART buffer and a conditional variable.
-    uart: Arc<(Mutex<[u8; UART_SIZE as usize]>, Condvar)>,
-    /// Bit if an interrupt happens.
-    interrupting: Arc<AtomicBool>,
+    /// The registers.
+    uart: [u8; UART_SIZE as usize],
+    /// The host side of the UART.
+    backend: Box<dyn CharBackend>,
+    /// The number of cycles since the last check for received bytes.
+    cycles: u64,
+    /// The interrupt line, raised while a received byte waits in the receive holding register.
+    irq: IrqLine,
 }
 
 impl Device for Uart {
     fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
         match size {
             8 => Ok(self.load8(addr)),
-            _ => Err(Exception::LoadAccessFault),
+            _ => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
     fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         match size {
-            8 => Ok(self.store8(addr, value)),
-            _ => Err(Exception::StoreAMOAccessFault),
+            8 => {
+                self.store8(addr, value);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
-}
 
-impl Uart {
-    /// Create a new `Uart` object.
-    pub fn new() -> Self {
-        let uart = Arc::new((Mutex::new([0; UART_SIZE as usize]), Condvar::new()));
-        let interrupting = Arc::new(AtomicBool::new(false));
-        {
-            let (uart, _cvar) = &*uart;
-            let mut uart = uart.lock().expect("failed to get an UART object");
-            // Transmitter hold register is empty.
-            uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_TX;
+    fn reset(&mut self) {
+        self.uart = [0; UART_SIZE as usize];
+        self.uart[UART_LSR as usize] |= UART_LSR_TX;
+        self.irq.lower();
+    }
+
+    fn tick(&mut self, cycles: u64, _dma: &mut DmaContext) {
+        self.cycles += cycles;
+        // Take the next byte from the backend once the previous one has been read.
+        if self.cycles < POLL_INTERVAL || self.uart[UART_LSR as usize] & UART_LSR_RX != 0 {
+            return;
         }
+        self.cycles = 0;
+        if let Some(byte) = self.backend.read() {
+            self.uart[UART_RHR as usize] = byte;
+            // Data has been receive.
+            self.uart[UART_LSR as usize] |= UART_LSR_RX;
+            self.irq.raise();
+        }
+    }
 
-        let mut byte = [0; 1];
-        let cloned_uart = uart.clone();
-        let cloned_interrupting = interrupting.clone();
-        let _uart_thread_for_read = thread::spawn(move || loop {
-            match io::stdin().read(&mut byte) {
-                Ok(_) => {
-                    let (uart, cvar) = &*cloned_uart;
-                    let mut uart = uart.lock().expect("failed to get an UART object");
-                    // Wait for the thread to start up.
-                    while (uart[(UART_LSR - UART_BASE) as usize] & UART_LSR_RX) == 1 {
-                        uart = cvar.wait(uart).expect("the mutex is poisoned");
-                    }
-                    uart[0] = byte[0];
-                    cloned_interrupting.store(true, Ordering::Release);
-                    // Data has been receive.
-                    uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_RX;
-                }
-                Err(e) => {
-                    println!("{}", e);
-                }
-            }
-        });
-        Self { uart, interrupting }
+    fn describe(&self, base: u64, size: u64, fdt: &mut Fdt) {
+        fdt.begin_node(&format!("serial@{:x}", base));
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_reg(base, size);
+        fdt.property_u32("clock-frequency", 0x384000);
+        fdt.property_u32("interrupt-parent", PHANDLE_PLIC);
+        fdt.property_u32("interrupts", self.irq.irq() as u32);
+        fdt.end_node();
+    }
+
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        snapshot.bytes(&self.uart).u64(self.cycles);
+        Ok(())
     }
 
-    /// Return true if an interrupt is pending. Clear the interrupting flag by swapping a value.
-    pub fn is_interrupting(&self) -> bool {
-        self.interrupting.swap(false, Ordering::Acquire)
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        let uart = snapshot.bytes()?;
+        if uart.len() != self.uart.len() {
+            return Err(snapshot_error("invalid UART registers"));
+        }
+        self.uart.copy_from_slice(uart);
+        self.cycles = snapshot.u64()?;
+        // The interrupt is raised while a received byte hasn't been read.
+        self.irq
+            .set(self.uart[UART_LSR as usize] & UART_LSR_RX != 0);
+        Ok(())
+    }
+}
+
+impl Uart {
+    /// Create a new `Uart` object connected to `backend` that raises `irq` when a byte is
+    /// received.
+    pub fn new(backend: Box<dyn CharBackend>, irq: IrqLine) -> Self {
+        let mut uart = [0; UART_SIZE as usize];
+        // Transmitter hold register is empty.
+        uart[UART_LSR as usize] |= UART_LSR_TX;
+        Self {
+            uart,
+            backend,
+            cycles: 0,
+            irq,
+        }
     }
 
     fn load8(&mut self, addr: u64) -> u64 {
-        let (uart, cvar) = &*self.uart;
-        let mut uart = uart.lock().expect("failed to get an UART object");
         match addr {
             UART_RHR => {
-                cvar.notify_one();
-                uart[(UART_LSR - UART_BASE) as usize] &= !UART_LSR_RX;
-                uart[(UART_RHR - UART_BASE) as usize] as u64
+                self.uart[UART_LSR as usize] &= !UART_LSR_RX;
+                self.irq.lower();
+                self.uart[UART_RHR as usize] as u64
             }
-            _ => uart[(addr - UART_BASE) as usize] as u64,
+            _ => self.uart[addr as usize] as u64,
         }
     }
 
     fn store8(&mut self, addr: u64, value: u64) {
-        let (uart, _cvar) = &*self.uart;
-        let mut uart = uart.lock().expect("failed to get an UART object");
         match addr {
             UART_THR => {
-                print!("{}", value as u8 as char);
-                io::stdout().flush().expect("failed to flush stdout");
+                if let Err(e) = self.backend.write(&[value as u8]) {
+                    println!("{}", e);
+                }
             }
             _ => {
-                uart[(addr - UART_BASE) as usize] = value as u8;
+                self.uart[addr as usize] = value as u8;
             }
         }
     }
@@ -1,118 +1,693 @@
-//! The virtio module contains a virtualization standard for network and disk device drivers.
-//! This is the "legacy" virtio interface.
+//! The virtio module contains the virtio-mmio transport, a virtualization standard for network and
+//! disk device drivers. `Virtio` implements the registers of the virtio-mmio interface, and a
+//! `VirtioDevice` behind it implements the device type, e.g. a block device. The "legacy" version 1
+//! interface is used by default, same as QEMU, because drivers such as xv6's accept only it. The
+//! "modern" version 2 interface is enabled with `Virtio::set_legacy`.
 //!
 //! The virtio spec:
 //! https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
 
+use std::io;
+use std::sync::atomic::{fence, Ordering};
+
 use crate::bus::*;
-use crate::cpu::*;
+use crate::fdt::*;
+use crate::irq::*;
+use crate::snapshot::*;
 use crate::trap::*;
 
-/// The interrupt request of virtio.
+/// The interrupt request of the first virtio device. The device in the slot `n` uses
+/// `VIRTIO_IRQ + n`, same as QEMU virt machine.
 pub const VIRTIO_IRQ: u64 = 1;
 
-const VRING_DESC_SIZE: u64 = 16;
-/// The number of virtio descriptors. It must be a power of two.
-const DESC_NUM: u64 = 8;
+/// The bit of the interrupt status which notifies that the used ring has been updated.
+const VIRTIO_INT_USED_RING: u32 = 1;
+/// The bit of the interrupt status which notifies that the configuration has changed.
+const VIRTIO_INT_CONFIG_CHANGE: u32 = 2;
+
+/// The largest number of entries of a virtqueue.
+const QUEUE_NUM_MAX: u32 = 256;
+/// The default alignment of the used ring of a legacy virtqueue.
+const LEGACY_QUEUE_ALIGN: u32 = 4096;
 
 /// Always return 0x74726976.
-pub const VIRTIO_MAGIC: u64 = VIRTIO_BASE + 0x000;
-/// The version. 1 is legacy.
-pub const VIRTIO_VERSION: u64 = VIRTIO_BASE + 0x004;
+pub const VIRTIO_MAGIC: u64 = 0x000;
+/// The version. 1 is legacy and 2 is modern.
+pub const VIRTIO_VERSION: u64 = 0x004;
 /// device type; 1 is net, 2 is disk.
-pub const VIRTIO_DEVICE_ID: u64 = VIRTIO_BASE + 0x008;
+pub const VIRTIO_DEVICE_ID: u64 = 0x008;
 /// Always return 0x554d4551
-pub const VIRTIO_VENDOR_ID: u64 = VIRTIO_BASE + 0x00c;
-/// Device features.
-pub const VIRTIO_DEVICE_FEATURES: u64 = VIRTIO_BASE + 0x010;
-/// Driver features.
-pub const VIRTIO_DRIVER_FEATURES: u64 = VIRTIO_BASE + 0x020;
-/// Page size for PFN, write-only.
-pub const VIRTIO_GUEST_PAGE_SIZE: u64 = VIRTIO_BASE + 0x028;
+pub const VIRTIO_VENDOR_ID: u64 = 0x00c;
+/// Device features, the 32 bits selected by `VIRTIO_DEVICE_FEATURES_SEL`.
+pub const VIRTIO_DEVICE_FEATURES: u64 = 0x010;
+/// Select the word of the device features, write-only.
+pub const VIRTIO_DEVICE_FEATURES_SEL: u64 = 0x014;
+/// Driver features, the 32 bits selected by `VIRTIO_DRIVER_FEATURES_SEL`.
+pub const VIRTIO_DRIVER_FEATURES: u64 = 0x020;
+/// Select the word of the driver features, write-only.
+pub const VIRTIO_DRIVER_FEATURES_SEL: u64 = 0x024;
+/// Page size for PFN, write-only. Legacy only.
+pub const VIRTIO_GUEST_PAGE_SIZE: u64 = 0x028;
 /// Select queue, write-only.
-pub const VIRTIO_QUEUE_SEL: u64 = VIRTIO_BASE + 0x030;
-/// Max size of current queue, read-only. In QEMU, `VIRTIO_COUNT = 8`.
-pub const VIRTIO_QUEUE_NUM_MAX: u64 = VIRTIO_BASE + 0x034;
+pub const VIRTIO_QUEUE_SEL: u64 = 0x030;
+/// Max size of current queue, read-only.
+pub const VIRTIO_QUEUE_NUM_MAX: u64 = 0x034;
 /// Size of current queue, write-only.
-pub const VIRTIO_QUEUE_NUM: u64 = VIRTIO_BASE + 0x038;
-/// Physical page number for queue, read and write.
-pub const VIRTIO_QUEUE_PFN: u64 = VIRTIO_BASE + 0x040;
+pub const VIRTIO_QUEUE_NUM: u64 = 0x038;
+/// Alignment of the used ring of the current queue, write-only. Legacy only.
+pub const VIRTIO_QUEUE_ALIGN: u64 = 0x03c;
+/// Physical page number for queue, read and write. Legacy only.
+pub const VIRTIO_QUEUE_PFN: u64 = 0x040;
+/// Whether the current queue is ready to be used, read and write.
+pub const VIRTIO_QUEUE_READY: u64 = 0x044;
 /// Notify the queue number, write-only.
-pub const VIRTIO_QUEUE_NOTIFY: u64 = VIRTIO_BASE + 0x050;
+pub const VIRTIO_QUEUE_NOTIFY: u64 = 0x050;
+/// Interrupt status, read-only. Bit 0 means the used ring has been updated and bit 1 means the
+/// configuration has changed.
+pub const VIRTIO_INTERRUPT_STATUS: u64 = 0x060;
+/// Interrupt acknowledge, write-only. Clears the bits of the interrupt status written as 1.
+pub const VIRTIO_INTERRUPT_ACK: u64 = 0x064;
 /// Device status, read and write. Reading from this register returns the current device status flags.
 /// Writing non-zero values to this register sets the status flags, indicating the OS/driver
 /// progress. Writing zero (0x0) to this register triggers a device reset.
-pub const VIRTIO_STATUS: u64 = VIRTIO_BASE + 0x070;
+pub const VIRTIO_STATUS: u64 = 0x070;
+/// The address of the descriptor table of the current queue, low and high 32 bits.
+pub const VIRTIO_QUEUE_DESC_LOW: u64 = 0x080;
+pub const VIRTIO_QUEUE_DESC_HIGH: u64 = 0x084;
+/// The address of the driver area (available ring) of the current queue, low and high 32 bits.
+pub const VIRTIO_QUEUE_DRIVER_LOW: u64 = 0x090;
+pub const VIRTIO_QUEUE_DRIVER_HIGH: u64 = 0x094;
+/// The address of the device area (used ring) of the current queue, low and high 32 bits.
+pub const VIRTIO_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+pub const VIRTIO_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+/// Changes every time the configuration changes, read-only.
+pub const VIRTIO_CONFIG_GENERATION: u64 = 0x0fc;
+/// The start of the device-specific configuration space.
+pub const VIRTIO_CONFIG: u64 = 0x100;
+
+// Device status bits.
+pub const VIRTIO_STATUS_ACKNOWLEDGE: u32 = 1;
+pub const VIRTIO_STATUS_DRIVER: u32 = 2;
+pub const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+pub const VIRTIO_STATUS_FEATURES_OK: u32 = 8;
+pub const VIRTIO_STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+pub const VIRTIO_STATUS_FAILED: u32 = 128;
+
+/// The feature bit which means the device complies with the virtio 1.0 spec or later. It's
+/// offered only by a modern device, and a modern driver must accept it.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+/// The feature bit which means the driver can use a table of descriptors in the guest memory as a
+/// single descriptor.
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+
+// Descriptor flags.
+/// The descriptor continues via the `next` field.
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// The buffer is write-only for the device, otherwise read-only.
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+/// The buffer contains a table of descriptors.
+pub const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+/// The size of a descriptor.
+// struct virtq_desc {
+//   uint64 addr;
+//   uint32 len;
+//   uint16 flags;
+//   uint16 next;
+// };
+const VIRTQ_DESC_SIZE: u64 = 16;
+
+/// A virtqueue: the descriptor table, the driver area (available ring) and the device area (used
+/// ring) in the guest memory, set up by the driver.
+#[derive(Debug, Default, Clone)]
+pub struct Virtqueue {
+    /// The number of entries. It's a power of two.
+    pub num: u32,
+    /// Whether the driver has finished setting up the queue.
+    pub ready: bool,
+    /// The guest physical address of the descriptor table.
+    pub desc: u64,
+    /// The guest physical address of the driver area.
+    pub driver: u64,
+    /// The guest physical address of the device area.
+    pub device: u64,
+    /// The index of the next entry of the available ring to process.
+    pub last_avail: u16,
+    /// The index of the next entry of the used ring to fill.
+    pub used_idx: u16,
+}
+
+impl Virtqueue {
+    /// Take the next descriptor chain made available by the driver, or None if the driver hasn't
+    /// made any new chain available. A malformed chain is an access fault at the descriptor.
+    pub fn pop(&mut self, dma: &mut DmaContext) -> Result<Option<DescChain>, Exception> {
+        // struct virtq_avail {
+        //   uint16 flags;
+        //   uint16 idx;
+        //   uint16 ring[num];
+        //   uint16 used_event;
+        // };
+        let avail_idx = dma.read(self.driver.wrapping_add(2), 16)? as u16;
+        if avail_idx == self.last_avail || self.num == 0 {
+            return Ok(None);
+        }
+        // Read the ring entries after the driver has published them with the index.
+        fence(Ordering::Acquire);
+        let slot = self.last_avail as u64 % self.num as u64;
+        let head = dma.read(self.driver.wrapping_add(4 + 2 * slot), 16)? as u16;
+        self.last_avail = self.last_avail.wrapping_add(1);
+        self.walk(head, dma).map(Some)
+    }
+
+    /// Return the chain that starts from the descriptor at `head`, following the `next` fields and
+    /// indirect tables.
+    fn walk(&self, head: u16, dma: &mut DmaContext) -> Result<DescChain, Exception> {
+        let mut chain = DescChain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+        let (mut table, mut size) = (self.desc, self.num as u64);
+        let mut index = head as u64;
+        let mut indirect = false;
+        // The number of descriptors in the current table visited so far, to detect a loop.
+        let mut visited = 0;
+        loop {
+            let desc = table.wrapping_add(VIRTQ_DESC_SIZE * index);
+            if index >= size || visited >= size {
+                return Err(Exception::LoadAccessFault(desc));
+            }
+            visited += 1;
+
+            let addr = dma.read(desc, 64)?;
+            let len = dma.read(desc.wrapping_add(8), 32)? as u32;
+            let flags = dma.read(desc.wrapping_add(12), 16)? as u16;
+            let next = dma.read(desc.wrapping_add(14), 16)?;
+
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                // An indirect table can't be nested, chained or empty.
+                if indirect
+                    || flags & VIRTQ_DESC_F_NEXT != 0
+                    || len == 0
+                    || (len as u64) % VIRTQ_DESC_SIZE != 0
+                {
+                    return Err(Exception::LoadAccessFault(desc));
+                }
+                table = addr;
+                size = len as u64 / VIRTQ_DESC_SIZE;
+                index = 0;
+                indirect = true;
+                visited = 0;
+                continue;
+            }
+
+            let buffer = Buffer { addr, len };
+            if flags & VIRTQ_DESC_F_WRITE != 0 {
+                chain.writable.push(buffer);
+            } else if chain.writable.is_empty() {
+                chain.readable.push(buffer);
+            } else {
+                // The read-only buffers must come before the write-only ones.
+                return Err(Exception::LoadAccessFault(desc));
+            }
+
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(chain);
+            }
+            index = next;
+        }
+    }
+
+    /// Return the chain at `head` to the driver, with `len` bytes written to its write-only
+    /// buffers.
+    pub fn push(&mut self, dma: &mut DmaContext, head: u16, len: u32) -> Result<(), Exception> {
+        // struct virtq_used {
+        //   uint16 flags;
+        //   uint16 idx;
+        //   struct virtq_used_elem {
+        //     uint32 id;
+        //     uint32 len;
+        //   } ring[num];
+        //   uint16 avail_event;
+        // };
+        let slot = self.used_idx as u64 % self.num.max(1) as u64;
+        let elem = self.device.wrapping_add(4 + 8 * slot);
+        dma.write(elem, 32, head as u64)?;
+        dma.write(elem.wrapping_add(4), 32, len as u64)?;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        // Publish the used element before the index.
+        fence(Ordering::Release);
+        dma.write(self.device.wrapping_add(2), 16, self.used_idx as u64)
+    }
+}
+
+/// A buffer in the guest memory described by a descriptor.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    /// The guest physical address.
+    pub addr: u64,
+    /// The length in bytes.
+    pub len: u32,
+}
+
+/// A descriptor chain, a request made available by the driver. The read-only buffers come before
+/// the write-only ones, and each group is accessed as a single sequence of bytes.
+#[derive(Debug, Clone)]
+pub struct DescChain {
+    /// The index of the first descriptor, which identifies the chain in the used ring.
+    pub head: u16,
+    /// The buffers the device reads from.
+    pub readable: Vec<Buffer>,
+    /// The buffers the device writes to.
+    pub writable: Vec<Buffer>,
+}
+
+impl DescChain {
+    /// Return the total length of the read-only buffers.
+    pub fn readable_len(&self) -> u64 {
+        self.readable.iter().map(|buffer| buffer.len as u64).sum()
+    }
+
+    /// Return the total length of the write-only buffers.
+    pub fn writable_len(&self) -> u64 {
+        self.writable.iter().map(|buffer| buffer.len as u64).sum()
+    }
+
+    /// Copy the read-only buffers starting at `offset` to `data`. Return the number of bytes
+    /// copied, which is less than the length of `data` at the end of the buffers.
+    pub fn read(
+        &self,
+        dma: &mut DmaContext,
+        offset: u64,
+        data: &mut [u8],
+    ) -> Result<usize, Exception> {
+        let mut copied = 0;
+        for (addr, len) in segments(&self.readable, offset, data.len()) {
+            dma.read_bytes(addr, &mut data[copied..copied + len])?;
+            copied += len;
+        }
+        Ok(copied)
+    }
+
+    /// Copy `data` to the write-only buffers starting at `offset`. Return the number of bytes
+    /// copied, which is less than the length of `data` at the end of the buffers.
+    pub fn write(
+        &self,
+        dma: &mut DmaContext,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, Exception> {
+        let mut copied = 0;
+        for (addr, len) in segments(&self.writable, offset, data.len()) {
+            dma.write_bytes(addr, &data[copied..copied + len])?;
+            copied += len;
+        }
+        Ok(copied)
+    }
+}
+
+/// Load `size` bits at `offset` in `config`, the little-endian bytes of a configuration space.
+/// The bytes outside of `config` read as zeros.
+pub fn read_config_bytes(config: &[u8], offset: u64, size: u64) -> u64 {
+    let mut value = 0;
+    for i in 0..size / 8 {
+        if let Some(byte) = config.get((offset + i) as usize) {
+            value |= (*byte as u64) << (i * 8);
+        }
+    }
+    value
+}
+
+/// Return the address and the length of each part of `buffers` covering `len` bytes from
+/// `offset`, as if the buffers were contiguous.
+fn segments(buffers: &[Buffer], mut offset: u64, mut len: usize) -> Vec<(u64, usize)> {
+    let mut segments = Vec::new();
+    for buffer in buffers {
+        if len == 0 {
+            break;
+        }
+        let buffer_len = buffer.len as u64;
+        if offset >= buffer_len {
+            offset -= buffer_len;
+            continue;
+        }
+        let part = (buffer_len - offset).min(len as u64) as usize;
+        segments.push((buffer.addr.wrapping_add(offset), part));
+        offset = 0;
+        len -= part;
+    }
+    segments
+}
+
+/// A device type behind the virtio-mmio transport.
+pub trait VirtioDevice: Send {
+    /// Return the device ID, e.g. 2 for a block device.
+    fn device_id(&self) -> u32;
+
+    /// Return the device-specific feature bits. The transport adds `VIRTIO_F_VERSION_1`.
+    fn features(&self) -> u64 {
+        0
+    }
+
+    /// Return the number of virtqueues.
+    fn queues(&self) -> usize;
+
+    /// Load `size` bits at `offset` in the device-specific configuration space.
+    fn read_config(&self, _offset: u64, _size: u64) -> u64 {
+        0
+    }
+
+    /// Store `size` bits of `value` at `offset` in the device-specific configuration space.
+    fn write_config(&mut self, _offset: u64, _size: u64, _value: u64) {}
 
-/// Paravirtualized drivers for IO virtualization.
+    /// Put the device back to its state before the driver initialized it.
+    fn reset(&mut self) {}
+
+    /// Start the device with `features`, the features accepted by the driver, after the driver
+    /// has set up the device.
+    fn driver_ok(&mut self, _features: u64) {}
+
+    /// Do the work that isn't requested by the driver, e.g. deliver data received from the host
+    /// to `queues`. Called every time the device is advanced by `cycles` while the driver is
+    /// ready. Return true if a used ring has been updated.
+    fn poll(
+        &mut self,
+        _cycles: u64,
+        _queues: &mut [Virtqueue],
+        _dma: &mut DmaContext,
+    ) -> Result<bool, Exception> {
+        Ok(false)
+    }
+
+    /// Process the requests made available in `queue`, the virtqueue at `index`, by the driver.
+    /// Return true if the used ring has been updated.
+    fn process(
+        &mut self,
+        index: usize,
+        queue: &mut Virtqueue,
+        dma: &mut DmaContext,
+    ) -> Result<bool, Exception>;
+
+    /// Save the device-specific state to `snapshot`. The state of the transport and the queues
+    /// is saved by the transport.
+    fn save(&mut self, _snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Restore the device-specific state saved by `save`.
+    fn restore(&mut self, _snapshot: &mut SnapshotReader) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The virtio-mmio transport of a virtio device.
 pub struct Virtio {
-    id: u64,
-    driver_features: u32,
+    device: Box<dyn VirtioDevice>,
+    /// Whether the legacy (version 1) interface is used instead of the modern (version 2) one.
+    legacy: bool,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
     page_size: u32,
     queue_sel: u32,
-    queue_num: u32,
-    queue_pfn: u32,
-    queue_notify: u32,
+    queues: Vec<Virtqueue>,
+    /// The alignment and the physical page number of each legacy queue.
+    legacy_queues: Vec<(u32, u32)>,
+    /// The queues that have been notified and not processed yet, a bit for each queue.
+    notified: u64,
+    interrupt_status: u32,
     status: u32,
-    disk: Vec<u8>,
+    config_generation: u32,
+    /// The interrupt line, raised while the interrupt status is not zero.
+    irq: IrqLine,
 }
 
 impl Device for Virtio {
     fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        if addr >= VIRTIO_CONFIG {
+            return Ok(self.device.read_config(addr - VIRTIO_CONFIG, size));
+        }
         match size {
             32 => Ok(self.load32(addr)),
-            _ => Err(Exception::LoadAccessFault),
+            _ => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
     fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if addr >= VIRTIO_CONFIG {
+            self.device.write_config(addr - VIRTIO_CONFIG, size, value);
+            return Ok(());
+        }
         match size {
-            32 => Ok(self.store32(addr, value)),
-            _ => Err(Exception::StoreAMOAccessFault),
+            32 => {
+                self.store32(addr, value);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.page_size = 0;
+        self.queue_sel = 0;
+        self.queues = vec![Virtqueue::default(); self.device.queues()];
+        self.legacy_queues = vec![(LEGACY_QUEUE_ALIGN, 0); self.device.queues()];
+        self.notified = 0;
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.irq.lower();
+        self.device.reset();
+    }
+
+    fn tick(&mut self, cycles: u64, dma: &mut DmaContext) {
+        if self.status & (VIRTIO_STATUS_DRIVER_OK | VIRTIO_STATUS_DEVICE_NEEDS_RESET)
+            != VIRTIO_STATUS_DRIVER_OK
+        {
+            return;
+        }
+        let notified = std::mem::take(&mut self.notified);
+        let (mut used, mut broken) = (false, false);
+        let mut update = |result| match result {
+            Ok(processed) => used |= processed,
+            Err(_) => broken = true,
+        };
+        for (index, queue) in self.queues.iter_mut().enumerate() {
+            if notified & (1 << index) != 0 && queue.ready {
+                update(self.device.process(index, queue, dma));
+            }
+        }
+        update(self.device.poll(cycles, &mut self.queues, dma));
+
+        if broken {
+            // A queue is broken, e.g. a descriptor is outside of the guest memory. The driver has
+            // to reset the device, and the requests done so far are still used.
+            self.status |= VIRTIO_STATUS_DEVICE_NEEDS_RESET;
+            self.interrupt_status |= VIRTIO_INT_CONFIG_CHANGE;
+            used = true;
+        }
+        if used {
+            self.interrupt_status |= VIRTIO_INT_USED_RING;
+            self.irq.raise();
         }
     }
+
+    fn describe(&self, base: u64, size: u64, fdt: &mut Fdt) {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_reg(base, size);
+        fdt.property_u32("interrupt-parent", PHANDLE_PLIC);
+        fdt.property_u32("interrupts", self.irq.irq() as u32);
+        fdt.end_node();
+    }
+
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        snapshot
+            .u32(self.device.device_id())
+            .bool(self.legacy)
+            .u32(self.device_features_sel)
+            .u64(self.driver_features)
+            .u32(self.driver_features_sel)
+            .u32(self.page_size)
+            .u32(self.queue_sel)
+            .u64(self.queues.len() as u64);
+        for (queue, (align, pfn)) in self.queues.iter().zip(&self.legacy_queues) {
+            snapshot
+                .u32(queue.num)
+                .bool(queue.ready)
+                .u64(queue.desc)
+                .u64(queue.driver)
+                .u64(queue.device)
+                .u16(queue.last_avail)
+                .u16(queue.used_idx)
+                .u32(*align)
+                .u32(*pfn);
+        }
+        snapshot
+            .u64(self.notified)
+            .u32(self.interrupt_status)
+            .u32(self.status)
+            .u32(self.config_generation);
+        snapshot.section(|snapshot| self.device.save(snapshot))
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        if snapshot.u32()? != self.device.device_id() {
+            return Err(snapshot_error("the virtio device type doesn't match"));
+        }
+        self.legacy = snapshot.bool()?;
+        self.device_features_sel = snapshot.u32()?;
+        self.driver_features = snapshot.u64()?;
+        self.driver_features_sel = snapshot.u32()?;
+        self.page_size = snapshot.u32()?;
+        self.queue_sel = snapshot.u32()?;
+        if snapshot.u64()? != self.queues.len() as u64 {
+            return Err(snapshot_error("the number of virtqueues doesn't match"));
+        }
+        for (queue, legacy_queue) in self.queues.iter_mut().zip(&mut self.legacy_queues) {
+            *queue = Virtqueue {
+                num: snapshot.u32()?,
+                ready: snapshot.bool()?,
+                desc: snapshot.u64()?,
+                driver: snapshot.u64()?,
+                device: snapshot.u64()?,
+                last_avail: snapshot.u16()?,
+                used_idx: snapshot.u16()?,
+            };
+            *legacy_queue = (snapshot.u32()?, snapshot.u32()?);
+        }
+        self.notified = snapshot.u64()?;
+        self.interrupt_status = snapshot.u32()?;
+        self.status = snapshot.u32()?;
+        self.config_generation = snapshot.u32()?;
+        self.irq.set(self.interrupt_status != 0);
+        snapshot.section(|snapshot| self.device.restore(snapshot))
+    }
 }
 
 impl Virtio {
-    /// Create a new virtio object.
-    pub fn new(disk_image: Vec<u8>) -> Self {
-        let mut disk = Vec::new();
-        disk.extend(disk_image.iter().cloned());
-
+    /// Create a new virtio transport for `device` that raises `irq` when a request has been
+    /// processed.
+    pub fn new(device: Box<dyn VirtioDevice>, irq: IrqLine) -> Self {
+        let queues = device.queues();
+        assert!(queues <= 64, "too many virtqueues");
         Self {
-            id: 0,
+            device,
+            legacy: true,
+            device_features_sel: 0,
             driver_features: 0,
+            driver_features_sel: 0,
             page_size: 0,
             queue_sel: 0,
-            queue_num: 0,
-            queue_pfn: 0,
-            queue_notify: 9999, // TODO: what is the correct initial value?
+            queues: vec![Virtqueue::default(); queues],
+            legacy_queues: vec![(LEGACY_QUEUE_ALIGN, 0); queues],
+            notified: 0,
+            interrupt_status: 0,
             status: 0,
-            disk,
+            config_generation: 0,
+            irq,
         }
     }
 
-    /// Return true if an interrupt is pending.
-    pub fn is_interrupting(&mut self) -> bool {
-        if self.queue_notify != 9999 {
-            self.queue_notify = 9999;
-            return true;
+    /// Use the legacy (version 1) interface, the default, or the modern (version 2) one for
+    /// drivers that support it.
+    pub fn set_legacy(&mut self, legacy: bool) {
+        self.legacy = legacy;
+        self.reset();
+    }
+
+    /// Notify the driver that the device-specific configuration has changed.
+    pub fn config_changed(&mut self) {
+        self.config_generation = self.config_generation.wrapping_add(1);
+        self.interrupt_status |= VIRTIO_INT_CONFIG_CHANGE;
+        self.irq.raise();
+    }
+
+    /// Return the features offered to the driver.
+    fn device_features(&self) -> u64 {
+        let features = self.device.features() | VIRTIO_F_INDIRECT_DESC;
+        if self.legacy {
+            features
+        } else {
+            features | VIRTIO_F_VERSION_1
         }
-        false
+    }
+
+    /// Return the selected queue, or None if the selected queue doesn't exist.
+    fn queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// Update the addresses of the selected legacy queue from its page number. The areas are laid
+    /// out contiguously, and the used ring is aligned.
+    fn update_legacy_queue(&mut self) {
+        let index = self.queue_sel as usize;
+        let page_size = self.page_size as u64;
+        let (align, pfn) = match self.legacy_queues.get(index) {
+            Some(&(align, pfn)) => (align.max(1) as u64, pfn as u64),
+            None => return,
+        };
+        let queue = &mut self.queues[index];
+        let num = queue.num as u64;
+        queue.desc = pfn * page_size;
+        queue.driver = queue.desc + 16 * num;
+        // The available ring has flags, idx, ring[num] and used_event, all 16-bit.
+        queue.device = (queue.driver + 6 + 2 * num).div_ceil(align) * align;
+        queue.ready = pfn != 0;
+    }
+
+    /// Check the features accepted by the driver when it sets FEATURES_OK. The driver can't accept
+    /// features the device doesn't offer, and a modern driver must accept `VIRTIO_F_VERSION_1`.
+    fn features_ok(&self) -> bool {
+        let offered = self.device_features();
+        self.driver_features & !offered == 0
+            && (self.legacy || self.driver_features & VIRTIO_F_VERSION_1 != 0)
+    }
+
+    /// Store the low or high 32 bits of an address of the selected queue.
+    fn store_queue_addr(&mut self, addr: u64, val: u32) {
+        let queue = match self.queue() {
+            Some(queue) => queue,
+            None => return,
+        };
+        let field = match addr & !0x4 {
+            VIRTIO_QUEUE_DESC_LOW => &mut queue.desc,
+            VIRTIO_QUEUE_DRIVER_LOW => &mut queue.driver,
+            VIRTIO_QUEUE_DEVICE_LOW => &mut queue.device,
+            _ => return,
+        };
+        *field = if addr & 0x4 == 0 {
+            (*field & !0xffff_ffff) | val as u64
+        } else {
+            (*field & 0xffff_ffff) | ((val as u64) << 32)
+        };
     }
 
     /// Load 4 bytes from virtio only if the addr is valid. Otherwise, return 0.
     pub fn load32(&self, addr: u64) -> u64 {
+        let queue = self.queues.get(self.queue_sel as usize);
         match addr {
             VIRTIO_MAGIC => 0x74726976,
-            VIRTIO_VERSION => 0x1,
-            VIRTIO_DEVICE_ID => 0x2,
+            VIRTIO_VERSION if self.legacy => 1,
+            VIRTIO_VERSION => 2,
+            VIRTIO_DEVICE_ID => self.device.device_id() as u64,
             VIRTIO_VENDOR_ID => 0x554d4551,
-            VIRTIO_DEVICE_FEATURES => 0, // TODO: what should it return?
-            VIRTIO_DRIVER_FEATURES => self.driver_features as u64,
-            VIRTIO_QUEUE_NUM_MAX => 8,
-            VIRTIO_QUEUE_PFN => self.queue_pfn as u64,
+            VIRTIO_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() & 0xffff_ffff,
+                1 => self.device_features() >> 32,
+                _ => 0,
+            },
+            VIRTIO_QUEUE_NUM_MAX => queue.map_or(0, |_| QUEUE_NUM_MAX as u64),
+            VIRTIO_QUEUE_PFN if self.legacy => self
+                .legacy_queues
+                .get(self.queue_sel as usize)
+                .map_or(0, |&(_, pfn)| pfn as u64),
+            VIRTIO_QUEUE_READY if !self.legacy => queue.map_or(0, |queue| queue.ready as u64),
+            VIRTIO_INTERRUPT_STATUS => self.interrupt_status as u64,
             VIRTIO_STATUS => self.status as u64,
+            VIRTIO_CONFIG_GENERATION if !self.legacy => self.config_generation as u64,
             _ => 0,
         }
     }
@@ -120,145 +695,476 @@ impl Virtio {
     /// Store 4 bytes to virtio only if the addr is valid. Otherwise, does nothing.
     pub fn store32(&mut self, addr: u64, value: u64) {
         let val = value as u32;
+        let legacy = self.legacy;
         match addr {
-            VIRTIO_DEVICE_FEATURES => self.driver_features = val,
-            VIRTIO_GUEST_PAGE_SIZE => self.page_size = val,
+            VIRTIO_DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            VIRTIO_DRIVER_FEATURES => match self.driver_features_sel {
+                0 => self.driver_features = (self.driver_features & !0xffff_ffff) | val as u64,
+                1 => {
+                    self.driver_features =
+                        (self.driver_features & 0xffff_ffff) | ((val as u64) << 32)
+                }
+                _ => {}
+            },
+            VIRTIO_DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            VIRTIO_GUEST_PAGE_SIZE if legacy => self.page_size = val,
             VIRTIO_QUEUE_SEL => self.queue_sel = val,
-            VIRTIO_QUEUE_NUM => self.queue_num = val,
-            VIRTIO_QUEUE_PFN => self.queue_pfn = val,
-            VIRTIO_QUEUE_NOTIFY => self.queue_notify = val,
-            VIRTIO_STATUS => self.status = val,
+            // The queue size must be a power of two not larger than the maximum.
+            VIRTIO_QUEUE_NUM if val.is_power_of_two() && val <= QUEUE_NUM_MAX => {
+                if let Some(queue) = self.queue() {
+                    queue.num = val;
+                }
+                if legacy {
+                    self.update_legacy_queue();
+                }
+            }
+            VIRTIO_QUEUE_ALIGN if legacy => {
+                if let Some(queue) = self.legacy_queues.get_mut(self.queue_sel as usize) {
+                    queue.0 = val;
+                }
+            }
+            VIRTIO_QUEUE_PFN if legacy => {
+                if let Some(queue) = self.legacy_queues.get_mut(self.queue_sel as usize) {
+                    queue.1 = val;
+                    self.update_legacy_queue();
+                }
+            }
+            VIRTIO_QUEUE_READY if !legacy => {
+                if let Some(queue) = self.queue() {
+                    queue.ready = val & 1 != 0;
+                }
+            }
+            VIRTIO_QUEUE_DESC_LOW..=VIRTIO_QUEUE_DEVICE_HIGH if !legacy => {
+                self.store_queue_addr(addr, val)
+            }
+            VIRTIO_QUEUE_NOTIFY if (val as usize) < self.queues.len() => self.notified |= 1 << val,
+            VIRTIO_INTERRUPT_ACK => {
+                self.interrupt_status &= !val;
+                if self.interrupt_status == 0 {
+                    self.irq.lower();
+                }
+            }
+            // Writing zero triggers a device reset.
+            VIRTIO_STATUS if val == 0 => self.reset(),
+            VIRTIO_STATUS => {
+                let mut status = val;
+                // FEATURES_OK isn't set if the driver accepts features which can't be used. The
+                // driver reads the status back to find out that the device is unusable.
+                if status & !self.status & VIRTIO_STATUS_FEATURES_OK != 0 && !self.features_ok() {
+                    status &= !VIRTIO_STATUS_FEATURES_OK;
+                }
+                if status & !self.status & VIRTIO_STATUS_DRIVER_OK != 0 {
+                    self.device.driver_ok(self.driver_features);
+                }
+                self.status = status;
+            }
             _ => {}
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::*;
+    use std::sync::{Arc, Mutex};
+
+    const DESC: u64 = MEMORY_BASE;
+    const DRIVER: u64 = MEMORY_BASE + 0x1000;
+    const DEVICE: u64 = MEMORY_BASE + 0x2000;
+    const INDIRECT: u64 = MEMORY_BASE + 0x3000;
+
+    fn queue() -> Virtqueue {
+        Virtqueue {
+            num: 8,
+            ready: true,
+            desc: DESC,
+            driver: DRIVER,
+            device: DEVICE,
+            ..Virtqueue::default()
+        }
+    }
 
-    fn get_new_id(&mut self) -> u64 {
-        self.id = self.id.wrapping_add(1);
-        self.id
+    fn set_desc(dma: &mut DmaContext, table: u64, index: u64, desc: (u64, u32, u16, u16)) {
+        let (addr, len, flags, next) = desc;
+        let desc = table + VIRTQ_DESC_SIZE * index;
+        dma.write(desc, 64, addr).unwrap();
+        dma.write(desc + 8, 32, len as u64).unwrap();
+        dma.write(desc + 12, 16, flags as u64).unwrap();
+        dma.write(desc + 14, 16, next as u64).unwrap();
     }
 
-    fn desc_addr(&self) -> u64 {
-        self.queue_pfn as u64 * self.page_size as u64
+    /// Make the chains at `heads` available, as the driver does.
+    fn make_available(dma: &mut DmaContext, heads: &[u16]) {
+        for (i, head) in heads.iter().enumerate() {
+            dma.write(DRIVER + 4 + 2 * i as u64, 16, *head as u64)
+                .unwrap();
+        }
+        dma.write(DRIVER + 2, 16, heads.len() as u64).unwrap();
     }
 
-    fn read_disk(&self, addr: u64) -> u64 {
-        self.disk[addr as usize] as u64
+    /// Return the address of the descriptor that `pop` rejects.
+    fn rejected(result: Result<Option<DescChain>, Exception>) -> u64 {
+        match result {
+            Err(Exception::LoadAccessFault(addr)) => addr,
+            result => panic!("the chain isn't rejected: {:?}", result),
+        }
     }
 
-    fn write_disk(&mut self, addr: u64, value: u64) {
-        self.disk[addr as usize] = value as u8
+    #[test]
+    fn chain_is_split_into_readable_and_writable() {
+        let memory = Memory::new(Vec::new(), 0x10000);
+        let mut dma = DmaContext::new(&memory);
+        let mut queue = queue();
+        set_desc(&mut dma, DESC, 3, (0x8000_4000, 16, VIRTQ_DESC_F_NEXT, 5));
+        set_desc(&mut dma, DESC, 5, (0x8000_5000, 512, VIRTQ_DESC_F_WRITE, 0));
+        make_available(&mut dma, &[3]);
+
+        let chain = queue.pop(&mut dma).unwrap().expect("no chain");
+        assert_eq!(chain.head, 3);
+        assert_eq!(chain.readable.len(), 1);
+        assert_eq!(chain.readable[0].addr, 0x8000_4000);
+        assert_eq!(chain.readable_len(), 16);
+        assert_eq!(chain.writable.len(), 1);
+        assert_eq!(chain.writable_len(), 512);
+        assert!(queue.pop(&mut dma).unwrap().is_none());
+
+        queue.push(&mut dma, chain.head, 512).unwrap();
+        assert_eq!(dma.read(DEVICE + 2, 16).unwrap(), 1);
+        assert_eq!(dma.read(DEVICE + 4, 32).unwrap(), 3);
+        assert_eq!(dma.read(DEVICE + 8, 32).unwrap(), 512);
     }
 
-    /// Access the disk via virtio. This is an associated function which takes a `cpu` object to
-    /// read and write with a memory directly (DMA).
-    pub fn disk_access(cpu: &mut Cpu) {
-        // See more information in
-        // https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/virtio_disk.c
+    #[test]
+    fn indirect_table_is_followed() {
+        let memory = Memory::new(Vec::new(), 0x10000);
+        let mut dma = DmaContext::new(&memory);
+        let mut queue = queue();
+        set_desc(&mut dma, DESC, 0, (INDIRECT, 48, VIRTQ_DESC_F_INDIRECT, 0));
+        set_desc(
+            &mut dma,
+            INDIRECT,
+            0,
+            (0x8000_4000, 16, VIRTQ_DESC_F_NEXT, 2),
+        );
+        let flags = VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT;
+        set_desc(&mut dma, INDIRECT, 2, (0x8000_5000, 512, flags, 1));
+        set_desc(
+            &mut dma,
+            INDIRECT,
+            1,
+            (0x8000_6000, 1, VIRTQ_DESC_F_WRITE, 0),
+        );
+        make_available(&mut dma, &[0]);
 
-        // the spec says that legacy block operations use three
-        // descriptors: one for type/reserved/sector, one for
-        // the data, one for a 1-byte status result.
+        let chain = queue.pop(&mut dma).unwrap().expect("no chain");
+        assert_eq!(chain.head, 0);
+        assert_eq!(chain.readable_len(), 16);
+        assert_eq!(chain.writable.len(), 2);
+        assert_eq!(chain.writable[1].addr, 0x8000_6000);
+        assert_eq!(chain.writable_len(), 513);
+    }
 
-        // desc = pages -- num * VRingDesc
-        // avail = pages + 0x40 -- 2 * uint16, then num * uint16
-        // used = pages + 4096 -- 2 * uint16, then num * vRingUsedElem
-        let desc_addr = cpu.bus.virtio.desc_addr();
-        let avail_addr = cpu.bus.virtio.desc_addr() + 0x40;
-        let used_addr = cpu.bus.virtio.desc_addr() + 4096;
+    #[test]
+    fn loop_is_rejected() {
+        let memory = Memory::new(Vec::new(), 0x10000);
+        let mut dma = DmaContext::new(&memory);
+        let mut queue = queue();
+        set_desc(&mut dma, DESC, 0, (0x8000_4000, 16, VIRTQ_DESC_F_NEXT, 1));
+        set_desc(&mut dma, DESC, 1, (0x8000_5000, 16, VIRTQ_DESC_F_NEXT, 0));
+        make_available(&mut dma, &[0]);
+        rejected(queue.pop(&mut dma));
 
-        // avail[0] is flags
-        // avail[1] tells the device how far to look in avail[2...].
-        let offset = cpu
-            .bus
-            .load(avail_addr.wrapping_add(1), 16)
-            .expect("failed to read offset");
-        // avail[2...] are desc[] indices the device should process.
-        // we only tell device the first index in our chain of descriptors.
-        let index = cpu
-            .bus
-            .load(
-                avail_addr.wrapping_add(offset % DESC_NUM).wrapping_add(2),
-                16,
-            )
-            .expect("failed to read index");
+        // A loop inside an indirect table is rejected too.
+        let mut queue = self::queue();
+        set_desc(&mut dma, DESC, 0, (INDIRECT, 32, VIRTQ_DESC_F_INDIRECT, 0));
+        set_desc(
+            &mut dma,
+            INDIRECT,
+            0,
+            (0x8000_4000, 16, VIRTQ_DESC_F_NEXT, 1),
+        );
+        set_desc(
+            &mut dma,
+            INDIRECT,
+            1,
+            (0x8000_5000, 16, VIRTQ_DESC_F_NEXT, 1),
+        );
+        rejected(queue.pop(&mut dma));
+    }
 
-        // Read `VRingDesc`, virtio descriptors.
-        let desc_addr0 = desc_addr + VRING_DESC_SIZE * index;
-        let addr0 = cpu
-            .bus
-            .load(desc_addr0, 64)
-            .expect("failed to read an address field in a descriptor");
-        // Add 14 because of `VRingDesc` structure.
-        // struct VRingDesc {
-        //   uint64 addr;
-        //   uint32 len;
-        //   uint16 flags;
-        //   uint16 next
-        // };
-        // The `next` field can be accessed by offset 14 (8 + 4 + 2) bytes.
-        let next0 = cpu
-            .bus
-            .load(desc_addr0.wrapping_add(14), 16)
-            .expect("failed to read a next field in a descripor");
-
-        // Read `VRingDesc` again, virtio descriptors.
-        let desc_addr1 = desc_addr + VRING_DESC_SIZE * next0;
-        let addr1 = cpu
-            .bus
-            .load(desc_addr1, 64)
-            .expect("failed to read an address field in a descriptor");
-        let len1 = cpu
-            .bus
-            .load(desc_addr1.wrapping_add(8), 32)
-            .expect("failed to read a length field in a descriptor");
-        let flags1 = cpu
-            .bus
-            .load(desc_addr1.wrapping_add(12), 16)
-            .expect("failed to read a flags field in a descriptor");
-
-        // Read `virtio_blk_outhdr`. Add 8 because of its structure.
-        // struct virtio_blk_outhdr {
-        //   uint32 type;
-        //   uint32 reserved;
-        //   uint64 sector;
-        // } buf0;
-        let blk_sector = cpu
-            .bus
-            .load(addr0.wrapping_add(8), 64)
-            .expect("failed to read a sector field in a virtio_blk_outhdr");
-
-        // Write to a device if the second bit `flag1` is set.
-        match (flags1 & 2) == 0 {
-            true => {
-                // Read memory data and write it to a disk directly (DMA).
-                for i in 0..len1 as u64 {
-                    let data = cpu
-                        .bus
-                        .load(addr1 + i, 8)
-                        .expect("failed to read from memory");
-                    cpu.bus.virtio.write_disk(blk_sector * 512 + i, data);
-                }
-            }
-            false => {
-                // Read disk data and write it to memory directly (DMA).
-                for i in 0..len1 as u64 {
-                    let data = cpu.bus.virtio.read_disk(blk_sector * 512 + i);
-                    cpu.bus
-                        .store(addr1 + i, 8, data)
-                        .expect("failed to write to memory");
-                }
+    #[test]
+    fn out_of_range_index_is_rejected() {
+        let memory = Memory::new(Vec::new(), 0x10000);
+        let mut dma = DmaContext::new(&memory);
+        let mut queue = queue();
+        set_desc(&mut dma, DESC, 0, (0x8000_4000, 16, VIRTQ_DESC_F_NEXT, 8));
+        make_available(&mut dma, &[0, 9]);
+        assert_eq!(rejected(queue.pop(&mut dma)), DESC + VIRTQ_DESC_SIZE * 8);
+        // The head itself is out of range.
+        assert_eq!(rejected(queue.pop(&mut dma)), DESC + VIRTQ_DESC_SIZE * 9);
+    }
+
+    #[test]
+    fn nested_indirect_table_is_rejected() {
+        let memory = Memory::new(Vec::new(), 0x10000);
+        let mut dma = DmaContext::new(&memory);
+        let mut queue = queue();
+        set_desc(&mut dma, DESC, 0, (INDIRECT, 32, VIRTQ_DESC_F_INDIRECT, 0));
+        set_desc(
+            &mut dma,
+            INDIRECT,
+            0,
+            (0x8000_4000, 16, VIRTQ_DESC_F_NEXT, 1),
+        );
+        set_desc(
+            &mut dma,
+            INDIRECT,
+            1,
+            (INDIRECT, 32, VIRTQ_DESC_F_INDIRECT, 0),
+        );
+        make_available(&mut dma, &[0]);
+        assert_eq!(rejected(queue.pop(&mut dma)), INDIRECT + VIRTQ_DESC_SIZE);
+    }
+
+    #[test]
+    fn readable_after_writable_is_rejected() {
+        let memory = Memory::new(Vec::new(), 0x10000);
+        let mut dma = DmaContext::new(&memory);
+        let mut queue = queue();
+        let flags = VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT;
+        set_desc(&mut dma, DESC, 0, (0x8000_4000, 16, flags, 1));
+        set_desc(&mut dma, DESC, 1, (0x8000_5000, 16, 0, 0));
+        make_available(&mut dma, &[0]);
+        assert_eq!(rejected(queue.pop(&mut dma)), DESC + VIRTQ_DESC_SIZE);
+    }
+
+    /// A device-specific feature offered by `Recorder`, in the high word.
+    const FEATURE: u64 = 1 << 33;
+
+    /// A device with 3 queues that records the queues it processes.
+    struct Recorder(Arc<Mutex<Vec<usize>>>);
+
+    impl VirtioDevice for Recorder {
+        fn device_id(&self) -> u32 {
+            0x42
+        }
+
+        fn features(&self) -> u64 {
+            FEATURE
+        }
+
+        fn queues(&self) -> usize {
+            3
+        }
+
+        fn process(
+            &mut self,
+            index: usize,
+            _queue: &mut Virtqueue,
+            _dma: &mut DmaContext,
+        ) -> Result<bool, Exception> {
+            self.0.lock().unwrap().push(index);
+            Ok(true)
+        }
+    }
+
+    /// Return a transport with the legacy or the modern interface, and the queues processed by its
+    /// device.
+    fn virtio(legacy: bool) -> (Virtio, Arc<Mutex<Vec<usize>>>) {
+        let processed = Arc::new(Mutex::new(Vec::new()));
+        let irq = IrqLine::new(VIRTIO_IRQ, Arc::default());
+        let mut virtio = Virtio::new(Box::new(Recorder(processed.clone())), irq);
+        virtio.set_legacy(legacy);
+        (virtio, processed)
+    }
+
+    /// Return the 64 bits of the device features.
+    fn device_features(virtio: &mut Virtio) -> u64 {
+        virtio.store32(VIRTIO_DEVICE_FEATURES_SEL, 0);
+        let low = virtio.load32(VIRTIO_DEVICE_FEATURES);
+        virtio.store32(VIRTIO_DEVICE_FEATURES_SEL, 1);
+        low | virtio.load32(VIRTIO_DEVICE_FEATURES) << 32
+    }
+
+    /// Reset the device, accept `features` and return whether the device keeps FEATURES_OK.
+    fn accept(virtio: &mut Virtio, features: u64) -> bool {
+        virtio.store32(VIRTIO_STATUS, 0);
+        let status = (VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER) as u64;
+        virtio.store32(VIRTIO_STATUS, status);
+        virtio.store32(VIRTIO_DRIVER_FEATURES_SEL, 0);
+        virtio.store32(VIRTIO_DRIVER_FEATURES, features & 0xffff_ffff);
+        virtio.store32(VIRTIO_DRIVER_FEATURES_SEL, 1);
+        virtio.store32(VIRTIO_DRIVER_FEATURES, features >> 32);
+        virtio.store32(VIRTIO_STATUS, status | VIRTIO_STATUS_FEATURES_OK as u64);
+        assert_eq!(virtio.driver_features, features);
+        let ok = virtio.load32(VIRTIO_STATUS) & VIRTIO_STATUS_FEATURES_OK as u64 != 0;
+        assert_eq!(ok, virtio.features_ok());
+        ok
+    }
+
+    fn tick(virtio: &mut Virtio) {
+        let memory = Memory::new(Vec::new(), 0x1000);
+        virtio.tick(1, &mut DmaContext::new(&memory));
+    }
+
+    #[test]
+    fn legacy_interface_is_the_default() {
+        let irq = IrqLine::new(VIRTIO_IRQ, Arc::default());
+        let virtio = Virtio::new(Box::new(Recorder(Arc::default())), irq);
+        assert_eq!(virtio.load32(VIRTIO_MAGIC), 0x74726976);
+        assert_eq!(virtio.load32(VIRTIO_VERSION), 1);
+        assert_eq!(virtio.load32(VIRTIO_DEVICE_ID), 0x42);
+        assert_eq!(virtio.load32(VIRTIO_VENDOR_ID), 0x554d4551);
+    }
+
+    #[test]
+    fn modern_driver_must_accept_version_1() {
+        let (mut virtio, _) = virtio(false);
+        assert_eq!(virtio.load32(VIRTIO_VERSION), 2);
+        let offered = FEATURE | VIRTIO_F_INDIRECT_DESC | VIRTIO_F_VERSION_1;
+        assert_eq!(device_features(&mut virtio), offered);
+        virtio.store32(VIRTIO_DEVICE_FEATURES_SEL, 2);
+        assert_eq!(virtio.load32(VIRTIO_DEVICE_FEATURES), 0);
+
+        assert!(!accept(&mut virtio, FEATURE));
+        assert!(accept(&mut virtio, VIRTIO_F_VERSION_1));
+        assert!(accept(&mut virtio, offered));
+        // A feature that isn't offered is refused.
+        assert!(!accept(&mut virtio, VIRTIO_F_VERSION_1 | 1 << 5));
+    }
+
+    #[test]
+    fn legacy_driver_doesnt_get_version_1() {
+        let (mut virtio, _) = virtio(true);
+        assert_eq!(virtio.load32(VIRTIO_VERSION), 1);
+        assert_eq!(
+            device_features(&mut virtio),
+            FEATURE | VIRTIO_F_INDIRECT_DESC
+        );
+        assert!(accept(&mut virtio, 0));
+        assert!(accept(&mut virtio, FEATURE));
+        assert!(!accept(&mut virtio, VIRTIO_F_VERSION_1));
+    }
+
+    #[test]
+    fn modern_queue_is_set_up_by_halves() {
+        let (mut virtio, _) = virtio(false);
+        virtio.store32(VIRTIO_QUEUE_SEL, 1);
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_NUM_MAX), QUEUE_NUM_MAX as u64);
+        virtio.store32(VIRTIO_QUEUE_NUM, 16);
+        // The size must be a power of two.
+        virtio.store32(VIRTIO_QUEUE_NUM, 24);
+        for (low, addr) in [
+            (VIRTIO_QUEUE_DESC_LOW, 0x1_2345_6000),
+            (VIRTIO_QUEUE_DRIVER_LOW, 0x2_0000_1000),
+            (VIRTIO_QUEUE_DEVICE_LOW, 0x3_8000_2000),
+        ] {
+            virtio.store32(low, addr & 0xffff_ffff);
+            virtio.store32(low + 4, addr >> 32);
+        }
+        // The low half can be changed alone.
+        virtio.store32(VIRTIO_QUEUE_DESC_LOW, 0x7000);
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_READY), 0);
+        virtio.store32(VIRTIO_QUEUE_READY, 1);
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_READY), 1);
+
+        let queue = &virtio.queues[1];
+        assert_eq!(queue.num, 16);
+        assert!(queue.ready);
+        assert_eq!(queue.desc, 0x1_0000_7000);
+        assert_eq!(queue.driver, 0x2_0000_1000);
+        assert_eq!(queue.device, 0x3_8000_2000);
+        assert!(!virtio.queues[0].ready && !virtio.queues[2].ready);
+
+        // A queue that doesn't exist has no registers.
+        virtio.store32(VIRTIO_QUEUE_SEL, 3);
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_NUM_MAX), 0);
+        virtio.store32(VIRTIO_QUEUE_READY, 1);
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_READY), 0);
+        // The legacy registers are ignored.
+        virtio.store32(VIRTIO_QUEUE_SEL, 0);
+        virtio.store32(VIRTIO_GUEST_PAGE_SIZE, 4096);
+        virtio.store32(VIRTIO_QUEUE_PFN, 0x80010);
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_PFN), 0);
+        assert!(!virtio.queues[0].ready);
+    }
+
+    #[test]
+    fn legacy_queue_is_laid_out_from_the_page_number() {
+        let (mut virtio, _) = virtio(true);
+        virtio.store32(VIRTIO_GUEST_PAGE_SIZE, 4096);
+        virtio.store32(VIRTIO_QUEUE_SEL, 2);
+        virtio.store32(VIRTIO_QUEUE_NUM, 8);
+        virtio.store32(VIRTIO_QUEUE_ALIGN, 4096);
+        virtio.store32(VIRTIO_QUEUE_PFN, 0x80010);
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_PFN), 0x80010);
+
+        let queue = &virtio.queues[2];
+        assert!(queue.ready);
+        assert_eq!(queue.desc, 0x8001_0000);
+        assert_eq!(queue.driver, 0x8001_0000 + 16 * 8);
+        assert_eq!(queue.device, 0x8001_1000);
+
+        // The modern registers are ignored.
+        assert_eq!(virtio.load32(VIRTIO_QUEUE_READY), 0);
+        virtio.store32(VIRTIO_QUEUE_DESC_LOW, 0x1000);
+        assert_eq!(virtio.queues[2].desc, 0x8001_0000);
+        // A zero page number releases the queue.
+        virtio.store32(VIRTIO_QUEUE_PFN, 0);
+        assert!(!virtio.queues[2].ready);
+    }
+
+    #[test]
+    fn interrupts_are_acknowledged_by_bit() {
+        for legacy in [true, false] {
+            let (mut virtio, _) = virtio(legacy);
+            virtio.store32(VIRTIO_QUEUE_SEL, 0);
+            if legacy {
+                virtio.store32(VIRTIO_GUEST_PAGE_SIZE, 4096);
+                virtio.store32(VIRTIO_QUEUE_PFN, 0x80010);
+            } else {
+                virtio.store32(VIRTIO_QUEUE_READY, 1);
             }
-        };
+            virtio.store32(VIRTIO_STATUS, VIRTIO_STATUS_DRIVER_OK as u64);
+            virtio.store32(VIRTIO_QUEUE_NOTIFY, 0);
+            tick(&mut virtio);
+            virtio.config_changed();
+            assert_eq!(virtio.load32(VIRTIO_INTERRUPT_STATUS), 3);
+            assert!(virtio.irq.is_raised());
 
-        // Write id to `UsedArea`. Add 2 because of its structure.
-        // struct UsedArea {
-        //   uint16 flags;
-        //   uint16 id;
-        //   struct VRingUsedElem elems[NUM];
-        // };
-        let new_id = cpu.bus.virtio.get_new_id();
-        cpu.bus
-            .store(used_addr.wrapping_add(2), 16, new_id % 8)
-            .expect("failed to write to memory");
+            // The configuration generation exists only in the modern interface.
+            let generation = if legacy { 0 } else { 1 };
+            assert_eq!(virtio.load32(VIRTIO_CONFIG_GENERATION), generation);
+
+            virtio.store32(VIRTIO_INTERRUPT_ACK, VIRTIO_INT_USED_RING as u64);
+            assert_eq!(virtio.load32(VIRTIO_INTERRUPT_STATUS), 2);
+            assert!(virtio.irq.is_raised());
+            virtio.store32(VIRTIO_INTERRUPT_ACK, VIRTIO_INT_CONFIG_CHANGE as u64);
+            assert_eq!(virtio.load32(VIRTIO_INTERRUPT_STATUS), 0);
+            assert!(!virtio.irq.is_raised());
+        }
+    }
+
+    #[test]
+    fn notified_queues_are_processed() {
+        let (mut virtio, processed) = virtio(false);
+        for queue in 0..3 {
+            virtio.store32(VIRTIO_QUEUE_SEL, queue);
+            virtio.store32(VIRTIO_QUEUE_READY, (queue != 1) as u64);
+        }
+        // Nothing is processed until the driver is ready.
+        virtio.store32(VIRTIO_QUEUE_NOTIFY, 2);
+        virtio.store32(VIRTIO_QUEUE_NOTIFY, 0);
+        virtio.store32(VIRTIO_QUEUE_NOTIFY, 1);
+        virtio.store32(VIRTIO_QUEUE_NOTIFY, 3);
+        assert!(processed.lock().unwrap().is_empty());
+
+        virtio.store32(VIRTIO_STATUS, VIRTIO_STATUS_DRIVER_OK as u64);
+        tick(&mut virtio);
+        // Queue 1 isn't ready, and queue 3 doesn't exist.
+        assert_eq!(*processed.lock().unwrap(), [0, 2]);
+        tick(&mut virtio);
+        assert_eq!(processed.lock().unwrap().len(), 2);
     }
 }
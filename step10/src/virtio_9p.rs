@@ -0,0 +1,1125 @@
+//! The virtio_9p module contains a virtio 9P transport device that shares a host directory with
+//! the guest over the 9P2000.L protocol, e.g. `mount -t 9p -o trans=virtio,version=9p2000.L rvemu
+//! /mnt` on Linux. The guest can't reach a host file outside the directory, neither with `..` nor
+//! through a symbolic link, and can't modify anything in the read-only mode.
+//!
+//! The virtio spec:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
+//! The 9P2000.L protocol:
+//! https://github.com/chaos/diod/blob/master/protocol.md
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, DirBuilder, File, FileTimes, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{
+    symlink, DirBuilderExt, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt,
+};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::bus::*;
+use crate::snapshot::*;
+use crate::trap::*;
+use crate::virtio::*;
+
+/// The device ID of a 9P transport device.
+const VIRTIO_ID_9P: u32 = 9;
+
+/// The feature bit which means the device has a mount tag in the configuration space.
+const VIRTIO_9P_MOUNT_TAG: u64 = 1;
+
+/// The default mount tag, which the guest uses to find the share.
+pub const DEFAULT_MOUNT_TAG: &str = "rvemu";
+
+/// The largest message size. The guest proposes a size and the smaller one is used.
+const MAX_MSIZE: u32 = 128 * 1024;
+/// The size of the header of a message, `size[4] type[1] tag[2]`.
+const HEADER_SIZE: usize = 7;
+/// The tag of a request without a reply, e.g. `Tversion`.
+const NOTAG: u16 = 0xffff;
+
+// Message types. A reply is the request plus one.
+const RLERROR: u8 = 7;
+const TSTATFS: u8 = 8;
+const TLOPEN: u8 = 12;
+const TLCREATE: u8 = 14;
+const TSYMLINK: u8 = 16;
+const TRENAME: u8 = 20;
+const TREADLINK: u8 = 22;
+const TGETATTR: u8 = 24;
+const TSETATTR: u8 = 26;
+const TREADDIR: u8 = 40;
+const TFSYNC: u8 = 50;
+const TLINK: u8 = 70;
+const TMKDIR: u8 = 72;
+const TRENAMEAT: u8 = 74;
+const TUNLINKAT: u8 = 76;
+const TVERSION: u8 = 100;
+const TATTACH: u8 = 104;
+const TFLUSH: u8 = 108;
+const TWALK: u8 = 110;
+const TREAD: u8 = 116;
+const TWRITE: u8 = 118;
+const TCLUNK: u8 = 120;
+const TREMOVE: u8 = 122;
+
+// Linux errno values, which are the error codes of 9P2000.L.
+const EPERM: u32 = 1;
+const ENOENT: u32 = 2;
+const EIO: u32 = 5;
+const EBADF: u32 = 9;
+const EACCES: u32 = 13;
+const EEXIST: u32 = 17;
+const EISDIR: u32 = 21;
+const EINVAL: u32 = 22;
+const EROFS: u32 = 30;
+const ELOOP: u32 = 40;
+const EPROTO: u32 = 71;
+const EOPNOTSUPP: u32 = 95;
+
+// Linux open flags in `Tlopen` and `Tlcreate`.
+const O_ACCMODE: u32 = 0o3;
+const O_WRONLY: u32 = 0o1;
+const O_RDWR: u32 = 0o2;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+// Valid bits of `Tsetattr`.
+const P9_SETATTR_MODE: u32 = 0x1;
+const P9_SETATTR_SIZE: u32 = 0x8;
+const P9_SETATTR_ATIME: u32 = 0x10;
+const P9_SETATTR_MTIME: u32 = 0x20;
+const P9_SETATTR_ATIME_SET: u32 = 0x80;
+const P9_SETATTR_MTIME_SET: u32 = 0x100;
+
+/// The valid bits of `Rgetattr`, all the fields except `btime`, `gen` and `data_version`.
+const P9_GETATTR_BASIC: u64 = 0x7ff;
+
+/// The flag of `Tunlinkat` to remove a directory.
+const AT_REMOVEDIR: u32 = 0x200;
+
+/// The magic number of the filesystem in `Rstatfs`.
+const V9FS_MAGIC: u32 = 0x0102_1997;
+
+/// The result of a request, or the errno to reply with `Rlerror`.
+type P9Result<T> = Result<T, u32>;
+
+/// Return the errno of `e`.
+fn errno(e: io::Error) -> u32 {
+    match e.raw_os_error() {
+        Some(code) if code > 0 => code as u32,
+        _ => match e.kind() {
+            io::ErrorKind::NotFound => ENOENT,
+            io::ErrorKind::PermissionDenied => EACCES,
+            io::ErrorKind::AlreadyExists => EEXIST,
+            _ => EIO,
+        },
+    }
+}
+
+/// A reader of the fields of a request.
+struct Request<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Request<'a> {
+    fn bytes(&mut self, len: usize) -> P9Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).ok_or(EPROTO)?;
+        let bytes = self.data.get(self.pos..end).ok_or(EPROTO)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> P9Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> P9Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> P9Result<u64> {
+        let b = self.bytes(8)?;
+        let mut value = [0; 8];
+        value.copy_from_slice(b);
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn string(&mut self) -> P9Result<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| EINVAL)
+    }
+}
+
+/// A writer of the fields of a reply.
+struct Reply {
+    data: Vec<u8>,
+}
+
+impl Reply {
+    fn new() -> Self {
+        Self { data: Vec::new() }
+    }
+
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.data.push(value);
+        self
+    }
+
+    fn u16(&mut self, value: u16) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn string(&mut self, value: &str) -> &mut Self {
+        self.u16(value.len() as u16);
+        self.data.extend_from_slice(value.as_bytes());
+        self
+    }
+
+    fn qid(&mut self, metadata: &fs::Metadata) -> &mut Self {
+        // struct qid {
+        //   uint8 type;
+        //   uint32 version;
+        //   uint64 path;
+        // };
+        let file_type = metadata.file_type();
+        let qid_type = if file_type.is_dir() {
+            0x80
+        } else if file_type.is_symlink() {
+            0x02
+        } else {
+            0
+        };
+        self.u8(qid_type).u32(0).u64(metadata.ino())
+    }
+}
+
+/// A file identifier, which the guest uses to refer to a file.
+struct Fid {
+    /// The path of the file relative to the shared directory, without `.` or `..`.
+    path: PathBuf,
+    /// The open file, or None if the file isn't open or is a directory.
+    file: Option<File>,
+    /// The flags the file or the directory has been opened with, or None if it isn't open.
+    flags: Option<u32>,
+    /// The entries of the open directory, read when the guest reads from the start.
+    entries: Vec<(String, fs::Metadata)>,
+}
+
+impl Fid {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            file: None,
+            flags: None,
+            entries: Vec::new(),
+        }
+    }
+}
+
+/// A virtio 9P transport device that shares a host directory.
+pub struct Virtio9p {
+    /// The shared directory, canonicalized.
+    root: PathBuf,
+    tag: String,
+    readonly: bool,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl VirtioDevice for Virtio9p {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_9P
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, offset: u64, size: u64) -> u64 {
+        // struct virtio_9p_config {
+        //   uint16 tag_len;
+        //   uint8 tag[tag_len];
+        // };
+        let mut config = (self.tag.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(self.tag.as_bytes());
+        read_config_bytes(&config, offset, size)
+    }
+
+    fn reset(&mut self) {
+        self.msize = MAX_MSIZE;
+        self.fids.clear();
+    }
+
+    fn process(
+        &mut self,
+        _index: usize,
+        queue: &mut Virtqueue,
+        dma: &mut DmaContext,
+    ) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(dma)? {
+            let mut request = vec![0; chain.readable_len().min(MAX_MSIZE as u64) as usize];
+            chain.read(dma, 0, &mut request)?;
+            let reply = self.handle(&request, chain.writable_len() as usize);
+            let len = chain.write(dma, 0, &reply)?;
+            queue.push(dma, chain.head, len as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        // The open files are saved as their paths and opened again when the snapshot is restored.
+        let mut fids: Vec<(&u32, &Fid)> = self.fids.iter().collect();
+        fids.sort_by_key(|(fid, _)| **fid);
+        snapshot.u32(self.msize).u64(fids.len() as u64);
+        for (fid, entry) in fids {
+            snapshot
+                .u32(*fid)
+                .bytes(entry.path.as_os_str().as_bytes())
+                .bool(entry.flags.is_some())
+                .u32(entry.flags.unwrap_or(0));
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        self.msize = snapshot.u32()?;
+        self.fids.clear();
+        for _ in 0..snapshot.u64()? {
+            let fid = snapshot.u32()?;
+            let path = PathBuf::from(OsStr::from_bytes(snapshot.bytes()?));
+            let open = snapshot.bool()?;
+            let flags = snapshot.u32()?;
+            if !path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+            {
+                return Err(snapshot_error("invalid 9P path"));
+            }
+            let mut entry = Fid::new(path);
+            if open {
+                // A file that can't be opened anymore, e.g. because it has been removed from the
+                // host, stays closed and the guest gets an error when it uses the fid.
+                if let Err(e) = self.reopen(&mut entry, flags) {
+                    eprintln!(
+                        "virtio-9p: {}: {}",
+                        entry.path.display(),
+                        io::Error::from_raw_os_error(e as i32)
+                    );
+                }
+            }
+            self.fids.insert(fid, entry);
+        }
+        Ok(())
+    }
+}
+
+impl Virtio9p {
+    /// Create a new 9P device that shares the directory `root` with the mount tag `tag`.
+    pub fn new(root: &Path, tag: &str, readonly: bool) -> io::Result<Self> {
+        let root = root.canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(Self {
+            root,
+            tag: tag.to_string(),
+            readonly,
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        })
+    }
+
+    /// Handle `request` and return the reply, which fits in `max` bytes.
+    fn handle(&mut self, request: &[u8], max: usize) -> Vec<u8> {
+        let mut req = Request {
+            data: request,
+            pos: HEADER_SIZE,
+        };
+        let (kind, tag) = match request.get(4..HEADER_SIZE) {
+            Some(header) => (header[0], u16::from_le_bytes([header[1], header[2]])),
+            None => (0, NOTAG),
+        };
+        // The space for the data of `Rread` and `Rreaddir`, after the header and the count.
+        let room = (self.msize as usize)
+            .min(max)
+            .saturating_sub(HEADER_SIZE + 4);
+        let (kind, body) = match self.dispatch(kind, &mut req, room) {
+            Ok(body) => (kind + 1, body),
+            Err(code) => {
+                let mut reply = Reply::new();
+                reply.u32(code);
+                (RLERROR, reply)
+            }
+        };
+        let mut reply = Reply::new();
+        reply
+            .u32((HEADER_SIZE + body.data.len()) as u32)
+            .u8(kind)
+            .u16(tag);
+        reply.data.extend_from_slice(&body.data);
+        reply.data
+    }
+
+    /// Handle a request of `kind` and return the body of the reply.
+    fn dispatch(&mut self, kind: u8, req: &mut Request, room: usize) -> P9Result<Reply> {
+        let mut reply = Reply::new();
+        match kind {
+            TVERSION => {
+                let msize = req.u32()?;
+                let version = req.string()?;
+                // A new session forgets the files of the previous one.
+                self.fids.clear();
+                self.msize = msize.min(MAX_MSIZE);
+                let version = if version == "9P2000.L" {
+                    "9P2000.L"
+                } else {
+                    "unknown"
+                };
+                reply.u32(self.msize).string(version);
+            }
+            TATTACH => {
+                let fid = req.u32()?;
+                let _afid = req.u32()?;
+                let _uname = req.string()?;
+                let _aname = req.string()?;
+                let metadata = fs::metadata(&self.root).map_err(errno)?;
+                self.fids.insert(fid, Fid::new(PathBuf::new()));
+                reply.qid(&metadata);
+            }
+            TFLUSH => {
+                // Requests are handled in order, so the flushed request has been replied to.
+                let _oldtag = req.u16()?;
+            }
+            TWALK => {
+                let fid = req.u32()?;
+                let newfid = req.u32()?;
+                let mut path = self.fid(fid)?.path.clone();
+                let names = req.u16()?;
+                let mut qids = Reply::new();
+                let mut walked = 0;
+                for i in 0..names {
+                    let name = req.string()?;
+                    let next = match name.as_str() {
+                        // The parent of the shared directory is the shared directory.
+                        ".." => path.parent().map(Path::to_path_buf).unwrap_or_default(),
+                        name => path.join(check_name(name)?),
+                    };
+                    match self.resolve(&next, false).and_then(symlink_metadata) {
+                        Ok(metadata) => {
+                            qids.qid(&metadata);
+                            path = next;
+                            walked += 1;
+                        }
+                        // Only a failure on the first name is an error.
+                        Err(code) if i == 0 => return Err(code),
+                        Err(_) => break,
+                    }
+                }
+                if walked == names {
+                    self.fids.insert(newfid, Fid::new(path));
+                }
+                reply.u16(walked);
+                reply.data.extend_from_slice(&qids.data);
+            }
+            TLOPEN => {
+                let fid = req.u32()?;
+                let flags = req.u32()?;
+                self.check_open_flags(flags)?;
+                let path = self.resolve(&self.fid(fid)?.path, true)?;
+                let metadata = fs::metadata(&path).map_err(errno)?;
+                let file = if metadata.is_dir() {
+                    None
+                } else {
+                    Some(open_options(flags).open(&path).map_err(errno)?)
+                };
+                let entry = self.fid_mut(fid)?;
+                entry.file = file;
+                entry.flags = Some(flags);
+                entry.entries.clear();
+                reply.qid(&metadata).u32(0);
+            }
+            TLCREATE => {
+                let fid = req.u32()?;
+                let name = req.string()?;
+                let flags = req.u32()?;
+                let mode = req.u32()?;
+                let _gid = req.u32()?;
+                self.check_writable()?;
+                let path = self.fid(fid)?.path.join(check_name(&name)?);
+                let host = self.resolve(&path, false)?;
+                let mut options = open_options(flags);
+                if flags & O_EXCL != 0 {
+                    // An existing name fails, even a symbolic link.
+                    options.create_new(true);
+                } else {
+                    // Opening an existing symbolic link would follow it, possibly to a file out of
+                    // the shared directory, so it fails the same as with O_NOFOLLOW.
+                    if fs::symlink_metadata(&host).is_ok_and(|m| m.file_type().is_symlink()) {
+                        return Err(ELOOP);
+                    }
+                    options.create(true);
+                }
+                let file = options.mode(mode & 0o7777).open(&host).map_err(errno)?;
+                let metadata = file.metadata().map_err(errno)?;
+                // The fid now refers to the new file.
+                let entry = self.fid_mut(fid)?;
+                entry.path = path;
+                entry.file = Some(file);
+                entry.flags = Some(flags);
+                reply.qid(&metadata).u32(0);
+            }
+            TSYMLINK => {
+                let fid = req.u32()?;
+                let name = req.string()?;
+                let target = req.string()?;
+                let _gid = req.u32()?;
+                self.check_writable()?;
+                let path = self.fid(fid)?.path.join(check_name(&name)?);
+                let host = self.resolve(&path, false)?;
+                // A link out of the shared directory is created, but never followed.
+                symlink(&target, &host).map_err(errno)?;
+                reply.qid(&symlink_metadata(host)?);
+            }
+            TLINK => {
+                let dfid = req.u32()?;
+                let fid = req.u32()?;
+                let name = req.string()?;
+                self.check_writable()?;
+                let target = self.resolve(&self.fid(fid)?.path, false)?;
+                let path = self.fid(dfid)?.path.join(check_name(&name)?);
+                fs::hard_link(target, self.resolve(&path, false)?).map_err(errno)?;
+            }
+            TMKDIR => {
+                let dfid = req.u32()?;
+                let name = req.string()?;
+                let mode = req.u32()?;
+                let _gid = req.u32()?;
+                self.check_writable()?;
+                let path = self.fid(dfid)?.path.join(check_name(&name)?);
+                let host = self.resolve(&path, false)?;
+                DirBuilder::new()
+                    .mode(mode & 0o7777)
+                    .create(&host)
+                    .map_err(errno)?;
+                reply.qid(&symlink_metadata(host)?);
+            }
+            TREADLINK => {
+                let fid = req.u32()?;
+                let path = self.resolve(&self.fid(fid)?.path, false)?;
+                let target = fs::read_link(path).map_err(errno)?;
+                reply.string(target.to_str().ok_or(EINVAL)?);
+            }
+            TGETATTR => {
+                let fid = req.u32()?;
+                let _request_mask = req.u64()?;
+                let metadata = symlink_metadata(self.resolve(&self.fid(fid)?.path, false)?)?;
+                reply
+                    .u64(P9_GETATTR_BASIC)
+                    .qid(&metadata)
+                    .u32(metadata.mode())
+                    .u32(metadata.uid())
+                    .u32(metadata.gid())
+                    .u64(metadata.nlink())
+                    .u64(metadata.rdev())
+                    .u64(metadata.size())
+                    .u64(metadata.blksize())
+                    .u64(metadata.blocks())
+                    .u64(metadata.atime() as u64)
+                    .u64(metadata.atime_nsec() as u64)
+                    .u64(metadata.mtime() as u64)
+                    .u64(metadata.mtime_nsec() as u64)
+                    .u64(metadata.ctime() as u64)
+                    .u64(metadata.ctime_nsec() as u64)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0);
+            }
+            TSETATTR => {
+                let fid = req.u32()?;
+                let valid = req.u32()?;
+                let mode = req.u32()?;
+                let _uid = req.u32()?;
+                let _gid = req.u32()?;
+                let size = req.u64()?;
+                let atime = time(req.u64()?, req.u64()?);
+                let mtime = time(req.u64()?, req.u64()?);
+                self.check_writable()?;
+                // The owner of the host files isn't changed.
+                let path = self.resolve(&self.fid(fid)?.path, true)?;
+                if valid & P9_SETATTR_MODE != 0 {
+                    fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777))
+                        .map_err(errno)?;
+                }
+                if valid & P9_SETATTR_SIZE != 0 {
+                    let file = OpenOptions::new().write(true).open(&path).map_err(errno)?;
+                    file.set_len(size).map_err(errno)?;
+                }
+                if valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
+                    let now = SystemTime::now();
+                    let mut times = FileTimes::new();
+                    if valid & P9_SETATTR_ATIME != 0 {
+                        times = times.set_accessed(if valid & P9_SETATTR_ATIME_SET != 0 {
+                            atime
+                        } else {
+                            now
+                        });
+                    }
+                    if valid & P9_SETATTR_MTIME != 0 {
+                        times = times.set_modified(if valid & P9_SETATTR_MTIME_SET != 0 {
+                            mtime
+                        } else {
+                            now
+                        });
+                    }
+                    let file = File::open(&path).map_err(errno)?;
+                    file.set_times(times).map_err(errno)?;
+                }
+            }
+            TREADDIR => {
+                let fid = req.u32()?;
+                let offset = req.u64()?;
+                let count = (req.u32()? as usize).min(room);
+                if offset == 0 {
+                    let entries = self.read_dir(&self.fid(fid)?.path)?;
+                    self.fid_mut(fid)?.entries = entries;
+                }
+                let mut data = Reply::new();
+                let entries = &self.fid(fid)?.entries;
+                for (i, (name, metadata)) in entries.iter().enumerate().skip(offset as usize) {
+                    // struct dirent {
+                    //   qid qid;
+                    //   uint64 offset;
+                    //   uint8 type;
+                    //   string name;
+                    // };
+                    if data.data.len() + 13 + 8 + 1 + 2 + name.len() > count {
+                        break;
+                    }
+                    data.qid(metadata)
+                        .u64(i as u64 + 1)
+                        .u8(dirent_type(metadata))
+                        .string(name);
+                }
+                reply.u32(data.data.len() as u32);
+                reply.data.extend_from_slice(&data.data);
+            }
+            TREAD => {
+                let fid = req.u32()?;
+                let offset = req.u64()?;
+                let count = (req.u32()? as usize).min(room);
+                let file = self.fid(fid)?.file.as_ref().ok_or(EISDIR)?;
+                let mut data = vec![0; count];
+                let mut len = 0;
+                while len < count {
+                    match file.read_at(&mut data[len..], offset + len as u64) {
+                        Ok(0) => break,
+                        Ok(n) => len += n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(errno(e)),
+                    }
+                }
+                reply.u32(len as u32);
+                reply.data.extend_from_slice(&data[..len]);
+            }
+            TWRITE => {
+                let fid = req.u32()?;
+                let offset = req.u64()?;
+                let count = req.u32()? as usize;
+                let data = req.bytes(count)?;
+                self.check_writable()?;
+                let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+                file.write_all_at(data, offset).map_err(errno)?;
+                reply.u32(count as u32);
+            }
+            TFSYNC => {
+                let fid = req.u32()?;
+                if let Some(file) = &self.fid(fid)?.file {
+                    file.sync_all().map_err(errno)?;
+                }
+            }
+            TCLUNK => {
+                let fid = req.u32()?;
+                self.fids.remove(&fid).ok_or(EBADF)?;
+            }
+            TREMOVE => {
+                let fid = req.u32()?;
+                // The fid is clunked even if the file isn't removed.
+                let entry = self.fids.remove(&fid).ok_or(EBADF)?;
+                self.check_writable()?;
+                self.remove(&entry.path)?;
+            }
+            TUNLINKAT => {
+                let dfid = req.u32()?;
+                let name = req.string()?;
+                let flags = req.u32()?;
+                self.check_writable()?;
+                let path = self.fid(dfid)?.path.join(check_name(&name)?);
+                let host = self.resolve(&path, false)?;
+                if flags & AT_REMOVEDIR != 0 {
+                    fs::remove_dir(host).map_err(errno)?;
+                } else {
+                    fs::remove_file(host).map_err(errno)?;
+                }
+            }
+            TRENAME => {
+                let fid = req.u32()?;
+                let dfid = req.u32()?;
+                let name = req.string()?;
+                self.check_writable()?;
+                let path = self.fid(dfid)?.path.join(check_name(&name)?);
+                let from = self.resolve(&self.fid(fid)?.path, false)?;
+                fs::rename(from, self.resolve(&path, false)?).map_err(errno)?;
+                self.fid_mut(fid)?.path = path;
+            }
+            TRENAMEAT => {
+                let olddirfid = req.u32()?;
+                let oldname = req.string()?;
+                let newdirfid = req.u32()?;
+                let newname = req.string()?;
+                self.check_writable()?;
+                let from = self.fid(olddirfid)?.path.join(check_name(&oldname)?);
+                let to = self.fid(newdirfid)?.path.join(check_name(&newname)?);
+                fs::rename(self.resolve(&from, false)?, self.resolve(&to, false)?)
+                    .map_err(errno)?;
+            }
+            TSTATFS => {
+                let fid = req.u32()?;
+                self.fid(fid)?;
+                // The usage of the host filesystem isn't available without libc, so it's unknown.
+                reply
+                    .u32(V9FS_MAGIC)
+                    .u32(4096)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0)
+                    .u64(0)
+                    .u32(255);
+            }
+            // Extended attributes, locks and device files aren't supported.
+            _ => return Err(EOPNOTSUPP),
+        }
+        Ok(reply)
+    }
+
+    fn fid(&self, fid: u32) -> P9Result<&Fid> {
+        self.fids.get(&fid).ok_or(EBADF)
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> P9Result<&mut Fid> {
+        self.fids.get_mut(&fid).ok_or(EBADF)
+    }
+
+    fn check_writable(&self) -> P9Result<()> {
+        match self.readonly {
+            true => Err(EROFS),
+            false => Ok(()),
+        }
+    }
+
+    fn check_open_flags(&self, flags: u32) -> P9Result<()> {
+        if flags & O_ACCMODE != 0 || flags & (O_TRUNC | O_APPEND) != 0 {
+            self.check_writable()?;
+        }
+        Ok(())
+    }
+
+    /// Return the host path of `path`, relative to the shared directory. The directories in the
+    /// path must stay in the shared directory after following symbolic links, and so must the
+    /// last name if `follow` is true.
+    fn resolve(&self, path: &Path, follow: bool) -> P9Result<PathBuf> {
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Ok(self.root.clone()),
+        };
+        let parent = self.root.join(parent).canonicalize().map_err(errno)?;
+        if !parent.starts_with(&self.root) {
+            return Err(EACCES);
+        }
+        let host = parent.join(name);
+        if !follow {
+            return Ok(host);
+        }
+        let host = host.canonicalize().map_err(errno)?;
+        match host.starts_with(&self.root) {
+            true => Ok(host),
+            false => Err(EACCES),
+        }
+    }
+
+    /// Open the file or the directory of `entry` again with `flags` after a snapshot has been
+    /// restored. The file isn't truncated again.
+    fn reopen(&self, entry: &mut Fid, flags: u32) -> P9Result<()> {
+        let path = self.resolve(&entry.path, true)?;
+        if fs::metadata(&path).map_err(errno)?.is_dir() {
+            entry.entries = self.read_dir(&entry.path)?;
+        } else {
+            entry.file = Some(open_options(flags & !O_TRUNC).open(&path).map_err(errno)?);
+        }
+        entry.flags = Some(flags);
+        Ok(())
+    }
+
+    /// Return the entries of the directory at `path`, including `.` and `..`.
+    fn read_dir(&self, path: &Path) -> P9Result<Vec<(String, fs::Metadata)>> {
+        let host = self.resolve(path, true)?;
+        let mut entries = vec![
+            (".".to_string(), symlink_metadata(host.clone())?),
+            // The parent of the shared directory is hidden.
+            (
+                "..".to_string(),
+                symlink_metadata(self.resolve(path.parent().unwrap_or(path), true)?)?,
+            ),
+        ];
+        for entry in fs::read_dir(&host).map_err(errno)? {
+            let entry = entry.map_err(errno)?;
+            // A name that isn't UTF-8 can't be sent.
+            if let (Ok(name), Ok(metadata)) = (entry.file_name().into_string(), entry.metadata()) {
+                entries.push((name, metadata));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Remove the file or the empty directory at `path`.
+    fn remove(&self, path: &Path) -> P9Result<()> {
+        if path.as_os_str().is_empty() {
+            return Err(EPERM);
+        }
+        let host = self.resolve(path, false)?;
+        if symlink_metadata(host.clone())?.is_dir() {
+            fs::remove_dir(host).map_err(errno)
+        } else {
+            fs::remove_file(host).map_err(errno)
+        }
+    }
+}
+
+/// Check that `name` is a single name in a directory.
+fn check_name(name: &str) -> P9Result<&str> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.contains('/') => Ok(name),
+        _ => Err(EINVAL),
+    }
+}
+
+fn symlink_metadata(path: PathBuf) -> P9Result<fs::Metadata> {
+    fs::symlink_metadata(path).map_err(errno)
+}
+
+/// Return the options to open a file with the Linux open `flags`.
+fn open_options(flags: u32) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    match flags & O_ACCMODE {
+        O_WRONLY => options.write(true),
+        O_RDWR => options.read(true).write(true),
+        _ => options.read(true),
+    };
+    options
+        .append(flags & O_APPEND != 0)
+        .truncate(flags & O_TRUNC != 0);
+    options
+}
+
+/// Return the `d_type` of a directory entry.
+fn dirent_type(metadata: &fs::Metadata) -> u8 {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        4
+    } else if file_type.is_symlink() {
+        10
+    } else {
+        8
+    }
+}
+
+fn time(sec: u64, nsec: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::new(sec, nsec as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::env;
+    use std::process;
+
+    const NOFID: u32 = u32::MAX;
+
+    /// A shared directory and a directory next to it that the guest must not reach.
+    struct Dirs {
+        top: PathBuf,
+        share: PathBuf,
+        outside: PathBuf,
+    }
+
+    impl Dirs {
+        fn new(name: &str) -> Self {
+            let top = env::temp_dir().join(format!("rvemu-9p-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&top);
+            let (share, outside) = (top.join("share"), top.join("outside"));
+            fs::create_dir_all(&share).unwrap();
+            fs::create_dir_all(&outside).unwrap();
+            fs::write(outside.join("secret"), "secret").unwrap();
+            fs::write(share.join("file"), "hello").unwrap();
+            Self {
+                top,
+                share,
+                outside,
+            }
+        }
+    }
+
+    impl Drop for Dirs {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.top);
+        }
+    }
+
+    /// Send a request of `kind` with `body` and return the body of the reply, or the errno of
+    /// `Rlerror`.
+    fn call(p9: &mut Virtio9p, kind: u8, body: &mut Reply) -> P9Result<Vec<u8>> {
+        let mut request = Reply::new();
+        request
+            .u32((HEADER_SIZE + body.data.len()) as u32)
+            .u8(kind)
+            .u16(1);
+        request.data.extend_from_slice(&body.data);
+        let reply = p9.handle(&request.data, MAX_MSIZE as usize);
+        assert_eq!(
+            reply.len(),
+            u32::from_le_bytes(reply[..4].try_into().unwrap()) as usize
+        );
+        match reply[4] {
+            RLERROR => Err(u32::from_le_bytes(reply[7..11].try_into().unwrap())),
+            reply_kind => {
+                assert_eq!(reply_kind, kind + 1);
+                Ok(reply[7..].to_vec())
+            }
+        }
+    }
+
+    /// Create a device on `dirs` with the root attached to the fid 0.
+    fn attach(dirs: &Dirs, readonly: bool) -> Virtio9p {
+        let mut p9 = Virtio9p::new(&dirs.share, DEFAULT_MOUNT_TAG, readonly).unwrap();
+        call(&mut p9, TVERSION, Reply::new().u32(8192).string("9P2000.L")).unwrap();
+        call(
+            &mut p9,
+            TATTACH,
+            Reply::new().u32(0).u32(NOFID).string("root").string(""),
+        )
+        .unwrap();
+        p9
+    }
+
+    /// Walk from the fid 0 to `newfid` through `names`. Return the number of names walked.
+    fn walk(p9: &mut Virtio9p, newfid: u32, names: &[&str]) -> P9Result<u16> {
+        let mut body = Reply::new();
+        body.u32(0).u32(newfid).u16(names.len() as u16);
+        for name in names {
+            body.string(name);
+        }
+        let reply = call(p9, TWALK, &mut body)?;
+        Ok(u16::from_le_bytes([reply[0], reply[1]]))
+    }
+
+    fn lcreate(p9: &mut Virtio9p, fid: u32, name: &str, flags: u32) -> P9Result<Vec<u8>> {
+        call(
+            p9,
+            TLCREATE,
+            Reply::new()
+                .u32(fid)
+                .string(name)
+                .u32(flags)
+                .u32(0o644)
+                .u32(0),
+        )
+    }
+
+    #[test]
+    fn lcreate_doesnt_follow_symlink() {
+        let dirs = Dirs::new("lcreate");
+        let mut p9 = attach(&dirs, false);
+        let secret = dirs.outside.join("secret");
+        call(
+            &mut p9,
+            TSYMLINK,
+            Reply::new()
+                .u32(0)
+                .string("evil")
+                .string(secret.to_str().unwrap())
+                .u32(0),
+        )
+        .unwrap();
+        let missing = dirs.outside.join("missing");
+        symlink(&missing, dirs.share.join("dangling")).unwrap();
+
+        assert_eq!(walk(&mut p9, 1, &[]), Ok(0));
+        assert_eq!(lcreate(&mut p9, 1, "evil", O_WRONLY | O_TRUNC), Err(ELOOP));
+        assert_eq!(lcreate(&mut p9, 1, "dangling", O_WRONLY), Err(ELOOP));
+        assert_eq!(lcreate(&mut p9, 1, "evil", O_WRONLY | O_EXCL), Err(EEXIST));
+        assert_eq!(fs::read_to_string(&secret).unwrap(), "secret");
+        assert!(!missing.exists());
+
+        // A new file is created in the shared directory.
+        lcreate(&mut p9, 1, "new", O_WRONLY).unwrap();
+        let write = &mut Reply::new();
+        write.u32(1).u64(0).u32(2).u8(b'o').u8(b'k');
+        call(&mut p9, TWRITE, write).unwrap();
+        assert_eq!(fs::read_to_string(dirs.share.join("new")).unwrap(), "ok");
+    }
+
+    #[test]
+    fn dotdot_stays_in_share() {
+        let dirs = Dirs::new("dotdot");
+        let mut p9 = attach(&dirs, false);
+        let share_ino = fs::metadata(&dirs.share).unwrap().ino();
+
+        // The parent of the shared directory is the shared directory.
+        let mut body = Reply::new();
+        body.u32(0).u32(1).u16(2).string("..").string("..");
+        let reply = call(&mut p9, TWALK, &mut body).unwrap();
+        assert_eq!(u16::from_le_bytes([reply[0], reply[1]]), 2);
+        assert_eq!(
+            u64::from_le_bytes(reply[7..15].try_into().unwrap()),
+            share_ino
+        );
+        // The directory next to it isn't reachable.
+        assert_eq!(walk(&mut p9, 2, &["..", "outside"]), Ok(1));
+        assert_eq!(
+            call(&mut p9, TGETATTR, Reply::new().u32(2).u64(0)),
+            Err(EBADF)
+        );
+
+        // A name with `..` or `/` is invalid.
+        assert_eq!(walk(&mut p9, 3, &["../outside"]), Err(EINVAL));
+        assert_eq!(lcreate(&mut p9, 0, "../escape", O_WRONLY), Err(EINVAL));
+        assert_eq!(lcreate(&mut p9, 0, "a/b", O_WRONLY), Err(EINVAL));
+        assert!(!dirs.top.join("escape").exists());
+    }
+
+    #[test]
+    fn walk_through_symlink_is_rejected() {
+        let dirs = Dirs::new("walk");
+        let mut p9 = attach(&dirs, false);
+        symlink(&dirs.outside, dirs.share.join("link")).unwrap();
+        symlink(dirs.outside.join("secret"), dirs.share.join("secret")).unwrap();
+
+        // The link itself can be walked to and read, but not followed.
+        assert_eq!(walk(&mut p9, 1, &["link", "secret"]), Ok(1));
+        assert_eq!(walk(&mut p9, 1, &["link"]), Ok(1));
+        let reply = call(&mut p9, TREADLINK, Reply::new().u32(1)).unwrap();
+        assert_eq!(&reply[2..], dirs.outside.to_str().unwrap().as_bytes());
+        assert_eq!(
+            call(&mut p9, TLOPEN, Reply::new().u32(1).u32(0)),
+            Err(EACCES)
+        );
+        assert_eq!(
+            call(&mut p9, TREADDIR, Reply::new().u32(1).u64(0).u32(4096)),
+            Err(EACCES)
+        );
+        assert_eq!(
+            call(
+                &mut p9,
+                TLCREATE,
+                Reply::new()
+                    .u32(1)
+                    .string("x")
+                    .u32(O_WRONLY)
+                    .u32(0o644)
+                    .u32(0)
+            ),
+            Err(EACCES)
+        );
+
+        assert_eq!(walk(&mut p9, 2, &["secret"]), Ok(1));
+        assert_eq!(
+            call(&mut p9, TLOPEN, Reply::new().u32(2).u32(0)),
+            Err(EACCES)
+        );
+        assert!(!dirs.outside.join("x").exists());
+    }
+
+    #[test]
+    fn readonly_share_isnt_modified() {
+        let dirs = Dirs::new("readonly");
+        let mut p9 = attach(&dirs, true);
+
+        assert_eq!(lcreate(&mut p9, 0, "new", O_WRONLY), Err(EROFS));
+        assert_eq!(
+            call(
+                &mut p9,
+                TMKDIR,
+                Reply::new().u32(0).string("dir").u32(0o755).u32(0)
+            ),
+            Err(EROFS)
+        );
+        assert_eq!(
+            call(
+                &mut p9,
+                TSYMLINK,
+                Reply::new().u32(0).string("link").string("file").u32(0)
+            ),
+            Err(EROFS)
+        );
+        assert_eq!(
+            call(
+                &mut p9,
+                TUNLINKAT,
+                Reply::new().u32(0).string("file").u32(0)
+            ),
+            Err(EROFS)
+        );
+
+        assert_eq!(walk(&mut p9, 1, &["file"]), Ok(1));
+        for flags in [O_WRONLY, O_RDWR, O_TRUNC, O_APPEND].iter().copied() {
+            assert_eq!(
+                call(&mut p9, TLOPEN, Reply::new().u32(1).u32(flags)),
+                Err(EROFS)
+            );
+        }
+        call(&mut p9, TLOPEN, Reply::new().u32(1).u32(0)).unwrap();
+        let reply = call(&mut p9, TREAD, Reply::new().u32(1).u64(0).u32(100)).unwrap();
+        assert_eq!(&reply[4..], b"hello");
+        let write = &mut Reply::new();
+        write.u32(1).u64(0).u32(1).u8(b'x');
+        assert_eq!(call(&mut p9, TWRITE, write), Err(EROFS));
+        assert_eq!(call(&mut p9, TREMOVE, Reply::new().u32(1)), Err(EROFS));
+
+        assert_eq!(
+            fs::read_to_string(dirs.share.join("file")).unwrap(),
+            "hello"
+        );
+        assert_eq!(fs::read_dir(&dirs.share).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,221 @@
+//! The virtio_blk module contains a virtio block device, a disk behind the virtio-mmio transport.
+//!
+//! The virtio spec:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
+
+use std::io;
+
+use crate::bus::*;
+use crate::disk::*;
+use crate::snapshot::*;
+use crate::trap::*;
+use crate::virtio::*;
+
+/// The device ID of a block device.
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+/// The feature bit which means the device is read-only.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// The feature bit which means the device supports the flush request.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+/// The size of a sector. The sector in a request is always in this unit.
+pub const SECTOR_SIZE: u64 = 512;
+
+// Request types.
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+// Request status.
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// The size of the header at the start of a request.
+// struct virtio_blk_req {
+//   uint32 type;
+//   uint32 reserved;
+//   uint64 sector;
+// };
+const VIRTIO_BLK_REQ_SIZE: usize = 16;
+
+/// The length of the ID string returned by `VIRTIO_BLK_T_GET_ID`.
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+/// The largest number of bytes copied between the disk and the guest memory at once.
+const TRANSFER_SIZE: u64 = 64 * 1024;
+
+/// A virtio block device backed by a `Disk`.
+pub struct VirtioBlk {
+    disk: Box<dyn Disk>,
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        if self.disk.is_readonly() {
+            VIRTIO_BLK_F_FLUSH | VIRTIO_BLK_F_RO
+        } else {
+            VIRTIO_BLK_F_FLUSH
+        }
+    }
+
+    fn read_config(&self, offset: u64, size: u64) -> u64 {
+        // struct virtio_blk_config {
+        //   uint64 capacity;
+        //   ...
+        // };
+        // The capacity is in 512-byte sectors, and the other fields are for features not offered.
+        let capacity = self.disk.capacity() / SECTOR_SIZE;
+        read_config_bytes(&capacity.to_le_bytes(), offset, size)
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    fn process(
+        &mut self,
+        _index: usize,
+        queue: &mut Virtqueue,
+        dma: &mut DmaContext,
+    ) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(dma)? {
+            let len = self.request(&chain, dma);
+            queue.push(dma, chain.head, len)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        self.disk.save(snapshot)
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        self.disk.restore(snapshot)
+    }
+}
+
+/// Report `e`, an error of the disk, and return the status of a failed request.
+fn io_error(e: io::Error) -> u8 {
+    eprintln!("virtio-blk: {}", e);
+    VIRTIO_BLK_S_IOERR
+}
+
+impl VirtioBlk {
+    /// Create a new block device that stores the data in `disk`.
+    pub fn new(disk: Box<dyn Disk>) -> Self {
+        Self { disk }
+    }
+
+    /// Execute the request in `chain` and write its status to the last byte of the chain. Return
+    /// the number of bytes written to the chain.
+    fn request(&mut self, chain: &DescChain, dma: &mut DmaContext) -> u32 {
+        // The status byte is the last byte of the write-only buffers, and the data is between the
+        // header and the status byte.
+        let data_len = match chain.writable_len().checked_sub(1) {
+            Some(len) => len,
+            // A request without room for the status can't be completed.
+            None => return 0,
+        };
+        let mut header = [0; VIRTIO_BLK_REQ_SIZE];
+        let result = match chain.read(dma, 0, &mut header) {
+            Ok(VIRTIO_BLK_REQ_SIZE) => {
+                let request_type = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+                let mut sector = [0; 8];
+                sector.copy_from_slice(&header[8..]);
+                let sector = u64::from_le_bytes(sector);
+                self.execute(request_type, sector, data_len, chain, dma)
+            }
+            _ => Err(VIRTIO_BLK_S_IOERR),
+        };
+        let (status, written) = match result {
+            Ok(written) => (VIRTIO_BLK_S_OK, written),
+            Err(status) => (status, 0),
+        };
+        // The status can't be reported if its buffer is outside of the guest memory.
+        let _ = chain.write(dma, data_len, &[status]);
+        (written + 1) as u32
+    }
+
+    /// Execute a request of `request_type` at `sector`, with `data_len` bytes of write-only data
+    /// buffers. Return the number of bytes written to the data buffers, or the status on failure.
+    fn execute(
+        &mut self,
+        request_type: u32,
+        sector: u64,
+        data_len: u64,
+        chain: &DescChain,
+        dma: &mut DmaContext,
+    ) -> Result<u64, u8> {
+        match request_type {
+            VIRTIO_BLK_T_IN => {
+                // Read disk data and write it to memory directly (DMA).
+                let start = self.start(sector, data_len)?;
+                let mut done = 0;
+                while done < data_len {
+                    let mut data = vec![0; (data_len - done).min(TRANSFER_SIZE) as usize];
+                    self.disk
+                        .read_at(start + done, &mut data)
+                        .map_err(io_error)?;
+                    chain
+                        .write(dma, done, &data)
+                        .map_err(|_| VIRTIO_BLK_S_IOERR)?;
+                    done += data.len() as u64;
+                }
+                Ok(data_len)
+            }
+            VIRTIO_BLK_T_OUT => {
+                // Read memory data and write it to a disk directly (DMA).
+                if self.disk.is_readonly() {
+                    return Err(VIRTIO_BLK_S_IOERR);
+                }
+                let len = chain.readable_len() - VIRTIO_BLK_REQ_SIZE as u64;
+                let start = self.start(sector, len)?;
+                let mut done = 0;
+                while done < len {
+                    let mut data = vec![0; (len - done).min(TRANSFER_SIZE) as usize];
+                    chain
+                        .read(dma, VIRTIO_BLK_REQ_SIZE as u64 + done, &mut data)
+                        .map_err(|_| VIRTIO_BLK_S_IOERR)?;
+                    self.disk.write_at(start + done, &data).map_err(io_error)?;
+                    done += data.len() as u64;
+                }
+                Ok(0)
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                self.disk.flush().map_err(io_error)?;
+                Ok(0)
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = [0; VIRTIO_BLK_ID_BYTES];
+                let name = b"rvemu-for-book";
+                id[..name.len()].copy_from_slice(name);
+                let len = (data_len as usize).min(VIRTIO_BLK_ID_BYTES);
+                chain
+                    .write(dma, 0, &id[..len])
+                    .map_err(|_| VIRTIO_BLK_S_IOERR)?;
+                Ok(len as u64)
+            }
+            _ => Err(VIRTIO_BLK_S_UNSUPP),
+        }
+    }
+
+    /// Return the offset in the disk of `sector`, or an I/O error if `len` bytes from it are
+    /// outside of the disk.
+    fn start(&self, sector: u64, len: u64) -> Result<u64, u8> {
+        let start = sector.checked_mul(SECTOR_SIZE).ok_or(VIRTIO_BLK_S_IOERR)?;
+        let end = start.checked_add(len).ok_or(VIRTIO_BLK_S_IOERR)?;
+        if end > self.disk.capacity() {
+            return Err(VIRTIO_BLK_S_IOERR);
+        }
+        Ok(start)
+    }
+}
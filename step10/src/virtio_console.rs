@@ -0,0 +1,673 @@
+//! The virtio_console module contains a virtio console device behind the virtio-mmio transport.
+//! Each port is connected to a `CharBackend`. The first port is the console, e.g. `hvc0` on
+//! Linux, and the other ports are named serial ports, e.g. `/dev/virtio-ports/<name>`, which are
+//! set up through the control queues.
+//!
+//! The virtio spec:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
+
+use std::collections::VecDeque;
+use std::io;
+
+use crate::bus::*;
+use crate::chardev::*;
+use crate::snapshot::*;
+use crate::trap::*;
+use crate::virtio::*;
+
+/// The device ID of a console device.
+const VIRTIO_ID_CONSOLE: u32 = 3;
+
+/// The feature bit which means the device has more than one port and the control queues.
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+/// The feature bit which means the driver can write a byte to the console through the
+/// configuration space before the queues are set up.
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+/// The offset of `emerg_wr` in the configuration space.
+// struct virtio_console_config {
+//   uint16 cols;
+//   uint16 rows;
+//   uint32 max_nr_ports;
+//   uint32 emerg_wr;
+// };
+const CONFIG_MAX_NR_PORTS: usize = 4;
+const CONFIG_EMERG_WR: u64 = 8;
+
+/// The index of the virtqueue for the control messages from the device to the driver.
+const CONTROL_RECEIVEQ: usize = 2;
+/// The index of the virtqueue for the control messages from the driver to the device.
+const CONTROL_TRANSMITQ: usize = 3;
+
+// Control events.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// The size of a control message, without the name that follows `VIRTIO_CONSOLE_PORT_NAME`.
+// struct virtio_console_control {
+//   uint32 id;
+//   uint16 event;
+//   uint16 value;
+// };
+const CONTROL_SIZE: usize = 8;
+
+/// The number of cycles between checks for bytes received by the backends.
+const POLL_INTERVAL: u64 = 1000;
+/// The largest number of received bytes of a port waiting for the driver to provide buffers.
+const MAX_PENDING_BYTES: usize = 4096;
+/// The largest number of bytes copied from the guest memory to a backend at once.
+const TRANSFER_SIZE: u64 = 64 * 1024;
+
+/// A port of the console.
+struct Port {
+    name: String,
+    backend: Box<dyn CharBackend>,
+    /// The bytes received by the backend and not delivered to the driver yet.
+    pending: VecDeque<u8>,
+}
+
+/// A virtio console device with one or more ports.
+pub struct VirtioConsole {
+    ports: Vec<Port>,
+    /// Whether the driver uses the ports other than the console and the control queues.
+    multiport: bool,
+    /// The control messages not delivered to the driver yet.
+    control: VecDeque<Vec<u8>>,
+    /// The number of cycles since the last check for received bytes.
+    cycles: u64,
+}
+
+/// Return the indexes of the receive and the transmit queues of `port`.
+fn port_queues(port: usize) -> (usize, usize) {
+    match port {
+        0 => (0, 1),
+        // The control queues are between the queues of the port 0 and the port 1.
+        _ => (2 * port + 2, 2 * port + 3),
+    }
+}
+
+/// Return the port whose receive or transmit queue is `index`, and true if it's the transmit
+/// queue.
+fn queue_port(index: usize) -> Option<(usize, bool)> {
+    match index {
+        0 | 1 => Some((0, index == 1)),
+        CONTROL_RECEIVEQ | CONTROL_TRANSMITQ => None,
+        _ => Some((index / 2 - 1, index % 2 == 1)),
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        if self.ports.len() > 1 {
+            VIRTIO_CONSOLE_F_MULTIPORT | VIRTIO_CONSOLE_F_EMERG_WRITE
+        } else {
+            VIRTIO_CONSOLE_F_EMERG_WRITE
+        }
+    }
+
+    fn queues(&self) -> usize {
+        if self.ports.len() > 1 {
+            2 * self.ports.len() + 2
+        } else {
+            2
+        }
+    }
+
+    fn read_config(&self, offset: u64, size: u64) -> u64 {
+        let mut config = [0; 12];
+        config[CONFIG_MAX_NR_PORTS..CONFIG_MAX_NR_PORTS + 4]
+            .copy_from_slice(&(self.ports.len() as u32).to_le_bytes());
+        read_config_bytes(&config, offset, size)
+    }
+
+    fn write_config(&mut self, offset: u64, _size: u64, value: u64) {
+        if offset == CONFIG_EMERG_WR {
+            self.write_port(0, &[value as u8]);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.multiport = false;
+        self.control.clear();
+    }
+
+    fn driver_ok(&mut self, features: u64) {
+        self.multiport = features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+    }
+
+    fn process(
+        &mut self,
+        index: usize,
+        queue: &mut Virtqueue,
+        dma: &mut DmaContext,
+    ) -> Result<bool, Exception> {
+        match index {
+            CONTROL_RECEIVEQ => self.deliver_control(queue, dma),
+            CONTROL_TRANSMITQ => self.receive_control(queue, dma),
+            _ => match queue_port(index) {
+                Some((port, true)) => self.transmit(port, queue, dma),
+                Some((port, false)) => self.deliver(port, queue, dma),
+                None => Ok(false),
+            },
+        }
+    }
+
+    fn poll(
+        &mut self,
+        cycles: u64,
+        queues: &mut [Virtqueue],
+        dma: &mut DmaContext,
+    ) -> Result<bool, Exception> {
+        self.cycles += cycles;
+        if self.cycles < POLL_INTERVAL {
+            return Ok(false);
+        }
+        self.cycles = 0;
+
+        let mut used = false;
+        let ports = if self.multiport { self.ports.len() } else { 1 };
+        for port in 0..ports {
+            let Port {
+                backend, pending, ..
+            } = &mut self.ports[port];
+            while pending.len() < MAX_PENDING_BYTES {
+                match backend.read() {
+                    Some(byte) => pending.push_back(byte),
+                    None => break,
+                }
+            }
+            let (receiveq, _) = port_queues(port);
+            if !self.ports[port].pending.is_empty() && queues[receiveq].ready {
+                used |= self.deliver(port, &mut queues[receiveq], dma)?;
+            }
+        }
+        if self.multiport && !self.control.is_empty() && queues[CONTROL_RECEIVEQ].ready {
+            used |= self.deliver_control(&mut queues[CONTROL_RECEIVEQ], dma)?;
+        }
+        Ok(used)
+    }
+
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        snapshot
+            .u64(self.ports.len() as u64)
+            .bool(self.multiport)
+            .u64(self.cycles)
+            .u64(self.control.len() as u64);
+        for message in &self.control {
+            snapshot.bytes(message);
+        }
+        for port in &self.ports {
+            snapshot.bytes(&port.pending.iter().copied().collect::<Vec<u8>>());
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        if snapshot.u64()? != self.ports.len() as u64 {
+            return Err(snapshot_error("the number of console ports doesn't match"));
+        }
+        self.multiport = snapshot.bool()?;
+        self.cycles = snapshot.u64()?;
+        self.control.clear();
+        for _ in 0..snapshot.u64()? {
+            self.control.push_back(snapshot.bytes()?.to_vec());
+        }
+        for port in &mut self.ports {
+            port.pending = snapshot.bytes()?.iter().copied().collect();
+        }
+        Ok(())
+    }
+}
+
+impl VirtioConsole {
+    /// Create a new console device with `ports`, pairs of a name and a backend. The first port is
+    /// the console, and its name isn't used.
+    pub fn new(ports: Vec<(String, Box<dyn CharBackend>)>) -> Self {
+        assert!(!ports.is_empty(), "a console needs at least one port");
+        Self {
+            ports: ports
+                .into_iter()
+                .map(|(name, backend)| Port {
+                    name,
+                    backend,
+                    pending: VecDeque::new(),
+                })
+                .collect(),
+            multiport: false,
+            control: VecDeque::new(),
+            cycles: 0,
+        }
+    }
+
+    /// Send `data` to the backend of `port`.
+    fn write_port(&mut self, port: usize, data: &[u8]) {
+        if let Err(e) = self.ports[port].backend.write(data) {
+            eprintln!("virtio-console: {}", e);
+        }
+    }
+
+    /// Send the bytes made available in the transmit queue of `port` to its backend.
+    fn transmit(
+        &mut self,
+        port: usize,
+        queue: &mut Virtqueue,
+        dma: &mut DmaContext,
+    ) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(dma)? {
+            let len = chain.readable_len();
+            let mut done = 0;
+            while done < len {
+                let mut data = vec![0; (len - done).min(TRANSFER_SIZE) as usize];
+                chain.read(dma, done, &mut data)?;
+                if port < self.ports.len() {
+                    self.write_port(port, &data);
+                }
+                done += data.len() as u64;
+            }
+            queue.push(dma, chain.head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    /// Deliver the bytes received by the backend of `port` to the buffers made available in its
+    /// receive queue.
+    fn deliver(
+        &mut self,
+        port: usize,
+        queue: &mut Virtqueue,
+        dma: &mut DmaContext,
+    ) -> Result<bool, Exception> {
+        let pending = match self.ports.get_mut(port) {
+            Some(port) => &mut port.pending,
+            None => return Ok(false),
+        };
+        let mut used = false;
+        while !pending.is_empty() {
+            let chain = match queue.pop(dma)? {
+                Some(chain) => chain,
+                // Wait for the driver to provide more buffers.
+                None => break,
+            };
+            let len = (chain.writable_len() as usize).min(pending.len());
+            let data: Vec<u8> = pending.drain(..len).collect();
+            chain.write(dma, 0, &data)?;
+            queue.push(dma, chain.head, len as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    /// Queue a control message with `event` and `value` for `port`, followed by `data`.
+    fn send_control(&mut self, port: usize, event: u16, value: u16, data: &[u8]) {
+        let mut message = Vec::with_capacity(CONTROL_SIZE + data.len());
+        message.extend_from_slice(&(port as u32).to_le_bytes());
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(data);
+        self.control.push_back(message);
+    }
+
+    /// Handle the control messages made available in the control transmit queue.
+    fn receive_control(
+        &mut self,
+        queue: &mut Virtqueue,
+        dma: &mut DmaContext,
+    ) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(dma)? {
+            let mut message = [0; CONTROL_SIZE];
+            if chain.read(dma, 0, &mut message)? == CONTROL_SIZE {
+                let port = u32::from_le_bytes([message[0], message[1], message[2], message[3]]);
+                let event = u16::from_le_bytes([message[4], message[5]]);
+                let value = u16::from_le_bytes([message[6], message[7]]);
+                self.handle_control(port as usize, event, value);
+            }
+            queue.push(dma, chain.head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    /// Handle a control message from the driver.
+    fn handle_control(&mut self, port: usize, event: u16, value: u16) {
+        match event {
+            // The driver is ready to add the ports.
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for port in 0..self.ports.len() {
+                    self.send_control(port, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[]);
+                }
+            }
+            // The driver has added the port.
+            VIRTIO_CONSOLE_PORT_READY if value == 1 && port < self.ports.len() => {
+                if port == 0 {
+                    self.send_control(port, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                } else {
+                    let name = self.ports[port].name.clone();
+                    self.send_control(port, VIRTIO_CONSOLE_PORT_NAME, 1, name.as_bytes());
+                }
+                // The host side is always open.
+                self.send_control(port, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+            }
+            // Other messages, e.g. the driver opening a port, need nothing from the device.
+            _ => {}
+        }
+    }
+
+    /// Deliver the queued control messages to the buffers made available in the control receive
+    /// queue.
+    fn deliver_control(
+        &mut self,
+        queue: &mut Virtqueue,
+        dma: &mut DmaContext,
+    ) -> Result<bool, Exception> {
+        let mut used = false;
+        while !self.control.is_empty() {
+            let chain = match queue.pop(dma)? {
+                Some(chain) => chain,
+                // Wait for the driver to provide more buffers.
+                None => break,
+            };
+            let message = self.control.pop_front().expect("no control message");
+            // A message that doesn't fit in the buffer is dropped.
+            let len = match chain.write(dma, 0, &message)? {
+                len if len == message.len() => len,
+                _ => 0,
+            };
+            queue.push(dma, chain.head, len as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::*;
+    use std::sync::{Arc, Mutex};
+
+    /// The guest memory of the queues and the buffers.
+    const DATA: u64 = MEMORY_BASE + 0x8000;
+
+    /// A backend that records the bytes written to it.
+    struct Recorder(Arc<Mutex<Vec<u8>>>);
+
+    impl CharBackend for Recorder {
+        fn write(&mut self, data: &[u8]) -> io::Result<()> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(())
+        }
+
+        fn read(&mut self) -> Option<u8> {
+            None
+        }
+    }
+
+    /// Return a console with a port for each of `names`, and the bytes written to each port.
+    fn console(names: &[&str]) -> (VirtioConsole, Vec<Arc<Mutex<Vec<u8>>>>) {
+        let written: Vec<_> = names.iter().map(|_| Arc::default()).collect();
+        let ports = names
+            .iter()
+            .zip(&written)
+            .map(|(name, written)| {
+                let backend: Box<dyn CharBackend> = Box::new(Recorder(Arc::clone(written)));
+                (name.to_string(), backend)
+            })
+            .collect();
+        (VirtioConsole::new(ports), written)
+    }
+
+    /// Return a queue of 8 entries whose areas are at `base`.
+    fn queue(base: u64) -> Virtqueue {
+        Virtqueue {
+            num: 8,
+            ready: true,
+            desc: base,
+            driver: base + 0x100,
+            device: base + 0x200,
+            ..Virtqueue::default()
+        }
+    }
+
+    /// Make a chain of `buffers`, pairs of a buffer and whether it's writable, available in
+    /// `queue` as the driver does.
+    fn make_available(dma: &mut DmaContext, queue: &Virtqueue, buffers: &[(Buffer, bool)]) {
+        for (i, (buffer, writable)) in buffers.iter().enumerate() {
+            let desc = queue.desc + 16 * i as u64;
+            let mut flags = if *writable { VIRTQ_DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            dma.write(desc, 64, buffer.addr).unwrap();
+            dma.write(desc + 8, 32, buffer.len as u64).unwrap();
+            dma.write(desc + 12, 16, flags as u64).unwrap();
+            dma.write(desc + 14, 16, i as u64 + 1).unwrap();
+        }
+        let idx = dma.read(queue.driver + 2, 16).unwrap();
+        dma.write(queue.driver + 4 + 2 * (idx % 8), 16, 0).unwrap();
+        dma.write(queue.driver + 2, 16, (idx + 1) & 0xffff).unwrap();
+    }
+
+    /// Return the length of the last chain returned by the device in `queue`.
+    fn used_len(dma: &mut DmaContext, queue: &Virtqueue) -> u64 {
+        let slot = (queue.used_idx as u64 + 7) % 8;
+        dma.read(queue.device + 4 + 8 * slot + 4, 32).unwrap()
+    }
+
+    fn buffer(addr: u64, len: u32) -> Buffer {
+        Buffer { addr, len }
+    }
+
+    #[test]
+    fn long_transmit_is_copied_in_chunks() {
+        let memory = Memory::new(Vec::new(), 0x40000);
+        let mut dma = DmaContext::new(&memory);
+        let (mut console, written) = console(&[""]);
+        let mut queue = queue(MEMORY_BASE);
+        let data: Vec<u8> = (0..0x18000).map(|i| (i % 251) as u8).collect();
+        dma.write_bytes(DATA, &data).unwrap();
+        make_available(
+            &mut dma,
+            &queue,
+            &[
+                (buffer(DATA, 0x10000), false),
+                (buffer(DATA + 0x10000, 0x8000), false),
+            ],
+        );
+
+        assert!(console.process(1, &mut queue, &mut dma).unwrap());
+        assert_eq!(*written[0].lock().unwrap(), data);
+        assert_eq!(queue.used_idx, 1);
+        assert_eq!(used_len(&mut dma, &queue), 0);
+    }
+
+    #[test]
+    fn oversized_transmit_is_rejected() {
+        let memory = Memory::new(Vec::new(), 0x40000);
+        let mut dma = DmaContext::new(&memory);
+        let (mut console, written) = console(&[""]);
+        let mut queue = queue(MEMORY_BASE);
+        // Two buffers of 4 GiB, far beyond the memory.
+        make_available(
+            &mut dma,
+            &queue,
+            &[
+                (buffer(DATA, u32::MAX), false),
+                (buffer(DATA, u32::MAX), false),
+            ],
+        );
+
+        assert!(matches!(
+            console.process(1, &mut queue, &mut dma),
+            Err(Exception::LoadAccessFault(_))
+        ));
+        // The chunks before the one that crosses the end of the memory are sent.
+        assert_eq!(written[0].lock().unwrap().len(), 3 * TRANSFER_SIZE as usize);
+        assert_eq!(queue.used_idx, 0);
+    }
+
+    /// Send a control message from the driver to `console`.
+    fn send_control(
+        console: &mut VirtioConsole,
+        dma: &mut DmaContext,
+        queue: &mut Virtqueue,
+        port: u32,
+        event: u16,
+        value: u16,
+    ) {
+        let mut message = port.to_le_bytes().to_vec();
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        dma.write_bytes(DATA, &message).unwrap();
+        make_available(dma, queue, &[(buffer(DATA, CONTROL_SIZE as u32), false)]);
+        assert!(console.process(CONTROL_TRANSMITQ, queue, dma).unwrap());
+    }
+
+    /// Deliver the next control message of `console` to a buffer of `len` bytes, and return the
+    /// bytes written.
+    fn receive_control(
+        console: &mut VirtioConsole,
+        dma: &mut DmaContext,
+        queue: &mut Virtqueue,
+        len: u32,
+    ) -> Vec<u8> {
+        make_available(dma, queue, &[(buffer(DATA + 0x1000, len), true)]);
+        assert!(console.process(CONTROL_RECEIVEQ, queue, dma).unwrap());
+        let mut message = vec![0; used_len(dma, queue) as usize];
+        dma.read_bytes(DATA + 0x1000, &mut message).unwrap();
+        message
+    }
+
+    /// Return a control message with `event` and `value` for `port`, followed by `data`.
+    fn control(port: u32, event: u16, value: u16, data: &[u8]) -> Vec<u8> {
+        let mut message = port.to_le_bytes().to_vec();
+        message.extend_from_slice(&event.to_le_bytes());
+        message.extend_from_slice(&value.to_le_bytes());
+        message.extend_from_slice(data);
+        message
+    }
+
+    #[test]
+    fn queues_map_to_ports() {
+        assert_eq!(port_queues(0), (0, 1));
+        assert_eq!(port_queues(1), (4, 5));
+        assert_eq!(port_queues(2), (6, 7));
+        for port in 0..8 {
+            let (receiveq, transmitq) = port_queues(port);
+            assert_eq!(queue_port(receiveq), Some((port, false)));
+            assert_eq!(queue_port(transmitq), Some((port, true)));
+        }
+        assert_eq!(queue_port(CONTROL_RECEIVEQ), None);
+        assert_eq!(queue_port(CONTROL_TRANSMITQ), None);
+
+        let (console, _) = console(&["", "a", "b"]);
+        assert_eq!(console.queues(), 8);
+        assert_eq!(port_queues(2).1, console.queues() - 1);
+        let (console, _) = self::console(&[""]);
+        assert_eq!(console.queues(), 2);
+    }
+
+    #[test]
+    fn ports_are_added_and_named() {
+        let memory = Memory::new(Vec::new(), 0x40000);
+        let mut dma = DmaContext::new(&memory);
+        let (mut console, written) = console(&["", "org.test.0"]);
+        let mut transmitq = queue(MEMORY_BASE);
+        let mut receiveq = queue(MEMORY_BASE + 0x1000);
+        console.driver_ok(console.features());
+
+        send_control(
+            &mut console,
+            &mut dma,
+            &mut transmitq,
+            0,
+            VIRTIO_CONSOLE_DEVICE_READY,
+            1,
+        );
+        for port in 0..2 {
+            assert_eq!(
+                receive_control(&mut console, &mut dma, &mut receiveq, 64),
+                control(port, VIRTIO_CONSOLE_DEVICE_ADD, 0, &[])
+            );
+        }
+
+        send_control(
+            &mut console,
+            &mut dma,
+            &mut transmitq,
+            0,
+            VIRTIO_CONSOLE_PORT_READY,
+            1,
+        );
+        send_control(
+            &mut console,
+            &mut dma,
+            &mut transmitq,
+            1,
+            VIRTIO_CONSOLE_PORT_READY,
+            1,
+        );
+        let expected = [
+            control(0, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]),
+            control(0, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]),
+            control(1, VIRTIO_CONSOLE_PORT_NAME, 1, b"org.test.0"),
+            control(1, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]),
+        ];
+        for message in expected {
+            assert_eq!(
+                receive_control(&mut console, &mut dma, &mut receiveq, 64),
+                message
+            );
+        }
+        assert!(console.control.is_empty());
+
+        // The driver opening a port needs no reply, and a port that doesn't exist is ignored.
+        send_control(
+            &mut console,
+            &mut dma,
+            &mut transmitq,
+            1,
+            VIRTIO_CONSOLE_PORT_OPEN,
+            1,
+        );
+        send_control(
+            &mut console,
+            &mut dma,
+            &mut transmitq,
+            2,
+            VIRTIO_CONSOLE_PORT_READY,
+            1,
+        );
+        assert!(console.control.is_empty());
+        assert!(written.iter().all(|port| port.lock().unwrap().is_empty()));
+    }
+
+    #[test]
+    fn control_message_too_long_for_the_buffer_is_dropped() {
+        let memory = Memory::new(Vec::new(), 0x40000);
+        let mut dma = DmaContext::new(&memory);
+        let (mut console, _) = console(&["", "org.test.0"]);
+        let mut receiveq = queue(MEMORY_BASE + 0x1000);
+        console.driver_ok(console.features());
+        console.handle_control(1, VIRTIO_CONSOLE_PORT_READY, 1);
+
+        // The name doesn't fit in a buffer of the size of a message without a name.
+        let len = CONTROL_SIZE as u32;
+        assert!(receive_control(&mut console, &mut dma, &mut receiveq, len).is_empty());
+        assert_eq!(
+            receive_control(&mut console, &mut dma, &mut receiveq, len),
+            control(1, VIRTIO_CONSOLE_PORT_OPEN, 1, &[])
+        );
+        assert!(console.control.is_empty());
+    }
+}
@@ -0,0 +1,652 @@
+//! The virtio_input module contains a virtio input device, a keyboard behind the virtio-mmio
+//! transport. The keys are pressed and released by a script instead of a person, so that a test
+//! of an interactive guest runs unattended.
+//!
+//! A script has one command per line, and `#` starts a comment. Each command starts with a time
+//! in milliseconds since the driver got the device ready, and commands run in order:
+//!
+//! ```text
+//! 100 press KEY_LEFTCTRL
+//! 100 press c
+//! 120 release c
+//! 120 release KEY_LEFTCTRL
+//! 500 type ls -l\n
+//! ```
+//!
+//! A key is a name such as `KEY_ENTER`, a letter or a symbol such as `c` or `/`, or a Linux key
+//! code such as `28`. `type` presses and releases the keys of the rest of the line on a US
+//! keyboard, with `\n`, `\t` and `\\` as escapes.
+//!
+//! The virtio spec:
+//! https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.pdf
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::bus::*;
+use crate::clint::*;
+use crate::snapshot::*;
+use crate::trap::*;
+use crate::virtio::*;
+
+/// The device ID of an input device.
+const VIRTIO_ID_INPUT: u32 = 18;
+
+/// The index of the virtqueue for the events to the driver.
+const EVENTQ: usize = 0;
+/// The index of the virtqueue for the events from the driver, e.g. LED changes.
+const STATUSQ: usize = 1;
+
+// The values of `select` in the configuration space.
+const VIRTIO_INPUT_CFG_ID_NAME: u8 = 0x01;
+const VIRTIO_INPUT_CFG_ID_SERIAL: u8 = 0x02;
+const VIRTIO_INPUT_CFG_ID_DEVIDS: u8 = 0x03;
+const VIRTIO_INPUT_CFG_EV_BITS: u8 = 0x11;
+
+/// The offset of the data selected by `select` and `subsel` in the configuration space.
+// struct virtio_input_config {
+//   uint8 select;
+//   uint8 subsel;
+//   uint8 size;
+//   uint8 reserved[5];
+//   union {
+//     char string[128];
+//     uint8 bitmap[128];
+//     ...
+//   } u;
+// };
+const CONFIG_DATA: usize = 8;
+const CONFIG_SIZE: usize = CONFIG_DATA + 128;
+
+// Linux input event types and codes.
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const SYN_REPORT: u16 = 0;
+const BUS_VIRTUAL: u16 = 0x06;
+
+/// The name of the keyboard seen by the guest.
+const DEVICE_NAME: &str = "rvemu keyboard";
+
+/// The largest Linux key code accepted by scripts. The configuration space has room for the
+/// bitmap of 1024 codes, but the keys of a keyboard are below 256.
+const MAX_KEY_CODE: u16 = 255;
+
+/// The number of cycles in a millisecond of the script.
+const CYCLES_PER_MS: u64 = CLINT_TIMEBASE_FREQUENCY / 1000;
+
+/// The largest number of events waiting for the driver to provide buffers. Events after that are
+/// dropped, as a full event queue of a real keyboard does.
+const MAX_PENDING_EVENTS: usize = 1024;
+
+/// The names of Linux key codes, without `KEY_`.
+const KEY_NAMES: &[(&str, u16)] = &[
+    ("ESC", 1),
+    ("1", 2),
+    ("2", 3),
+    ("3", 4),
+    ("4", 5),
+    ("5", 6),
+    ("6", 7),
+    ("7", 8),
+    ("8", 9),
+    ("9", 10),
+    ("0", 11),
+    ("MINUS", 12),
+    ("EQUAL", 13),
+    ("BACKSPACE", 14),
+    ("TAB", 15),
+    ("Q", 16),
+    ("W", 17),
+    ("E", 18),
+    ("R", 19),
+    ("T", 20),
+    ("Y", 21),
+    ("U", 22),
+    ("I", 23),
+    ("O", 24),
+    ("P", 25),
+    ("LEFTBRACE", 26),
+    ("RIGHTBRACE", 27),
+    ("ENTER", 28),
+    ("LEFTCTRL", 29),
+    ("A", 30),
+    ("S", 31),
+    ("D", 32),
+    ("F", 33),
+    ("G", 34),
+    ("H", 35),
+    ("J", 36),
+    ("K", 37),
+    ("L", 38),
+    ("SEMICOLON", 39),
+    ("APOSTROPHE", 40),
+    ("GRAVE", 41),
+    ("LEFTSHIFT", 42),
+    ("BACKSLASH", 43),
+    ("Z", 44),
+    ("X", 45),
+    ("C", 46),
+    ("V", 47),
+    ("B", 48),
+    ("N", 49),
+    ("M", 50),
+    ("COMMA", 51),
+    ("DOT", 52),
+    ("SLASH", 53),
+    ("RIGHTSHIFT", 54),
+    ("LEFTALT", 56),
+    ("SPACE", 57),
+    ("CAPSLOCK", 58),
+    ("F1", 59),
+    ("F2", 60),
+    ("F3", 61),
+    ("F4", 62),
+    ("F5", 63),
+    ("F6", 64),
+    ("F7", 65),
+    ("F8", 66),
+    ("F9", 67),
+    ("F10", 68),
+    ("F11", 87),
+    ("F12", 88),
+    ("RIGHTCTRL", 97),
+    ("RIGHTALT", 100),
+    ("HOME", 102),
+    ("UP", 103),
+    ("PAGEUP", 104),
+    ("LEFT", 105),
+    ("RIGHT", 106),
+    ("END", 107),
+    ("DOWN", 108),
+    ("PAGEDOWN", 109),
+    ("INSERT", 110),
+    ("DELETE", 111),
+];
+
+/// The code of the left shift key.
+const KEY_LEFTSHIFT: u16 = 42;
+
+/// The unshifted and shifted characters of the symbol keys on a US keyboard.
+const SYMBOL_KEYS: &[(char, char, &str)] = &[
+    ('-', '_', "MINUS"),
+    ('=', '+', "EQUAL"),
+    ('[', '{', "LEFTBRACE"),
+    (']', '}', "RIGHTBRACE"),
+    (';', ':', "SEMICOLON"),
+    ('\'', '"', "APOSTROPHE"),
+    ('`', '~', "GRAVE"),
+    ('\\', '|', "BACKSLASH"),
+    (',', '<', "COMMA"),
+    ('.', '>', "DOT"),
+    ('/', '?', "SLASH"),
+    ('1', '!', "1"),
+    ('2', '@', "2"),
+    ('3', '#', "3"),
+    ('4', '$', "4"),
+    ('5', '%', "5"),
+    ('6', '^', "6"),
+    ('7', '&', "7"),
+    ('8', '*', "8"),
+    ('9', '(', "9"),
+    ('0', ')', "0"),
+];
+
+/// Return the code of the key named `name`, without `KEY_`.
+fn key_code(name: &str) -> Option<u16> {
+    KEY_NAMES
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, code)| *code)
+}
+
+/// Return the code of the key that types `c` on a US keyboard, and whether shift is needed.
+fn char_key(c: char) -> Option<(u16, bool)> {
+    match c {
+        'a'..='z' => Some((key_code(&c.to_string())?, false)),
+        'A'..='Z' => Some((key_code(&c.to_string())?, true)),
+        ' ' => Some((key_code("SPACE")?, false)),
+        '\n' => Some((key_code("ENTER")?, false)),
+        '\t' => Some((key_code("TAB")?, false)),
+        _ => SYMBOL_KEYS.iter().find_map(|(plain, shifted, name)| {
+            if c == *plain {
+                Some((key_code(name)?, false))
+            } else if c == *shifted {
+                Some((key_code(name)?, true))
+            } else {
+                None
+            }
+        }),
+    }
+}
+
+/// A key pressed or released by a script.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct KeyEvent {
+    /// The time in milliseconds since the driver got the device ready.
+    pub time: u64,
+    /// The Linux key code.
+    pub code: u16,
+    pub pressed: bool,
+}
+
+/// Parse the key given to `press` or `release`.
+fn parse_key(key: &str) -> Option<u16> {
+    if let Some(name) = key.strip_prefix("KEY_") {
+        return key_code(name);
+    }
+    let mut chars = key.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if !c.is_ascii_digit() {
+            return char_key(c.to_ascii_lowercase()).map(|(code, _)| code);
+        }
+    }
+    match key.parse::<u16>() {
+        Ok(code) if code > 0 && code <= MAX_KEY_CODE => Some(code),
+        _ => None,
+    }
+}
+
+/// Append the events that type `text` at `time` to `events`.
+fn type_text(text: &str, time: u64, events: &mut Vec<KeyEvent>) -> Option<()> {
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                '\\' => '\\',
+                _ => return None,
+            },
+            c => c,
+        };
+        let (code, shift) = char_key(c)?;
+        let mut key = |code, pressed| {
+            events.push(KeyEvent {
+                time,
+                code,
+                pressed,
+            })
+        };
+        if shift {
+            key(KEY_LEFTSHIFT, true);
+        }
+        key(code, true);
+        key(code, false);
+        if shift {
+            key(KEY_LEFTSHIFT, false);
+        }
+    }
+    Some(())
+}
+
+/// Parse a script into the key events in order.
+pub fn parse_key_script(script: &str) -> io::Result<Vec<KeyEvent>> {
+    let mut events = Vec::new();
+    let mut last = 0;
+    for (i, line) in script.lines().enumerate() {
+        let line = line.trim_start();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("key script line {}: {}", i + 1, message),
+            )
+        };
+        let mut parts = line.splitn(3, ' ');
+        let time = parts
+            .next()
+            .and_then(|time| time.parse::<u64>().ok())
+            .ok_or_else(|| error("invalid time"))?;
+        if time < last {
+            return Err(error("the time goes backwards"));
+        }
+        last = time;
+        let command = parts.next().unwrap_or("");
+        // `type` keeps the spaces in the text.
+        let argument = parts.next().unwrap_or("");
+        match command {
+            "press" | "release" => {
+                let code = parse_key(argument.trim()).ok_or_else(|| error("unknown key"))?;
+                events.push(KeyEvent {
+                    time,
+                    code,
+                    pressed: command == "press",
+                });
+            }
+            "type" => type_text(argument, time, &mut events)
+                .ok_or_else(|| error("the text can't be typed"))?,
+            _ => return Err(error("unknown command")),
+        }
+    }
+    Ok(events)
+}
+
+/// A virtio keyboard whose keys are pressed and released by a script.
+pub struct VirtioInput {
+    events: Vec<KeyEvent>,
+    /// The index of the next event of the script.
+    next: usize,
+    /// The number of cycles since the driver got the device ready.
+    cycles: u64,
+    /// The events sent to the driver when it provides buffers, as `(type, code, value)`.
+    pending: VecDeque<(u16, u16, u32)>,
+    select: u8,
+    subsel: u8,
+}
+
+impl VirtioDevice for VirtioInput {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_INPUT
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queues(&self) -> usize {
+        2
+    }
+
+    fn read_config(&self, offset: u64, size: u64) -> u64 {
+        let mut config = [0; CONFIG_SIZE];
+        config[0] = self.select;
+        config[1] = self.subsel;
+        let data = &mut config[CONFIG_DATA..];
+        let len = match (self.select, self.subsel as u16) {
+            (VIRTIO_INPUT_CFG_ID_NAME, _) => {
+                data[..DEVICE_NAME.len()].copy_from_slice(DEVICE_NAME.as_bytes());
+                DEVICE_NAME.len()
+            }
+            (VIRTIO_INPUT_CFG_ID_SERIAL, _) => {
+                data[0] = b'0';
+                1
+            }
+            (VIRTIO_INPUT_CFG_ID_DEVIDS, _) => {
+                // struct virtio_input_devids {
+                //   uint16 bustype;
+                //   uint16 vendor;
+                //   uint16 product;
+                //   uint16 version;
+                // };
+                data[..2].copy_from_slice(&BUS_VIRTUAL.to_le_bytes());
+                data[4..6].copy_from_slice(&1u16.to_le_bytes());
+                data[6..8].copy_from_slice(&1u16.to_le_bytes());
+                8
+            }
+            (VIRTIO_INPUT_CFG_EV_BITS, EV_KEY) => {
+                for code in 1..=MAX_KEY_CODE as usize {
+                    data[code / 8] |= 1 << (code % 8);
+                }
+                MAX_KEY_CODE as usize / 8 + 1
+            }
+            // Nothing else is supported, e.g. relative axes of a mouse.
+            _ => 0,
+        };
+        config[2] = len as u8;
+        read_config_bytes(&config, offset, size)
+    }
+
+    fn write_config(&mut self, offset: u64, _size: u64, value: u64) {
+        match offset {
+            0 => self.select = value as u8,
+            1 => self.subsel = value as u8,
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        // The script starts over when the driver gets the device ready again.
+        self.next = 0;
+        self.cycles = 0;
+        self.pending.clear();
+    }
+
+    fn process(
+        &mut self,
+        index: usize,
+        queue: &mut Virtqueue,
+        dma: &mut DmaContext,
+    ) -> Result<bool, Exception> {
+        match index {
+            EVENTQ => self.deliver(queue, dma),
+            STATUSQ => {
+                // The LEDs aren't shown anywhere.
+                let mut used = false;
+                while let Some(chain) = queue.pop(dma)? {
+                    queue.push(dma, chain.head, 0)?;
+                    used = true;
+                }
+                Ok(used)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn poll(
+        &mut self,
+        cycles: u64,
+        queues: &mut [Virtqueue],
+        dma: &mut DmaContext,
+    ) -> Result<bool, Exception> {
+        self.cycles += cycles;
+        let now = self.cycles / CYCLES_PER_MS;
+        while let Some(event) = self.events.get(self.next) {
+            if event.time > now {
+                break;
+            }
+            self.next += 1;
+            if self.pending.len() < MAX_PENDING_EVENTS {
+                self.pending
+                    .push_back((EV_KEY, event.code, event.pressed as u32));
+                self.pending.push_back((EV_SYN, SYN_REPORT, 0));
+            }
+        }
+        match queues.get_mut(EVENTQ) {
+            Some(queue) if queue.ready && !self.pending.is_empty() => self.deliver(queue, dma),
+            _ => Ok(false),
+        }
+    }
+
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        snapshot
+            .u64(self.next as u64)
+            .u64(self.cycles)
+            .u64(self.pending.len() as u64);
+        for (kind, code, value) in &self.pending {
+            snapshot.u16(*kind).u16(*code).u32(*value);
+        }
+        snapshot.u8(self.select).u8(self.subsel);
+        Ok(())
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        // The script is part of the configuration, and it goes on from the saved position.
+        self.next = (snapshot.u64()? as usize).min(self.events.len());
+        self.cycles = snapshot.u64()?;
+        self.pending.clear();
+        for _ in 0..snapshot.u64()? {
+            self.pending
+                .push_back((snapshot.u16()?, snapshot.u16()?, snapshot.u32()?));
+        }
+        self.select = snapshot.u8()?;
+        self.subsel = snapshot.u8()?;
+        Ok(())
+    }
+}
+
+impl VirtioInput {
+    /// Create a new keyboard that sends `events` in order.
+    pub fn new(events: Vec<KeyEvent>) -> Self {
+        Self {
+            events,
+            next: 0,
+            cycles: 0,
+            pending: VecDeque::new(),
+            select: 0,
+            subsel: 0,
+        }
+    }
+
+    /// Create a new keyboard that runs the script in the file at `path`.
+    pub fn from_script(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(parse_key_script(&fs::read_to_string(path)?)?))
+    }
+
+    /// Deliver the pending events to the buffers made available in the event queue, one event
+    /// per buffer.
+    fn deliver(&mut self, queue: &mut Virtqueue, dma: &mut DmaContext) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(&(kind, code, value)) = self.pending.front() {
+            let chain = match queue.pop(dma)? {
+                Some(chain) => chain,
+                // Wait for the driver to provide more buffers.
+                None => break,
+            };
+            self.pending.pop_front();
+            // struct virtio_input_event {
+            //   uint16 type;
+            //   uint16 code;
+            //   uint32 value;
+            // };
+            let mut event = [0; 8];
+            event[..2].copy_from_slice(&kind.to_le_bytes());
+            event[2..4].copy_from_slice(&code.to_le_bytes());
+            event[4..].copy_from_slice(&value.to_le_bytes());
+            let len = chain.write(dma, 0, &event)?;
+            queue.push(dma, chain.head, len as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(time: u64, code: u16) -> KeyEvent {
+        KeyEvent {
+            time,
+            code,
+            pressed: true,
+        }
+    }
+
+    fn release(time: u64, code: u16) -> KeyEvent {
+        KeyEvent {
+            time,
+            code,
+            pressed: false,
+        }
+    }
+
+    /// Return the message of the error of `script`.
+    fn error(script: &str) -> String {
+        parse_key_script(script).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn keys_are_names_characters_or_codes() {
+        let script = "# Ctrl-C\n\
+                      100 press KEY_LEFTCTRL\n\
+                      \n\
+                      100 press c\n\
+                      120 release C\n\
+                      120 release KEY_leftctrl\n\
+                      130 press /\n\
+                      140 press 1\n\
+                      150 press 255\n";
+        assert_eq!(
+            parse_key_script(script).unwrap(),
+            [
+                press(100, 29),
+                press(100, 46),
+                release(120, 46),
+                release(120, 29),
+                press(130, 53),
+                // A digit is a key code, not the key of the digit.
+                press(140, 1),
+                press(150, MAX_KEY_CODE),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert_eq!(error("0 press KEY_NOPE"), "key script line 1: unknown key");
+        assert_eq!(error("0 press 256"), "key script line 1: unknown key");
+        assert_eq!(error("0 press 0"), "key script line 1: unknown key");
+        assert_eq!(error("0 press é"), "key script line 1: unknown key");
+        assert_eq!(error("0 press"), "key script line 1: unknown key");
+    }
+
+    #[test]
+    fn text_is_typed_with_escapes() {
+        let events = parse_key_script("5 type a\\tb\\n\\\\").unwrap();
+        let codes: Vec<(u16, bool)> = events
+            .iter()
+            .map(|event| (event.code, event.pressed))
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                (30, true),
+                (30, false),
+                (15, true),
+                (15, false),
+                (48, true),
+                (48, false),
+                (28, true),
+                (28, false),
+                (43, true),
+                (43, false),
+            ]
+        );
+        assert!(events.iter().all(|event| event.time == 5));
+    }
+
+    #[test]
+    fn shifted_characters_hold_shift() {
+        assert_eq!(
+            parse_key_script("0 type A?").unwrap(),
+            [
+                press(0, KEY_LEFTSHIFT),
+                press(0, 30),
+                release(0, 30),
+                release(0, KEY_LEFTSHIFT),
+                press(0, KEY_LEFTSHIFT),
+                press(0, 53),
+                release(0, 53),
+                release(0, KEY_LEFTSHIFT),
+            ]
+        );
+        // The spaces after `type` are kept.
+        assert_eq!(
+            parse_key_script("0 type  ").unwrap(),
+            [press(0, 57), release(0, 57)]
+        );
+    }
+
+    #[test]
+    fn untypable_text_is_rejected() {
+        let message = "key script line 1: the text can't be typed";
+        assert_eq!(error("0 type \\x"), message);
+        assert_eq!(error("0 type abc\\"), message);
+        assert_eq!(error("0 type é"), message);
+    }
+
+    #[test]
+    fn time_must_not_go_backwards() {
+        assert!(parse_key_script("10 press a\n10 release a").is_ok());
+        assert_eq!(
+            error("10 press a\n# comment\n9 release a"),
+            "key script line 3: the time goes backwards"
+        );
+        assert_eq!(error("-1 press a"), "key script line 1: invalid time");
+        assert_eq!(error("0 hold a"), "key script line 1: unknown command");
+    }
+}
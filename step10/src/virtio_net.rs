@@ -0,0 +1,418 @@
+//! The virtio_net module contains a virtio network device, an Ethernet card behind the virtio-mmio
+//! transport. The frames are sent and received through a `NetBackend`.
+//!
+//! The virtio spec:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
+
+use std::collections::VecDeque;
+use std::io;
+
+use crate::bus::*;
+use crate::net::*;
+use crate::snapshot::*;
+use crate::trap::*;
+use crate::virtio::*;
+
+/// The device ID of a network device.
+const VIRTIO_ID_NET: u32 = 1;
+
+/// The feature bit which means the device has a MAC address in the configuration space.
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+/// The feature bit which means the device has the link status in the configuration space.
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+
+/// The link status bit which means the link is up.
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// The index of the virtqueue for the received frames.
+const RECEIVEQ: usize = 0;
+/// The index of the virtqueue for the frames to send.
+const TRANSMITQ: usize = 1;
+
+/// The size of the header before each frame. The legacy header doesn't have `num_buffers`.
+// struct virtio_net_hdr {
+//   uint8 flags;
+//   uint8 gso_type;
+//   uint16 hdr_len;
+//   uint16 gso_size;
+//   uint16 csum_start;
+//   uint16 csum_offset;
+//   uint16 num_buffers;
+// };
+const VIRTIO_NET_HDR_SIZE: usize = 12;
+const VIRTIO_NET_LEGACY_HDR_SIZE: usize = 10;
+
+/// The default MAC address, same as QEMU.
+pub const DEFAULT_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+/// The number of cycles between checks for frames received by the backend.
+const POLL_INTERVAL: u64 = 10_000;
+/// The largest number of received frames waiting for the driver to provide buffers. Frames
+/// received after that are dropped.
+const MAX_PENDING_FRAMES: usize = 256;
+
+/// A virtio network device.
+pub struct VirtioNet {
+    mac: [u8; 6],
+    backend: Box<dyn NetBackend>,
+    /// The size of the header before each frame, which depends on the features.
+    header_size: usize,
+    /// The frames received by the backend and not delivered to the driver yet.
+    pending: VecDeque<Vec<u8>>,
+    /// The number of cycles since the last check for received frames.
+    cycles: u64,
+    /// The buffer to receive a frame from the backend.
+    buf: Vec<u8>,
+    /// The number of frames lost because of a backend error or because the driver couldn't take
+    /// them.
+    dropped: u64,
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_NET
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC | VIRTIO_NET_F_STATUS
+    }
+
+    fn queues(&self) -> usize {
+        2
+    }
+
+    fn read_config(&self, offset: u64, size: u64) -> u64 {
+        // struct virtio_net_config {
+        //   uint8 mac[6];
+        //   uint16 status;
+        //   ...
+        // };
+        let mut config = [0; 8];
+        config[..6].copy_from_slice(&self.mac);
+        config[6..].copy_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        read_config_bytes(&config, offset, size)
+    }
+
+    fn reset(&mut self) {
+        self.pending.clear();
+    }
+
+    fn driver_ok(&mut self, features: u64) {
+        self.header_size = if features & VIRTIO_F_VERSION_1 != 0 {
+            VIRTIO_NET_HDR_SIZE
+        } else {
+            VIRTIO_NET_LEGACY_HDR_SIZE
+        };
+    }
+
+    fn process(
+        &mut self,
+        index: usize,
+        queue: &mut Virtqueue,
+        dma: &mut DmaContext,
+    ) -> Result<bool, Exception> {
+        match index {
+            RECEIVEQ => self.deliver(queue, dma),
+            TRANSMITQ => self.transmit(queue, dma),
+            _ => Ok(false),
+        }
+    }
+
+    fn poll(
+        &mut self,
+        cycles: u64,
+        queues: &mut [Virtqueue],
+        dma: &mut DmaContext,
+    ) -> Result<bool, Exception> {
+        self.cycles += cycles;
+        if self.cycles < POLL_INTERVAL {
+            return Ok(false);
+        }
+        self.cycles = 0;
+
+        loop {
+            match self.backend.recv(&mut self.buf) {
+                Ok(Some(len)) if self.pending.len() < MAX_PENDING_FRAMES => {
+                    self.pending.push_back(self.buf[..len].to_vec())
+                }
+                // The driver can't keep up, so the frame is dropped.
+                Ok(Some(_)) => self.dropped += 1,
+                Ok(None) => break,
+                // The frame, if any, is lost, and the next ones are received at the next poll.
+                Err(_) => {
+                    self.dropped += 1;
+                    break;
+                }
+            }
+        }
+        match queues.get_mut(RECEIVEQ) {
+            Some(queue) if queue.ready => self.deliver(queue, dma),
+            _ => Ok(false),
+        }
+    }
+
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        snapshot
+            .u64(self.header_size as u64)
+            .u64(self.cycles)
+            .u64(self.pending.len() as u64);
+        for frame in &self.pending {
+            snapshot.bytes(frame);
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        self.header_size = match snapshot.u64()? as usize {
+            size @ (VIRTIO_NET_HDR_SIZE | VIRTIO_NET_LEGACY_HDR_SIZE) => size,
+            _ => return Err(snapshot_error("invalid virtio-net header size")),
+        };
+        self.cycles = snapshot.u64()?;
+        self.pending.clear();
+        for _ in 0..snapshot.u64()? {
+            self.pending.push_back(snapshot.bytes()?.to_vec());
+        }
+        Ok(())
+    }
+}
+
+impl VirtioNet {
+    /// Create a new network device with the MAC address `mac` connected to `backend`.
+    pub fn new(mac: [u8; 6], backend: Box<dyn NetBackend>) -> Self {
+        Self {
+            mac,
+            backend,
+            header_size: VIRTIO_NET_HDR_SIZE,
+            pending: VecDeque::new(),
+            cycles: 0,
+            buf: vec![0; MAX_FRAME_SIZE],
+            dropped: 0,
+        }
+    }
+
+    /// Return the number of frames lost so far, sent or received.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Send the frames made available in the transmit queue to the backend.
+    fn transmit(&mut self, queue: &mut Virtqueue, dma: &mut DmaContext) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(dma)? {
+            // The header is ignored because no offloading feature is offered.
+            let len = chain.readable_len().saturating_sub(self.header_size as u64);
+            if len > 0 && len <= MAX_FRAME_SIZE as u64 {
+                let mut frame = vec![0; len as usize];
+                chain.read(dma, self.header_size as u64, &mut frame)?;
+                if self.backend.send(&frame).is_err() {
+                    self.dropped += 1;
+                }
+            } else {
+                self.dropped += 1;
+            }
+            queue.push(dma, chain.head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    /// Deliver the received frames to the buffers made available in the receive queue.
+    fn deliver(&mut self, queue: &mut Virtqueue, dma: &mut DmaContext) -> Result<bool, Exception> {
+        let mut used = false;
+        while !self.pending.is_empty() {
+            let chain = match queue.pop(dma)? {
+                Some(chain) => chain,
+                // Wait for the driver to provide more buffers.
+                None => break,
+            };
+            let frame = self.pending.pop_front().expect("no pending frame");
+            let len = self.header_size + frame.len();
+            if chain.writable_len() < len as u64 {
+                // The frame doesn't fit in the buffer and is dropped.
+                self.dropped += 1;
+                queue.push(dma, chain.head, 0)?;
+            } else {
+                let mut header = [0; VIRTIO_NET_HDR_SIZE];
+                // `num_buffers` is always 1 because the buffers aren't merged.
+                header[10] = 1;
+                chain.write(dma, 0, &header[..self.header_size])?;
+                chain.write(dma, self.header_size as u64, &frame)?;
+                queue.push(dma, chain.head, len as u32)?;
+            }
+            used = true;
+        }
+        Ok(used)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::*;
+
+    /// The guest memory of the buffers, after the queues.
+    const DATA: u64 = MEMORY_BASE + 0x8000;
+
+    /// Return a network device connected to a peer backend.
+    fn net() -> (VirtioNet, UnixDatagramBackend) {
+        let (backend, peer) = UnixDatagramBackend::pair().unwrap();
+        (VirtioNet::new(DEFAULT_MAC, Box::new(backend)), peer)
+    }
+
+    /// Return the receive and the transmit queues of 8 entries.
+    fn queues() -> Vec<Virtqueue> {
+        [MEMORY_BASE, MEMORY_BASE + 0x1000]
+            .iter()
+            .map(|&base| Virtqueue {
+                num: 8,
+                ready: true,
+                desc: base,
+                driver: base + 0x100,
+                device: base + 0x200,
+                ..Virtqueue::default()
+            })
+            .collect()
+    }
+
+    /// Make a chain of `buffers`, pairs of a buffer and whether it's writable, available in
+    /// `queue` as the driver does.
+    fn make_available(dma: &mut DmaContext, queue: &Virtqueue, buffers: &[(Buffer, bool)]) {
+        for (i, (buffer, writable)) in buffers.iter().enumerate() {
+            let desc = queue.desc + 16 * i as u64;
+            let mut flags = if *writable { VIRTQ_DESC_F_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+            dma.write(desc, 64, buffer.addr).unwrap();
+            dma.write(desc + 8, 32, buffer.len as u64).unwrap();
+            dma.write(desc + 12, 16, flags as u64).unwrap();
+            dma.write(desc + 14, 16, i as u64 + 1).unwrap();
+        }
+        let idx = dma.read(queue.driver + 2, 16).unwrap();
+        dma.write(queue.driver + 4 + 2 * (idx % 8), 16, 0).unwrap();
+        dma.write(queue.driver + 2, 16, (idx + 1) & 0xffff).unwrap();
+    }
+
+    /// Return the length of the last chain returned by the device in `queue`.
+    fn used_len(dma: &mut DmaContext, queue: &Virtqueue) -> u64 {
+        let slot = (queue.used_idx as u64 + 7) % 8;
+        dma.read(queue.device + 4 + 8 * slot + 4, 32).unwrap()
+    }
+
+    fn buffer(addr: u64, len: u32) -> Buffer {
+        Buffer { addr, len }
+    }
+
+    /// Receive the frames sent by the peer into the receive queue.
+    fn poll(net: &mut VirtioNet, queues: &mut [Virtqueue], dma: &mut DmaContext) {
+        net.poll(POLL_INTERVAL, queues, dma).unwrap();
+    }
+
+    #[test]
+    fn transmitted_frame_is_sent_without_the_header() {
+        for (features, header_size) in [(VIRTIO_F_VERSION_1, 12), (0, 10)] {
+            let memory = Memory::new(Vec::new(), 0x10000);
+            let mut dma = DmaContext::new(&memory);
+            let (mut net, mut peer) = net();
+            net.driver_ok(features);
+            let mut queues = queues();
+            // The header and the start of the frame in one buffer, the rest in another.
+            dma.write_bytes(DATA, &[0xff; 12]).unwrap();
+            dma.write_bytes(DATA + header_size, b"ethernet ").unwrap();
+            dma.write_bytes(DATA + 0x100, b"frame").unwrap();
+            let len = header_size as u32 + 9;
+            let readable = [(buffer(DATA, len), false), (buffer(DATA + 0x100, 5), false)];
+            make_available(&mut dma, &queues[TRANSMITQ], &readable);
+            assert!(net
+                .process(TRANSMITQ, &mut queues[TRANSMITQ], &mut dma)
+                .unwrap());
+
+            let mut frame = [0; 64];
+            let len = peer.recv(&mut frame).unwrap().expect("no frame");
+            assert_eq!(frame[..len], *b"ethernet frame");
+            assert_eq!(used_len(&mut dma, &queues[TRANSMITQ]), 0);
+
+            // A chain without a frame after the header isn't sent.
+            let header = [(buffer(DATA, header_size as u32), false)];
+            make_available(&mut dma, &queues[TRANSMITQ], &header);
+            net.process(TRANSMITQ, &mut queues[TRANSMITQ], &mut dma)
+                .unwrap();
+            assert_eq!(peer.recv(&mut frame).unwrap(), None);
+            assert_eq!(net.dropped(), 1);
+        }
+    }
+
+    #[test]
+    fn received_frame_is_written_after_the_header() {
+        for (features, header_size) in [(VIRTIO_F_VERSION_1, 12), (0, 10)] {
+            let memory = Memory::new(Vec::new(), 0x10000);
+            let mut dma = DmaContext::new(&memory);
+            let (mut net, mut peer) = net();
+            net.driver_ok(features);
+            let mut queues = queues();
+            dma.write_bytes(DATA, &[0xff; 64]).unwrap();
+            peer.send(b"received frame").unwrap();
+            // Nothing is received before the poll interval.
+            assert!(!net.poll(1, &mut queues, &mut dma).unwrap());
+
+            make_available(&mut dma, &queues[RECEIVEQ], &[(buffer(DATA, 64), true)]);
+            poll(&mut net, &mut queues, &mut dma);
+            let len = header_size + 14;
+            assert_eq!(used_len(&mut dma, &queues[RECEIVEQ]), len);
+            let mut data = vec![0; len as usize + 1];
+            dma.read_bytes(DATA, &mut data).unwrap();
+            let mut expected = vec![0; header_size as usize];
+            if header_size == 12 {
+                // num_buffers
+                expected[10] = 1;
+            }
+            expected.extend_from_slice(b"received frame");
+            expected.push(0xff);
+            assert_eq!(data, expected);
+            assert_eq!(net.dropped(), 0);
+        }
+    }
+
+    #[test]
+    fn received_frame_too_large_for_the_buffer_is_dropped() {
+        let memory = Memory::new(Vec::new(), 0x10000);
+        let mut dma = DmaContext::new(&memory);
+        let (mut net, mut peer) = net();
+        net.driver_ok(VIRTIO_F_VERSION_1);
+        let mut queues = queues();
+        peer.send(&[1; 20]).unwrap();
+        peer.send(&[2; 4]).unwrap();
+        make_available(&mut dma, &queues[RECEIVEQ], &[(buffer(DATA, 16), true)]);
+        poll(&mut net, &mut queues, &mut dma);
+        assert_eq!(used_len(&mut dma, &queues[RECEIVEQ]), 0);
+        assert_eq!(net.dropped(), 1);
+
+        // The next frame fits.
+        make_available(&mut dma, &queues[RECEIVEQ], &[(buffer(DATA, 16), true)]);
+        net.process(RECEIVEQ, &mut queues[RECEIVEQ], &mut dma)
+            .unwrap();
+        assert_eq!(used_len(&mut dma, &queues[RECEIVEQ]), 16);
+        assert_eq!(dma.read(DATA + 12, 32).unwrap(), 0x0202_0202);
+    }
+
+    #[test]
+    fn pending_frames_are_limited() {
+        let memory = Memory::new(Vec::new(), 0x10000);
+        let mut dma = DmaContext::new(&memory);
+        let (mut net, mut peer) = net();
+        let mut queues = queues();
+        // The driver provides no buffer, and the frames are received in rounds that fit in the
+        // socket buffer.
+        for round in 0..3 {
+            for i in 0..100 {
+                peer.send(&[round, i]).unwrap();
+            }
+            poll(&mut net, &mut queues, &mut dma);
+        }
+        assert_eq!(net.pending.len(), MAX_PENDING_FRAMES);
+        assert_eq!(net.dropped(), 300 - MAX_PENDING_FRAMES as u64);
+        // The oldest frames are kept.
+        assert_eq!(net.pending.front().unwrap(), &[0, 0]);
+        assert_eq!(net.pending.back().unwrap(), &[2, 55]);
+    }
+}
@@ -0,0 +1,197 @@
+//! The virtio_rng module contains a virtio entropy device behind the virtio-mmio transport. The
+//! bytes come from a pseudorandom generator on the host, which is seeded so that a run can be
+//! repeated.
+//!
+//! The virtio spec:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+
+use crate::bus::*;
+use crate::snapshot::*;
+use crate::trap::*;
+use crate::virtio::*;
+
+/// The device ID of an entropy device.
+const VIRTIO_ID_RNG: u32 = 4;
+
+/// The largest number of bytes written to a buffer at once, so that a huge buffer doesn't stall
+/// the machine.
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// A xoshiro256** generator. It isn't cryptographically secure, but it's enough for a guest
+/// kernel to initialize its own generator.
+struct Xoshiro256 {
+    s: [u64; 4],
+}
+
+impl Xoshiro256 {
+    /// Create a generator whose state is expanded from `seed` with splitmix64.
+    fn new(mut seed: u64) -> Self {
+        let mut s = [0; 4];
+        for word in s.iter_mut() {
+            seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *word = z ^ (z >> 31);
+        }
+        Self { s }
+    }
+
+    fn next(&mut self) -> u64 {
+        let result = self.s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.s[1] << 17;
+        self.s[2] ^= self.s[0];
+        self.s[3] ^= self.s[1];
+        self.s[1] ^= self.s[2];
+        self.s[0] ^= self.s[3];
+        self.s[2] ^= t;
+        self.s[3] = self.s[3].rotate_left(45);
+        result
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+/// A virtio entropy device.
+pub struct VirtioRng {
+    rng: Xoshiro256,
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_RNG
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn queues(&self) -> usize {
+        1
+    }
+
+    fn process(
+        &mut self,
+        _index: usize,
+        queue: &mut Virtqueue,
+        dma: &mut DmaContext,
+    ) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(chain) = queue.pop(dma)? {
+            let mut data = vec![0; (chain.writable_len() as usize).min(MAX_REQUEST_SIZE)];
+            self.rng.fill(&mut data);
+            let len = chain.write(dma, 0, &data)?;
+            queue.push(dma, chain.head, len as u32)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    fn save(&mut self, snapshot: &mut SnapshotWriter) -> io::Result<()> {
+        for word in self.rng.s.iter() {
+            snapshot.u64(*word);
+        }
+        Ok(())
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> io::Result<()> {
+        // The guest gets the same bytes as after the snapshot was taken.
+        for word in self.rng.s.iter_mut() {
+            *word = snapshot.u64()?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioRng {
+    /// Create a new entropy device. The same `seed` gives the same bytes, and None gives a seed
+    /// that differs between runs.
+    pub fn new(seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            // The keys of `RandomState` are random for each process.
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u64(0);
+            hasher.finish()
+        });
+        Self {
+            rng: Xoshiro256::new(seed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::*;
+
+    const DESC: u64 = MEMORY_BASE;
+    const DRIVER: u64 = MEMORY_BASE + 0x1000;
+    const DEVICE: u64 = MEMORY_BASE + 0x2000;
+    const DATA: u64 = MEMORY_BASE + 0x4000;
+
+    /// Make a writable buffer of `len` bytes at `DATA` available, let `rng` fill it, and return the
+    /// bytes written.
+    fn request(rng: &mut VirtioRng, len: u32) -> Vec<u8> {
+        let memory = Memory::new(Vec::new(), 0x40000);
+        let mut dma = DmaContext::new(&memory);
+        let mut queue = Virtqueue {
+            num: 8,
+            ready: true,
+            desc: DESC,
+            driver: DRIVER,
+            device: DEVICE,
+            ..Virtqueue::default()
+        };
+        dma.write(DESC, 64, DATA).unwrap();
+        dma.write(DESC + 8, 32, len as u64).unwrap();
+        dma.write(DESC + 12, 16, VIRTQ_DESC_F_WRITE as u64).unwrap();
+        dma.write(DRIVER + 2, 16, 1).unwrap();
+
+        assert!(rng.process(0, &mut queue, &mut dma).unwrap());
+        let mut data = vec![0; dma.read(DEVICE + 8, 32).unwrap() as usize];
+        dma.read_bytes(DATA, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn buffer_is_filled_from_the_seed() {
+        let mut rng = VirtioRng::new(Some(1));
+        let data = request(&mut rng, 100);
+        assert_eq!(data.len(), 100);
+        assert_eq!(request(&mut VirtioRng::new(Some(1)), 100), data);
+        assert_ne!(request(&mut VirtioRng::new(Some(2)), 100), data);
+        // The next request continues the sequence.
+        assert_ne!(request(&mut rng, 100), data);
+    }
+
+    #[test]
+    fn large_buffer_is_filled_up_to_the_limit() {
+        let mut rng = VirtioRng::new(Some(1));
+        assert_eq!(request(&mut rng, 0x20000).len(), MAX_REQUEST_SIZE);
+    }
+
+    #[test]
+    fn restored_rng_repeats_the_bytes() {
+        let mut rng = VirtioRng::new(Some(1));
+        request(&mut rng, 16);
+        let mut snapshot = SnapshotWriter::new();
+        rng.save(&mut snapshot).unwrap();
+        let snapshot = snapshot.into_bytes();
+        let expected = request(&mut rng, 100);
+
+        let mut restored = VirtioRng::new(Some(2));
+        let mut reader = SnapshotReader::new(&snapshot);
+        restored.restore(&mut reader).unwrap();
+        reader.finish().unwrap();
+        assert_eq!(request(&mut restored, 100), expected);
+    }
+}